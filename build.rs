@@ -3,6 +3,7 @@ use std::io::{Result, Write};
 use std::path::PathBuf;
 
 fn main() -> Result<()> {
+    println!("cargo:rerun-if-changed=build.rs");
     gen_vector_asm()?;
     Ok(())
 }
@@ -5,15 +5,21 @@ use x86::msr::{rdmsr, wrmsr};
 #[derive(Debug, Copy, Clone)]
 #[allow(non_camel_case_types)]
 pub enum Msr {
+    IA32_APIC_BASE = 0x1b,
     IA32_FEATURE_CONTROL = 0x3a,
+    IA32_PMC0 = 0xc1,
 
     IA32_SYSENTER_CS = 0x174,
     IA32_SYSENTER_ESP = 0x175,
     IA32_SYSENTER_EIP = 0x176,
+    IA32_PERFEVTSEL0 = 0x186,
 
     IA32_PAT = 0x277,
     IA32_MTRR_DEF_TYPE = 0x2ff,
+    IA32_PERF_CAPABILITIES = 0x345,
+    IA32_PERF_GLOBAL_STATUS = 0x38e,
     IA32_PERF_GLOBAL_CTRL = 0x38f,
+    IA32_PERF_GLOBAL_OVF_CTRL = 0x390,
 
     IA32_VMX_BASIC = 0x480,
     IA32_VMX_PINBASED_CTLS = 0x481,
@@ -31,6 +37,16 @@ pub enum Msr {
     IA32_VMX_TRUE_PROCBASED_CTLS = 0x48e,
     IA32_VMX_TRUE_EXIT_CTLS = 0x48f,
     IA32_VMX_TRUE_ENTRY_CTLS = 0x490,
+    IA32_A_PMC0 = 0x4c1,
+    IA32_TSC_DEADLINE = 0x6e0,
+
+    IA32_X2APIC_APICID = 0x802,
+    IA32_X2APIC_ICR = 0x830,
+    IA32_X2APIC_LVT_TIMER = 0x832,
+    IA32_X2APIC_LVT_PMI = 0x834,
+    IA32_X2APIC_INIT_COUNT = 0x838,
+    IA32_X2APIC_CUR_COUNT = 0x839,
+    IA32_X2APIC_DIV_CONF = 0x83e,
 
     IA32_EFER = 0xc000_0080,
     IA32_STAR = 0xc000_0081,
@@ -41,6 +57,7 @@ pub enum Msr {
     IA32_GS_BASE = 0xc000_0101,
     IA32_KERNEL_GSBASE = 0xc000_0102,
     IA32_TSC_AUX = 0xc000_0103,
+    TSC_RATIO = 0xc000_0104,
 
     // SVM Related MSRs:
     VM_CR = 0xc001_0114,
@@ -48,6 +65,7 @@ pub enum Msr {
     VM_HSAVE_PA = 0xc001_0117,
 
     PERF_EVT_SEL0 = 0xc001_0200,
+    PERF_CTR0 = 0xc001_0201,
     PERF_EVT_SEL1 = 0xc001_0202,
     PERF_EVT_SEL2 = 0xc001_0204,
     PERF_EVT_SEL3 = 0xc001_0206,
@@ -0,0 +1,307 @@
+//! The local APIC timer, which drives the hypervisor tick on AMD CPUs.
+//!
+//! SVM has no preemption timer, so the timer is armed in one-shot mode with NMI
+//! delivery before each VM entry. The NMI is intercepted even if the guest is
+//! halted or has interrupts disabled. The timer of the guest is emulated on top
+//! of it: its registers are intercepted, the physical timer also expires at the
+//! deadline of the guest timer, whose interrupt is then sent as a self-IPI with
+//! the vector of the guest. The state of the guest timer is taken over when
+//! enabling the hypervisor, and handed back when leaving.
+//!
+//! The guest must not software-disable its local APIC, which also masks the
+//! timer of the hypervisor.
+
+use libvmm::msr::Msr;
+
+use crate::arch::apic::{self, LVT_DELIVERY_MODE_NMI};
+use crate::arch::clocksource::{self, Deadline};
+use crate::arch::cpu::current_cycle;
+use crate::cell::Cell;
+use crate::config::HvSystemConfig;
+use crate::error::HvResult;
+
+const LVT_TIMER: u32 = Msr::IA32_X2APIC_LVT_TIMER as u32;
+const INIT_COUNT: u32 = Msr::IA32_X2APIC_INIT_COUNT as u32;
+const CUR_COUNT: u32 = Msr::IA32_X2APIC_CUR_COUNT as u32;
+const DIV_CONF: u32 = Msr::IA32_X2APIC_DIV_CONF as u32;
+const TSC_DEADLINE: u32 = Msr::IA32_TSC_DEADLINE as u32;
+
+/// The intercepted MSRs, the others of the local APIC are passed through.
+pub const TIMER_MSRS: [u32; 5] = [LVT_TIMER, INIT_COUNT, CUR_COUNT, DIV_CONF, TSC_DEADLINE];
+
+/// Fields of the LVT timer register.
+const LVT_VECTOR: u32 = 0xff;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_MODE: u32 = 0b11 << 17;
+const LVT_MODE_PERIODIC: u32 = 0b01 << 17;
+const LVT_MODE_TSC_DEADLINE: u32 = 0b10 << 17;
+/// Bits of the divide configuration register, and the value to divide by 1.
+const DIV_CONF_MASK: u32 = 0b1011;
+const DIV_CONF_BY_1: u32 = 0b1011;
+
+/// Measure the timer for 10 ms if the driver did not.
+const CALIBRATION_NS: u64 = 10_000_000;
+
+/// The divisor of the timer clock selected by the divide configuration.
+fn divisor(div_conf: u32) -> u64 {
+    match (div_conf >> 1 & 0b100) | (div_conf & 0b11) {
+        0b111 => 1,
+        shift => 2 << shift,
+    }
+}
+
+/// Measure the frequency of the timer clock against the TSC, in kHz.
+fn calibrate() -> Option<u32> {
+    apic::write_reg(Msr::IA32_X2APIC_LVT_TIMER, LVT_MASKED);
+    apic::write_reg(Msr::IA32_X2APIC_DIV_CONF, DIV_CONF_BY_1);
+    apic::write_reg(Msr::IA32_X2APIC_INIT_COUNT, u32::MAX);
+    let start = current_cycle();
+    let deadline = Deadline::after_nanos(CALIBRATION_NS);
+    while !deadline.expired() {
+        core::hint::spin_loop();
+    }
+    let clocks = u32::MAX - apic::read_reg(Msr::IA32_X2APIC_CUR_COUNT);
+    let nanos = clocksource::ticks_to_nanos(current_cycle() - start);
+    apic::write_reg(Msr::IA32_X2APIC_INIT_COUNT, 0);
+    Some((clocks as u64 * 1_000_000 / nanos) as u32).filter(|&khz| khz != 0)
+}
+
+#[derive(Debug)]
+pub struct ApicTimer {
+    /// Frequency of the timer clock before the divisor, in kHz.
+    khz: u32,
+    /// The physical timer is counting, and its NMI is not consumed yet.
+    armed: bool,
+    /// The physical timer expired in the hypervisor before being armed again,
+    /// so its NMI is still pending.
+    expired: bool,
+    /// Registers of the guest timer.
+    lvt: u32,
+    init_count: u32,
+    div_conf: u32,
+    /// The deadline in guest TSC, only used in TSC-deadline mode.
+    tsc_deadline: u64,
+    /// The host TSC at which the guest timer expires next, if armed.
+    deadline: Option<u64>,
+    /// TSC ticks between expirations in periodic mode, 0 otherwise.
+    period: u64,
+}
+
+impl ApicTimer {
+    /// Take over the timer of the current CPU from the guest.
+    pub fn new(cell: &Cell) -> HvResult<Self> {
+        let lvt = apic::read_reg(Msr::IA32_X2APIC_LVT_TIMER) & (LVT_VECTOR | LVT_MASKED | LVT_MODE);
+        let div_conf = apic::read_reg(Msr::IA32_X2APIC_DIV_CONF) & DIV_CONF_MASK;
+        let init_count = apic::read_reg(Msr::IA32_X2APIC_INIT_COUNT);
+        let cur_count = apic::read_reg(Msr::IA32_X2APIC_CUR_COUNT);
+        // Only readable if the CPU supports the mode.
+        let host_deadline = match lvt & LVT_MODE {
+            LVT_MODE_TSC_DEADLINE => Msr::IA32_TSC_DEADLINE.read(),
+            _ => 0,
+        };
+        let now = current_cycle();
+
+        let khz = match HvSystemConfig::get().apic_khz() {
+            0 => calibrate(),
+            khz => Some(khz),
+        };
+        let mut timer = Self {
+            khz: khz.unwrap_or(0),
+            armed: false,
+            expired: false,
+            lvt,
+            init_count: 0,
+            div_conf,
+            tsc_deadline: 0,
+            deadline: None,
+            period: 0,
+        };
+        match lvt & LVT_MODE {
+            LVT_MODE_TSC_DEADLINE if host_deadline != 0 => {
+                timer.tsc_deadline = cell.tsc.guest_tsc(host_deadline);
+                timer.deadline = Some(host_deadline);
+            }
+            LVT_MODE_TSC_DEADLINE => {}
+            mode => {
+                timer.init_count = init_count;
+                if cur_count != 0 {
+                    timer.deadline = Some(now + timer.counts_to_ticks(cur_count));
+                }
+                if mode == LVT_MODE_PERIODIC {
+                    timer.period = timer.counts_to_ticks(init_count);
+                }
+            }
+        }
+        if khz.is_none() {
+            timer.restore();
+            return hv_result_err!(ENODEV, "Local APIC timer is not running!");
+        }
+
+        apic::write_reg(Msr::IA32_X2APIC_INIT_COUNT, 0);
+        apic::write_reg(Msr::IA32_X2APIC_DIV_CONF, DIV_CONF_BY_1);
+        apic::write_reg(Msr::IA32_X2APIC_LVT_TIMER, LVT_DELIVERY_MODE_NMI);
+        Ok(timer)
+    }
+
+    /// Hand the timer back to the guest when leaving the hypervisor.
+    pub fn restore(&self) {
+        apic::write_reg(Msr::IA32_X2APIC_INIT_COUNT, 0);
+        apic::write_reg(Msr::IA32_X2APIC_DIV_CONF, self.div_conf);
+        apic::write_reg(Msr::IA32_X2APIC_LVT_TIMER, self.lvt);
+        let deadline = match self.deadline {
+            Some(deadline) => deadline,
+            None => return,
+        };
+        match self.lvt & LVT_MODE {
+            LVT_MODE_TSC_DEADLINE => unsafe { Msr::IA32_TSC_DEADLINE.write(deadline) },
+            // Restarts the period, which is close enough.
+            LVT_MODE_PERIODIC => apic::write_reg(Msr::IA32_X2APIC_INIT_COUNT, self.init_count),
+            _ => {
+                let count = self.ticks_to_counts(deadline.saturating_sub(current_cycle()));
+                apic::write_reg(Msr::IA32_X2APIC_INIT_COUNT, count.max(1));
+            }
+        }
+    }
+
+    /// TSC ticks of `count` periods of the guest timer.
+    fn counts_to_ticks(&self, count: u32) -> u64 {
+        let clocks = count as u128 * divisor(self.div_conf) as u128;
+        (clocks * clocksource::tsc_khz() as u128 / self.khz.max(1) as u128) as u64
+    }
+
+    /// Periods of the guest timer in `ticks` TSC ticks, saturated to `u32`.
+    fn ticks_to_counts(&self, ticks: u64) -> u32 {
+        let clocks = ticks as u128 * self.khz as u128 / clocksource::tsc_khz() as u128;
+        (clocks / divisor(self.div_conf) as u128).min(u32::MAX as u128) as u32
+    }
+
+    fn disarm(&mut self) {
+        self.deadline = None;
+        self.period = 0;
+        self.tsc_deadline = 0;
+    }
+
+    /// Arm the physical timer to expire after `ticks` TSC ticks, or at the
+    /// deadline of the guest timer if earlier.
+    pub fn arm(&mut self, ticks: u64) {
+        // GIF is cleared in the hypervisor, the NMI is delivered after VMRUN.
+        if self.armed && apic::read_reg(Msr::IA32_X2APIC_CUR_COUNT) == 0 {
+            self.expired = true;
+        }
+        let now = current_cycle();
+        let ticks = match self.deadline {
+            Some(deadline) => ticks.min(deadline.saturating_sub(now)),
+            None => ticks,
+        };
+        let clocks = ticks as u128 * self.khz as u128 / clocksource::tsc_khz() as u128;
+        let clocks = clocks.clamp(1, u32::MAX as u128) as u32;
+        apic::write_reg(Msr::IA32_X2APIC_INIT_COUNT, clocks);
+        self.armed = true;
+    }
+
+    /// Whether the current NMI was raised by the physical timer. The expiration
+    /// is consumed, so that later NMIs go to the guest.
+    pub fn take_nmi(&mut self) -> bool {
+        let fired = self.armed && apic::read_reg(Msr::IA32_X2APIC_CUR_COUNT) == 0;
+        if fired {
+            self.armed = false;
+        }
+        // Both expirations raised a single NMI if they are not consumed yet.
+        let taken = fired || self.expired;
+        self.expired = false;
+        taken
+    }
+
+    /// Send the interrupt of the guest timer if its deadline has passed.
+    pub fn check_guest(&mut self) -> HvResult {
+        let now = current_cycle();
+        let deadline = match self.deadline {
+            Some(deadline) if deadline <= now => deadline,
+            _ => return Ok(()),
+        };
+        if self.period != 0 {
+            // Skip the periods missed in the hypervisor.
+            let missed = (now - deadline) / self.period;
+            self.deadline = Some(deadline + (missed + 1) * self.period);
+        } else {
+            self.disarm();
+        }
+        if self.lvt & LVT_MASKED == 0 {
+            apic::send_self_ipi((self.lvt & LVT_VECTOR) as u8)?;
+        }
+        Ok(())
+    }
+
+    /// Returns `None` if `msr` is not a register of the timer.
+    pub fn read(&self, msr: u32) -> Option<u64> {
+        let deadline_mode = self.lvt & LVT_MODE == LVT_MODE_TSC_DEADLINE;
+        let value = match msr {
+            LVT_TIMER => self.lvt as u64,
+            INIT_COUNT => self.init_count as u64,
+            CUR_COUNT if deadline_mode => 0,
+            CUR_COUNT => self.deadline.map_or(0, |deadline| {
+                self.ticks_to_counts(deadline.saturating_sub(current_cycle())) as u64
+            }),
+            DIV_CONF => self.div_conf as u64,
+            TSC_DEADLINE if deadline_mode => self.tsc_deadline,
+            TSC_DEADLINE => 0,
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Returns `false` if `msr` is not a register of the timer.
+    pub fn write(&mut self, cell: &Cell, msr: u32, value: u64) -> bool {
+        let deadline_mode = self.lvt & LVT_MODE == LVT_MODE_TSC_DEADLINE;
+        match msr {
+            LVT_TIMER => {
+                let lvt = value as u32 & (LVT_VECTOR | LVT_MASKED | LVT_MODE);
+                // Changing the mode disarms the timer.
+                if lvt & LVT_MODE != self.lvt & LVT_MODE {
+                    self.disarm();
+                    self.init_count = 0;
+                }
+                self.lvt = lvt;
+            }
+            // Ignored in TSC-deadline mode.
+            INIT_COUNT if deadline_mode => {}
+            INIT_COUNT => {
+                self.init_count = value as u32;
+                self.disarm();
+                if self.init_count != 0 {
+                    let ticks = self.counts_to_ticks(self.init_count);
+                    self.deadline = Some(current_cycle() + ticks);
+                    if self.lvt & LVT_MODE == LVT_MODE_PERIODIC {
+                        self.period = ticks.max(1);
+                    }
+                }
+            }
+            // Read-only.
+            CUR_COUNT => {}
+            DIV_CONF => self.div_conf = value as u32 & DIV_CONF_MASK,
+            TSC_DEADLINE if deadline_mode => {
+                self.tsc_deadline = value;
+                self.deadline = Some(value)
+                    .filter(|&value| value != 0)
+                    .map(|value| cell.tsc.host_tsc(value));
+            }
+            // Ignored in other modes.
+            TSC_DEADLINE => {}
+            _ => return false,
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_divisor() {
+        assert_eq!(divisor(0b0000), 2);
+        assert_eq!(divisor(0b0011), 16);
+        assert_eq!(divisor(0b1010), 128);
+        assert_eq!(divisor(DIV_CONF_BY_1), 1);
+    }
+}
@@ -0,0 +1,681 @@
+//! AMD IOMMU (AMD-Vi).
+//!
+//! All units share one device table, sized for the buses of the configured
+//! devices. Each PCI device of the root cell gets a device table entry pointing
+//! to the I/O page table of its domain, which maps the DMA-capable memory
+//! regions of the cell. All other entries block DMA. Faults are reported in the
+//! event log of each unit, which is polled on each hypervisor tick, so the MSIs
+//! of the units are disabled.
+//!
+//! Interrupts of a device are remapped once the first one is programmed by the
+//! cell, by an interrupt remapping table of that device. Its entries are only
+//! filled after checking that they target the CPUs of the cell. Until then, and
+//! for all other devices, interrupts are blocked.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use bit_field::BitField;
+use bitflags::bitflags;
+use spin::Mutex;
+
+use crate::arch::clocksource::Deadline;
+use crate::arch::irq::IrqMsg;
+use crate::arch::pci;
+use crate::cell::Cell;
+use crate::config::{HvIommu, HvPciDevice, HvSystemConfig};
+use crate::error::HvResult;
+use crate::memory::addr::{phys_to_virt, virt_to_phys};
+use crate::memory::addr::{GuestPhysAddr, HostPhysAddr, HostVirtAddr};
+use crate::memory::{Frame, GenericPTE, GenericPageTableImmut, Level4PageTable, PagingInstr};
+use crate::memory::{MemFlags, MemoryRegion, MemorySet, PAGE_SIZE};
+
+const MMIO_DEV_TABLE_BASE: usize = 0x0000;
+const MMIO_CMD_BUF_BASE: usize = 0x0008;
+const MMIO_EVT_LOG_BASE: usize = 0x0010;
+const MMIO_CONTROL: usize = 0x0018;
+const MMIO_EXT_FEATURES: usize = 0x0030;
+const MMIO_CMD_HEAD: usize = 0x2000;
+const MMIO_CMD_TAIL: usize = 0x2008;
+const MMIO_EVT_HEAD: usize = 0x2010;
+const MMIO_EVT_TAIL: usize = 0x2018;
+const MMIO_STATUS: usize = 0x2020;
+
+const CONTROL_IOMMU_EN: u64 = 1 << 0;
+const CONTROL_HT_TUN_EN: u64 = 1 << 1;
+const CONTROL_EVT_LOG_EN: u64 = 1 << 2;
+const CONTROL_INV_TIMEOUT_1S: u64 = 4 << 5;
+const CONTROL_PASS_PW: u64 = 1 << 8;
+const CONTROL_RES_PASS_PW: u64 = 1 << 9;
+const CONTROL_COHERENT: u64 = 1 << 10;
+const CONTROL_ISOC: u64 = 1 << 11;
+const CONTROL_CMD_BUF_EN: u64 = 1 << 12;
+
+/// IVHD flags, and the control bits enabling them.
+const IVHD_FLAGS: [(u32, u64); 5] = [
+    (1 << 0, CONTROL_HT_TUN_EN),
+    (1 << 1, CONTROL_PASS_PW),
+    (1 << 2, CONTROL_RES_PASS_PW),
+    (1 << 3, CONTROL_ISOC),
+    (1 << 5, CONTROL_COHERENT),
+];
+
+const STATUS_EVT_OVERFLOW: u64 = 1 << 0;
+
+/// `INVALIDATE_IOMMU_ALL` is supported.
+const EXT_FEATURE_IA_SUP: u64 = 1 << 6;
+
+/// Command buffer and event log of 256 entries, one page each.
+const RING_ENTRY_SIZE: usize = 16;
+const RING_LEN_LOG2: u64 = 8;
+/// Head and tail pointers are byte offsets in bits 4..19.
+const RING_PTR_MASK: u64 = 0x7_fff0;
+
+const CMD_COMPLETION_WAIT: u32 = 0x1;
+const CMD_INVALIDATE_DEVTAB_ENTRY: u32 = 0x2;
+const CMD_INVALIDATE_IOMMU_PAGES: u32 = 0x3;
+const CMD_INVALIDATE_INTERRUPT_TABLE: u32 = 0x5;
+const CMD_INVALIDATE_IOMMU_ALL: u32 = 0x8;
+
+const DTE_VALID: u64 = 1 << 0;
+const DTE_TRANSLATION_VALID: u64 = 1 << 1;
+const DTE_MODE_4LEVEL: u64 = 4 << 9;
+const DTE_IR: u64 = 1 << 61;
+const DTE_IW: u64 = 1 << 62;
+/// Interrupt remapping in the third quadword.
+const DTE_INT_VALID: u64 = 1 << 0;
+const DTE_INT_TABLE_LEN_256: u64 = 8 << 1;
+const DTE_INT_TABLE_MASK: u64 = 0x000f_ffff_ffff_ffc0;
+const DTE_INT_CTL_REMAPPED: u64 = 2 << 60;
+/// 256 entries of 32 bytes per bus.
+const DEV_TABLE_PAGES_PER_BUS: usize = 2;
+
+/// MSI enable in the first dword of the MSI capability.
+const PCI_MSI_ENABLE: u32 = 1 << 16;
+/// Enable bit in the base address low register of the IOMMU capability.
+const PCI_IOMMU_BASE_ENABLE: u32 = 1 << 0;
+
+/// 32-bit interrupt remapping table entries, one page per device.
+const IRT_ENTRIES: usize = 256;
+const IRTE_REMAP_EN: u32 = 1 << 0;
+
+const ROOT_DOMAIN_ID: u16 = 1;
+
+const TIMEOUT_NS: u64 = 1_000_000_000; // 1 s
+
+bitflags! {
+    struct IoPTFlags: u64 {
+        const PRESENT =     1 << 0;
+        const READ =        1 << 61;
+        const WRITE =       1 << 62;
+    }
+}
+
+/// I/O page table entry.
+#[derive(Clone)]
+pub struct IoPTEntry(u64);
+
+impl From<MemFlags> for IoPTFlags {
+    fn from(f: MemFlags) -> Self {
+        let mut ret = Self::empty();
+        if f.contains(MemFlags::READ) {
+            ret |= Self::READ;
+        }
+        if f.contains(MemFlags::WRITE) {
+            ret |= Self::WRITE;
+        }
+        ret
+    }
+}
+
+impl From<IoPTFlags> for MemFlags {
+    fn from(f: IoPTFlags) -> Self {
+        let mut ret = MemFlags::empty();
+        if f.contains(IoPTFlags::READ) {
+            ret |= Self::READ;
+        }
+        if f.contains(IoPTFlags::WRITE) {
+            ret |= Self::WRITE;
+        }
+        ret
+    }
+}
+
+impl GenericPTE for IoPTEntry {
+    fn addr(&self) -> HostPhysAddr {
+        (self.0.get_bits(12..52) << 12) as usize
+    }
+    fn flags(&self) -> MemFlags {
+        self.pt_flags().into()
+    }
+    fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+    fn is_present(&self) -> bool {
+        self.pt_flags().contains(IoPTFlags::PRESENT)
+    }
+    fn is_huge(&self) -> bool {
+        // Entries with a next level of 0 map pages, 2M or 1G ones above the
+        // last level.
+        self.is_present() && self.next_level() == 0
+    }
+
+    fn set_addr(&mut self, paddr: HostPhysAddr) {
+        self.0.set_bits(12..52, paddr as u64 >> 12);
+    }
+    fn set_flags(&mut self, flags: MemFlags, _is_huge: bool) {
+        let flags = IoPTFlags::from(flags) | IoPTFlags::PRESENT;
+        self.0 = self.addr() as u64 | flags.bits();
+    }
+    fn set_table(&mut self, paddr: HostPhysAddr, next_level: usize) {
+        let flags = IoPTFlags::PRESENT | IoPTFlags::READ | IoPTFlags::WRITE;
+        self.0 = flags.bits() | (next_level as u64) << 9;
+        self.set_addr(paddr);
+    }
+    fn clear(&mut self) {
+        self.0 = 0
+    }
+}
+
+impl IoPTEntry {
+    fn pt_flags(&self) -> IoPTFlags {
+        IoPTFlags::from_bits_truncate(self.0)
+    }
+    fn next_level(&self) -> u64 {
+        self.0.get_bits(9..12)
+    }
+}
+
+impl fmt::Debug for IoPTEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IoPTEntry")
+            .field("raw", &self.0)
+            .field("hpaddr", &self.addr())
+            .field("flags", &self.pt_flags())
+            .field("next_level", &self.next_level())
+            .finish()
+    }
+}
+
+pub struct IoPTInstr;
+
+impl PagingInstr for IoPTInstr {
+    unsafe fn activate(_root_paddr: HostPhysAddr) {
+        // set in device table entries
+    }
+
+    fn flush(_vaddr: Option<usize>) {
+        // the caches are invalidated when enabling units
+    }
+}
+
+pub type IoPageTable = Level4PageTable<GuestPhysAddr, IoPTEntry, IoPTInstr>;
+
+fn pci_read_config(bdf: u16, offset: u8) -> u32 {
+    pci::read_config(bdf, offset as u16, 4)
+}
+
+fn pci_write_config(bdf: u16, offset: u8, value: u32) {
+    pci::write_config(bdf, offset as u16, 4, value)
+}
+
+fn event_name(code: u32) -> &'static str {
+    match code {
+        0x1 => "ILLEGAL_DEV_TABLE_ENTRY",
+        0x2 => "IO_PAGE_FAULT",
+        0x3 => "DEV_TAB_HARDWARE_ERROR",
+        0x4 => "PAGE_TAB_HARDWARE_ERROR",
+        0x5 => "ILLEGAL_COMMAND_ERROR",
+        0x6 => "COMMAND_HARDWARE_ERROR",
+        0x7 => "IOTLB_INV_TIMEOUT",
+        0x8 => "INVALID_DEVICE_REQUEST",
+        _ => "UNKNOWN",
+    }
+}
+
+struct AmdIommu {
+    base: HostVirtAddr,
+    /// Control bits from the IVHD flags.
+    control: u64,
+    ext_features: u64,
+    cmd_buf: Frame,
+    cmd_tail: usize,
+    evt_log: Frame,
+    /// Written by `COMPLETION_WAIT` commands.
+    sem: Box<AtomicU64>,
+}
+
+impl AmdIommu {
+    fn new(iommu: &HvIommu) -> HvResult<Self> {
+        let (bdf, base_cap, msi_cap) = (iommu.amd_bdf, iommu.amd_base_cap, iommu.amd_msi_cap);
+        let cap_base = (pci_read_config(bdf, base_cap + 4) & !0x3fff) as u64
+            | (pci_read_config(bdf, base_cap + 8) as u64) << 32;
+        if cap_base != iommu.base {
+            return hv_result_err!(
+                EINVAL,
+                format!("IOMMU {:#x} is at {:#x}, not {:#x}", bdf, cap_base, {
+                    iommu.base
+                })
+            );
+        }
+        let control = IVHD_FLAGS
+            .iter()
+            .filter(|(flag, _)| iommu.amd_features & flag != 0)
+            .fold(0, |control, (_, bit)| control | bit);
+        let mut unit = Self {
+            base: phys_to_virt(iommu.base as HostPhysAddr),
+            control,
+            ext_features: 0,
+            cmd_buf: Frame::new_zero()?,
+            cmd_tail: 0,
+            evt_log: Frame::new_zero()?,
+            sem: Box::new(AtomicU64::new(0)),
+        };
+        if unit.read64(MMIO_CONTROL) & CONTROL_IOMMU_EN != 0 {
+            return hv_result_err!(
+                EBUSY,
+                format!("IOMMU {:#x} is in use, boot Linux with amd_iommu=off", bdf)
+            );
+        }
+        unit.ext_features = unit.read64(MMIO_EXT_FEATURES);
+        // The event log is polled.
+        if msi_cap != 0 {
+            let msi_ctrl = pci_read_config(bdf, msi_cap);
+            pci_write_config(bdf, msi_cap, msi_ctrl & !PCI_MSI_ENABLE);
+        }
+        pci_write_config(
+            bdf,
+            base_cap + 4,
+            pci_read_config(bdf, base_cap + 4) | PCI_IOMMU_BASE_ENABLE,
+        );
+        Ok(unit)
+    }
+
+    fn read64(&self, reg: usize) -> u64 {
+        unsafe { core::ptr::read_volatile((self.base + reg) as *const u64) }
+    }
+
+    fn write64(&self, reg: usize, value: u64) {
+        unsafe { core::ptr::write_volatile((self.base + reg) as *mut u64, value) }
+    }
+
+    fn submit(&mut self, cmd: [u32; 4]) -> HvResult {
+        let next_tail = (self.cmd_tail + RING_ENTRY_SIZE) % PAGE_SIZE;
+        let deadline = Deadline::after_nanos(TIMEOUT_NS);
+        while self.read64(MMIO_CMD_HEAD) & RING_PTR_MASK == next_tail as u64 {
+            if deadline.expired() {
+                return hv_result_err!(ETIMEDOUT, "AMD-Vi command buffer is full");
+            }
+            core::hint::spin_loop();
+        }
+        let entry = unsafe { self.cmd_buf.as_mut_ptr().add(self.cmd_tail) as *mut [u32; 4] };
+        unsafe { entry.write_volatile(cmd) };
+        self.cmd_tail = next_tail;
+        self.write64(MMIO_CMD_TAIL, next_tail as u64);
+        Ok(())
+    }
+
+    /// Wait for all submitted commands.
+    fn wait_completion(&mut self) -> HvResult {
+        self.sem.store(0, Ordering::Release);
+        let addr = virt_to_phys(&*self.sem as *const _ as usize) as u64;
+        self.submit([
+            addr as u32 | 1, // store
+            (addr >> 32) as u32 | CMD_COMPLETION_WAIT << 28,
+            1,
+            0,
+        ])?;
+        let deadline = Deadline::after_nanos(TIMEOUT_NS);
+        while self.sem.load(Ordering::Acquire) == 0 {
+            if deadline.expired() {
+                return hv_result_err!(ETIMEDOUT, "Timed out waiting for AMD-Vi commands");
+            }
+            core::hint::spin_loop();
+        }
+        Ok(())
+    }
+
+    /// Invalidate the cached device table entry and interrupt remapping table
+    /// of `bdf`.
+    fn invalidate_irqs(&mut self, bdf: u16) -> HvResult {
+        self.submit([bdf as u32, CMD_INVALIDATE_DEVTAB_ENTRY << 28, 0, 0])?;
+        self.submit([bdf as u32, CMD_INVALIDATE_INTERRUPT_TABLE << 28, 0, 0])?;
+        self.wait_completion()
+    }
+
+    /// Invalidate cached device table entries of `devices`, and translations
+    /// of `domain_id`.
+    fn invalidate(&mut self, devices: &[u16], domain_id: u16) -> HvResult {
+        if self.ext_features & EXT_FEATURE_IA_SUP != 0 {
+            self.submit([0, CMD_INVALIDATE_IOMMU_ALL << 28, 0, 0])?;
+        } else {
+            for &bdf in devices {
+                self.submit([bdf as u32, CMD_INVALIDATE_DEVTAB_ENTRY << 28, 0, 0])?;
+            }
+            // All pages, including page directory entries.
+            self.submit([
+                0,
+                domain_id as u32 | CMD_INVALIDATE_IOMMU_PAGES << 28,
+                0xffff_f000 | 0b11,
+                0x7fff_ffff,
+            ])?;
+        }
+        self.wait_completion()
+    }
+
+    fn enable(&mut self, dev_table: &Frame, dev_table_pages: usize) {
+        self.write64(MMIO_CONTROL, 0);
+        self.write64(
+            MMIO_DEV_TABLE_BASE,
+            dev_table.start_paddr() as u64 | (dev_table_pages - 1) as u64,
+        );
+        let ring_base = |frame: &Frame| frame.start_paddr() as u64 | RING_LEN_LOG2 << 56;
+        self.write64(MMIO_CMD_BUF_BASE, ring_base(&self.cmd_buf));
+        self.write64(MMIO_EVT_LOG_BASE, ring_base(&self.evt_log));
+        for reg in [MMIO_CMD_HEAD, MMIO_CMD_TAIL, MMIO_EVT_HEAD, MMIO_EVT_TAIL] {
+            self.write64(reg, 0);
+        }
+        self.cmd_tail = 0;
+        self.write64(MMIO_STATUS, STATUS_EVT_OVERFLOW);
+        self.write64(
+            MMIO_CONTROL,
+            self.control
+                | CONTROL_INV_TIMEOUT_1S
+                | CONTROL_CMD_BUF_EN
+                | CONTROL_EVT_LOG_EN
+                | CONTROL_IOMMU_EN,
+        );
+    }
+
+    fn disable(&self) {
+        self.write64(MMIO_CONTROL, 0);
+    }
+
+    /// Report and consume logged events.
+    fn check_events(&self) {
+        let tail = self.read64(MMIO_EVT_TAIL) & RING_PTR_MASK;
+        let mut head = self.read64(MMIO_EVT_HEAD) & RING_PTR_MASK;
+        while head != tail {
+            let entry = unsafe { self.evt_log.as_ptr().add(head as usize) as *const [u32; 4] };
+            let event = unsafe { entry.read_volatile() };
+            let code = event[1] >> 28;
+            let sid = event[0].get_bits(0..16);
+            warn!(
+                "AMD-Vi event {} from {:02x}:{:02x}.{:x}, address {:#x}, flags {:#x}",
+                event_name(code),
+                sid.get_bits(8..16),
+                sid.get_bits(3..8),
+                sid.get_bits(0..3),
+                (event[3] as u64) << 32 | event[2] as u64,
+                event[1].get_bits(16..28),
+            );
+            head = (head + RING_ENTRY_SIZE as u64) % PAGE_SIZE as u64;
+            self.write64(MMIO_EVT_HEAD, head);
+        }
+        if self.read64(MMIO_STATUS) & STATUS_EVT_OVERFLOW != 0 {
+            warn!("AMD-Vi event log overflowed");
+            // The log is stopped on overflows, restart it.
+            let control = self.read64(MMIO_CONTROL);
+            self.write64(MMIO_CONTROL, control & !CONTROL_EVT_LOG_EN);
+            self.write64(MMIO_EVT_HEAD, 0);
+            self.write64(MMIO_EVT_TAIL, 0);
+            self.write64(MMIO_STATUS, STATUS_EVT_OVERFLOW);
+            self.write64(MMIO_CONTROL, control);
+        }
+    }
+}
+
+struct AmdVi {
+    units: Vec<AmdIommu>,
+    dev_table: Frame,
+    dev_table_pages: usize,
+    /// Devices with translation enabled.
+    devices: Vec<u16>,
+    /// I/O page table of the root cell.
+    root_domain: MemorySet<IoPageTable>,
+    /// Interrupt remapping tables, by source ID.
+    irq_tables: BTreeMap<u16, Frame>,
+    /// Pins of IOAPICs by source ID and vector, which is the remapping table
+    /// entry.
+    ioapic_vectors: BTreeMap<(u16, u8), u8>,
+}
+
+static AMD_VI: Mutex<Option<AmdVi>> = Mutex::new(None);
+
+impl AmdVi {
+    fn new(units: Vec<AmdIommu>, cell: &Cell) -> HvResult<Self> {
+        let pci_ids = cell.config.pci_devices().iter().map(|dev| dev.bdf);
+        let ioapic_ids = cell.config.irqchips().iter().map(|chip| chip.id as u16);
+        let buses = pci_ids
+            .chain(ioapic_ids)
+            .map(|bdf| (bdf >> 8) as usize + 1)
+            .max()
+            .unwrap_or(1);
+        let dev_table_pages = buses * DEV_TABLE_PAGES_PER_BUS;
+        let mut dev_table = Frame::new_contiguous(dev_table_pages, 0)?;
+        dev_table.zero();
+        let mut ret = Self {
+            units,
+            dev_table,
+            dev_table_pages,
+            devices: Vec::new(),
+            root_domain: MemorySet::new(),
+            irq_tables: BTreeMap::new(),
+            ioapic_vectors: BTreeMap::new(),
+        };
+        // Block DMA and interrupts of unassigned devices.
+        for dte in ret.dev_table_entries() {
+            dte[0] = DTE_VALID | DTE_TRANSLATION_VALID;
+            dte[2] = DTE_INT_VALID;
+        }
+        Ok(ret)
+    }
+
+    fn dev_table_entries(&mut self) -> &mut [[u64; 4]] {
+        let num = self.dev_table_pages * PAGE_SIZE / 32;
+        unsafe { core::slice::from_raw_parts_mut(self.dev_table.as_mut_ptr() as _, num) }
+    }
+
+    /// Mirror the DMA-capable memory regions of `cell`.
+    fn map_cell_memory(&mut self, cell: &Cell) -> HvResult {
+        for region in cell.config.mem_regions() {
+            if region.flags.contains(MemFlags::DMA) {
+                self.root_domain
+                    .insert(MemoryRegion::new_with_offset_mapper(
+                        region.virt_start as GuestPhysAddr,
+                        region.phys_start as HostPhysAddr,
+                        region.size as usize,
+                        region.flags & (MemFlags::READ | MemFlags::WRITE),
+                    ))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn add_device(&mut self, bdf: u16, domain_id: u16, page_table_paddr: HostPhysAddr) -> HvResult {
+        if self.devices.contains(&bdf) {
+            return hv_result_err!(EEXIST, format!("PCI device {:#x} added twice", bdf));
+        }
+        let dte = &mut self.dev_table_entries()[bdf as usize];
+        dte[1] = domain_id as u64;
+        dte[0] = page_table_paddr as u64
+            | DTE_VALID
+            | DTE_TRANSLATION_VALID
+            | DTE_MODE_4LEVEL
+            | DTE_IR
+            | DTE_IW;
+        self.devices.push(bdf);
+        Ok(())
+    }
+
+    /// Fill the remapping entry for interrupt `index` of `sid`, and start
+    /// remapping the interrupts of `sid` if not yet.
+    fn map_irq(&mut self, sid: u16, index: u16, msg: &IrqMsg) -> HvResult {
+        if sid as usize >= self.dev_table_pages * PAGE_SIZE / 32 {
+            return hv_result_err!(ENODEV, format!("No device table entry for {:#x}", sid));
+        }
+        if index as usize >= IRT_ENTRIES {
+            return hv_result_err!(ERANGE, format!("Interrupt index {} is too large", index));
+        }
+        if msg.dest > 0xff {
+            return hv_result_err!(
+                ENODEV,
+                format!("Interrupt destination {:#x} is not remappable", msg.dest)
+            );
+        }
+        let irte = IRTE_REMAP_EN
+            | (msg.delivery_mode as u32) << 2
+            | (msg.dest_logical as u32) << 6
+            | msg.dest << 8
+            | (msg.vector as u32) << 16;
+        let is_new = !self.irq_tables.contains_key(&sid);
+        if is_new {
+            let frame = Frame::new_zero()?;
+            let paddr = frame.start_paddr() as u64;
+            self.irq_tables.insert(sid, frame);
+            self.dev_table_entries()[sid as usize][2] = paddr & DTE_INT_TABLE_MASK
+                | DTE_INT_VALID
+                | DTE_INT_TABLE_LEN_256
+                | DTE_INT_CTL_REMAPPED;
+        }
+        let table = self.irq_tables.get_mut(&sid).unwrap();
+        unsafe {
+            (table.as_mut_ptr() as *mut u32)
+                .add(index as usize)
+                .write_volatile(irte)
+        };
+        for unit in self.units.iter_mut() {
+            unit.invalidate_irqs(sid)?;
+        }
+        Ok(())
+    }
+
+    /// Remap `pin` of the IOAPIC `sid` with the entry of its vector, so that
+    /// EOIs broadcast by local APICs still match the pin.
+    fn map_ioapic_pin(&mut self, sid: u16, pin: u8, msg: &IrqMsg) -> HvResult {
+        if let Some(&other) = self.ioapic_vectors.get(&(sid, msg.vector)) {
+            if other != pin {
+                return hv_result_err!(
+                    EBUSY,
+                    format!(
+                        "Vector {:#x} is used by IOAPIC {:#x} pin {}",
+                        msg.vector, sid, other
+                    )
+                );
+            }
+        }
+        self.unmap_ioapic_pin(sid, pin);
+        self.ioapic_vectors.insert((sid, msg.vector), pin);
+        self.map_irq(sid, msg.vector as u16, msg)
+    }
+
+    fn unmap_ioapic_pin(&mut self, sid: u16, pin: u8) {
+        self.ioapic_vectors
+            .retain(|&(s, _), &mut p| s != sid || p != pin);
+    }
+
+    fn add_cell_devices(&mut self, cell: &Cell) -> HvResult {
+        let page_table_paddr = self.root_domain.page_table().root_paddr();
+        for dev in cell.config.pci_devices() {
+            if dev.pci_device_type == HvPciDevice::TYPE_IVSHMEM {
+                continue;
+            }
+            self.add_device(dev.bdf, ROOT_DOMAIN_ID, page_table_paddr)?;
+        }
+        Ok(())
+    }
+
+    fn enable(&mut self) -> HvResult {
+        for (i, unit) in self.units.iter_mut().enumerate() {
+            unit.enable(&self.dev_table, self.dev_table_pages);
+            unit.invalidate(&self.devices, ROOT_DOMAIN_ID)
+                .map_err(|e| e.context(format!("failed to enable IOMMU {}", i)))?;
+        }
+        Ok(())
+    }
+}
+
+/// Isolate DMA of the devices of `cell`, called once by the primary CPU.
+pub fn init(cell: &Cell) -> HvResult {
+    let units = HvSystemConfig::get()
+        .iommu_units()
+        .iter()
+        .map(AmdIommu::new)
+        .collect::<HvResult<Vec<_>>>()?;
+    if units.is_empty() {
+        warn!("No AMD-Vi units, DMA is not isolated!");
+        return Ok(());
+    }
+    let mut amd_vi = AmdVi::new(units, cell)?;
+    amd_vi.map_cell_memory(cell)?;
+    amd_vi.add_cell_devices(cell)?;
+    // Stored before enabling, so that `shutdown()` disables the enabled units
+    // on errors.
+    let mut guard = AMD_VI.lock();
+    let amd_vi = guard.insert(amd_vi);
+    amd_vi.enable()?;
+    info!(
+        "AMD-Vi DMA remapping enabled on {} units.",
+        amd_vi.units.len()
+    );
+    Ok(())
+}
+
+/// Turn off DMA remapping when leaving the hypervisor, and free the tables.
+pub fn shutdown() {
+    // Another CPU may have stopped with the lock held.
+    match AMD_VI.try_lock() {
+        Some(mut amd_vi) => {
+            if let Some(amd_vi) = amd_vi.take() {
+                amd_vi.units.iter().for_each(AmdIommu::disable);
+            }
+        }
+        None => error!("AMD-Vi is locked, DMA remapping stays enabled!"),
+    }
+}
+
+/// Check the MSI `index` of PCI device `bdf` programmed by `cell`, and remap
+/// it. Returns the MSI address and data to write to the device.
+pub fn map_msi(cell: &Cell, bdf: u16, index: u16, address: u64, data: u32) -> HvResult<(u64, u32)> {
+    let msg = IrqMsg::from_msi(address, data)?;
+    msg.check_dest(cell)?;
+    match AMD_VI.lock().as_mut() {
+        Some(amd_vi) => amd_vi.map_irq(bdf, index, &msg)?,
+        None => return Ok((address, data)),
+    }
+    // The data selects the remapping table entry, the destination in the
+    // address is ignored.
+    Ok((address, index as u32))
+}
+
+/// Check the redirection table entry of `pin` of the IOAPIC with `sid`
+/// programmed by `cell`, and remap it. Returns the entry to write to the
+/// IOAPIC.
+pub fn map_ioapic_pin(cell: &Cell, sid: u16, pin: u8, rte: u64) -> HvResult<u64> {
+    const RTE_MASKED: u64 = 1 << 16;
+    if rte & RTE_MASKED != 0 {
+        if let Some(amd_vi) = AMD_VI.lock().as_mut() {
+            amd_vi.unmap_ioapic_pin(sid, pin);
+        }
+        return Ok(rte);
+    }
+    let msg = IrqMsg::from_ioapic_rte(rte);
+    msg.check_dest(cell)?;
+    match AMD_VI.lock().as_mut() {
+        Some(amd_vi) => amd_vi.map_ioapic_pin(sid, pin, &msg)?,
+        None => return Ok(rte),
+    }
+    // The vector selects the remapping table entry with fixed delivery, keep
+    // the polarity and trigger mode.
+    Ok(rte & (0xff | 1 << 13 | 1 << 15))
+}
+
+/// Report DMA remapping faults, called on hypervisor ticks.
+pub fn check_faults() {
+    if let Some(Some(amd_vi)) = AMD_VI.try_lock().as_deref() {
+        amd_vi.units.iter().for_each(AmdIommu::check_events);
+    }
+}
@@ -1,13 +1,20 @@
+mod apic_timer;
 mod npt;
 mod vcpu;
 mod vmexit;
 
+pub mod iommu;
+pub mod pmu;
+
 use libvmm::svm::flags::{VmCr, VmCrFlags};
 
+use crate::arch::cpuid::CpuFeatures;
+
 use crate::error::HvResult;
 
 pub use npt::NestedPageTable;
 pub use vcpu::Vcpu;
+pub use vmexit::exit_reason_name;
 
 pub fn check_hypervisor_feature() -> HvResult {
     if VmCr::read().contains(VmCrFlags::SVMDIS) {
@@ -16,3 +23,10 @@ pub fn check_hypervisor_feature() -> HvResult {
     // TODO: check cpuid
     Ok(())
 }
+
+/// Fractional bits of `TSC_RATIO`.
+pub const TSC_RATIO_FRAC_BITS: u32 = 32;
+
+pub fn has_tsc_scaling() -> bool {
+    CpuFeatures::new().has_tsc_rate_msr()
+}
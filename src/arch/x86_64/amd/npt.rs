@@ -31,8 +31,8 @@ impl GenericPTE for NPTEntry {
         // access at the nested page table level.
         self.0.set_flags(flags | MemFlags::USER, is_huge)
     }
-    fn set_table(&mut self, paddr: HostPhysAddr) {
-        self.0.set_table(paddr)
+    fn set_table(&mut self, paddr: HostPhysAddr, next_level: usize) {
+        self.0.set_table(paddr, next_level)
     }
     fn clear(&mut self) {
         self.0.clear()
@@ -0,0 +1,169 @@
+#![cfg_attr(not(feature = "profile"), allow(dead_code))]
+
+//! Performance monitoring counters, shared by the guest and the sampling
+//! profiler.
+//!
+//! The guest owns the PMU. Writes to its event selectors are intercepted to
+//! set the Guest-Only bit, so its counters stop in the hypervisor. The profiler
+//! borrows counter 0, whose state is switched by `CounterState` on each VM
+//! exit and before each VM entry.
+
+use libvmm::msr::Msr;
+use x86::msr::{rdmsr, wrmsr};
+
+use crate::arch::cpuid::CpuFeatures;
+use crate::error::HvResult;
+
+/// Event "CPU Clocks not Halted".
+const EVENT_CPU_CLOCKS_NOT_HALTED: u64 = 0x76;
+const PERF_EVT_SEL_OS: u64 = 1 << 17;
+const PERF_EVT_SEL_INT: u64 = 1 << 20;
+const PERF_EVT_SEL_EN: u64 = 1 << 22;
+/// Only count when in guest mode (EFER.SVME must be set).
+const PERF_EVT_SEL_GUEST_ONLY: u64 = 1 << 40;
+/// Only count when in host mode (EFER.SVME must be set).
+const PERF_EVT_SEL_HOST_ONLY: u64 = 1 << 41;
+/// Legacy event selectors of counters 0 to 3, aliases of the first ones of the
+/// core performance counter extensions.
+const LEGACY_EVT_SEL0: u32 = 0xc001_0000;
+const NUM_LEGACY_COUNTERS: u32 = 4;
+const NUM_CORE_COUNTERS: u32 = 6;
+/// Width of the performance counters.
+const PERF_CTR_BITS: u32 = 48;
+
+/// Counter 0 and its event selector.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CounterState {
+    evtsel: u64,
+    count: u64,
+}
+
+impl CounterState {
+    pub fn set_evtsel(&mut self, evtsel: u64) {
+        self.evtsel = evtsel;
+    }
+
+    pub fn save() -> Self {
+        Self {
+            evtsel: Msr::PERF_EVT_SEL0.read(),
+            count: Msr::PERF_CTR0.read(),
+        }
+    }
+
+    pub fn restore(&self) {
+        unsafe {
+            Msr::PERF_EVT_SEL0.write(0);
+            Msr::PERF_CTR0.write(self.count);
+            Msr::PERF_EVT_SEL0.write(self.evtsel);
+        }
+    }
+}
+
+/// Program counter 0 to overflow every `period` hypervisor cycles.
+pub fn sampling_start(period: u64) -> HvResult {
+    unsafe {
+        Msr::PERF_EVT_SEL0.write(0);
+        sampling_rearm(period);
+        Msr::PERF_EVT_SEL0.write(
+            EVENT_CPU_CLOCKS_NOT_HALTED
+                | PERF_EVT_SEL_OS
+                | PERF_EVT_SEL_INT
+                | PERF_EVT_SEL_EN
+                | PERF_EVT_SEL_HOST_ONLY,
+        );
+    }
+    Ok(())
+}
+
+/// Reload the counter.
+pub fn sampling_rearm(period: u64) {
+    let mask = (1 << PERF_CTR_BITS) - 1;
+    unsafe { Msr::PERF_CTR0.write(period.wrapping_neg() & mask) };
+}
+
+/// Whether the counter overflowed since the last `sampling_rearm()`, i.e. it
+/// wrapped around to a small value.
+pub fn sampling_overflowed() -> bool {
+    Msr::PERF_CTR0.read() & (1 << (PERF_CTR_BITS - 1)) == 0
+}
+
+/// Nothing to do, as the counter is switched out by `CounterState` before
+/// entering the guest.
+pub fn sampling_stop() {}
+
+/// Event selectors of the counters, those of the core performance counter
+/// extensions if supported.
+fn evtsel_msrs() -> impl Iterator<Item = u32> {
+    let (base, stride, num) = if CpuFeatures::new().has_perf_cntr_extensions() {
+        (Msr::PERF_EVT_SEL0 as u32, 2, NUM_CORE_COUNTERS)
+    } else {
+        (LEGACY_EVT_SEL0, 1, NUM_LEGACY_COUNTERS)
+    };
+    (0..num).map(move |i| base + i * stride)
+}
+
+/// Event selectors the guest may write, to be intercepted: the legacy ones, and
+/// those of the core performance counter extensions if supported.
+pub fn guest_evtsel_msrs() -> impl Iterator<Item = u32> {
+    (0..NUM_LEGACY_COUNTERS)
+        .map(|i| LEGACY_EVT_SEL0 + i)
+        .chain(evtsel_msrs().filter(|&msr| msr >= Msr::PERF_EVT_SEL0 as u32))
+}
+
+/// The counter of the event selector `msr`, and `value` of the guest restricted
+/// to count in guest mode. Returns `None` if `msr` is not an event selector.
+pub fn guest_evtsel(msr: u32, value: u64) -> Option<(u32, u64)> {
+    let counter = if (LEGACY_EVT_SEL0..LEGACY_EVT_SEL0 + NUM_LEGACY_COUNTERS).contains(&msr) {
+        msr - LEGACY_EVT_SEL0
+    } else {
+        match msr.checked_sub(Msr::PERF_EVT_SEL0 as u32) {
+            Some(offset) if offset % 2 == 0 && offset / 2 < NUM_CORE_COUNTERS => offset / 2,
+            _ => return None,
+        }
+    };
+    Some((
+        counter,
+        (value & !PERF_EVT_SEL_HOST_ONLY) | PERF_EVT_SEL_GUEST_ONLY,
+    ))
+}
+
+/// Restrict the counters already set up by the guest to guest mode, called
+/// after turning on SVM.
+pub fn restrict_guest_counters() {
+    for msr in evtsel_msrs() {
+        unsafe {
+            wrmsr(
+                msr,
+                (rdmsr(msr) & !PERF_EVT_SEL_HOST_ONLY) | PERF_EVT_SEL_GUEST_ONLY,
+            )
+        };
+    }
+}
+
+/// Let the counters of the guest count again after turning off SVM, as they
+/// count nothing with only the Guest-Only bit set and EFER.SVME clear.
+pub fn release_guest_counters() {
+    for msr in evtsel_msrs() {
+        unsafe { wrmsr(msr, rdmsr(msr) & !PERF_EVT_SEL_GUEST_ONLY) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guest_evtsel() {
+        let evtsel = EVENT_CPU_CLOCKS_NOT_HALTED | PERF_EVT_SEL_EN | PERF_EVT_SEL_HOST_ONLY;
+        let guest = EVENT_CPU_CLOCKS_NOT_HALTED | PERF_EVT_SEL_EN | PERF_EVT_SEL_GUEST_ONLY;
+        assert_eq!(guest_evtsel(0xc001_0002, evtsel), Some((2, guest)));
+        assert_eq!(
+            guest_evtsel(0xc001_020a, 0),
+            Some((5, PERF_EVT_SEL_GUEST_ONLY))
+        );
+        // Counters and MSRs beyond the last selector.
+        assert_eq!(guest_evtsel(0xc001_0004, 0), None);
+        assert_eq!(guest_evtsel(0xc001_0201, 0), None);
+        assert_eq!(guest_evtsel(0xc001_020c, 0), None);
+    }
+}
@@ -11,6 +11,7 @@ use x86_64::registers::model_specific::{Efer, EferFlags};
 use x86_64::registers::rflags::RFlags;
 use x86_64::structures::DescriptorTablePointer;
 
+use super::apic_timer::{ApicTimer, TIMER_MSRS};
 use crate::arch::segmentation::Segment;
 use crate::arch::vmm::VcpuAccessGuestState;
 use crate::arch::{GeneralRegisters, GuestPageTableImmut, LinuxContext};
@@ -29,26 +30,27 @@ pub struct Vcpu {
     host_stack_top: u64,
     /// host state-save area.
     host_save_area: Frame,
+    /// MSR permissions map (8 KiB). Only the local APIC timer, writes to the
+    /// x2APIC ICR and the event selectors of the PMU, and MSRs outside of its
+    /// ranges, such as the kvmclock MSRs, are intercepted.
+    msr_bitmap: Frame,
+    /// I/O permissions map (12 KiB), only the ports mediated by the hypervisor
+    /// are intercepted.
+    io_bitmap: Frame,
     /// Virtual machine control block.
     pub(super) vmcb: Vmcb,
+    /// An NMI received in the hypervisor is waiting to be injected.
+    nmi_pending: bool,
+    /// The guest is handling an injected NMI, until its next `IRET`.
+    nmi_masked: bool,
+    /// Drives the hypervisor tick, and emulates the timer of the guest.
+    apic_timer: ApicTimer,
 }
 
 impl Vcpu {
     pub fn new(linux: &LinuxContext, cell: &Cell) -> HvResult<Self> {
         super::check_hypervisor_feature()?;
 
-        // make sure all perf counters are off
-        unsafe {
-            /// Core Performance Event-Select Register (PerfEvtSeln), Counter Enable (bit 22)
-            const PERF_EVT_SEL_EN: u64 = 1 << 22;
-            Msr::PERF_EVT_SEL0.write(Msr::PERF_EVT_SEL0.read() & !PERF_EVT_SEL_EN);
-            Msr::PERF_EVT_SEL1.write(Msr::PERF_EVT_SEL1.read() & !PERF_EVT_SEL_EN);
-            Msr::PERF_EVT_SEL2.write(Msr::PERF_EVT_SEL2.read() & !PERF_EVT_SEL_EN);
-            Msr::PERF_EVT_SEL3.write(Msr::PERF_EVT_SEL3.read() & !PERF_EVT_SEL_EN);
-            Msr::PERF_EVT_SEL4.write(Msr::PERF_EVT_SEL4.read() & !PERF_EVT_SEL_EN);
-            Msr::PERF_EVT_SEL5.write(Msr::PERF_EVT_SEL5.read() & !PERF_EVT_SEL_EN);
-        }
-
         // TODO: check linux CR0, CR4
 
         let efer = Efer::read();
@@ -66,15 +68,47 @@ impl Vcpu {
             Cr4::write(super::super::HOST_CR4);
         }
 
+        let mut msr_bitmap = Frame::new_contiguous(2, 0)?;
+        msr_bitmap.zero();
+        for &msr in &TIMER_MSRS {
+            intercept_msr(&mut msr_bitmap, msr, false);
+            intercept_msr(&mut msr_bitmap, msr, true);
+        }
+        intercept_msr(&mut msr_bitmap, Msr::IA32_X2APIC_ICR as u32, true);
+        for msr in super::pmu::guest_evtsel_msrs() {
+            intercept_msr(&mut msr_bitmap, msr, true);
+        }
+        let mut io_bitmap = Frame::new_contiguous(3, 0)?;
+        io_bitmap.zero();
+        for port in crate::arch::pci::INTERCEPTED_PORTS {
+            io_bitmap.as_slice_mut()[port as usize / 8] |= 1 << (port % 8);
+        }
+        // Local APIC timer NMIs drive the hypervisor tick.
+        let apic_timer = ApicTimer::new(cell).map_err(|e| {
+            unsafe { Efer::write(efer) };
+            unsafe { Msr::VM_HSAVE_PA.write(0) };
+            e
+        })?;
         let cpu_data = PerCpu::current();
         let mut ret = Self {
             guest_regs: Default::default(),
             host_tp: cpu_data as *const _ as _,
             host_stack_top: cpu_data.stack_top() as _,
             host_save_area,
+            msr_bitmap,
+            io_bitmap,
             vmcb: Default::default(),
+            nmi_pending: false,
+            nmi_masked: false,
+            apic_timer,
         };
         ret.vmcb_setup(linux, cell);
+        if super::has_tsc_scaling() {
+            // Only applies to the guest TSC.
+            unsafe { Msr::TSC_RATIO.write(cell.tsc.ratio()) };
+        }
+        // Released when dropped.
+        super::pmu::restrict_guest_counters();
 
         Ok(ret)
     }
@@ -105,15 +139,47 @@ impl Vcpu {
 
     pub fn exit(&self, linux: &mut LinuxContext) -> HvResult {
         self.load_vmcb_guest(linux);
+        self.turn_off()
+    }
+
+    /// Turn off SVM without saving the guest state, used when leaving the
+    /// hypervisor or rolling back a failed bring-up.
+    pub fn turn_off(&self) -> HvResult {
         unsafe {
             asm!("stgi");
             Efer::write(Efer::read() - EferFlags::SECURE_VIRTUAL_MACHINE_ENABLE);
             Msr::VM_HSAVE_PA.write(0);
+            if super::has_tsc_scaling() {
+                Msr::TSC_RATIO.write(1 << super::TSC_RATIO_FRAC_BITS);
+            }
         }
+        // After STGI, so that a pending NMI of the timer is consumed.
+        self.apic_timer.restore();
         info!("successed to turn off SVM.");
         Ok(())
     }
 
+    /// Resume the guest from anywhere in the VM exit handler, discarding the
+    /// host stack.
+    pub fn resume(&mut self) -> ! {
+        let vmcb_paddr = virt_to_phys(&self.vmcb as *const _ as usize);
+        self.vmcb.save.rax = self.guest_regs.rax;
+        self.guest_regs.rax = vmcb_paddr as _;
+        unsafe {
+            // Same as the end of `vmexit_handler_wrapper()`.
+            Msr::IA32_GS_BASE.write(self.vmcb.save.gs.base);
+            asm!(
+                "clgi",
+                "mov rsp, {0}",
+                restore_regs_from_stack!(),
+                "jmp {1}",
+                in(reg) &self.guest_regs,
+                sym svm_run,
+                options(noreturn),
+            );
+        }
+    }
+
     pub fn inject_fault(&mut self) -> HvResult {
         self.vmcb.inject_event(
             VmcbIntInfo::from(
@@ -125,6 +191,67 @@ impl Vcpu {
         Ok(())
     }
 
+    /// Forward an NMI received in the hypervisor to the guest.
+    pub fn queue_nmi(&mut self) {
+        self.nmi_pending = true;
+    }
+
+    /// Inject the pending NMI, unless the guest is still handling the last
+    /// one. It is kept pending until the next VM exit otherwise.
+    pub fn inject_pending_nmi(&mut self) -> HvResult {
+        let injecting = self.vmcb.control.event_inj & VmcbIntInfo::VALID.bits() != 0;
+        // The intercepted `IRET` is not executed yet.
+        let in_iret = matches!(
+            self.vmcb.control.exit_code.try_into(),
+            Ok(SvmExitCode::IRET)
+        );
+        if self.nmi_pending && !self.nmi_masked && !injecting && !in_iret {
+            self.vmcb.inject_event(
+                VmcbIntInfo::from(
+                    InterruptType::NMI,
+                    crate::arch::ExceptionType::NonMaskableInterrupt,
+                ),
+                0,
+            );
+            // SVM does not report the NMI blocking of the guest, intercept
+            // `IRET` to know when its NMI handler returns.
+            self.vmcb.set_intercept(SvmIntercept::IRET);
+            self.vmcb.control.clean_bits.remove(VmcbCleanBits::I);
+            self.nmi_pending = false;
+            self.nmi_masked = true;
+        }
+        Ok(())
+    }
+
+    pub(super) fn unmask_nmi(&mut self) {
+        self.vmcb.clear_intercept(SvmIntercept::IRET);
+        self.vmcb.control.clean_bits.remove(VmcbCleanBits::I);
+        self.nmi_masked = false;
+    }
+
+    /// Cause a VM exit after `ticks` TSC ticks in the guest, and deliver the
+    /// timer interrupt of the guest if due.
+    pub fn arm_timer(&mut self, ticks: u64) -> HvResult {
+        self.apic_timer.check_guest()?;
+        self.apic_timer.arm(ticks);
+        Ok(())
+    }
+
+    /// Whether the current NMI was raised by the timer of `arm_timer()`.
+    pub fn take_timer_nmi(&mut self) -> bool {
+        self.apic_timer.take_nmi()
+    }
+
+    /// Returns `None` if `msr` is not a register of the local APIC timer.
+    pub fn read_apic_timer(&self, msr: u32) -> Option<u64> {
+        self.apic_timer.read(msr)
+    }
+
+    /// Returns `false` if `msr` is not a register of the local APIC timer.
+    pub fn write_apic_timer(&mut self, cell: &Cell, msr: u32, value: u64) -> bool {
+        self.apic_timer.write(cell, msr, value)
+    }
+
     pub fn advance_rip(&mut self, instr_len: u8) -> HvResult {
         self.vmcb.save.rip += instr_len as u64;
         Ok(())
@@ -141,6 +268,16 @@ impl Vcpu {
         )
     }
 
+    /// Exit code of the last #VMEXIT.
+    pub fn exit_reason(&self) -> u32 {
+        self.vmcb.control.exit_code as u32
+    }
+
+    #[cfg_attr(not(feature = "trace"), allow(dead_code))]
+    pub fn exit_qualification(&self) -> u64 {
+        self.vmcb.control.exit_info_1
+    }
+
     pub fn guest_page_table(&self) -> GuestPageTableImmut {
         use crate::memory::addr::align_down;
         unsafe { GuestPageTableImmut::from_root(align_down(self.vmcb.save.cr3 as _)) }
@@ -201,9 +338,14 @@ impl Vcpu {
         vmcb.clean_bits = VmcbCleanBits::empty(); // Explicitly mark all of the state as new
         vmcb.nest_cr3 = cell.gpm.page_table().root_paddr() as _;
         vmcb.tlb_control = VmcbTlbControl::FlushAsid as _;
+        vmcb.msrpm_base_pa = self.msr_bitmap.start_paddr() as _;
+        vmcb.iopm_base_pa = self.io_bitmap.start_paddr() as _;
+        vmcb.tsc_offset = cell.tsc.offset();
 
         self.vmcb.set_intercept(SvmIntercept::NMI);
         self.vmcb.set_intercept(SvmIntercept::CPUID);
+        self.vmcb.set_intercept(SvmIntercept::MSR_PROT);
+        self.vmcb.set_intercept(SvmIntercept::IOIO_PROT);
         self.vmcb.set_intercept(SvmIntercept::SHUTDOWN);
         self.vmcb.set_intercept(SvmIntercept::VMRUN);
         self.vmcb.set_intercept(SvmIntercept::VMMCALL);
@@ -212,12 +354,17 @@ impl Vcpu {
         self.vmcb.set_intercept(SvmIntercept::STGI);
         self.vmcb.set_intercept(SvmIntercept::CLGI);
         self.vmcb.set_intercept(SvmIntercept::SKINIT);
+        if cell.tsc.intercepted() {
+            self.vmcb.set_intercept(SvmIntercept::RDTSC);
+            self.vmcb.set_intercept(SvmIntercept::RDTSCP);
+        }
     }
 
     fn load_vmcb_guest(&self, linux: &mut LinuxContext) {
         let vmcb = &self.vmcb.save;
         linux.rip = vmcb.rip;
         linux.rsp = vmcb.rsp;
+        linux.rflags = vmcb.rflags;
         linux.cr0 = Cr0Flags::from_bits_truncate(vmcb.cr0);
         linux.cr3 = vmcb.cr3;
         linux.cr4 = Cr4Flags::from_bits_truncate(vmcb.cr4);
@@ -239,6 +386,7 @@ impl Vcpu {
         linux.tss.selector = unsafe { task::tr() };
         linux.fs.base = Msr::IA32_FS_BASE.read();
         linux.gs.base = vmcb.gs.base;
+        linux.kernel_gsbase = Msr::IA32_KERNEL_GSBASE.read();
     }
 }
 
@@ -294,6 +442,12 @@ impl VcpuAccessGuestState for Vcpu {
     }
 }
 
+impl Drop for Vcpu {
+    fn drop(&mut self) {
+        super::pmu::release_guest_counters();
+    }
+}
+
 impl Debug for Vcpu {
     fn fmt(&self, f: &mut Formatter) -> Result {
         f.debug_struct("Vcpu")
@@ -340,3 +494,16 @@ extern "sysv64" fn vmexit_handler_wrapper(cpu_data: &mut PerCpu) {
     crate::arch::vmm::vmexit_handler();
     unsafe { Msr::IA32_GS_BASE.write(guest_tp) };
 }
+
+/// Intercept reads or writes of `msr` in the MSR permissions map.
+fn intercept_msr(msr_bitmap: &mut Frame, msr: u32, is_write: bool) {
+    let offset = match msr {
+        0..=0x1fff => 0,
+        0xc000_0000..=0xc000_1fff => 0x800,
+        0xc001_0000..=0xc001_1fff => 0x1000,
+        _ => return,
+    };
+    // Two bits per MSR, for reads and writes.
+    let bit = (msr & 0x1fff) as usize * 2 + is_write as usize;
+    msr_bitmap.as_slice_mut()[offset + bit / 8] |= 1 << (bit % 8);
+}
@@ -1,15 +1,30 @@
+use bit_field::BitField;
 use libvmm::svm::flags::VmcbCleanBits;
 use libvmm::svm::{SvmExitCode, VmExitInfo};
 
 use crate::arch::vmm::{VcpuAccessGuestState, VmExit};
 use crate::error::HvResult;
 
+#[cfg_attr(not(feature = "stats"), allow(dead_code))]
+pub fn exit_reason_name(reason: u32) -> alloc::string::String {
+    match SvmExitCode::try_from(reason as u64) {
+        Ok(code) => format!("{:?}", code),
+        Err(_) => format!("{:#x}", reason),
+    }
+}
+
 impl VmExit<'_> {
     fn handle_nmi(&mut self) -> HvResult {
         unsafe { core::arch::asm!("stgi; clgi") };
         Ok(())
     }
 
+    fn handle_iret(&mut self) -> HvResult {
+        // Only intercepted while the guest is handling an injected NMI.
+        self.cpu_data.vcpu.unmask_nmi();
+        Ok(())
+    }
+
     fn handle_exception(&mut self, vec: u8, exit_info: &VmExitInfo) -> HvResult {
         info!(
             "#VMEXIT(EXCP {}) @ RIP({:#x}): {:#x?}",
@@ -21,11 +36,28 @@ impl VmExit<'_> {
 
     fn handle_nested_page_fault(&mut self, exit_info: &VmExitInfo) -> HvResult {
         let guest_paddr = exit_info.exit_info_2;
-        warn!(
-            "#VMEXIT(NPF) @ {:#x} RIP({:#x}, {:#x})",
-            guest_paddr, exit_info.guest_rip, exit_info.guest_next_rip,
-        );
-        hv_result_err!(ENOSYS)
+        let res = self.handle_mmio(guest_paddr as _);
+        if res.is_err() {
+            warn!(
+                "#VMEXIT(NPF) @ {:#x} RIP({:#x}, {:#x})",
+                guest_paddr, exit_info.guest_rip, exit_info.guest_next_rip,
+            );
+        }
+        res
+    }
+
+    fn handle_io_instruction(&mut self, exit_info: &VmExitInfo) -> HvResult {
+        let info = exit_info.exit_info_1;
+        if info.get_bit(2) {
+            return hv_result_err!(ENOSYS, "String I/O instructions are not supported");
+        }
+        // EXITINFO2 is the RIP of the next instruction.
+        self.handle_io(
+            info.get_bits(16..32) as u16,
+            info.get_bits(4..7) as u8,
+            info.get_bit(0),
+            (exit_info.exit_info_2 - exit_info.guest_rip) as u8,
+        )
     }
 
     pub fn handle_exit(&mut self) -> HvResult {
@@ -49,14 +81,18 @@ impl VmExit<'_> {
             SvmExitCode::INVALID => panic!("VM entry failed: {:#x?}\n{:#x?}", exit_info, vcpu.vmcb),
             SvmExitCode::EXCP(vec) => self.handle_exception(vec, &exit_info),
             SvmExitCode::NMI => self.handle_nmi(),
+            SvmExitCode::IRET => self.handle_iret(),
             SvmExitCode::CPUID => self.handle_cpuid(),
             SvmExitCode::VMMCALL => self.handle_hypercall(),
             SvmExitCode::NPF => self.handle_nested_page_fault(&exit_info),
+            SvmExitCode::IOIO => self.handle_io_instruction(&exit_info),
             SvmExitCode::MSR => match exit_info.exit_info_1 {
                 0 => self.handle_msr_read(),
                 1 => self.handle_msr_write(),
                 _ => hv_result_err!(EIO),
             },
+            SvmExitCode::RDTSC => self.handle_rdtsc(false),
+            SvmExitCode::RDTSCP => self.handle_rdtsc(true),
             SvmExitCode::SHUTDOWN => {
                 error!("#VMEXIT(SHUTDOWN): {:#x?}", exit_info);
                 self.cpu_data.vcpu.inject_fault()?;
@@ -0,0 +1,246 @@
+//! Local APIC.
+//!
+//! The hypervisor uses the mode set by Linux, x2APIC through MSRs or xAPIC
+//! through the MMIO page. In xAPIC mode the page is emulated for the cell: ICR
+//! writes are checked like those to the x2APIC ICR, the logical ID is tracked
+//! for the checks, the timer registers are handled like the x2APIC ones by the
+//! vCPU, and other registers are passed through.
+
+use core::ops::Range;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::Ordering;
+
+use bit_field::BitField;
+use libvmm::msr::Msr;
+
+use super::cpuid::CpuId;
+use super::irq;
+use crate::cell::Cell;
+use crate::config::HvSystemConfig;
+use crate::error::HvResult;
+use crate::memory::addr::{phys_to_virt, HostPhysAddr};
+use crate::memory::PAGE_SIZE;
+use crate::mmio::{MmioAccess, MmioRegions};
+use crate::percpu::PerCpu;
+
+/// x2APIC mode enable (bit 10 of `IA32_APIC_BASE`).
+const APIC_BASE_EXTD: usize = 10;
+/// MSR of the first x2APIC register, at offset 0 of the xAPIC page.
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// Delivery mode NMI of an LVT entry, the mask bit (16) is cleared.
+#[cfg_attr(not(any(feature = "amd", feature = "profile")), allow(dead_code))]
+pub const LVT_DELIVERY_MODE_NMI: u32 = 0b100 << 8;
+
+/// Fields of the Interrupt Command Register (ICR).
+mod icr {
+    pub const DELIVERY_MODE_NMI: u64 = 0b100 << 8;
+    pub const DEST_LOGICAL: u64 = 1 << 11;
+    pub const SEND_PENDING: u32 = 1 << 12;
+    pub const LEVEL_ASSERT: u64 = 1 << 14;
+    #[cfg_attr(not(feature = "amd"), allow(dead_code))]
+    pub const SHORTHAND_SELF: u64 = 0b01 << 18;
+}
+
+/// Registers of the xAPIC page.
+mod xapic {
+    pub const ID: usize = 0x20;
+    pub const LDR: usize = 0xd0;
+    pub const DFR: usize = 0xe0;
+    pub const ICR_LOW: usize = 0x300;
+    pub const ICR_HIGH: usize = 0x310;
+    /// Flat model of logical destinations.
+    pub const DFR_FLAT: u32 = 0xffff_ffff;
+}
+
+pub fn x2apic_enabled() -> bool {
+    Msr::IA32_APIC_BASE.read().get_bit(APIC_BASE_EXTD)
+}
+
+/// Local APIC ID of the current CPU.
+pub fn current_apic_id() -> u32 {
+    if x2apic_enabled() {
+        Msr::IA32_X2APIC_APICID.read() as u32
+    } else {
+        CpuId::new()
+            .get_feature_info()
+            .map(|info| info.initial_local_apic_id() as u32)
+            .unwrap_or(0)
+    }
+}
+
+/// Physical address of the xAPIC page.
+pub fn xapic_base() -> HostPhysAddr {
+    (Msr::IA32_APIC_BASE.read() & !(PAGE_SIZE as u64 - 1)) as HostPhysAddr
+}
+
+fn xapic_read(reg: usize) -> u32 {
+    unsafe { read_volatile((phys_to_virt(xapic_base()) + reg) as *const u32) }
+}
+
+fn xapic_write(reg: usize, value: u32) {
+    unsafe { write_volatile((phys_to_virt(xapic_base()) + reg) as *mut u32, value) }
+}
+
+/// Read a register of the local APIC, given by its x2APIC MSR, in either mode.
+#[cfg_attr(not(feature = "amd"), allow(dead_code))]
+pub fn read_reg(msr: Msr) -> u32 {
+    if x2apic_enabled() {
+        msr.read() as u32
+    } else {
+        xapic_read(((msr as u32 - X2APIC_MSR_BASE) << 4) as usize)
+    }
+}
+
+/// Write a register of the local APIC, given by its x2APIC MSR, in either
+/// mode.
+#[cfg_attr(not(any(feature = "amd", feature = "profile")), allow(dead_code))]
+pub fn write_reg(msr: Msr, value: u32) {
+    if x2apic_enabled() {
+        unsafe { msr.write(value as u64) };
+    } else {
+        xapic_write(((msr as u32 - X2APIC_MSR_BASE) << 4) as usize, value);
+    }
+}
+
+/// Logical destination register of the current CPU in xAPIC mode, 0 in x2APIC
+/// mode.
+pub fn current_xapic_ldr() -> u32 {
+    if x2apic_enabled() {
+        0
+    } else {
+        xapic_read(xapic::LDR)
+    }
+}
+
+/// Bits of the destination field in the 64-bit ICR, with the high dword of the
+/// xAPIC ICR in bits 32..64.
+pub fn icr_dest_bits(x2apic: bool) -> Range<usize> {
+    if x2apic {
+        32..64
+    } else {
+        56..64
+    }
+}
+
+fn icr_dest(dest: u32) -> u64 {
+    if x2apic_enabled() {
+        (dest as u64) << 32
+    } else {
+        (dest as u64 & 0xff) << 56
+    }
+}
+
+/// Write `icr` to the ICR of the current CPU.
+pub fn write_icr(icr: u64) -> HvResult {
+    if x2apic_enabled() {
+        unsafe { Msr::IA32_X2APIC_ICR.write(icr) };
+    } else {
+        while xapic_read(xapic::ICR_LOW) & icr::SEND_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+        xapic_write(xapic::ICR_HIGH, (icr >> 32) as u32);
+        xapic_write(xapic::ICR_LOW, icr as u32);
+    }
+    Ok(())
+}
+
+/// Send an NMI to the CPU with the given local APIC ID.
+pub fn send_nmi(apic_id: u32) -> HvResult {
+    write_icr(icr_dest(apic_id) | icr::LEVEL_ASSERT | icr::DELIVERY_MODE_NMI)
+}
+
+/// Send a fixed interrupt with `vector` to the local APICs selected by `dest`.
+pub fn send_ipi(dest: u32, dest_logical: bool, vector: u8) -> HvResult {
+    let mut icr = icr_dest(dest) | icr::LEVEL_ASSERT | vector as u64;
+    if dest_logical {
+        icr |= icr::DEST_LOGICAL;
+    }
+    write_icr(icr)
+}
+
+/// Send a fixed interrupt with `vector` to the current CPU.
+#[cfg_attr(not(feature = "amd"), allow(dead_code))]
+pub fn send_self_ipi(vector: u8) -> HvResult {
+    write_icr(icr::SHORTHAND_SELF | icr::LEVEL_ASSERT | vector as u64)
+}
+
+/// Deliver performance counter overflows to this CPU as NMIs. The LVT entry is
+/// masked by some CPUs on each delivery, so it must be set again.
+#[cfg_attr(not(feature = "profile"), allow(dead_code))]
+pub fn set_pmi_nmi() -> HvResult {
+    write_reg(Msr::IA32_X2APIC_LVT_PMI, LVT_DELIVERY_MODE_NMI);
+    Ok(())
+}
+
+/// Check the mode of the local APIC against the configuration, and emulate the
+/// xAPIC page in xAPIC mode.
+pub fn register_mmio(mmio: &MmioRegions) -> HvResult {
+    let x2apic = x2apic_enabled();
+    match HvSystemConfig::get().apic_mode() {
+        HvSystemConfig::APIC_MODE_XAPIC if x2apic => {
+            return hv_result_err!(ENODEV, "Local APIC is in x2APIC mode, xAPIC is configured");
+        }
+        HvSystemConfig::APIC_MODE_X2APIC if !x2apic => {
+            return hv_result_err!(ENODEV, "Local APIC is in xAPIC mode, x2APIC is configured");
+        }
+        _ => {}
+    }
+    if !x2apic {
+        mmio.register(xapic_base(), PAGE_SIZE, xapic_access, 0)?;
+    }
+    Ok(())
+}
+
+fn xapic_access(cell: &Cell, access: &mut MmioAccess, _arg: usize) -> HvResult {
+    if access.size != 4 || access.offset % 16 != 0 {
+        return hv_result_err!(
+            EINVAL,
+            format!("Invalid xAPIC access at {:#x}", access.offset)
+        );
+    }
+    let cpu_data = PerCpu::current_mut();
+    let msr = X2APIC_MSR_BASE + (access.offset >> 4) as u32;
+    if access.is_write {
+        if cpu_data.vcpu.write_apic_timer(cell, msr, access.value) {
+            return Ok(());
+        }
+    } else if let Some(value) = cpu_data.vcpu.read_apic_timer(msr) {
+        access.value = value;
+        return Ok(());
+    }
+
+    let arch = &cpu_data.arch;
+    let value = access.value as u32;
+    match (access.offset, access.is_write) {
+        (xapic::ICR_HIGH, false) => {
+            access.value = arch.xapic_icr_high.load(Ordering::Relaxed) as u64
+        }
+        (xapic::ICR_HIGH, true) => arch.xapic_icr_high.store(value, Ordering::Relaxed),
+        (xapic::ICR_LOW, true) => {
+            let icr_high = arch.xapic_icr_high.load(Ordering::Relaxed);
+            irq::write_icr(cell, (icr_high as u64) << 32 | value as u64)?;
+        }
+        (xapic::ID, true) => warn!("Ignored xAPIC ID write: {:#x}", value),
+        (xapic::DFR, true) if value != xapic::DFR_FLAT => {
+            warn!(
+                "Ignored xAPIC DFR write: {:#x}, only the flat model is supported",
+                value
+            )
+        }
+        (xapic::LDR, true) => {
+            if irq::check_xapic_ldr(cell, value) {
+                arch.xapic_ldr.store(value, Ordering::Release);
+                xapic_write(xapic::LDR, value);
+            } else {
+                warn!(
+                    "Ignored xAPIC LDR write: {:#x}, used outside of the cell",
+                    value
+                );
+            }
+        }
+        (reg, false) => access.value = xapic_read(reg) as u64,
+        (reg, true) => xapic_write(reg, value),
+    }
+    Ok(())
+}
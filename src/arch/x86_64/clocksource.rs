@@ -0,0 +1,294 @@
+//! Hypervisor timekeeping based on the TSC.
+//!
+//! The TSC frequency is taken from `tsc_khz` of the system config if the
+//! driver measured it, otherwise it is calibrated against the ACPI PM timer,
+//! and CPUID is the last resort. The TSC is invariant and synchronized across
+//! CPUs, so the time is monotonic and comparable between CPUs.
+//!
+//! The wall clock time is read from the CMOS RTC once on init, and advanced by
+//! the TSC afterwards.
+
+use core::fmt::{Display, Formatter, Result};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use super::cpu::current_cycle;
+use super::cpuid::CpuId;
+use crate::config::HvSystemConfig;
+
+/// Frequency of the ACPI PM timer.
+const PM_TIMER_HZ: u64 = 3_579_545;
+/// Calibrate the TSC for 10 ms.
+const CALIBRATION_PM_TICKS: u32 = (PM_TIMER_HZ / 100) as u32;
+/// Give up if the PM timer does not advance in about a second.
+const CALIBRATION_MAX_CYCLES: u64 = 1 << 32;
+const DEFAULT_TSC_KHZ: u32 = 4_000_000;
+
+#[derive(Debug, Clone, Copy)]
+enum Source {
+    Config,
+    PmTimer,
+    CpuId,
+    Default,
+}
+
+/// TSC frequency, 0 until `init()`.
+static TSC_KHZ: AtomicU32 = AtomicU32::new(0);
+/// Whether `TSC_KHZ` was determined, rather than the default.
+static TSC_KHZ_KNOWN: AtomicBool = AtomicBool::new(false);
+/// Nanoseconds per tick, with 32 fractional bits.
+static MULT: AtomicU64 = AtomicU64::new(0);
+/// Nanoseconds since the Unix epoch read from the RTC, 0 if unavailable.
+static RTC_NANOS: AtomicU64 = AtomicU64::new(0);
+/// The TSC when the RTC was read.
+static RTC_TSC: AtomicU64 = AtomicU64::new(0);
+
+/// CMOS index and data ports, and registers of the RTC.
+const CMOS_ADDR: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+const RTC_SECONDS: u8 = 0x00;
+const RTC_MINUTES: u8 = 0x02;
+const RTC_HOURS: u8 = 0x04;
+const RTC_DAY_OF_MONTH: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09;
+const RTC_STATUS_A: u8 = 0x0a;
+const RTC_STATUS_B: u8 = 0x0b;
+/// Update in progress, in status register A.
+const RTC_UIP: u8 = 1 << 7;
+/// Binary instead of BCD values, in status register B.
+const RTC_DM_BINARY: u8 = 1 << 2;
+/// 24-hour instead of 12-hour mode, in status register B.
+const RTC_24H: u8 = 1 << 1;
+/// PM flag of the hour in 12-hour mode.
+const RTC_PM: u8 = 1 << 7;
+
+fn cmos_read(reg: u8) -> u8 {
+    unsafe {
+        x86::io::outb(CMOS_ADDR, reg);
+        x86::io::inb(CMOS_DATA)
+    }
+}
+
+/// Days since the Unix epoch of a date in the Gregorian calendar.
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    // Years start in March, so that the leap day is the last one.
+    let (year, month) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let (era, year_of_era) = (year / 400, year % 400);
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Read the RTC in seconds since the Unix epoch. Must not race with accesses
+/// of the guest, i.e. only called while enabling the hypervisor.
+fn read_rtc() -> Option<u64> {
+    let read_time = || {
+        // An update takes less than 2 ms after UIP is set.
+        let deadline = Deadline::after_nanos(10_000_000);
+        while cmos_read(RTC_STATUS_A) & RTC_UIP != 0 {
+            if deadline.expired() {
+                return None;
+            }
+        }
+        Some([
+            cmos_read(RTC_SECONDS),
+            cmos_read(RTC_MINUTES),
+            cmos_read(RTC_HOURS),
+            cmos_read(RTC_DAY_OF_MONTH),
+            cmos_read(RTC_MONTH),
+            cmos_read(RTC_YEAR),
+        ])
+    };
+    // Read again if an update started in between.
+    let mut time = read_time()?;
+    loop {
+        let again = read_time()?;
+        if again == time {
+            break;
+        }
+        time = again;
+    }
+
+    let status_b = cmos_read(RTC_STATUS_B);
+    let [sec, min, hour, day, month, year] = time;
+    let pm = status_b & RTC_24H == 0 && hour & RTC_PM != 0;
+    let decode = |v: u8| {
+        if status_b & RTC_DM_BINARY != 0 {
+            v as u64
+        } else {
+            (v >> 4) as u64 * 10 + (v & 0xf) as u64
+        }
+    };
+    let hour = match decode(hour & !RTC_PM) {
+        hour if status_b & RTC_24H == 0 => hour % 12 + if pm { 12 } else { 0 },
+        hour => hour,
+    };
+    let (day, month, year) = (decode(day), decode(month), decode(year));
+    if !(1..=31).contains(&day) || !(1..=12).contains(&month) {
+        return None;
+    }
+    // No century register, same as Linux.
+    let year = year + if year < 70 { 2000 } else { 1900 };
+    let days = days_from_civil(year, month, day);
+    Some(((days * 24 + hour) * 60 + decode(min)) * 60 + decode(sec))
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        let name = match self {
+            Self::Config => "system config",
+            Self::PmTimer => "PM timer calibration",
+            Self::CpuId => "CPUID",
+            Self::Default => "default",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Count TSC cycles during `CALIBRATION_PM_TICKS` of the PM timer. Only the
+/// low 24 bits of the timer are used, as it may not have 32 bits.
+fn calibrate_pm_timer(port: u16) -> Option<u32> {
+    let read_pm_timer = || unsafe { x86::io::inl(port) } & 0xff_ffff;
+    let pm_start = read_pm_timer();
+    let tsc_start = current_cycle();
+    loop {
+        let pm_ticks = read_pm_timer().wrapping_sub(pm_start) & 0xff_ffff;
+        let cycles = current_cycle() - tsc_start;
+        if pm_ticks >= CALIBRATION_PM_TICKS {
+            return Some((cycles * PM_TIMER_HZ / (pm_ticks as u64 * 1000)) as u32);
+        }
+        if cycles > CALIBRATION_MAX_CYCLES {
+            return None;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+fn cpuid_tsc_khz() -> Option<u32> {
+    let cpuid = CpuId::new();
+    if let Some(hz) = cpuid.get_tsc_info().and_then(|info| info.tsc_frequency()) {
+        return Some((hz / 1000) as u32);
+    }
+    cpuid
+        .get_processor_frequency_info()
+        .map(|info| info.processor_base_frequency() as u32 * 1000)
+        .filter(|&khz| khz != 0)
+}
+
+fn determine_tsc_khz() -> (u32, Source) {
+    let sys_config = HvSystemConfig::get();
+    let pm_timer_address = sys_config.pm_timer_address();
+    if sys_config.tsc_khz() != 0 {
+        (sys_config.tsc_khz(), Source::Config)
+    } else if let Some(khz) = Some(pm_timer_address)
+        .filter(|&port| port != 0)
+        .and_then(calibrate_pm_timer)
+    {
+        (khz, Source::PmTimer)
+    } else if let Some(khz) = cpuid_tsc_khz() {
+        (khz, Source::CpuId)
+    } else {
+        (DEFAULT_TSC_KHZ, Source::Default)
+    }
+}
+
+/// Determine the TSC frequency, called by the primary CPU on each enabling.
+/// The time reads 0 before.
+pub fn init() {
+    let (tsc_khz, source) = determine_tsc_khz();
+    TSC_KHZ.store(tsc_khz, Ordering::Release);
+    TSC_KHZ_KNOWN.store(!matches!(source, Source::Default), Ordering::Release);
+    MULT.store((1_000_000 << 32) / tsc_khz as u64, Ordering::Release);
+    info!("TSC frequency: {} kHz (from {})", tsc_khz, source);
+
+    match read_rtc() {
+        Some(secs) => {
+            RTC_TSC.store(current_cycle(), Ordering::Release);
+            RTC_NANOS.store(secs * 1_000_000_000, Ordering::Release);
+            info!("RTC time: {} s since the epoch", secs);
+        }
+        None => warn!("Failed to read the RTC, the wall clock is not available!"),
+    }
+}
+
+/// Forget the TSC frequency and the wall clock when leaving the hypervisor.
+pub fn reset() {
+    TSC_KHZ.store(0, Ordering::Release);
+    TSC_KHZ_KNOWN.store(false, Ordering::Release);
+    MULT.store(0, Ordering::Release);
+    RTC_NANOS.store(0, Ordering::Release);
+}
+
+pub fn tsc_khz() -> u32 {
+    TSC_KHZ.load(Ordering::Acquire)
+}
+
+/// Whether the TSC frequency was determined. If not, it is a guess and must
+/// not be promised to guests.
+pub fn tsc_khz_known() -> bool {
+    TSC_KHZ_KNOWN.load(Ordering::Acquire)
+}
+
+pub fn ticks_to_nanos(ticks: u64) -> u64 {
+    ((ticks as u128 * MULT.load(Ordering::Acquire) as u128) >> 32) as u64
+}
+
+pub fn nanos_to_ticks(nanos: u64) -> u64 {
+    (nanos as u128 * tsc_khz() as u128 / 1_000_000) as u64
+}
+
+/// Nanoseconds since the TSC was reset.
+pub fn current_time_nanos() -> u64 {
+    ticks_to_nanos(current_cycle())
+}
+
+/// Nanoseconds since the Unix epoch when the TSC is `tsc`, or `None` if the
+/// RTC could not be read.
+pub fn wall_clock_nanos(tsc: u64) -> Option<u64> {
+    let rtc_nanos = RTC_NANOS.load(Ordering::Acquire);
+    if rtc_nanos == 0 {
+        return None;
+    }
+    let elapsed = tsc.wrapping_sub(RTC_TSC.load(Ordering::Acquire));
+    Some(rtc_nanos + ticks_to_nanos(elapsed))
+}
+
+/// A point in time to wait for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Deadline {
+    tsc: u64,
+}
+
+impl Deadline {
+    pub fn after_nanos(nanos: u64) -> Self {
+        Self {
+            tsc: current_cycle().saturating_add(nanos_to_ticks(nanos)),
+        }
+    }
+
+    pub fn expired(&self) -> bool {
+        current_cycle() >= self.tsc
+    }
+
+    /// TSC ticks until the deadline, 0 if expired.
+    pub fn remaining_ticks(&self) -> u64 {
+        self.tsc.saturating_sub(current_cycle())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_days_from_civil() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        // 2024-02-29 00:00:00 UTC is 1709164800.
+        assert_eq!(days_from_civil(2024, 2, 29) * 86_400, 1_709_164_800);
+    }
+}
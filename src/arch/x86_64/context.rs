@@ -1,6 +1,7 @@
 use libvmm::msr::Msr;
 use x86::{segmentation, task};
 use x86_64::registers::control::{Cr0, Cr0Flags, Cr3, Cr3Flags, Cr4, Cr4Flags};
+use x86_64::registers::rflags;
 use x86_64::{addr::PhysAddr, structures::paging::PhysFrame, structures::DescriptorTablePointer};
 
 use super::segmentation::Segment;
@@ -12,6 +13,7 @@ const SAVED_LINUX_REGS: usize = 8;
 pub struct LinuxContext {
     pub rsp: u64,
     pub rip: u64,
+    pub rflags: u64,
 
     pub r15: u64,
     pub r14: u64,
@@ -45,7 +47,7 @@ pub struct LinuxContext {
 }
 
 #[repr(C)]
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct GeneralRegisters {
     pub rax: u64,
     pub rcx: u64,
@@ -65,6 +67,31 @@ pub struct GeneralRegisters {
     pub r15: u64,
 }
 
+impl GeneralRegisters {
+    /// Register number `index` in instruction encodings. RSP is not saved here.
+    pub fn get(&self, index: usize) -> u64 {
+        assert!(index != 4 && index < 16);
+        unsafe { (*(self as *const Self as *const [u64; 16]))[index] }
+    }
+
+    pub fn set(&mut self, index: usize, value: u64) {
+        assert!(index != 4 && index < 16);
+        unsafe { (*(self as *mut Self as *mut [u64; 16]))[index] = value }
+    }
+}
+
+/// General registers followed by an interrupt stack frame, used by `iretq` to
+/// return back to linux.
+#[repr(C)]
+struct ReturnFrame {
+    regs: GeneralRegisters,
+    rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
+}
+
 macro_rules! save_regs_to_stack {
     () => {
         "
@@ -128,6 +155,7 @@ impl LinuxContext {
             rbx: regs[5],
             rbp: regs[6],
             rip: regs[7],
+            rflags: rflags::read_raw(),
             es: Segment::from_selector(segmentation::es(), &gdt),
             cs: Segment::from_selector(segmentation::cs(), &gdt),
             ss: Segment::from_selector(segmentation::ss(), &gdt),
@@ -193,23 +221,26 @@ impl LinuxContext {
         }
     }
 
-    /// Restore linux general-purpose registers and stack, then return back to linux.
+    /// Restore linux general-purpose registers, stack and flags, then return
+    /// back to linux.
     pub fn return_to_linux(&self, guest_regs: &GeneralRegisters) -> ! {
+        // Use `iretq` with a frame on our stack, so that the linux stack is not
+        // touched, and RFLAGS (e.g. IF) is restored along with RIP.
+        let frame = ReturnFrame {
+            regs: guest_regs.clone(),
+            rip: self.rip,
+            cs: self.cs.selector.bits() as _,
+            rflags: self.rflags,
+            rsp: self.rsp,
+            ss: self.ss.selector.bits() as _,
+        };
         unsafe {
             Msr::IA32_GS_BASE.write(self.gs.base);
             core::arch::asm!(
-                "mov rsp, {linux_rsp}",
-                "push {linux_rip}",
-                "mov rcx, rsp",
-                "mov rsp, {guest_regs}",
-                "mov [rsp + {guest_regs_size}], rcx",
+                "mov rsp, {0}",
                 restore_regs_from_stack!(),
-                "pop rsp",
-                "ret",
-                linux_rsp = in(reg) self.rsp,
-                linux_rip = in(reg) self.rip,
-                guest_regs = in(reg) guest_regs,
-                guest_regs_size = const core::mem::size_of::<GeneralRegisters>(),
+                "iretq",
+                in(reg) &frame,
                 options(noreturn),
             );
         }
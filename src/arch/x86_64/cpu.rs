@@ -1,26 +1,19 @@
 use libvmm::msr::Msr;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::PhysFrame;
 
-use super::cpuid::CpuId;
-
-pub fn frequency() -> u16 {
-    static CPU_FREQUENCY: spin::Once<u16> = spin::Once::new();
-    *CPU_FREQUENCY.call_once(|| {
-        const DEFAULT: u16 = 4000;
-        CpuId::new()
-            .get_processor_frequency_info()
-            .map(|info| info.processor_base_frequency())
-            .unwrap_or(DEFAULT)
-            .max(DEFAULT)
-    })
-}
+use crate::memory::PhysAddr;
 
 pub fn current_cycle() -> u64 {
     let mut aux = 0;
     unsafe { core::arch::x86_64::__rdtscp(&mut aux) }
 }
 
-pub fn current_time_nanos() -> u64 {
-    current_cycle() * 1000 / frequency() as u64
+#[inline(always)]
+pub fn frame_pointer() -> usize {
+    let ret;
+    unsafe { core::arch::asm!("mov {0}, rbp", out(reg) ret, options(nomem, nostack)) };
+    ret
 }
 
 pub fn thread_pointer() -> usize {
@@ -32,3 +25,17 @@ pub fn thread_pointer() -> usize {
 pub fn set_thread_pointer(tp: usize) {
     unsafe { Msr::IA32_GS_BASE.write(tp as u64) };
 }
+
+/// Physical address and flags of the active page table root.
+pub fn page_table_root() -> (PhysAddr, u64) {
+    let (frame, flags) = Cr3::read();
+    (frame.start_address().as_u64() as PhysAddr, flags.bits())
+}
+
+/// # Safety
+///
+/// The page table must map the running code and data.
+pub unsafe fn set_page_table_root((paddr, flags): (PhysAddr, u64)) {
+    let frame = PhysFrame::containing_address(x86_64::PhysAddr::new(paddr as u64));
+    Cr3::write(frame, Cr3Flags::from_bits_truncate(flags));
+}
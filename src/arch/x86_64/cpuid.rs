@@ -1,17 +1,23 @@
 #![cfg_attr(not(feature = "intel"), allow(dead_code))]
 
+use bit_field::BitField;
 use bitflags::bitflags;
 
 pub use raw_cpuid::{cpuid, CpuId};
 
+use crate::config::HvCpuidEntry;
+
 #[repr(u32)]
 #[derive(Debug)]
 #[allow(dead_code)]
 pub(super) enum CpuIdEax {
     VendorInfo = 0x0,
     FeatureInfo = 0x1,
+    ExtendedTopology = 0xb,
+    V2ExtendedTopology = 0x1f,
     HypervisorInfo = 0x4000_0000,
     HypervisorFeatures = 0x4000_0001,
+    HypervisorFrequencies = 0x4000_0010,
     AmdFeatureInfo = 0x8000_0001,
 }
 
@@ -152,6 +158,58 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// EAX of the `HypervisorFeatures` leaf.
+    pub(super) struct HvFeatures: u32 {
+        /// Hypercalls by VMCALL (Intel) or VMMCALL (AMD).
+        const HYPERCALL = 1 << 0;
+        /// The hypervisor writes its log to the UART at 0x3F8.
+        const CONSOLE = 1 << 1;
+        /// The `CpuGetStats` hypercall.
+        const STATS = 1 << 2;
+        /// The `CpuGetTrace` hypercall.
+        const TRACE = 1 << 3;
+        /// The `CoverageGet` hypercall.
+        const COVERAGE = 1 << 4;
+        /// The `CpuGetProfile` hypercall.
+        const PROFILE = 1 << 5;
+        /// The `HypervisorFrequencies` leaf.
+        const FREQUENCIES = 1 << 6;
+        /// The kvmclock MSRs and the KVM leaves at 0x4000_0100, only if the TSC
+        /// frequency was determined.
+        const PVCLOCK = 1 << 7;
+        /// The `WatchdogStart` and `WatchdogPing` hypercalls.
+        const WATCHDOG = 1 << 8;
+    }
+}
+
+/// Increased when the meaning of a register of the `HypervisorFeatures` leaf
+/// changes. New feature bits do not change it.
+const HV_FEATURES_VERSION: u32 = 1;
+
+impl HvFeatures {
+    pub fn current() -> Self {
+        let mut features = Self::HYPERCALL | Self::CONSOLE | Self::FREQUENCIES | Self::WATCHDOG;
+        features.set(Self::PVCLOCK, super::pvclock::available());
+        features.set(Self::STATS, cfg!(feature = "stats"));
+        features.set(Self::TRACE, cfg!(feature = "trace"));
+        features.set(Self::COVERAGE, cfg!(feature = "coverage"));
+        features.set(Self::PROFILE, cfg!(feature = "profile"));
+        features
+    }
+
+    /// `[EAX, EBX, ECX, EDX]` of the `HypervisorFeatures` leaf: the features,
+    /// the version of this leaf, and the version of the configuration ABI.
+    pub fn leaf() -> [u32; 4] {
+        [
+            Self::current().bits(),
+            HV_FEATURES_VERSION,
+            crate::config::CONFIG_REVISION as u32,
+            0,
+        ]
+    }
+}
+
 pub struct CpuFeatures {
     cpuid: CpuId,
 }
@@ -171,6 +229,22 @@ impl CpuFeatures {
         }
     }
 
+    pub fn perf_counter_bit_width(&self) -> u8 {
+        if let Some(info) = self.cpuid.get_performance_monitoring_info() {
+            info.counter_bit_width()
+        } else {
+            0
+        }
+    }
+
+    pub fn has_pdcm(&self) -> bool {
+        if let Some(info) = self.cpuid.get_feature_info() {
+            info.has_pdcm()
+        } else {
+            false
+        }
+    }
+
     pub fn has_vmx(&self) -> bool {
         if let Some(info) = self.cpuid.get_feature_info() {
             info.has_vmx()
@@ -195,6 +269,24 @@ impl CpuFeatures {
         }
     }
 
+    #[cfg(feature = "amd")]
+    pub fn has_tsc_rate_msr(&self) -> bool {
+        if let Some(info) = self.cpuid.get_svm_info() {
+            info.has_tsc_rate_msr()
+        } else {
+            false
+        }
+    }
+
+    #[cfg(feature = "amd")]
+    pub fn has_perf_cntr_extensions(&self) -> bool {
+        if let Some(info) = self.cpuid.get_extended_processor_and_feature_identifiers() {
+            info.has_perf_cntr_extensions()
+        } else {
+            false
+        }
+    }
+
     pub fn has_invpcid(&self) -> bool {
         if let Some(info) = self.cpuid.get_extended_feature_info() {
             info.has_invpcid()
@@ -211,3 +303,150 @@ impl CpuFeatures {
         }
     }
 }
+
+/// Limit the numbers of logical processors in the extended topology leaves
+/// (0xB and 0x1F) to the CPUs of a cell. `cpus_sharing(shift)` counts the CPUs
+/// of the cell whose APIC IDs shifted right by `shift` equal that of the
+/// current CPU. CPUID.1:EBX[23:16] and the APIC ID shift widths stay native,
+/// as guests derive the APIC ID layout from them.
+pub fn limit_topology(function: u32, regs: &mut [u32; 4], cpus_sharing: impl Fn(u32) -> u32) {
+    if function == CpuIdEax::ExtendedTopology as u32
+        || function == CpuIdEax::V2ExtendedTopology as u32
+    {
+        // Subleaves after the last level have a level type of 0.
+        if regs[2].get_bits(8..16) != 0 {
+            let shift = regs[0].get_bits(0..5);
+            let count = cpus_sharing(shift).min(regs[1].get_bits(0..16));
+            regs[1].set_bits(0..16, count);
+        }
+    }
+}
+
+/// CPUID overrides of a cell, applied on top of the emulated results.
+pub struct CpuIdPolicy<'a> {
+    entries: &'a [HvCpuidEntry],
+}
+
+impl<'a> CpuIdPolicy<'a> {
+    pub fn new(entries: &'a [HvCpuidEntry]) -> Self {
+        Self { entries }
+    }
+
+    fn apply_entries(&self, function: u32, index: u32, mut regs: [u32; 4]) -> [u32; 4] {
+        for e in self.entries {
+            let (e_function, e_index, e_flags) = (e.function, e.index, e.flags);
+            if e_function != function
+                || (e_flags & HvCpuidEntry::SIGNIFICANT_INDEX != 0 && e_index != index)
+            {
+                continue;
+            }
+            let (mask, value) = (e.mask, e.value);
+            for i in 0..4 {
+                regs[i] = (regs[i] & !mask[i]) | (value[i] & mask[i]);
+            }
+        }
+        regs
+    }
+
+    /// Get `[EAX, EBX, ECX, EDX]` of a leaf and subleaf from `native`, with
+    /// the overrides applied. Leaves above the maximum leaf of their range
+    /// (basic, extended, or each block of 0x100 hypervisor leaves), as
+    /// reported by its first leaf after overriding, are all zeros.
+    pub fn cpuid(
+        &self,
+        function: u32,
+        index: u32,
+        native: impl Fn(u32, u32) -> [u32; 4],
+    ) -> [u32; 4] {
+        let base = match function & 0xc000_0000 {
+            0x4000_0000 => function & !0xff,
+            base => base,
+        };
+        if function != base {
+            let max_leaf = self.apply_entries(base, 0, native(base, 0))[0];
+            if function > max_leaf {
+                return [0; 4];
+            }
+        }
+        self.apply_entries(function, index, native(function, index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(function: u32, index: Option<u32>, mask: [u32; 4], value: [u32; 4]) -> HvCpuidEntry {
+        HvCpuidEntry {
+            function,
+            index: index.unwrap_or(0),
+            flags: index.map_or(0, |_| HvCpuidEntry::SIGNIFICANT_INDEX),
+            mask,
+            value,
+        }
+    }
+
+    fn native(function: u32, index: u32) -> [u32; 4] {
+        match function {
+            0 => [0xd, 1, 2, 3],
+            0x8000_0000 => [0x8000_0008, 0, 0, 0],
+            0x4000_0100 => [0x4000_0101, 0, 0, 0],
+            _ => [function, !0, index, !0],
+        }
+    }
+
+    #[test]
+    fn test_cpuid_policy() {
+        let entries = [
+            // Cap the basic leaves to 7.
+            entry(0, None, [!0, 0, 0, 0], [7, 0, 0, 0]),
+            // Hide AVX-512F (leaf 7, EBX bit 16) for all subleaves.
+            entry(7, None, [0, 1 << 16, 0, 0], [0; 4]),
+            // Override EBX of subleaf 1 of leaf 4 only.
+            entry(4, Some(1), [0, !0, 0, 0], [0, 0x1234, 0, 0]),
+        ];
+        let policy = CpuIdPolicy::new(&entries);
+        assert_eq!(policy.cpuid(0, 0, native), [7, 1, 2, 3]);
+        assert_eq!(policy.cpuid(7, 0, native)[1], !(1 << 16));
+        assert_eq!(policy.cpuid(7, 1, native)[1], !(1 << 16));
+        assert_eq!(policy.cpuid(4, 0, native), [4, !0, 0, !0]);
+        assert_eq!(policy.cpuid(4, 1, native), [4, 0x1234, 1, !0]);
+        assert_eq!(policy.cpuid(0xb, 0, native), [0; 4]);
+        // Other ranges are not capped by the basic maximum leaf.
+        assert_eq!(policy.cpuid(0x8000_0001, 0, native)[0], 0x8000_0001);
+        assert_eq!(policy.cpuid(0x8000_0009, 0, native), [0; 4]);
+        assert_eq!(policy.cpuid(0x4000_0101, 0, native)[0], 0x4000_0101);
+        assert_eq!(policy.cpuid(0x4000_0102, 0, native), [0; 4]);
+        // No overrides.
+        assert_eq!(CpuIdPolicy::new(&[]).cpuid(1, 0, native), native(1, 0));
+    }
+
+    #[test]
+    fn test_limit_topology() {
+        // The cell owns APIC IDs 2 and 3 (one core with two threads) and 4 of
+        // a package with 16 IDs, the current CPU has APIC ID 2.
+        let cell_ids = [2, 3, 4];
+        let cpus_sharing = |shift: u32| {
+            cell_ids
+                .iter()
+                .filter(|&&id| id >> shift == 2 >> shift)
+                .count() as u32
+        };
+        // Leaf 1 stays native.
+        let mut leaf1 = [0, 16 << 16 | 0x800, 0, 0];
+        limit_topology(1, &mut leaf1, cpus_sharing);
+        assert_eq!(leaf1[1], 16 << 16 | 0x800);
+        // SMT level: 2 threads per core, shift 1.
+        let mut smt = [1, 2, 0x100, 2];
+        limit_topology(0xb, &mut smt, cpus_sharing);
+        assert_eq!(smt, [1, 2, 0x100, 2]);
+        // Core level: 16 logical processors in the package, shift 4.
+        let mut core = [4, 16, 0x201, 2];
+        limit_topology(0xb, &mut core, cpus_sharing);
+        assert_eq!(core, [4, 3, 0x201, 2]);
+        // Invalid level.
+        let mut invalid = [0, 0, 2, 2];
+        limit_topology(0x1f, &mut invalid, cpus_sharing);
+        assert_eq!(invalid, [0, 0, 2, 2]);
+    }
+}
@@ -1,6 +1,7 @@
 use core::arch::{asm, global_asm};
 
 use super::context::GeneralRegisters;
+use crate::percpu::{CpuState, PerCpu};
 
 global_asm!(include_str!(concat!(env!("OUT_DIR"), "/exception.S")));
 
@@ -56,7 +57,7 @@ pub struct TrapFrame {
 fn exception_handler(frame: &TrapFrame) {
     trace!("Exception or interrupt #{:#x}", frame.num);
     match frame.num as u8 {
-        ExceptionType::NonMaskableInterrupt => handle_nmi(),
+        ExceptionType::NonMaskableInterrupt => handle_nmi(frame),
         ExceptionType::PageFault => handle_page_fault(frame),
         ExceptionType::IrqStart..=ExceptionType::IrqEnd => {
             error!("{:#x?}", frame);
@@ -69,8 +70,27 @@ fn exception_handler(frame: &TrapFrame) {
     }
 }
 
-fn handle_nmi() {
-    warn!("Unhandled exception: NMI");
+/// NMIs from several sources may be merged into one, so every source is
+/// checked and consumed.
+fn handle_nmi(frame: &TrapFrame) {
+    // NMIs are expected when stopping CPUs on hypervisor panic.
+    if crate::lang::panic_cpu().is_some() {
+        return;
+    }
+    let cpu_data = PerCpu::current_mut();
+    if cpu_data.state != CpuState::HvEnabled {
+        warn!("Unhandled exception: NMI");
+        return;
+    }
+    // Sent by the hypervisor for the guest, e.g. by the watchdog.
+    let for_guest = cpu_data.arch.take_guest_nmi();
+    let sampled = crate::profile::handle_pmi(frame.rip, frame.regs.rbp as usize);
+    // Only forces a VM exit for the hypervisor tick.
+    let tick = cpu_data.vcpu.take_timer_nmi();
+    // Others belong to the guest, e.g. for its own performance counters.
+    if for_guest || !(sampled || tick) {
+        cpu_data.vcpu.queue_nmi();
+    }
 }
 
 fn handle_page_fault(frame: &TrapFrame) {
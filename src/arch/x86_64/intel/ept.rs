@@ -112,7 +112,7 @@ impl GenericPTE for EPTEntry {
         }
         self.set_flags_and_mem_type(flags, EPTMemType::WriteBack);
     }
-    fn set_table(&mut self, paddr: HostPhysAddr) {
+    fn set_table(&mut self, paddr: HostPhysAddr, _next_level: usize) {
         self.set_addr(paddr);
         self.set_flags_and_mem_type(
             EPTFlags::READ | EPTFlags::WRITE | EPTFlags::EXECUTE,
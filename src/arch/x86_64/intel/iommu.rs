@@ -0,0 +1,702 @@
+//! Intel VT-d DMA remapping.
+//!
+//! All units share one root table. Each PCI device of the root cell gets a
+//! context entry pointing to the second-level page table of its domain, which
+//! maps the DMA-capable memory regions of the cell. DMA of other devices, and
+//! to any other memory, is blocked and recorded in the fault recording
+//! registers. Fault interrupts are masked, the registers are polled on each
+//! hypervisor tick.
+//!
+//! Interrupts from devices and IOAPICs are remapped by the entries of one
+//! interrupt remapping table, each only usable by one source ID. Entries are
+//! filled when a cell programs an MSI or IOAPIC pin, after checking that it
+//! targets the CPUs of the cell. Interrupts in compatibility format are
+//! blocked.
+
+use alloc::boxed::Box;
+use alloc::collections::btree_map::{BTreeMap, Entry};
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use bit_field::BitField;
+use bitflags::bitflags;
+use spin::Mutex;
+
+use crate::arch::apic;
+use crate::arch::clocksource::Deadline;
+use crate::arch::irq::{IrqMsg, MSI_ADDRESS_BASE, MSI_ADDRESS_REMAPPABLE};
+use crate::cell::Cell;
+use crate::config::{HvIommu, HvPciDevice, HvSystemConfig};
+use crate::error::HvResult;
+use crate::memory::addr::{phys_to_virt, virt_to_phys, GuestPhysAddr, HostPhysAddr, HostVirtAddr};
+use crate::memory::{Frame, GenericPTE, GenericPageTableImmut, Level4PageTable, PagingInstr};
+use crate::memory::{MemFlags, MemoryRegion, MemorySet, PAGE_SIZE};
+
+const CAP_REG: usize = 0x08;
+const ECAP_REG: usize = 0x10;
+const GCMD_REG: usize = 0x18;
+const GSTS_REG: usize = 0x1c;
+const RTADDR_REG: usize = 0x20;
+const CCMD_REG: usize = 0x28;
+const FSTS_REG: usize = 0x34;
+const FECTL_REG: usize = 0x38;
+const IQH_REG: usize = 0x80;
+const IQT_REG: usize = 0x88;
+const IQA_REG: usize = 0x90;
+const IRTA_REG: usize = 0xb8;
+
+/// Commands of `GCMD_REG`, with the same bits in `GSTS_REG` for the status.
+const GCMD_TE: u32 = 1 << 31;
+const GCMD_SRTP: u32 = 1 << 30;
+const GCMD_QIE: u32 = 1 << 26;
+const GCMD_IRE: u32 = 1 << 25;
+const GCMD_SIRTP: u32 = 1 << 24;
+const GCMD_CFI: u32 = 1 << 23;
+/// Enabled features to keep when writing `GCMD_REG`.
+const GSTS_PERSISTENT: u32 = GCMD_TE | GCMD_QIE | GCMD_IRE | GCMD_CFI;
+
+const CCMD_ICC: u64 = 1 << 63;
+const CCMD_CIRG_GLOBAL: u64 = 1 << 61;
+
+const IOTLB_IVT: u64 = 1 << 63;
+const IOTLB_IIRG_GLOBAL: u64 = 1 << 60;
+const IOTLB_DR: u64 = 1 << 49;
+const IOTLB_DW: u64 = 1 << 48;
+
+const FSTS_PFO: u32 = 1 << 0;
+const FSTS_PPF: u32 = 1 << 1;
+const FECTL_IM: u32 = 1 << 31;
+
+/// Fault bit in the upper half of a fault recording register.
+const FRCD_F: u64 = 1 << 63;
+
+/// 4-level page tables in `CAP_REG.SAGAW`.
+const CAP_SAGAW_48BIT: u64 = 1 << 2;
+/// 2 MiB and 1 GiB pages in `CAP_REG.SLLPS`.
+const CAP_SLLPS_ALL: u64 = 0b11;
+/// Page walks snoop the processor caches.
+const ECAP_C: u64 = 1 << 0;
+const ECAP_QI: u64 = 1 << 1;
+const ECAP_IR: u64 = 1 << 3;
+/// x2APIC IDs in interrupt remapping table entries.
+const ECAP_EIM: u64 = 1 << 4;
+
+/// Invalidation queue descriptors of 128 bits, one page.
+const QI_DESC_SIZE: usize = 16;
+const QI_TYPE_IEC: u64 = 0x4;
+const QI_TYPE_WAIT: u64 = 0x5;
+/// Write the status data of an invalidation wait descriptor.
+const QI_WAIT_SW: u64 = 1 << 5;
+
+const IRTA_EIME: u64 = 1 << 11;
+const IRTE_PRESENT: u64 = 1 << 0;
+/// Verify the source ID of requests.
+const IRTE_SVT_SID: u64 = 1 << 18;
+
+const CONTEXT_ADDR_WIDTH_48BIT: u64 = 0b010;
+/// DID 0 is reserved with caching mode.
+const ROOT_DOMAIN_ID: u16 = 1;
+
+const TIMEOUT_NS: u64 = 1_000_000_000; // 1 s
+
+bitflags! {
+    struct DmaFlags: u64 {
+        const READ =        1 << 0;
+        const WRITE =       1 << 1;
+        const HUGE_PAGE =   1 << 7;
+    }
+}
+
+/// Second-level page table entry.
+#[derive(Clone)]
+pub struct DmaEntry(u64);
+
+impl From<MemFlags> for DmaFlags {
+    fn from(f: MemFlags) -> Self {
+        let mut ret = Self::empty();
+        if f.contains(MemFlags::READ) {
+            ret |= Self::READ;
+        }
+        if f.contains(MemFlags::WRITE) {
+            ret |= Self::WRITE;
+        }
+        ret
+    }
+}
+
+impl From<DmaFlags> for MemFlags {
+    fn from(f: DmaFlags) -> Self {
+        let mut ret = MemFlags::empty();
+        if f.contains(DmaFlags::READ) {
+            ret |= Self::READ;
+        }
+        if f.contains(DmaFlags::WRITE) {
+            ret |= Self::WRITE;
+        }
+        ret
+    }
+}
+
+impl GenericPTE for DmaEntry {
+    fn addr(&self) -> HostPhysAddr {
+        (self.0.get_bits(12..52) << 12) as usize
+    }
+    fn flags(&self) -> MemFlags {
+        self.dma_flags().into()
+    }
+    fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+    fn is_present(&self) -> bool {
+        self.0.get_bits(0..2) != 0
+    }
+    fn is_huge(&self) -> bool {
+        self.dma_flags().contains(DmaFlags::HUGE_PAGE)
+    }
+
+    fn set_addr(&mut self, paddr: HostPhysAddr) {
+        self.0.set_bits(12..52, paddr as u64 >> 12);
+    }
+    fn set_flags(&mut self, flags: MemFlags, is_huge: bool) {
+        let mut flags = DmaFlags::from(flags);
+        if is_huge {
+            flags |= DmaFlags::HUGE_PAGE;
+        }
+        self.0.set_bits(0..12, flags.bits());
+    }
+    fn set_table(&mut self, paddr: HostPhysAddr, _next_level: usize) {
+        self.set_addr(paddr);
+        self.0
+            .set_bits(0..12, (DmaFlags::READ | DmaFlags::WRITE).bits());
+    }
+    fn clear(&mut self) {
+        self.0 = 0
+    }
+}
+
+impl DmaEntry {
+    fn dma_flags(&self) -> DmaFlags {
+        DmaFlags::from_bits_truncate(self.0)
+    }
+}
+
+impl fmt::Debug for DmaEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DmaEntry")
+            .field("raw", &self.0)
+            .field("hpaddr", &self.addr())
+            .field("flags", &self.dma_flags())
+            .finish()
+    }
+}
+
+pub struct DmaInstr;
+
+impl PagingInstr for DmaInstr {
+    unsafe fn activate(_root_paddr: HostPhysAddr) {
+        // set in context entries
+    }
+
+    fn flush(_vaddr: Option<usize>) {
+        // the IOTLB is invalidated when enabling translation
+    }
+}
+
+pub type DmaPageTable = Level4PageTable<GuestPhysAddr, DmaEntry, DmaInstr>;
+
+/// Root and context entries, 128 bits each.
+#[repr(C)]
+struct TableEntry {
+    lo: u64,
+    hi: u64,
+}
+
+fn entries_of(frame: &mut Frame) -> &mut [TableEntry] {
+    unsafe { core::slice::from_raw_parts_mut(frame.as_mut_ptr() as *mut TableEntry, 256) }
+}
+
+/// Invalidation queue, used for the interrupt entry cache.
+struct InvQueue {
+    frame: Frame,
+    tail: usize,
+    /// Written by invalidation wait descriptors.
+    status: Box<AtomicU32>,
+}
+
+struct VtdUnit {
+    base: HostVirtAddr,
+    cap: u64,
+    ecap: u64,
+    queue: Option<InvQueue>,
+}
+
+impl VtdUnit {
+    fn new(iommu: &HvIommu) -> HvResult<Self> {
+        let base = phys_to_virt(iommu.base as HostPhysAddr);
+        let mut unit = Self {
+            base,
+            cap: 0,
+            ecap: 0,
+            queue: None,
+        };
+        unit.cap = unit.read64(CAP_REG);
+        unit.ecap = unit.read64(ECAP_REG);
+        if unit.read32(GSTS_REG) & GCMD_TE != 0 {
+            return hv_result_err!(
+                EBUSY,
+                format!(
+                    "VT-d unit at {:#x} is in use, boot Linux with intel_iommu=off",
+                    { iommu.base }
+                )
+            );
+        }
+        if unit.cap & (CAP_SAGAW_48BIT << 8) == 0 {
+            return hv_result_err!(
+                ENODEV,
+                format!("VT-d unit at {:#x} does not support 4-level page tables", {
+                    iommu.base
+                })
+            );
+        }
+        Ok(unit)
+    }
+
+    fn read32(&self, reg: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((self.base + reg) as *const u32) }
+    }
+
+    fn read64(&self, reg: usize) -> u64 {
+        unsafe { core::ptr::read_volatile((self.base + reg) as *const u64) }
+    }
+
+    fn write32(&self, reg: usize, value: u32) {
+        unsafe { core::ptr::write_volatile((self.base + reg) as *mut u32, value) }
+    }
+
+    fn write64(&self, reg: usize, value: u64) {
+        unsafe { core::ptr::write_volatile((self.base + reg) as *mut u64, value) }
+    }
+
+    fn wait(&self, what: &str, done: impl Fn() -> bool) -> HvResult {
+        let deadline = Deadline::after_nanos(TIMEOUT_NS);
+        while !done() {
+            if deadline.expired() {
+                return hv_result_err!(ETIMEDOUT, format!("Timed out waiting for VT-d {}", what));
+            }
+            core::hint::spin_loop();
+        }
+        Ok(())
+    }
+
+    fn iotlb_reg(&self) -> usize {
+        self.ecap.get_bits(8..18) as usize * 16 + 8
+    }
+
+    fn fault_recording_reg(&self, index: usize) -> usize {
+        self.cap.get_bits(24..34) as usize * 16 + index * 16
+    }
+
+    fn num_fault_recording_regs(&self) -> usize {
+        self.cap.get_bits(40..48) as usize + 1
+    }
+
+    fn supports_huge_pages(&self) -> bool {
+        self.cap.get_bits(34..38) & CAP_SLLPS_ALL == CAP_SLLPS_ALL
+    }
+
+    fn is_coherent(&self) -> bool {
+        self.ecap & ECAP_C != 0
+    }
+
+    fn supports_irq_remapping(&self) -> bool {
+        let mut required = ECAP_QI | ECAP_IR;
+        if apic::x2apic_enabled() {
+            required |= ECAP_EIM;
+        }
+        self.ecap & required == required
+    }
+
+    /// Set or clear `cmd` in `GCMD_REG` and wait for its status.
+    fn update_gcmd(&self, cmd: u32, set: bool) -> HvResult {
+        let mut value = self.read32(GSTS_REG) & GSTS_PERSISTENT;
+        if set {
+            value |= cmd;
+        } else {
+            value &= !cmd;
+        }
+        self.write32(GCMD_REG, value);
+        self.wait("global status", || {
+            (self.read32(GSTS_REG) & cmd != 0) == set
+        })
+    }
+
+    fn invalidate(&self) -> HvResult {
+        self.write64(CCMD_REG, CCMD_ICC | CCMD_CIRG_GLOBAL);
+        self.wait("context cache invalidation", || {
+            self.read64(CCMD_REG) & CCMD_ICC == 0
+        })?;
+        let iotlb_reg = self.iotlb_reg();
+        self.write64(
+            iotlb_reg,
+            IOTLB_IVT | IOTLB_IIRG_GLOBAL | IOTLB_DR | IOTLB_DW,
+        );
+        self.wait("IOTLB invalidation", || {
+            self.read64(iotlb_reg) & IOTLB_IVT == 0
+        })
+    }
+
+    fn enable(&self, root_table_paddr: HostPhysAddr) -> HvResult {
+        // Faults are polled.
+        self.write32(FECTL_REG, FECTL_IM);
+        self.check_faults();
+        self.write64(RTADDR_REG, root_table_paddr as u64);
+        self.update_gcmd(GCMD_SRTP, true)?;
+        self.invalidate()?;
+        self.update_gcmd(GCMD_TE, true)
+    }
+
+    fn enable_irq_remapping(&mut self, irt_paddr: HostPhysAddr, irt_entries: usize) -> HvResult {
+        // The interrupt entry cache can only be invalidated by queued
+        // invalidation, register-based invalidation is not used from now on.
+        let queue = InvQueue {
+            frame: Frame::new_zero()?,
+            tail: 0,
+            status: Box::new(AtomicU32::new(0)),
+        };
+        self.write64(IQT_REG, 0);
+        self.write64(IQA_REG, queue.frame.start_paddr() as u64);
+        self.queue = Some(queue);
+        self.update_gcmd(GCMD_QIE, true)?;
+
+        let mut irta = irt_paddr as u64 | (irt_entries.trailing_zeros() - 1) as u64;
+        if apic::x2apic_enabled() {
+            irta |= IRTA_EIME;
+        }
+        self.write64(IRTA_REG, irta);
+        self.update_gcmd(GCMD_SIRTP, true)?;
+        self.invalidate_irq_entries()?;
+        // Interrupts in compatibility format are blocked.
+        self.update_gcmd(GCMD_IRE, true)
+    }
+
+    fn submit(&mut self, desc: [u64; 2]) -> HvResult {
+        let queue = match &mut self.queue {
+            Some(queue) => queue,
+            None => return hv_result_err!(ENODEV, "VT-d invalidation queue is disabled"),
+        };
+        let next_tail = (queue.tail + QI_DESC_SIZE) % PAGE_SIZE;
+        let deadline = Deadline::after_nanos(TIMEOUT_NS);
+        while self.read64(IQH_REG) as usize == next_tail {
+            if deadline.expired() {
+                return hv_result_err!(ETIMEDOUT, "VT-d invalidation queue is full");
+            }
+            core::hint::spin_loop();
+        }
+        let queue = self.queue.as_mut().unwrap();
+        let ptr = unsafe { queue.frame.as_mut_ptr().add(queue.tail) as *mut [u64; 2] };
+        unsafe { ptr.write_volatile(desc) };
+        queue.tail = next_tail;
+        self.write64(IQT_REG, next_tail as u64);
+        Ok(())
+    }
+
+    /// Invalidate the whole interrupt entry cache, and wait for it.
+    fn invalidate_irq_entries(&mut self) -> HvResult {
+        let status = match &self.queue {
+            Some(queue) => queue.status.as_ref() as *const AtomicU32,
+            None => return hv_result_err!(ENODEV, "VT-d invalidation queue is disabled"),
+        };
+        // The status stays boxed while the queue exists.
+        let status = unsafe { &*status };
+        status.store(0, Ordering::Release);
+        self.submit([QI_TYPE_IEC, 0])?;
+        let status_paddr = virt_to_phys(status as *const _ as usize) as u64;
+        self.submit([QI_TYPE_WAIT | QI_WAIT_SW | 1 << 32, status_paddr])?;
+        self.wait("invalidation wait", || status.load(Ordering::Acquire) != 0)
+    }
+
+    fn disable(&self) -> HvResult {
+        if self.queue.is_some() {
+            self.update_gcmd(GCMD_IRE, false)?;
+            self.update_gcmd(GCMD_CFI, false)?;
+            self.update_gcmd(GCMD_QIE, false)?;
+        }
+        self.update_gcmd(GCMD_TE, false)
+    }
+
+    /// Report and clear recorded faults.
+    fn check_faults(&self) {
+        let fsts = self.read32(FSTS_REG);
+        if fsts & FSTS_PPF != 0 {
+            let num = self.num_fault_recording_regs();
+            let mut index = fsts.get_bits(8..16) as usize;
+            for _ in 0..num {
+                let reg = self.fault_recording_reg(index);
+                let hi = self.read64(reg + 8);
+                if hi & FRCD_F == 0 {
+                    break;
+                }
+                let lo = self.read64(reg);
+                let sid = hi.get_bits(0..16);
+                warn!(
+                    "VT-d fault: {} {:#x} from {:02x}:{:02x}.{:x}, reason {:#x}",
+                    if hi.get_bit(62) { "read" } else { "write" },
+                    lo & !0xfff,
+                    sid.get_bits(8..16),
+                    sid.get_bits(3..8),
+                    sid.get_bits(0..3),
+                    hi.get_bits(32..40),
+                );
+                self.write64(reg + 8, FRCD_F);
+                index = (index + 1) % num;
+            }
+        }
+        if fsts & FSTS_PFO != 0 {
+            warn!("VT-d fault recording registers overflowed");
+            self.write32(FSTS_REG, FSTS_PFO);
+        }
+    }
+}
+
+struct Vtd {
+    units: Vec<VtdUnit>,
+    root_table: Frame,
+    /// Context tables by bus number.
+    context_tables: BTreeMap<u8, Frame>,
+    /// Second-level translation of the root cell.
+    root_domain: MemorySet<DmaPageTable>,
+    /// Interrupt remapping table, if supported by all units.
+    irt: Option<Frame>,
+    irt_entries: usize,
+    /// Allocated entries of `irt`, by source ID and the index of the
+    /// interrupt of that source.
+    irqs: BTreeMap<(u16, u16), usize>,
+}
+
+static VTD: Mutex<Option<Vtd>> = Mutex::new(None);
+
+impl Vtd {
+    fn new(units: Vec<VtdUnit>) -> HvResult<Self> {
+        Ok(Self {
+            units,
+            root_table: Frame::new_zero()?,
+            context_tables: BTreeMap::new(),
+            root_domain: MemorySet::new(),
+            irt: None,
+            irt_entries: 0,
+            irqs: BTreeMap::new(),
+        })
+    }
+
+    fn alloc_irq_table(&mut self) -> HvResult {
+        let limit = HvSystemConfig::get().vtd_interrupt_limit() as usize;
+        if !self.units.iter().all(VtdUnit::supports_irq_remapping) {
+            warn!("VT-d interrupt remapping is not supported, interrupts are not isolated!");
+            return Ok(());
+        }
+        if !limit.is_power_of_two() || !(2..=0x10000).contains(&limit) {
+            return hv_result_err!(EINVAL, format!("Invalid VT-d interrupt limit {}", limit));
+        }
+        let pages = (limit * 16 + PAGE_SIZE - 1) / PAGE_SIZE;
+        let mut irt = Frame::new_contiguous(pages, 0)?;
+        irt.zero();
+        self.irt = Some(irt);
+        self.irt_entries = limit;
+        Ok(())
+    }
+
+    /// Fill the remapping entry for interrupt `index` of `sid`, returns the
+    /// entry index.
+    fn map_irq(&mut self, sid: u16, index: u16, msg: &IrqMsg) -> HvResult<usize> {
+        let irt = match &mut self.irt {
+            Some(irt) => irt,
+            None => return hv_result_err!(ENODEV, "VT-d interrupt remapping is disabled"),
+        };
+        let next = self.irqs.len();
+        if next >= self.irt_entries && !self.irqs.contains_key(&(sid, index)) {
+            return hv_result_err!(ERANGE, "Out of VT-d interrupt remapping entries");
+        }
+        let irte_index = *self.irqs.entry((sid, index)).or_insert(next);
+
+        let dest = if apic::x2apic_enabled() {
+            msg.dest
+        } else {
+            msg.dest << 8
+        };
+        let irte = &mut entries_of(irt)[irte_index];
+        irte.lo = 0;
+        irte.hi = sid as u64 | IRTE_SVT_SID;
+        irte.lo = IRTE_PRESENT
+            | (msg.dest_logical as u64) << 2
+            | (msg.redir_hint as u64) << 3
+            | (msg.level_triggered as u64) << 4
+            | (msg.delivery_mode as u64) << 5
+            | (msg.vector as u64) << 16
+            | (dest as u64) << 32;
+        for unit in self.units.iter_mut() {
+            unit.invalidate_irq_entries()?;
+        }
+        Ok(irte_index)
+    }
+
+    /// Mirror the DMA-capable memory regions of `cell`.
+    fn map_cell_memory(&mut self, cell: &Cell) -> HvResult {
+        let mut flags = MemFlags::empty();
+        if !self.units.iter().all(VtdUnit::supports_huge_pages) {
+            flags |= MemFlags::NO_HUGEPAGES;
+        }
+        for region in cell.config.mem_regions() {
+            if region.flags.contains(MemFlags::DMA) {
+                self.root_domain
+                    .insert(MemoryRegion::new_with_offset_mapper(
+                        region.virt_start as GuestPhysAddr,
+                        region.phys_start as HostPhysAddr,
+                        region.size as usize,
+                        region.flags & (MemFlags::READ | MemFlags::WRITE) | flags,
+                    ))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn add_device(&mut self, bdf: u16, domain_id: u16, page_table_paddr: HostPhysAddr) -> HvResult {
+        let (bus, devfn) = ((bdf >> 8) as u8, bdf as u8 as usize);
+        let context_table = match self.context_tables.entry(bus) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let frame = e.insert(Frame::new_zero()?);
+                let root_entry = &mut entries_of(&mut self.root_table)[bus as usize];
+                root_entry.lo = frame.start_paddr() as u64 | 1;
+                frame
+            }
+        };
+        let entry = &mut entries_of(context_table)[devfn];
+        if entry.lo & 1 != 0 {
+            return hv_result_err!(EEXIST, format!("PCI device {:#x} added twice", bdf));
+        }
+        entry.hi = CONTEXT_ADDR_WIDTH_48BIT | (domain_id as u64) << 8;
+        // Present, only untranslated requests are allowed.
+        entry.lo = page_table_paddr as u64 | 1;
+        Ok(())
+    }
+
+    fn add_cell_devices(&mut self, cell: &Cell) -> HvResult {
+        let page_table_paddr = self.root_domain.page_table().root_paddr();
+        for dev in cell.config.pci_devices() {
+            if dev.pci_device_type == HvPciDevice::TYPE_IVSHMEM {
+                continue;
+            }
+            self.add_device(dev.bdf, ROOT_DOMAIN_ID, page_table_paddr)?;
+        }
+        Ok(())
+    }
+
+    fn enable(&mut self) -> HvResult {
+        if !self.units.iter().all(VtdUnit::is_coherent) {
+            // Page walks of some units don't snoop caches.
+            unsafe { core::arch::asm!("wbinvd") };
+        }
+        let irt = self.irt.as_ref().map(Frame::start_paddr);
+        for (i, unit) in self.units.iter_mut().enumerate() {
+            unit.enable(self.root_table.start_paddr())
+                .and_then(|_| match irt {
+                    Some(irt_paddr) => unit.enable_irq_remapping(irt_paddr, self.irt_entries),
+                    None => Ok(()),
+                })
+                .map_err(|e| e.context(format!("failed to enable VT-d unit {}", i)))?;
+        }
+        Ok(())
+    }
+}
+
+/// Isolate DMA of the devices of `cell`, called once by the primary CPU.
+pub fn init(cell: &Cell) -> HvResult {
+    let units = HvSystemConfig::get()
+        .iommu_units()
+        .iter()
+        .map(VtdUnit::new)
+        .collect::<HvResult<Vec<_>>>()?;
+    if units.is_empty() {
+        warn!("No VT-d units, DMA is not isolated!");
+        return Ok(());
+    }
+    let mut vtd = Vtd::new(units)?;
+    vtd.map_cell_memory(cell)?;
+    vtd.add_cell_devices(cell)?;
+    vtd.alloc_irq_table()?;
+    // Stored before enabling, so that `shutdown()` disables the enabled units
+    // on errors.
+    let mut guard = VTD.lock();
+    let vtd = guard.insert(vtd);
+    vtd.enable()?;
+    info!(
+        "VT-d DMA remapping enabled on {} units, interrupt remapping: {}.",
+        vtd.units.len(),
+        vtd.irt.is_some()
+    );
+    Ok(())
+}
+
+/// Check the MSI `index` of PCI device `bdf` programmed by `cell`, and remap
+/// it. Returns the MSI address and data to write to the device.
+pub fn map_msi(cell: &Cell, bdf: u16, index: u16, address: u64, data: u32) -> HvResult<(u64, u32)> {
+    let msg = IrqMsg::from_msi(address, data)?;
+    msg.check_dest(cell)?;
+    let irte_index = match VTD.lock().as_mut() {
+        Some(vtd) if vtd.irt.is_some() => vtd.map_irq(bdf, index, &msg)?,
+        _ => return Ok((address, data)),
+    };
+    let address = MSI_ADDRESS_BASE
+        | MSI_ADDRESS_REMAPPABLE
+        | (irte_index as u64 & 0x7fff) << 5
+        | (irte_index as u64 >> 15) << 2;
+    Ok((address, 0))
+}
+
+/// Check the redirection table entry of `pin` of the IOAPIC with `sid`
+/// programmed by `cell`, and remap it. Returns the entry to write to the
+/// IOAPIC.
+pub fn map_ioapic_pin(cell: &Cell, sid: u16, pin: u8, rte: u64) -> HvResult<u64> {
+    const RTE_MASKED: u64 = 1 << 16;
+    if rte & RTE_MASKED != 0 {
+        return Ok(rte);
+    }
+    let msg = IrqMsg::from_ioapic_rte(rte);
+    msg.check_dest(cell)?;
+    let irte_index = match VTD.lock().as_mut() {
+        Some(vtd) if vtd.irt.is_some() => vtd.map_irq(sid, pin as u16, &msg)?,
+        _ => return Ok(rte),
+    };
+    // Keep the vector for EOIs, and the polarity and trigger mode.
+    Ok(rte & (0xff | 1 << 13 | 1 << 15)
+        | 1 << 48
+        | (irte_index as u64 & 0x7fff) << 49
+        | (irte_index as u64 >> 15) << 11)
+}
+
+/// Turn off DMA remapping when leaving the hypervisor, and free the tables.
+pub fn shutdown() {
+    // Another CPU may have stopped with the lock held.
+    let vtd = match VTD.try_lock() {
+        Some(mut vtd) => vtd.take(),
+        None => {
+            error!("VT-d is locked, DMA remapping stays enabled!");
+            return;
+        }
+    };
+    if let Some(vtd) = vtd {
+        for (i, unit) in vtd.units.iter().enumerate() {
+            if let Err(e) = unit.disable() {
+                error!("Failed to disable VT-d unit {}: {:?}", i, e);
+            }
+        }
+    }
+}
+
+/// Report DMA remapping faults, called on hypervisor ticks.
+pub fn check_faults() {
+    if let Some(Some(vtd)) = VTD.try_lock().as_deref() {
+        vtd.units.iter().for_each(VtdUnit::check_faults);
+    }
+}
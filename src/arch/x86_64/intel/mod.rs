@@ -3,7 +3,11 @@ mod structs;
 mod vcpu;
 mod vmexit;
 
-use libvmm::vmx::Vmcs;
+pub mod iommu;
+pub mod pmu;
+
+use libvmm::msr::Msr;
+use libvmm::vmx::{flags::SecondaryVmExecControls, Vmcs};
 use x86::vmx::VmFail;
 
 use crate::arch::cpuid::CpuFeatures;
@@ -11,6 +15,7 @@ use crate::error::{HvError, HvResult};
 
 pub use ept::ExtendedPageTable as NestedPageTable;
 pub use vcpu::Vcpu;
+pub use vmexit::exit_reason_name;
 
 impl From<VmFail> for HvError {
     fn from(err: VmFail) -> Self {
@@ -32,3 +37,12 @@ pub fn check_hypervisor_feature() -> HvResult {
         hv_result_err!(ENODEV, "VMX feature checks failed!")
     }
 }
+
+/// Fractional bits of the TSC multiplier.
+pub const TSC_RATIO_FRAC_BITS: u32 = 48;
+
+pub fn has_tsc_scaling() -> bool {
+    (Msr::IA32_VMX_PROCBASED_CTLS2.read() >> 32) as u32
+        & SecondaryVmExecControls::TSC_SCALING.bits()
+        != 0
+}
@@ -0,0 +1,138 @@
+#![cfg_attr(not(feature = "profile"), allow(dead_code))]
+
+//! Performance monitoring counters, shared by the guest and the sampling
+//! profiler.
+//!
+//! The guest owns the PMU: `IA32_PERF_GLOBAL_CTRL` is switched by the VM-entry
+//! and VM-exit load controls, so its counters stop in the hypervisor. The
+//! profiler borrows counter 0, whose state is switched by `CounterState` on
+//! each VM exit and before each VM entry.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use libvmm::msr::Msr;
+use libvmm::vmx::flags::{VmEntryControls as EntryCtrl, VmExitControls as ExitCtrl};
+use libvmm::vmx::vmcs::VmcsField64Host;
+
+use crate::arch::cpuid::CpuFeatures;
+use crate::error::HvResult;
+
+/// Architectural event "UnHalted Core Cycles".
+const EVENT_UNHALTED_CORE_CYCLES: u64 = 0x3c;
+const PERFEVTSEL_OS: u64 = 1 << 17;
+const PERFEVTSEL_INT: u64 = 1 << 20;
+const PERFEVTSEL_EN: u64 = 1 << 22;
+/// Bit of counter 0 in `IA32_PERF_GLOBAL_{CTRL,STATUS,OVF_CTRL}`.
+const PMC0: u64 = 1 << 0;
+/// Full-width writes through `IA32_A_PMCx` in `IA32_PERF_CAPABILITIES`.
+const PERF_CAP_FW_WRITE: u64 = 1 << 13;
+
+/// Mask of the counter width if full-width writes are supported, 0 otherwise.
+/// Determined by `sampling_start()`.
+static FULL_WIDTH_MASK: AtomicU64 = AtomicU64::new(0);
+
+fn full_width_mask() -> u64 {
+    let features = CpuFeatures::new();
+    let width = features.perf_counter_bit_width();
+    if features.has_pdcm()
+        && Msr::IA32_PERF_CAPABILITIES.read() & PERF_CAP_FW_WRITE != 0
+        && (1..64).contains(&width)
+    {
+        (1 << width) - 1
+    } else {
+        0
+    }
+}
+
+/// Write counter 0, keeping all bits of `count` with full-width writes.
+fn write_counter(count: u64) {
+    let mask = FULL_WIDTH_MASK.load(Ordering::Relaxed);
+    unsafe {
+        if mask != 0 {
+            Msr::IA32_A_PMC0.write(count & mask);
+        } else {
+            // Writes to `IA32_PMCx` are sign-extended from bit 31, which keeps
+            // counters armed to overflow within 2^31 events, as Linux does
+            // without full-width writes.
+            Msr::IA32_PMC0.write(count & 0xffff_ffff);
+        }
+    }
+}
+
+/// Whether `IA32_PERF_GLOBAL_CTRL` exists, and can be loaded on VM entries
+/// and VM exits.
+pub fn has_global_ctrl() -> bool {
+    // Global control and status MSRs are available since version 2.
+    CpuFeatures::new().perf_monitor_version_id() >= 2
+        && (Msr::IA32_VMX_EXIT_CTLS.read() >> 32) as u32
+            & ExitCtrl::LOAD_IA32_PERF_GLOBAL_CTRL.bits()
+            != 0
+        && (Msr::IA32_VMX_ENTRY_CTLS.read() >> 32) as u32
+            & EntryCtrl::LOAD_IA32_PERF_GLOBAL_CTRL.bits()
+            != 0
+}
+
+/// Counter 0 and its event selector.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CounterState {
+    evtsel: u64,
+    count: u64,
+}
+
+impl CounterState {
+    pub fn set_evtsel(&mut self, evtsel: u64) {
+        self.evtsel = evtsel;
+    }
+
+    pub fn save() -> Self {
+        Self {
+            evtsel: Msr::IA32_PERFEVTSEL0.read(),
+            count: Msr::IA32_PMC0.read(),
+        }
+    }
+
+    pub fn restore(&self) {
+        unsafe { Msr::IA32_PERFEVTSEL0.write(self.evtsel) };
+        write_counter(self.count);
+    }
+}
+
+/// Event selectors of the guest are not intercepted, as its counters are
+/// stopped in the hypervisor by `IA32_PERF_GLOBAL_CTRL`.
+pub fn guest_evtsel(_msr: u32, _value: u64) -> Option<(u32, u64)> {
+    None
+}
+
+/// Program counter 0 to overflow every `period` hypervisor cycles, and enable
+/// it on VM exits. Must be called with the VMCS loaded.
+pub fn sampling_start(period: u64) -> HvResult {
+    if !has_global_ctrl() {
+        return hv_result_err!(ENODEV, "Architectural performance monitoring v2 required!");
+    }
+    FULL_WIDTH_MASK.store(full_width_mask(), Ordering::Relaxed);
+    unsafe {
+        Msr::IA32_PERFEVTSEL0
+            .write(EVENT_UNHALTED_CORE_CYCLES | PERFEVTSEL_OS | PERFEVTSEL_INT | PERFEVTSEL_EN)
+    };
+    sampling_rearm(period);
+    VmcsField64Host::IA32_PERF_GLOBAL_CTRL.write(PMC0)?;
+    Ok(())
+}
+
+/// Reload the counter and clear its overflow status.
+pub fn sampling_rearm(period: u64) {
+    write_counter(period.wrapping_neg());
+    unsafe { Msr::IA32_PERF_GLOBAL_OVF_CTRL.write(PMC0) };
+}
+
+/// Whether the counter overflowed since the last `sampling_rearm()`, i.e. it
+/// wrapped around to a small value. `IA32_PERF_GLOBAL_STATUS` is not used, as
+/// it also reports overflows of the guest counter.
+pub fn sampling_overflowed() -> bool {
+    Msr::IA32_PMC0.read() & (1 << 31) == 0
+}
+
+/// Stop counting on VM exits. Must be called with the VMCS loaded.
+pub fn sampling_stop() {
+    VmcsField64Host::IA32_PERF_GLOBAL_CTRL.write(0).ok();
+}
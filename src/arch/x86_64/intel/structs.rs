@@ -23,16 +23,78 @@ impl VmxRegion {
     }
 }
 
-pub(super) struct MsrBitmap(AlignedPage);
+/// VM-exit MSR-store area (Intel SDM Volume 3, Section 24.7.2), where the
+/// guest values of the given MSRs are saved on each VM exit.
+pub(super) struct MsrStoreArea {
+    frame: Frame,
+    count: usize,
+}
 
-impl MsrBitmap {
-    fn mask_range(&mut self, msr_range: core::ops::RangeInclusive<u32>, is_write: bool) {
-        for msr in msr_range {
-            self.mask(msr, is_write);
+impl MsrStoreArea {
+    pub fn new(msrs: &[u32]) -> HvResult<Self> {
+        let frame = Frame::new_zero()?;
+        let entries = frame.as_mut_ptr() as *mut [u64; 2];
+        for (i, &msr) in msrs.iter().enumerate() {
+            // Each entry is the MSR index in bits 31:0, and the data in bits 127:64.
+            unsafe { *entries.add(i) = [msr as u64, 0] };
+        }
+        Ok(Self {
+            frame,
+            count: msrs.len(),
+        })
+    }
+
+    pub fn paddr(&self) -> PhysAddr {
+        self.frame.start_paddr()
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count as u32
+    }
+
+    /// Value of `msr` saved on the last VM exit.
+    pub fn get(&self, msr: u32) -> Option<u64> {
+        let entries = unsafe {
+            core::slice::from_raw_parts(self.frame.as_ptr() as *const [u64; 2], self.count)
+        };
+        entries.iter().find(|e| e[0] == msr as u64).map(|e| e[1])
+    }
+}
+
+/// I/O bitmaps A and B (Intel SDM Volume 3, Section 24.6.4), for ports
+/// 0x0000..0x7FFF and 0x8000..0xFFFF.
+pub(super) struct IoBitmap([AlignedPage; 2]);
+
+impl IoBitmap {
+    fn intercept(&mut self, port: u16) {
+        let bitmap = &mut self.0[port as usize >> 15];
+        let port = port as usize & 0x7fff;
+        bitmap[port / 8] |= 1 << (port % 8);
+    }
+
+    pub fn paddr_a(&self) -> usize {
+        virt_to_phys(self.0[0].as_ptr() as usize)
+    }
+
+    pub fn paddr_b(&self) -> usize {
+        virt_to_phys(self.0[1].as_ptr() as usize)
+    }
+}
+
+impl Default for IoBitmap {
+    fn default() -> Self {
+        let mut bitmap = Self([AlignedPage::new(), AlignedPage::new()]);
+        for port in crate::arch::pci::INTERCEPTED_PORTS {
+            bitmap.intercept(port);
         }
+        bitmap
     }
+}
 
-    fn mask(&mut self, msr: u32, is_write: bool) {
+pub(super) struct MsrBitmap(AlignedPage);
+
+impl MsrBitmap {
+    fn intercept(&mut self, msr: u32, is_write: bool) {
         // (Intel SDM Volume 3, Section 24.6.9, MSR-Bitmap Address)
         // There are four contiguous MSR bitmaps, which are each 1-KByte in size:
         // 1. Read bitmap for low MSRs (0x0000_0000..0x0000_1FFF)
@@ -51,7 +113,7 @@ impl MsrBitmap {
             if is_write {
                 ptr = ptr.add(2 << 10);
             }
-            core::slice::from_raw_parts_mut(ptr, 1024)[msr_byte] &= 1 << msr_bit;
+            core::slice::from_raw_parts_mut(ptr, 1024)[msr_byte] |= 1 << msr_bit;
         }
     }
 
@@ -61,48 +123,11 @@ impl MsrBitmap {
 }
 
 impl Default for MsrBitmap {
+    /// Only writes to the x2APIC ICR are intercepted in the ranges of the
+    /// bitmap, other MSRs are passed through.
     fn default() -> Self {
         let mut map = Self(AlignedPage::new());
-        // read
-        map.mask(0x277, false); // IA32_PAT
-        map.mask(0x2FF, false); // IA32_MTRR_DEF_TYPE
-
-        map.mask(0x802, false); // IA32_X2APIC_APICID
-        map.mask(0x803, false); // IA32_X2APIC_VERSION
-        map.mask(0x808, false); // IA32_X2APIC_TPR
-        map.mask(0x80A, false); // IA32_X2APIC_PPR
-        map.mask(0x80D, false); // IA32_X2APIC_LDR
-        map.mask(0x80F, false); // IA32_X2APIC_SIVR
-        map.mask_range(0x810..=0x817, false); // IA32_X2APIC_ISR0..IA32_X2APIC_ISR7
-        map.mask_range(0x818..=0x81F, false); // IA32_X2APIC_TMR0..IA32_X2APIC_TMR7
-        map.mask_range(0x820..=0x827, false); // IA32_X2APIC_IRR0..IA32_X2APIC_IRR7
-        map.mask(0x828, false); // IA32_X2APIC_ESR
-        map.mask(0x82F, false); // IA32_X2APIC_LVT_CMCI
-        map.mask(0x830, false); // IA32_X2APIC_ICR
-        map.mask_range(0x832..=0x837, false); // IA32_X2APIC_LVT_*
-        map.mask(0x838, false); // IA32_X2APIC_INIT_COUNT
-        map.mask(0x839, false); // IA32_X2APIC_CUR_COUNT
-        map.mask(0x83E, false); // IA32_X2APIC_DIV_CONF
-
-        // write
-        map.mask(0x1B, true); // IA32_APIC_BASE
-        map.mask_range(0x200..=0x277, true); // IA32_MTRR_*
-        map.mask(0x277, true); // IA32_PAT
-        map.mask(0x2FF, true); // IA32_MTRR_DEF_TYPE
-        map.mask(0x38F, true); // IA32_PERF_GLOBAL_CTRL
-        map.mask_range(0xC80..=0xD8F, true);
-
-        map.mask(0x808, true); // IA32_X2APIC_TPR
-        map.mask(0x80B, true); // IA32_X2APIC_EOI
-        map.mask(0x80F, true); // IA32_X2APIC_SIVR
-        map.mask(0x828, true); // IA32_X2APIC_ESR
-        map.mask(0x82F, true); // IA32_X2APIC_LVT_CMCI
-        map.mask(0x830, true); // IA32_X2APIC_ICR
-        map.mask_range(0x832..=0x837, true); // IA32_X2APIC_LVT_*
-        map.mask(0x838, true); // IA32_X2APIC_INIT_COUNT
-        map.mask(0x839, true); // IA32_X2APIC_CUR_COUNT
-        map.mask(0x83E, true); // IA32_X2APIC_DIV_CONF
-
+        map.intercept(0x830, true); // IA32_X2APIC_ICR
         map
     }
 }
@@ -4,10 +4,10 @@ use core::fmt::{Debug, Formatter, Result};
 use libvmm::msr::Msr;
 use libvmm::vmx::{
     self,
-    flags::{FeatureControl, FeatureControlFlags, VmxBasic},
+    flags::{FeatureControl, FeatureControlFlags, InterruptInfo, VmxBasic},
     vmcs::{VmcsField16Guest, VmcsField32Guest, VmcsField64Guest},
     vmcs::{VmcsField16Host, VmcsField32Host, VmcsField64Host},
-    vmcs::{VmcsField32Control, VmcsField64Control},
+    vmcs::{VmcsField32Control, VmcsField64Control, VmcsField64ReadOnly},
     Vmcs, VmxExitReason,
 };
 use x86::segmentation::SegmentSelector;
@@ -15,7 +15,7 @@ use x86_64::addr::VirtAddr;
 use x86_64::registers::control::{Cr0, Cr0Flags, Cr3, Cr4, Cr4Flags};
 use x86_64::registers::rflags::RFlags;
 
-use super::structs::{MsrBitmap, VmxRegion};
+use super::structs::{IoBitmap, MsrBitmap, MsrStoreArea, VmxRegion};
 use crate::arch::cpuid::CpuFeatures;
 use crate::arch::segmentation::{Segment, SegmentAccessRights};
 use crate::arch::tables::{GdtStruct, IDT};
@@ -35,10 +35,17 @@ pub struct Vcpu {
     vmxon_region: VmxRegion,
     /// VMCS of this CPU, required by VMX
     vmcs_region: VmxRegion,
+    /// Guest MSRs saved on VM exits.
+    msr_store: MsrStoreArea,
+    /// An NMI received in the hypervisor is waiting to be injected.
+    nmi_pending: bool,
+    /// The VMX preemption timer counts down every 2^shift TSC ticks.
+    preemption_timer_shift: Option<u8>,
 }
 
 lazy_static! {
     static ref MSR_BITMAP: MsrBitmap = MsrBitmap::default();
+    static ref IO_BITMAP: IoBitmap = IoBitmap::default();
 }
 
 macro_rules! set_guest_segment {
@@ -57,11 +64,6 @@ impl Vcpu {
     pub fn new(linux: &LinuxContext, cell: &Cell) -> HvResult<Self> {
         super::check_hypervisor_feature()?;
 
-        // make sure all perf counters are off
-        if CpuFeatures::new().perf_monitor_version_id() > 0 {
-            unsafe { Msr::IA32_PERF_GLOBAL_CTRL.write(0) };
-        }
-
         // Check control registers.
         let _cr0 = linux.cr0;
         let cr4 = linux.cr4;
@@ -86,6 +88,13 @@ impl Vcpu {
         let vmx_basic = VmxBasic::read();
         let vmxon_region = VmxRegion::new(vmx_basic.revision_id, false)?;
         let vmcs_region = VmxRegion::new(vmx_basic.revision_id, false)?;
+        // The guest PMU is left enabled, but its counters are stopped on VM
+        // exits by switching `IA32_PERF_GLOBAL_CTRL`.
+        let msr_store = if super::pmu::has_global_ctrl() {
+            MsrStoreArea::new(&[Msr::IA32_PERF_GLOBAL_CTRL as u32])?
+        } else {
+            MsrStoreArea::new(&[])?
+        };
 
         // bring CR0 and CR4 into well-defined states.
         let mut cr4 = super::super::HOST_CR4 | Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS;
@@ -107,8 +116,14 @@ impl Vcpu {
             host_stack_top: PerCpu::current().stack_top() as _,
             vmxon_region,
             vmcs_region,
+            msr_store,
+            nmi_pending: false,
+            preemption_timer_shift: Self::probe_preemption_timer(),
         };
-        ret.vmcs_setup(linux, cell)?;
+        if let Err(e) = ret.vmcs_setup(linux, cell) {
+            ret.turn_off()?;
+            return Err(e);
+        }
 
         Ok(ret)
     }
@@ -140,17 +155,110 @@ impl Vcpu {
 
     pub fn exit(&self, linux: &mut LinuxContext) -> HvResult {
         self.load_vmcs_guest(linux)?;
+        self.turn_off()
+    }
+
+    /// Turn off VMX without saving the guest state, used when leaving the
+    /// hypervisor or rolling back a failed bring-up.
+    pub fn turn_off(&self) -> HvResult {
         Vmcs::clear(self.vmcs_region.paddr())?;
         unsafe { vmx::vmxoff()? };
         info!("successed to turn off VMX.");
         Ok(())
     }
 
+    /// Resume the guest from anywhere in the VM exit handler, discarding the
+    /// host stack.
+    pub fn resume(&mut self) -> ! {
+        unsafe {
+            asm!(
+                "mov rsp, {0}",
+                restore_regs_from_stack!(),
+                "vmresume",
+                "jmp {1}",
+                in(reg) &self.guest_regs,
+                sym vmresume_failed,
+                options(noreturn),
+            );
+        }
+    }
+
+    fn probe_preemption_timer() -> Option<u8> {
+        use vmx::flags::PinVmExecControls as PinCtrl;
+        let allowed = (Msr::IA32_VMX_PINBASED_CTLS.read() >> 32) as u32;
+        if allowed & PinCtrl::PREEMPTION_TIMER.bits() != 0 {
+            Some(Msr::IA32_VMX_MISC.read() as u8 & 0x1f)
+        } else {
+            warn!("VMX preemption timer is not supported, no hypervisor tick!");
+            None
+        }
+    }
+
+    /// Cause a VM exit after `ticks` TSC ticks in the guest.
+    pub fn arm_timer(&mut self, ticks: u64) -> HvResult {
+        if let Some(shift) = self.preemption_timer_shift {
+            let value = (ticks >> shift).min(u32::MAX as u64) as u32;
+            VmcsField32Guest::VMX_PREEMPTION_TIMER_VALUE.write(value)?;
+        }
+        Ok(())
+    }
+
+    /// The preemption timer causes VM exits, not NMIs.
+    pub fn take_timer_nmi(&mut self) -> bool {
+        false
+    }
+
+    /// The local APIC timer belongs to the guest.
+    pub fn read_apic_timer(&self, _msr: u32) -> Option<u64> {
+        None
+    }
+
+    pub fn write_apic_timer(&mut self, _cell: &Cell, _msr: u32, _value: u64) -> bool {
+        false
+    }
+
     pub fn inject_fault(&mut self) -> HvResult {
         Vmcs::inject_interrupt(crate::arch::ExceptionType::GeneralProtectionFault, Some(0))?;
         Ok(())
     }
 
+    /// Forward an NMI received in the hypervisor to the guest.
+    pub fn queue_nmi(&mut self) {
+        self.nmi_pending = true;
+    }
+
+    /// Inject the pending NMI, or request a VM exit as soon as the guest can
+    /// take it.
+    pub fn inject_pending_nmi(&mut self) -> HvResult {
+        use vmx::flags::PrimaryVmExecControls as CpuCtrl;
+        if !self.nmi_pending {
+            return Ok(());
+        }
+        // Blocking by STI, by MOV SS, or by (virtual) NMI.
+        let blocked = VmcsField32Guest::INTERRUPTIBILITY_INFO.read()? & 0b1011 != 0;
+        let injecting =
+            VmcsField32Control::VM_ENTRY_INTR_INFO_FIELD.read()? & InterruptInfo::VALID.bits() != 0;
+        let ctrl = VmcsField32Control::PROC_BASED_VM_EXEC_CONTROL.read()?;
+        let window = CpuCtrl::NMI_WINDOW_EXITING.bits();
+        if blocked || injecting {
+            VmcsField32Control::PROC_BASED_VM_EXEC_CONTROL.write(ctrl | window)?;
+        } else {
+            Vmcs::inject_interrupt(crate::arch::ExceptionType::NonMaskableInterrupt, None)?;
+            VmcsField32Control::PROC_BASED_VM_EXEC_CONTROL.write(ctrl & !window)?;
+            self.nmi_pending = false;
+        }
+        Ok(())
+    }
+
+    /// Update the guest `IA32_PERF_GLOBAL_CTRL` loaded on the next VM entry
+    /// with the value saved on this VM exit.
+    pub(super) fn save_perf_global_ctrl(&self) -> HvResult {
+        if let Some(val) = self.msr_store.get(Msr::IA32_PERF_GLOBAL_CTRL as u32) {
+            VmcsField64Guest::IA32_PERF_GLOBAL_CTRL.write(val)?;
+        }
+        Ok(())
+    }
+
     pub fn advance_rip(&mut self, instr_len: u8) -> HvResult {
         VmcsField64Guest::RIP.write(VmcsField64Guest::RIP.read()? + instr_len as u64)?;
         Ok(())
@@ -165,6 +273,15 @@ impl Vcpu {
         matches!(Vmcs::exit_reason(), Ok(VmxExitReason::VMCALL))
     }
 
+    /// Basic exit reason of the last VM exit.
+    pub fn exit_reason(&self) -> u32 {
+        Vmcs::exit_reason().map_or(u32::MAX, |reason| reason as u32)
+    }
+
+    pub fn exit_qualification(&self) -> u64 {
+        VmcsField64ReadOnly::EXIT_QUALIFICATION.read().unwrap_or(0)
+    }
+
     pub fn guest_page_table(&self) -> GuestPageTableImmut {
         use crate::memory::{addr::align_down, GenericPageTableImmut};
         unsafe { GuestPageTableImmut::from_root(align_down(self.cr(3) as _)) }
@@ -172,6 +289,10 @@ impl Vcpu {
 }
 
 impl Vcpu {
+    fn switches_perf_global_ctrl(&self) -> bool {
+        self.msr_store.count() != 0
+    }
+
     fn vmcs_setup(&mut self, linux: &LinuxContext, cell: &Cell) -> HvResult {
         let paddr = self.vmcs_region.paddr();
         Vmcs::clear(paddr)?;
@@ -185,6 +306,9 @@ impl Vcpu {
     fn setup_vmcs_host(&mut self) -> HvResult {
         VmcsField64Host::IA32_PAT.write(Msr::IA32_PAT.read())?;
         VmcsField64Host::IA32_EFER.write(Msr::IA32_EFER.read())?;
+        if self.switches_perf_global_ctrl() {
+            VmcsField64Host::IA32_PERF_GLOBAL_CTRL.write(0)?;
+        }
 
         VmcsField64Host::CR0.write(Cr0::read_raw())?;
         VmcsField64Host::CR3.write(Cr3::read().0.start_address().as_u64())?;
@@ -217,6 +341,9 @@ impl Vcpu {
     fn setup_vmcs_guest(&mut self, linux: &LinuxContext) -> HvResult {
         VmcsField64Guest::IA32_PAT.write(linux.pat)?;
         VmcsField64Guest::IA32_EFER.write(linux.efer)?;
+        if self.switches_perf_global_ctrl() {
+            VmcsField64Guest::IA32_PERF_GLOBAL_CTRL.write(Msr::IA32_PERF_GLOBAL_CTRL.read())?;
+        }
 
         self.set_cr(0, linux.cr0.bits());
         self.set_cr(4, linux.cr4.bits());
@@ -259,6 +386,7 @@ impl Vcpu {
     fn load_vmcs_guest(&self, linux: &mut LinuxContext) -> HvResult {
         linux.rip = VmcsField64Guest::RIP.read()?;
         linux.rsp = VmcsField64Guest::RSP.read()?;
+        linux.rflags = VmcsField64Guest::RFLAGS.read()?;
         linux.cr0 = Cr0Flags::from_bits_truncate(VmcsField64Guest::CR0.read()?);
         linux.cr3 = VmcsField64Guest::CR3.read()?;
         linux.cr4 = Cr4Flags::from_bits_truncate(VmcsField64Guest::CR4.read()?)
@@ -278,11 +406,16 @@ impl Vcpu {
         linux.gdt.limit = VmcsField32Guest::GDTR_LIMIT.read()? as _;
         linux.idt.base = VirtAddr::new(VmcsField64Guest::IDTR_BASE.read()?);
         linux.idt.limit = VmcsField32Guest::IDTR_LIMIT.read()? as _;
+        // IA32_KERNEL_GS_BASE is not switched on VM exits.
+        linux.kernel_gsbase = Msr::IA32_KERNEL_GSBASE.read();
 
         unsafe {
             Msr::IA32_SYSENTER_CS.write(VmcsField32Guest::SYSENTER_CS.read()? as _);
             Msr::IA32_SYSENTER_ESP.write(VmcsField64Guest::SYSENTER_ESP.read()?);
             Msr::IA32_SYSENTER_EIP.write(VmcsField64Guest::SYSENTER_EIP.read()?);
+            if self.switches_perf_global_ctrl() {
+                Msr::IA32_PERF_GLOBAL_CTRL.write(VmcsField64Guest::IA32_PERF_GLOBAL_CTRL.read()?);
+            }
         }
 
         Ok(())
@@ -290,20 +423,32 @@ impl Vcpu {
 
     fn setup_vmcs_control(&mut self, cell: &Cell) -> HvResult {
         use vmx::flags::PinVmExecControls as PinCtrl;
+        // NO INTR_EXITING to pass-through interrupts
+        let mut val = PinCtrl::NMI_EXITING | PinCtrl::VIRTUAL_NMIS;
+        if self.preemption_timer_shift.is_some() {
+            val |= PinCtrl::PREEMPTION_TIMER;
+        }
         Vmcs::set_control(
             VmcsField32Control::PIN_BASED_VM_EXEC_CONTROL,
             Msr::IA32_VMX_PINBASED_CTLS.read(),
-            // NO INTR_EXITING to pass-through interrupts
-            PinCtrl::NMI_EXITING.bits(),
+            val.bits(),
             0,
         )?;
 
         use vmx::flags::PrimaryVmExecControls as CpuCtrl;
+        // Only ports in the I/O bitmaps are intercepted.
+        let mut val = CpuCtrl::USE_MSR_BITMAPS
+            | CpuCtrl::USE_IO_BITMAPS
+            | CpuCtrl::SEC_CONTROLS
+            | CpuCtrl::USE_TSC_OFFSETTING;
+        if cell.tsc.intercepted() {
+            // Also intercepts RDTSCP.
+            val |= CpuCtrl::RDTSC_EXITING;
+        }
         Vmcs::set_control(
             VmcsField32Control::PROC_BASED_VM_EXEC_CONTROL,
             Msr::IA32_VMX_PROCBASED_CTLS.read(),
-            // NO UNCOND_IO_EXITING to pass-through PIO
-            (CpuCtrl::USE_MSR_BITMAPS | CpuCtrl::SEC_CONTROLS).bits(),
+            val.bits(),
             (CpuCtrl::CR3_LOAD_EXITING | CpuCtrl::CR3_STORE_EXITING).bits(),
         )?;
 
@@ -319,6 +464,11 @@ impl Vcpu {
         if features.has_xsaves_xrstors() {
             val |= CpuCtrl2::XSAVES;
         }
+        if cell.tsc.is_scaled() {
+            val |= CpuCtrl2::TSC_SCALING;
+            VmcsField64Control::TSC_MULTIPLIER.write(cell.tsc.ratio())?;
+        }
+        VmcsField64Control::TSC_OFFSET.write(cell.tsc.offset())?;
         Vmcs::set_control(
             VmcsField32Control::SECONDARY_VM_EXEC_CONTROL,
             Msr::IA32_VMX_PROCBASED_CTLS2.read(),
@@ -327,27 +477,35 @@ impl Vcpu {
         )?;
 
         use vmx::flags::VmExitControls as ExitCtrl;
+        let mut val = ExitCtrl::HOST_ADDR_SPACE_SIZE
+            | ExitCtrl::SAVE_IA32_PAT
+            | ExitCtrl::LOAD_IA32_PAT
+            | ExitCtrl::SAVE_IA32_EFER
+            | ExitCtrl::LOAD_IA32_EFER;
+        if self.switches_perf_global_ctrl() {
+            val |= ExitCtrl::LOAD_IA32_PERF_GLOBAL_CTRL;
+        }
         Vmcs::set_control(
             VmcsField32Control::VM_EXIT_CONTROLS,
             Msr::IA32_VMX_EXIT_CTLS.read(),
-            (ExitCtrl::HOST_ADDR_SPACE_SIZE
-                | ExitCtrl::SAVE_IA32_PAT
-                | ExitCtrl::LOAD_IA32_PAT
-                | ExitCtrl::SAVE_IA32_EFER
-                | ExitCtrl::LOAD_IA32_EFER)
-                .bits(),
+            val.bits(),
             0,
         )?;
 
         use vmx::flags::VmEntryControls as EntryCtrl;
+        let mut val = EntryCtrl::IA32E_MODE | EntryCtrl::LOAD_IA32_PAT | EntryCtrl::LOAD_IA32_EFER;
+        if self.switches_perf_global_ctrl() {
+            val |= EntryCtrl::LOAD_IA32_PERF_GLOBAL_CTRL;
+        }
         Vmcs::set_control(
             VmcsField32Control::VM_ENTRY_CONTROLS,
             Msr::IA32_VMX_ENTRY_CTLS.read(),
-            (EntryCtrl::IA32E_MODE | EntryCtrl::LOAD_IA32_PAT | EntryCtrl::LOAD_IA32_EFER).bits(),
+            val.bits(),
             0,
         )?;
 
-        VmcsField32Control::VM_EXIT_MSR_STORE_COUNT.write(0)?;
+        VmcsField64Control::VM_EXIT_MSR_STORE_ADDR.write(self.msr_store.paddr() as _)?;
+        VmcsField32Control::VM_EXIT_MSR_STORE_COUNT.write(self.msr_store.count())?;
         VmcsField32Control::VM_EXIT_MSR_LOAD_COUNT.write(0)?;
         VmcsField32Control::VM_ENTRY_MSR_LOAD_COUNT.write(0)?;
 
@@ -357,6 +515,8 @@ impl Vcpu {
         unsafe { cell.gpm.activate() }; // Set EPT_POINTER
 
         VmcsField64Control::MSR_BITMAP.write(MSR_BITMAP.paddr() as _)?;
+        VmcsField64Control::IO_BITMAP_A.write(IO_BITMAP.paddr_a() as _)?;
+        VmcsField64Control::IO_BITMAP_B.write(IO_BITMAP.paddr_b() as _)?;
         VmcsField32Control::EXCEPTION_BITMAP.write(0)?;
 
         Ok(())
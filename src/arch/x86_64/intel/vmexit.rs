@@ -1,3 +1,4 @@
+use bit_field::BitField;
 use libvmm::vmx::vmcs::{EptViolationInfo, ExitInterruptInfo, VmExitInfo};
 use libvmm::vmx::VmxExitReason;
 
@@ -5,6 +6,14 @@ use crate::arch::vmm::VmExit;
 use crate::arch::ExceptionType;
 use crate::error::HvResult;
 
+#[cfg_attr(not(feature = "stats"), allow(dead_code))]
+pub fn exit_reason_name(reason: u32) -> alloc::string::String {
+    match VmxExitReason::try_from(reason) {
+        Ok(reason) => format!("{:?}", reason),
+        Err(_) => format!("{:#x}", reason),
+    }
+}
+
 impl VmExit<'_> {
     fn handle_exception_nmi(&mut self, exit_info: &VmExitInfo) -> HvResult {
         let intr_info = ExitInterruptInfo::new()?;
@@ -23,14 +32,30 @@ impl VmExit<'_> {
 
     fn handle_ept_violation(&mut self, exit_info: &VmExitInfo) -> HvResult {
         let ept_vio_info = EptViolationInfo::new()?;
-        warn!(
-            "VM exit: EPT violation @ {:#x} RIP({:#x}, {}): {:#x?}",
-            ept_vio_info.guest_paddr,
-            exit_info.guest_rip,
-            exit_info.exit_instruction_length,
-            ept_vio_info
-        );
-        hv_result_err!(ENOSYS)
+        let res = self.handle_mmio(ept_vio_info.guest_paddr);
+        if res.is_err() {
+            warn!(
+                "VM exit: EPT violation @ {:#x} RIP({:#x}, {}): {:#x?}",
+                ept_vio_info.guest_paddr,
+                exit_info.guest_rip,
+                exit_info.exit_instruction_length,
+                ept_vio_info
+            );
+        }
+        res
+    }
+
+    fn handle_io_instruction(&mut self, exit_info: &VmExitInfo) -> HvResult {
+        let qualification = self.cpu_data.vcpu.exit_qualification();
+        if qualification.get_bit(4) {
+            return hv_result_err!(ENOSYS, "String I/O instructions are not supported");
+        }
+        self.handle_io(
+            qualification.get_bits(16..32) as u16,
+            qualification.get_bits(0..3) as u8 + 1,
+            qualification.get_bit(3),
+            exit_info.exit_instruction_length as u8,
+        )
     }
 
     pub fn handle_exit(&mut self) -> HvResult {
@@ -40,6 +65,7 @@ impl VmExit<'_> {
         if exit_info.entry_failure {
             panic!("VM entry failed: {:#x?}", exit_info);
         }
+        self.cpu_data.vcpu.save_perf_global_ctrl()?;
         // self.test_read_guest_memory(
         //     exit_info.guest_rip as _,
         //     exit_info.exit_instruction_length as _,
@@ -47,10 +73,17 @@ impl VmExit<'_> {
 
         let res = match exit_info.exit_reason {
             VmxExitReason::EXCEPTION_NMI => self.handle_exception_nmi(&exit_info),
+            // The pending NMI is injected before VM entry.
+            VmxExitReason::NMI_WINDOW => Ok(()),
+            // The hypervisor tick is handled after each VM exit.
+            VmxExitReason::PREEMPTION_TIMER => Ok(()),
             VmxExitReason::CPUID => self.handle_cpuid(),
             VmxExitReason::VMCALL => self.handle_hypercall(),
             VmxExitReason::MSR_READ => self.handle_msr_read(),
             VmxExitReason::MSR_WRITE => self.handle_msr_write(),
+            VmxExitReason::RDTSC => self.handle_rdtsc(false),
+            VmxExitReason::RDTSCP => self.handle_rdtsc(true),
+            VmxExitReason::IO_INSTRUCTION => self.handle_io_instruction(&exit_info),
             VmxExitReason::EPT_VIOLATION => self.handle_ept_violation(&exit_info),
             VmxExitReason::TRIPLE_FAULT => {
                 error!("Triple fault: {:#x?}", exit_info);
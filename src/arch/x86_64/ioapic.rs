@@ -0,0 +1,284 @@
+//! IOAPIC partitioning.
+//!
+//! Cells access the IOAPICs of their configuration through emulated MMIO, and
+//! may only program the redirection entries of pins in their pin bitmap. The
+//! entries are checked to only target CPUs of the cell and remapped by the
+//! IOMMU, the cell reads back the entries it has written. EOIs of level
+//! triggered pins reach the IOAPIC with the vector programmed by the cell,
+//! which is kept by the remapping.
+//!
+//! EOIs written to the passed-through x2APIC EOI register are broadcast to all
+//! IOAPICs, and clear the Remote IRR of every level triggered pin with the
+//! vector. Therefore a vector of level triggered pins belongs to a single cell,
+//! entries reusing it in another cell are rejected.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use spin::Mutex;
+
+use super::vmm::iommu;
+use crate::cell::Cell;
+use crate::config::{CellConfig, HvIrqChip};
+use crate::error::HvResult;
+use crate::memory::addr::{phys_to_virt, GuestPhysAddr, HostPhysAddr, HostVirtAddr};
+use crate::memory::PAGE_SIZE;
+use crate::mmio::{MmioAccess, MmioRegions};
+
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const IOEOI: usize = 0x40;
+
+const REG_VERSION: u32 = 0x01;
+const REG_REDIR_TABLE: u32 = 0x10;
+
+const RTE_DELIVERY_STATUS: u64 = 1 << 12;
+const RTE_REMOTE_IRR: u64 = 1 << 14;
+const RTE_LEVEL_TRIGGERED: u64 = 1 << 15;
+const RTE_MASKED: u64 = 1 << 16;
+/// Bits set by the IOAPIC.
+const RTE_STATUS: u64 = RTE_DELIVERY_STATUS | RTE_REMOTE_IRR;
+
+/// Serializes the register select and window accesses of the hypervisor.
+static REG_LOCK: Mutex<()> = Mutex::new(());
+
+lazy_static! {
+    /// Unmasked level triggered pins by IOAPIC and pin, with their cell and
+    /// vector.
+    static ref LEVEL_VECTORS: Mutex<BTreeMap<(u16, usize), (usize, u8)>> =
+        Mutex::new(BTreeMap::new());
+}
+
+/// Record the vector of `pin` if `rte` is an unmasked level triggered entry,
+/// failing if level triggered pins of another cell use it.
+fn claim_vector(cell: &Cell, sid: u16, pin: usize, rte: u64) -> HvResult {
+    let owner = cell as *const Cell as usize;
+    let vector = rte as u8;
+    let mut vectors = LEVEL_VECTORS.lock();
+    if rte & (RTE_MASKED | RTE_LEVEL_TRIGGERED) != RTE_LEVEL_TRIGGERED {
+        vectors.remove(&(sid, pin));
+        return Ok(());
+    }
+    let used = vectors
+        .iter()
+        .find(|&(_, &(other, v))| other != owner && v == vector);
+    if let Some(((other_sid, other_pin), _)) = used {
+        return hv_result_err!(
+            EBUSY,
+            format!(
+                "Vector {:#x} is used by level triggered IOAPIC {:#x} pin {} of another cell",
+                vector, other_sid, other_pin
+            )
+        );
+    }
+    vectors.insert((sid, pin), (owner, vector));
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Ioapic {
+    base: HostVirtAddr,
+    sid: u16,
+    pin_base: usize,
+    pin_bitmap: [u32; 4],
+    /// Register selected by the cell.
+    index: AtomicU32,
+    /// Redirection entries as written by the cell.
+    shadow: Mutex<Vec<u64>>,
+}
+
+impl Ioapic {
+    fn new(chip: &HvIrqChip) -> Self {
+        let mut ioapic = Self {
+            base: phys_to_virt(chip.address as HostPhysAddr),
+            sid: chip.id as u16,
+            pin_base: chip.pin_base as usize,
+            pin_bitmap: chip.pin_bitmap,
+            index: AtomicU32::new(0),
+            shadow: Mutex::new(Vec::new()),
+        };
+        let num_pins = (ioapic.read_reg(REG_VERSION) >> 16 & 0xff) as usize + 1;
+        *ioapic.shadow.get_mut() = (0..num_pins).map(|pin| ioapic.read_rte(pin)).collect();
+        ioapic
+    }
+
+    fn owns_pin(&self, pin: usize) -> bool {
+        pin.checked_sub(self.pin_base)
+            .and_then(|i| {
+                self.pin_bitmap
+                    .get(i / 32)
+                    .map(|bits| bits & (1 << (i % 32)) != 0)
+            })
+            .unwrap_or(false)
+    }
+
+    fn read_reg(&self, reg: u32) -> u32 {
+        let _lock = REG_LOCK.lock();
+        unsafe {
+            write_volatile((self.base + IOREGSEL) as *mut u32, reg);
+            read_volatile((self.base + IOWIN) as *const u32)
+        }
+    }
+
+    fn write_reg(&self, reg: u32, value: u32) {
+        let _lock = REG_LOCK.lock();
+        unsafe {
+            write_volatile((self.base + IOREGSEL) as *mut u32, reg);
+            write_volatile((self.base + IOWIN) as *mut u32, value);
+        }
+    }
+
+    fn read_rte(&self, pin: usize) -> u64 {
+        let reg = REG_REDIR_TABLE + pin as u32 * 2;
+        (self.read_reg(reg + 1) as u64) << 32 | self.read_reg(reg) as u64
+    }
+
+    fn write_rte(&self, pin: usize, rte: u64) {
+        let reg = REG_REDIR_TABLE + pin as u32 * 2;
+        // The same order as Linux, the destination first.
+        self.write_reg(reg + 1, (rte >> 32) as u32);
+        self.write_reg(reg, rte as u32);
+    }
+
+    fn program(&self, cell: &Cell, pin: usize, rte: u64) -> HvResult {
+        claim_vector(cell, self.sid, pin, rte)?;
+        let rte = iommu::map_ioapic_pin(cell, self.sid, pin as u8, rte & !RTE_STATUS)?;
+        self.write_rte(pin, rte);
+        Ok(())
+    }
+
+    fn read_window(&self) -> u32 {
+        let reg = self.index.load(Ordering::Relaxed);
+        if reg < REG_REDIR_TABLE {
+            return self.read_reg(reg);
+        }
+        let pin = ((reg - REG_REDIR_TABLE) / 2) as usize;
+        let rte = match self.shadow.lock().get(pin) {
+            Some(&rte) if self.owns_pin(pin) => {
+                let status = self.read_reg(REG_REDIR_TABLE + pin as u32 * 2) as u64;
+                (rte & !RTE_STATUS) | (status & RTE_STATUS)
+            }
+            Some(_) => RTE_MASKED,
+            None => 0,
+        };
+        if reg % 2 == 0 {
+            rte as u32
+        } else {
+            (rte >> 32) as u32
+        }
+    }
+
+    fn write_window(&self, cell: &Cell, value: u32) -> HvResult {
+        let reg = self.index.load(Ordering::Relaxed);
+        if reg < REG_REDIR_TABLE {
+            // The ID and arbitration ID are shared by all cells.
+            return Ok(());
+        }
+        let pin = ((reg - REG_REDIR_TABLE) / 2) as usize;
+        let mut shadow = self.shadow.lock();
+        if pin >= shadow.len() || !self.owns_pin(pin) {
+            warn!(
+                "Ignored write to IOAPIC {:#x} pin {}: {:#x}",
+                self.sid, pin, value
+            );
+            return Ok(());
+        }
+        let rte = if reg % 2 == 0 {
+            (shadow[pin] & !0xffff_ffff) | value as u64
+        } else {
+            (shadow[pin] & 0xffff_ffff) | (value as u64) << 32
+        };
+        self.program(cell, pin, rte)?;
+        shadow[pin] = rte;
+        Ok(())
+    }
+
+    /// Forward the EOI of `vector` if it belongs to a level triggered pin of
+    /// the cell.
+    fn eoi(&self, vector: u8) {
+        let shadow = self.shadow.lock();
+        let owned = shadow.iter().enumerate().any(|(pin, &rte)| {
+            self.owns_pin(pin) && rte & RTE_LEVEL_TRIGGERED != 0 && rte as u8 == vector
+        });
+        if owned {
+            unsafe { write_volatile((self.base + IOEOI) as *mut u32, vector as u32) };
+        }
+    }
+
+    fn access(&self, cell: &Cell, access: &mut MmioAccess) -> HvResult {
+        if access.size != 4 {
+            return hv_result_err!(
+                EINVAL,
+                format!("Invalid IOAPIC access of size {}", access.size)
+            );
+        }
+        let value = access.value as u32;
+        match (access.offset, access.is_write) {
+            (IOREGSEL, false) => access.value = self.index.load(Ordering::Relaxed) as u64,
+            (IOREGSEL, true) => self.index.store(value & 0xff, Ordering::Relaxed),
+            (IOWIN, false) => access.value = self.read_window() as u64,
+            (IOWIN, true) => self.write_window(cell, value)?,
+            (IOEOI, true) => self.eoi(value as u8),
+            (_, false) => access.value = 0,
+            (_, true) => {}
+        }
+        Ok(())
+    }
+
+    /// Program the entries of the cell again, and mask the other pins.
+    fn remap(&self, cell: &Cell) -> HvResult {
+        let shadow = self.shadow.lock();
+        for (pin, &rte) in shadow.iter().enumerate() {
+            if self.owns_pin(pin) {
+                self.program(cell, pin, rte)?;
+            } else {
+                self.write_rte(pin, self.read_rte(pin) | RTE_MASKED);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// IOAPICs of a cell.
+#[derive(Debug, Default)]
+pub struct CellIoapics {
+    ioapics: Vec<Ioapic>,
+}
+
+impl CellIoapics {
+    /// Emulate the IOAPICs in the configuration of a cell.
+    pub fn new(config: &CellConfig, mmio: &MmioRegions) -> HvResult<Self> {
+        let mut ioapics = Vec::new();
+        for (i, chip) in config.irqchips().iter().enumerate() {
+            mmio.register(chip.address as GuestPhysAddr, PAGE_SIZE, ioapic_access, i)?;
+            ioapics.push(Ioapic::new(chip));
+        }
+        Ok(Self { ioapics })
+    }
+
+    /// Apply the checks and remapping to the entries programmed before.
+    pub fn remap(&self, cell: &Cell) -> HvResult {
+        self.ioapics
+            .iter()
+            .try_for_each(|ioapic| ioapic.remap(cell))
+    }
+
+    /// Give the entries of the cell back to the IOAPICs, before the IOMMU stops
+    /// remapping interrupts.
+    pub fn restore(&self) {
+        for ioapic in &self.ioapics {
+            for (pin, &rte) in ioapic.shadow.lock().iter().enumerate() {
+                if ioapic.owns_pin(pin) {
+                    LEVEL_VECTORS.lock().remove(&(ioapic.sid, pin));
+                    ioapic.write_rte(pin, rte & !RTE_STATUS);
+                }
+            }
+        }
+    }
+}
+
+fn ioapic_access(cell: &Cell, access: &mut MmioAccess, index: usize) -> HvResult {
+    cell.ioapics.ioapics[index].access(cell, access)
+}
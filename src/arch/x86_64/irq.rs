@@ -0,0 +1,284 @@
+//! Interrupt messages from devices and IOAPICs, and IPIs of cells.
+//!
+//! Messages programmed by a cell are decoded to `IrqMsg`, and only allowed to
+//! target the CPUs of that cell before they are remapped by the IOMMU. Writes
+//! to the ICR are intercepted in both APIC modes, IPIs are limited to the CPUs
+//! of the sender's cell in the same way.
+
+use bit_field::BitField;
+
+use super::apic;
+use crate::cell::Cell;
+use crate::error::HvResult;
+use crate::percpu::PerCpu;
+
+/// Base of MSI addresses.
+#[cfg_attr(not(feature = "intel"), allow(dead_code))]
+pub const MSI_ADDRESS_BASE: u64 = 0xfee0_0000;
+/// Interrupt format of MSI addresses: remappable or compatibility.
+pub const MSI_ADDRESS_REMAPPABLE: u64 = 1 << 4;
+
+const DELIVERY_MODE_FIXED: u8 = 0;
+const DELIVERY_MODE_LOWEST_PRIORITY: u8 = 1;
+const DELIVERY_MODE_NMI: u8 = 4;
+const DELIVERY_MODE_INIT: u8 = 5;
+const DELIVERY_MODE_STARTUP: u8 = 6;
+
+/// Destination shorthands of the ICR.
+const SHORTHAND_NONE: u64 = 0;
+const SHORTHAND_SELF: u64 = 1;
+const SHORTHAND_ALL_EXCLUDING_SELF: u64 = 3;
+
+/// All CPUs in physical destination mode.
+const XAPIC_BROADCAST: u32 = 0xff;
+const X2APIC_BROADCAST: u32 = 0xffff_ffff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqMsg {
+    pub vector: u8,
+    pub delivery_mode: u8,
+    pub dest_logical: bool,
+    pub level_triggered: bool,
+    /// Redirection hint of MSIs.
+    pub redir_hint: bool,
+    pub dest: u32,
+}
+
+impl IrqMsg {
+    /// Decode an MSI in compatibility format.
+    pub fn from_msi(address: u64, data: u32) -> HvResult<Self> {
+        if address & MSI_ADDRESS_REMAPPABLE != 0 {
+            return hv_result_err!(EPERM, "MSIs in remappable format are not allowed");
+        }
+        Ok(Self {
+            vector: data.get_bits(0..8) as u8,
+            delivery_mode: data.get_bits(8..11) as u8,
+            dest_logical: address.get_bit(2),
+            level_triggered: data.get_bit(15),
+            redir_hint: address.get_bit(3),
+            dest: address.get_bits(12..20) as u32,
+        })
+    }
+
+    /// Decode an IOAPIC redirection table entry in compatibility format.
+    pub fn from_ioapic_rte(rte: u64) -> Self {
+        Self {
+            vector: rte.get_bits(0..8) as u8,
+            delivery_mode: rte.get_bits(8..11) as u8,
+            dest_logical: rte.get_bit(11),
+            level_triggered: rte.get_bit(15),
+            redir_hint: false,
+            dest: rte.get_bits(56..64) as u32,
+        }
+    }
+
+    /// Make sure the message only targets CPUs of `cell`.
+    pub fn check_dest(&self, cell: &Cell) -> HvResult {
+        if !matches!(
+            self.delivery_mode,
+            DELIVERY_MODE_FIXED | DELIVERY_MODE_LOWEST_PRIORITY
+        ) {
+            return hv_result_err!(
+                EPERM,
+                format!(
+                    "Interrupt delivery mode {} is not allowed",
+                    self.delivery_mode
+                )
+            );
+        }
+        let allowed = if self.dest == XAPIC_BROADCAST || self.dest == X2APIC_BROADCAST {
+            owns_all_cpus(cell)
+        } else if !self.dest_logical {
+            owns_apic_id(cell, self.dest)
+        } else {
+            owned_logical_dest(cell, self.dest, apic::x2apic_enabled()) == Some(self.dest)
+        };
+        if !allowed {
+            return hv_result_err!(
+                EPERM,
+                format!("Interrupt destination {:#x?} is outside of the cell", self)
+            );
+        }
+        Ok(())
+    }
+
+    /// Deliver the message from the hypervisor, as a fixed interrupt.
+    pub fn send(&self) -> HvResult {
+        let dest = if self.dest == XAPIC_BROADCAST {
+            X2APIC_BROADCAST
+        } else {
+            self.dest
+        };
+        apic::send_ipi(dest, self.dest_logical, self.vector)
+    }
+}
+
+/// Emulate a write of `icr` to the ICR by a CPU of `cell`, with the high dword
+/// of the xAPIC ICR in bits 32..64. Logical destinations and broadcasts are
+/// narrowed to the CPUs of the cell, other IPIs leaving the cell, or with a
+/// delivery mode such as SMI, are dropped.
+pub fn write_icr(cell: &Cell, icr: u64) -> HvResult {
+    let delivery_mode = icr.get_bits(8..11) as u8;
+    if !matches!(
+        delivery_mode,
+        DELIVERY_MODE_FIXED
+            | DELIVERY_MODE_LOWEST_PRIORITY
+            | DELIVERY_MODE_NMI
+            | DELIVERY_MODE_INIT
+            | DELIVERY_MODE_STARTUP
+    ) {
+        warn!(
+            "Dropped IPI with delivery mode {}: {:#x}",
+            delivery_mode, icr
+        );
+        return Ok(());
+    }
+    let x2apic = apic::x2apic_enabled();
+    let shorthand = icr.get_bits(18..20);
+    let dest_logical = icr.get_bit(11);
+    let dest = icr.get_bits(apic::icr_dest_bits(x2apic)) as u32;
+    let broadcast = if x2apic {
+        X2APIC_BROADCAST
+    } else {
+        XAPIC_BROADCAST
+    };
+    match shorthand {
+        SHORTHAND_SELF => return apic::write_icr(icr),
+        SHORTHAND_NONE if dest != broadcast => {}
+        _ if owns_all_cpus(cell) => return apic::write_icr(icr),
+        _ => {
+            // Send the broadcast to each CPU of the cell instead.
+            let self_id = apic::current_apic_id();
+            for apic_id in cell_apic_ids(cell) {
+                if shorthand != SHORTHAND_ALL_EXCLUDING_SELF || apic_id != self_id {
+                    apic::write_icr(physical_icr(icr, apic_id, x2apic))?;
+                }
+            }
+            return Ok(());
+        }
+    }
+
+    if !dest_logical {
+        if owns_apic_id(cell, dest) {
+            return apic::write_icr(icr);
+        }
+        warn!("Dropped IPI to APIC ID {:#x} outside of the cell", dest);
+        return Ok(());
+    }
+    match owned_logical_dest(cell, dest, x2apic) {
+        Some(owned) => {
+            if owned != dest {
+                warn!(
+                    "IPI to logical destination {:#x} narrowed to the cell: {:#x}",
+                    dest, owned
+                );
+            }
+            let mut icr = icr;
+            icr.set_bits(apic::icr_dest_bits(x2apic), owned as u64);
+            apic::write_icr(icr)
+        }
+        None => {
+            warn!(
+                "Dropped IPI to logical destination {:#x} outside of the cell",
+                dest
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Whether CPUs outside of `cell` do not use the logical IDs of `ldr`, written
+/// to the xAPIC LDR by a CPU of `cell`.
+pub fn check_xapic_ldr(cell: &Cell, ldr: u32) -> bool {
+    let ids = ldr >> 24;
+    (0..PerCpu::entered_cpus())
+        .filter(|&id| !cell.owns_cpu(id))
+        .all(|id| PerCpu::from_id(id).arch.xapic_ldr() >> 24 & ids == 0)
+}
+
+/// The logical destination `dest` narrowed to the CPUs of `cell`, or `None` if
+/// it selects none of them.
+fn owned_logical_dest(cell: &Cell, dest: u32, x2apic: bool) -> Option<u32> {
+    if x2apic {
+        // Cluster in bits 16..32, one bit for each of its 16 CPUs.
+        let cluster = dest >> 16;
+        let owned = (0..16)
+            .filter(|&i| dest.get_bit(i) && owns_apic_id(cell, cluster << 4 | i as u32))
+            .fold(0, |bits, i| bits | 1 << i);
+        (owned != 0).then(|| cluster << 16 | owned)
+    } else if owns_all_cpus(cell) {
+        // Also before the logical IDs are read when the CPUs are initialized.
+        (dest != 0).then(|| dest)
+    } else {
+        // Flat model, one bit for each logical ID set by the guest. IDs also
+        // used outside of the cell are left out.
+        let (mut owned, mut others) = (0, 0);
+        for id in 0..PerCpu::entered_cpus() {
+            let ids = PerCpu::from_id(id).arch.xapic_ldr() >> 24;
+            if cell.owns_cpu(id) {
+                owned |= ids;
+            } else {
+                others |= ids;
+            }
+        }
+        let owned = dest & owned & !others;
+        (owned != 0).then(|| owned)
+    }
+}
+
+/// `icr` without shorthand, to the physical destination `apic_id`.
+fn physical_icr(icr: u64, apic_id: u32, x2apic: bool) -> u64 {
+    let mut icr = icr.get_bits(0..32);
+    icr.set_bits(18..20, SHORTHAND_NONE);
+    icr.set_bit(11, false);
+    icr.set_bits(apic::icr_dest_bits(x2apic), apic_id as u64);
+    icr
+}
+
+/// APIC IDs of the CPUs of `cell`.
+pub fn cell_apic_ids<'a>(cell: &'a Cell) -> impl Iterator<Item = u32> + 'a {
+    (0..PerCpu::entered_cpus())
+        .filter(move |&id| cell.owns_cpu(id))
+        .map(|id| PerCpu::from_id(id).arch.apic_id())
+}
+
+fn owns_apic_id(cell: &Cell, apic_id: u32) -> bool {
+    cell_apic_ids(cell).any(|id| id == apic_id)
+}
+
+fn owns_all_cpus(cell: &Cell) -> bool {
+    (0..PerCpu::entered_cpus()).all(|id| cell.owns_cpu(id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let msg =
+            IrqMsg::from_msi(MSI_ADDRESS_BASE | 3 << 12 | 1 << 2, 0x8000 | 0x100 | 0x41).unwrap();
+        assert_eq!(
+            msg,
+            IrqMsg {
+                vector: 0x41,
+                delivery_mode: DELIVERY_MODE_LOWEST_PRIORITY,
+                dest_logical: true,
+                level_triggered: true,
+                redir_hint: false,
+                dest: 3,
+            }
+        );
+        assert!(IrqMsg::from_msi(MSI_ADDRESS_BASE | MSI_ADDRESS_REMAPPABLE, 0).is_err());
+        let msg = IrqMsg::from_ioapic_rte(2 << 56 | 1 << 15 | 0x30);
+        assert_eq!((msg.vector, msg.dest, msg.level_triggered), (0x30, 2, true));
+        assert!(!msg.dest_logical);
+    }
+
+    #[test]
+    fn test_physical_icr() {
+        let icr = 0xffff_ffff << 32 | SHORTHAND_ALL_EXCLUDING_SELF << 18 | 1 << 11 | 0x4500;
+        assert_eq!(physical_icr(icr, 3, true), 3 << 32 | 0x4500);
+        assert_eq!(physical_icr(icr, 3, false), 3 << 56 | 0x4500);
+    }
+}
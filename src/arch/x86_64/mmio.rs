@@ -0,0 +1,236 @@
+//! Decoding of guest instructions accessing emulated MMIO.
+//!
+//! Only the `MOV` and `MOVZX` forms used for device register accesses are
+//! supported, with a memory operand and no string or stack operations.
+
+use super::GeneralRegisters;
+use crate::error::HvResult;
+
+pub const MAX_INSTR_LEN: usize = 15;
+
+const PREFIX_OPERAND_SIZE: u8 = 0x66;
+const PREFIXES_IGNORED: [u8; 7] = [0x26, 0x2e, 0x36, 0x3e, 0x64, 0x65, 0xf0];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operand {
+    /// General register number, `high_byte` for AH, CH, DH and BH.
+    Reg {
+        index: usize,
+        high_byte: bool,
+    },
+    Imm(u64),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct MmioInstr {
+    pub len: u8,
+    /// Access size in bytes.
+    pub size: u8,
+    pub is_write: bool,
+    operand: Operand,
+    /// Size of the destination register of `MOVZX`.
+    zero_extend_to: Option<u8>,
+}
+
+fn get(bytes: &[u8], i: usize) -> HvResult<u8> {
+    match bytes.get(i) {
+        Some(&b) => Ok(b),
+        None => hv_result_err!(EINVAL, "Truncated MMIO instruction"),
+    }
+}
+
+fn read_imm(bytes: &[u8], i: usize, size: usize) -> HvResult<u64> {
+    let mut imm = 0;
+    for n in 0..size {
+        imm |= (get(bytes, i + n)? as u64) << (n * 8);
+    }
+    Ok(imm)
+}
+
+fn read_reg(regs: &GeneralRegisters, index: usize, high_byte: bool) -> HvResult<u64> {
+    if index == 4 && !high_byte {
+        return hv_result_err!(EINVAL, "MMIO access from RSP");
+    }
+    Ok(if high_byte {
+        regs.get(index - 4) >> 8
+    } else {
+        regs.get(index)
+    })
+}
+
+impl MmioInstr {
+    pub fn decode(bytes: &[u8]) -> HvResult<Self> {
+        let mut i = 0;
+        let mut operand_size_16 = false;
+        loop {
+            match get(bytes, i)? {
+                PREFIX_OPERAND_SIZE => operand_size_16 = true,
+                b if PREFIXES_IGNORED.contains(&b) => {}
+                _ => break,
+            }
+            i += 1;
+        }
+        let mut rex = 0;
+        if get(bytes, i)? & 0xf0 == 0x40 {
+            rex = get(bytes, i)?;
+            i += 1;
+        }
+        let operand_size = if rex & 0x8 != 0 {
+            8
+        } else if operand_size_16 {
+            2
+        } else {
+            4
+        };
+
+        let opcode = get(bytes, i)?;
+        i += 1;
+        let (size, is_write, has_imm, zero_extend_to) = match opcode {
+            0x88 => (1, true, false, None),
+            0x89 => (operand_size, true, false, None),
+            0x8a => (1, false, false, None),
+            0x8b => (operand_size, false, false, None),
+            0xc6 => (1, true, true, None),
+            0xc7 => (operand_size, true, true, None),
+            0x0f => {
+                let opcode2 = get(bytes, i)?;
+                i += 1;
+                match opcode2 {
+                    0xb6 => (1, false, false, Some(operand_size)),
+                    0xb7 => (2, false, false, Some(operand_size)),
+                    _ => return hv_result_err!(ENOSYS, format!("MMIO opcode 0f {:02x}", opcode2)),
+                }
+            }
+            _ => return hv_result_err!(ENOSYS, format!("MMIO opcode {:02x}", opcode)),
+        };
+
+        let modrm = get(bytes, i)?;
+        i += 1;
+        let (mode, rm) = (modrm >> 6, modrm & 7);
+        if mode == 3 {
+            return hv_result_err!(EINVAL, "MMIO instruction without memory operand");
+        }
+        if rm == 4 {
+            let sib = get(bytes, i)?;
+            i += 1;
+            if mode == 0 && sib & 7 == 5 {
+                i += 4;
+            }
+        } else if mode == 0 && rm == 5 {
+            // RIP-relative
+            i += 4;
+        }
+        i += match mode {
+            1 => 1,
+            2 => 4,
+            _ => 0,
+        };
+
+        let operand = if has_imm {
+            let imm_size = (size as usize).min(4);
+            let imm = read_imm(bytes, i, imm_size)?;
+            i += imm_size;
+            // Sign-extended to 64 bits.
+            Operand::Imm(if size == 8 {
+                imm as i32 as i64 as u64
+            } else {
+                imm
+            })
+        } else {
+            let index = ((modrm >> 3) & 7) as usize | ((rex as usize & 0x4) << 1);
+            let high_byte = size == 1 && zero_extend_to.is_none() && rex == 0 && index >= 4;
+            Operand::Reg { index, high_byte }
+        };
+        if i > bytes.len() {
+            return hv_result_err!(EINVAL, "Truncated MMIO instruction");
+        }
+        Ok(Self {
+            len: i as u8,
+            size,
+            is_write,
+            operand,
+            zero_extend_to,
+        })
+    }
+
+    /// The value written by the instruction.
+    pub fn write_value(&self, regs: &GeneralRegisters) -> HvResult<u64> {
+        let value = match self.operand {
+            Operand::Imm(imm) => imm,
+            Operand::Reg { index, high_byte } => read_reg(regs, index, high_byte)?,
+        };
+        Ok(value & size_mask(self.size))
+    }
+
+    /// Complete a read by loading `value` to the destination register.
+    pub fn complete_read(&self, regs: &mut GeneralRegisters, value: u64) -> HvResult {
+        let (index, high_byte) = match self.operand {
+            Operand::Reg { index, high_byte } => (index, high_byte),
+            Operand::Imm(_) => unreachable!(),
+        };
+        let value = value & size_mask(self.size);
+        let (index, shift, size) = if high_byte {
+            (index - 4, 8, 1)
+        } else {
+            (index, 0, self.zero_extend_to.unwrap_or(self.size))
+        };
+        let old = read_reg(regs, index, false)?;
+        let new = match size {
+            // 32-bit destinations are zero-extended to 64 bits.
+            4 | 8 => value,
+            _ => (old & !(size_mask(size) << shift)) | value << shift,
+        };
+        regs.set(index, new);
+        Ok(())
+    }
+}
+
+fn size_mask(size: u8) -> u64 {
+    match size {
+        8 => u64::MAX,
+        _ => (1 << (size as u32 * 8)) - 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let mut regs = GeneralRegisters::default();
+        regs.rbx = 0x1234_5678_9abc_def0;
+
+        // mov eax, [rdi + 0x10]
+        let instr = MmioInstr::decode(&[0x8b, 0x47, 0x10, 0x90]).unwrap();
+        assert_eq!((instr.len, instr.size, instr.is_write), (3, 4, false));
+        instr.complete_read(&mut regs, 0xaabb_ccdd).unwrap();
+        assert_eq!(regs.rax, 0xaabb_ccdd);
+
+        // mov [r8 + rcx * 4], bx
+        let instr = MmioInstr::decode(&[0x66, 0x41, 0x89, 0x1c, 0x88]).unwrap();
+        assert_eq!((instr.len, instr.size, instr.is_write), (5, 2, true));
+        assert_eq!(instr.write_value(&regs).unwrap(), 0xdef0);
+
+        // mov bh, [rip + 0x100]
+        let instr = MmioInstr::decode(&[0x8a, 0x3d, 0, 1, 0, 0]).unwrap();
+        assert_eq!(instr.len, 6);
+        instr.complete_read(&mut regs, 0x42).unwrap();
+        assert_eq!(regs.rbx, 0x1234_5678_9abc_42f0);
+
+        // movzx r9d, word ptr [rax]
+        let instr = MmioInstr::decode(&[0x44, 0x0f, 0xb7, 0x08]).unwrap();
+        regs.r9 = u64::MAX;
+        instr.complete_read(&mut regs, 0xffff_beef).unwrap();
+        assert_eq!(regs.r9, 0xbeef);
+
+        // mov qword ptr [rdx], -1
+        let instr = MmioInstr::decode(&[0x48, 0xc7, 0x02, 0xff, 0xff, 0xff, 0xff]).unwrap();
+        assert_eq!((instr.len, instr.size), (7, 8));
+        assert_eq!(instr.write_value(&regs).unwrap(), u64::MAX);
+
+        assert!(MmioInstr::decode(&[0x8b, 0xc0]).is_err());
+        assert!(MmioInstr::decode(&[0xa5]).is_err());
+        assert!(MmioInstr::decode(&[0x8b, 0x47]).is_err());
+    }
+}
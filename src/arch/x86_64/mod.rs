@@ -3,19 +3,30 @@ mod context;
 mod cpuid;
 mod entry;
 mod exception;
+mod ioapic;
+mod mmio;
 mod page_table;
 mod percpu;
+mod pvclock;
 mod segmentation;
 mod tables;
+mod timer;
+mod tsc;
 
+pub mod apic;
+pub mod clocksource;
 pub mod cpu;
+pub mod irq;
+pub mod pci;
 pub mod serial;
 pub mod vmm;
 
 pub use context::{GeneralRegisters, LinuxContext};
 pub use exception::ExceptionType;
+pub use ioapic::CellIoapics;
 pub use page_table::PageTable as HostPageTable;
 pub use page_table::PageTable as GuestPageTable;
 pub use page_table::PageTableImmut as GuestPageTableImmut;
 pub use percpu::ArchPerCpu;
+pub use tsc::CellTsc;
 pub use vmm::NestedPageTable;
@@ -87,7 +87,7 @@ impl GenericPTE for PTEntry {
         }
         self.0 = self.addr() as u64 | flags.bits();
     }
-    fn set_table(&mut self, paddr: PhysAddr) {
+    fn set_table(&mut self, paddr: PhysAddr, _next_level: usize) {
         self.0 = (paddr as u64 & PHYS_ADDR_MASK)
             | (PTF::PRESENT | PTF::WRITABLE | PTF::USER_ACCESSIBLE).bits();
     }
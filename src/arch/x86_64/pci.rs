@@ -0,0 +1,139 @@
+//! PCI configuration access on x86.
+//!
+//! The hypervisor uses the MMCONFIG region if present, and the 0xCF8/0xCFC
+//! ports otherwise. Accesses of cells to these ports are intercepted, with the
+//! address port emulated per CPU.
+
+use core::ptr::{read_volatile, write_volatile};
+
+use spin::Mutex;
+use x86::io::{inb, inl, inw, outb, outl, outw};
+
+use crate::cell::Cell;
+use crate::config::HvSystemConfig;
+use crate::error::HvResult;
+use crate::memory::addr::phys_to_virt;
+
+const PCI_ADDR_PORT: u16 = 0xcf8;
+const PCI_DATA_PORT: u16 = 0xcfc;
+const PCI_ADDR_ENABLE: u32 = 1 << 31;
+
+/// Ports intercepted in all cells, 0xCF9 is the reset control register.
+pub const INTERCEPTED_PORTS: [u16; 5] = [PCI_ADDR_PORT, 0xcfc, 0xcfd, 0xcfe, 0xcff];
+
+/// Serializes the port accesses of the hypervisor.
+static PORT_LOCK: Mutex<()> = Mutex::new(());
+
+fn mmconfig_vaddr(bdf: u16, reg: u16) -> Option<usize> {
+    let sys_config = HvSystemConfig::get();
+    let base = sys_config.pci_mmconfig_base() as usize;
+    if base == 0 || (bdf >> 8) as u8 > sys_config.pci_mmconfig_end_bus() {
+        return None;
+    }
+    Some(phys_to_virt(base + ((bdf as usize) << 12) + reg as usize))
+}
+
+fn port_address(bdf: u16, reg: u16) -> u32 {
+    PCI_ADDR_ENABLE | (bdf as u32) << 8 | (reg & 0xfc) as u32
+}
+
+/// Read `size` bytes at `reg` of the configuration space of `bdf`.
+pub fn read_config(bdf: u16, reg: u16, size: u8) -> u32 {
+    if let Some(vaddr) = mmconfig_vaddr(bdf, reg) {
+        unsafe {
+            match size {
+                1 => read_volatile(vaddr as *const u8) as u32,
+                2 => read_volatile(vaddr as *const u16) as u32,
+                _ => read_volatile(vaddr as *const u32),
+            }
+        }
+    } else if reg < 0x100 {
+        let _lock = PORT_LOCK.lock();
+        let port = PCI_DATA_PORT + (reg & 3);
+        unsafe {
+            outl(PCI_ADDR_PORT, port_address(bdf, reg));
+            match size {
+                1 => inb(port) as u32,
+                2 => inw(port) as u32,
+                _ => inl(port),
+            }
+        }
+    } else {
+        u32::MAX
+    }
+}
+
+pub fn write_config(bdf: u16, reg: u16, size: u8, value: u32) {
+    if let Some(vaddr) = mmconfig_vaddr(bdf, reg) {
+        unsafe {
+            match size {
+                1 => write_volatile(vaddr as *mut u8, value as u8),
+                2 => write_volatile(vaddr as *mut u16, value as u16),
+                _ => write_volatile(vaddr as *mut u32, value),
+            }
+        }
+    } else if reg < 0x100 {
+        let _lock = PORT_LOCK.lock();
+        let port = PCI_DATA_PORT + (reg & 3);
+        unsafe {
+            outl(PCI_ADDR_PORT, port_address(bdf, reg));
+            match size {
+                1 => outb(port, value as u8),
+                2 => outw(port, value as u16),
+                _ => outl(port, value),
+            }
+        }
+    }
+}
+
+/// Emulate an access of `cell` to the configuration ports. `addr_port` is the
+/// address port of the CPU, and `value` the data of `OUT` or the result of
+/// `IN`.
+pub fn handle_port_io(
+    cell: &Cell,
+    addr_port: &mut u32,
+    port: u16,
+    size: u8,
+    is_in: bool,
+    value: &mut u32,
+) -> HvResult {
+    if !INTERCEPTED_PORTS.contains(&port) {
+        return hv_result_err!(EIO, format!("Unexpected I/O port {:#x}", port));
+    }
+    if port == PCI_ADDR_PORT {
+        if size != 4 {
+            return hv_result_err!(
+                EINVAL,
+                format!("Invalid PCI address port access of size {}", size)
+            );
+        }
+        if is_in {
+            *value = *addr_port;
+        } else {
+            *addr_port = *value;
+        }
+        return Ok(());
+    }
+
+    let offset = port - PCI_DATA_PORT;
+    if offset + size as u16 > 4 {
+        return hv_result_err!(
+            EINVAL,
+            format!("Invalid PCI data port access at {:#x}", port)
+        );
+    }
+    if *addr_port & PCI_ADDR_ENABLE == 0 {
+        if is_in {
+            *value = u32::MAX;
+        }
+        return Ok(());
+    }
+    let bdf = (*addr_port >> 8) as u16;
+    let reg = (*addr_port & 0xfc) as u16 + offset;
+    if is_in {
+        *value = cell.pci.config_read(bdf, reg, size)?;
+        Ok(())
+    } else {
+        cell.pci.config_write(cell, bdf, reg, size, *value)
+    }
+}
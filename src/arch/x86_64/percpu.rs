@@ -1,15 +1,40 @@
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
 use libvmm::msr::Msr;
 use x86::{segmentation, segmentation::SegmentSelector};
 
+use super::apic;
+use super::pvclock::PvClock;
 use super::tables::{GdtStruct, TssStruct, IDT};
+use super::timer::HvTimer;
+use crate::error::HvResult;
 
 pub struct ArchPerCpu {
     tss: TssStruct,
     gdt: GdtStruct,
+    apic_id: u32,
+    pub pvclock: PvClock,
+    pub timer: HvTimer,
+    /// PCI address port of the guest.
+    pub pci_addr_port: u32,
+    /// High dword of the xAPIC ICR written by the guest.
+    pub xapic_icr_high: AtomicU32,
+    /// Logical destination register of the guest in xAPIC mode.
+    pub xapic_ldr: AtomicU32,
+    /// An NMI was sent by `send_guest_nmi()` and not received yet.
+    guest_nmi: AtomicBool,
 }
 
 impl ArchPerCpu {
     pub fn init(&mut self) {
+        self.apic_id = apic::current_apic_id();
+        self.pvclock = PvClock::default();
+        self.timer = HvTimer::new();
+        self.pci_addr_port = 0;
+        self.xapic_icr_high = AtomicU32::new(0);
+        self.xapic_ldr = AtomicU32::new(apic::current_xapic_ldr());
+        self.guest_nmi = AtomicBool::new(false);
+
         self.tss = TssStruct::alloc();
 
         self.gdt = GdtStruct::alloc();
@@ -29,4 +54,29 @@ impl ArchPerCpu {
         // PAT0: WB, PAT1: WC, PAT2: UC
         unsafe { Msr::IA32_PAT.write(0x070106) };
     }
+
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id
+    }
+
+    pub fn xapic_ldr(&self) -> u32 {
+        self.xapic_ldr.load(Ordering::Acquire)
+    }
+
+    /// Send an NMI to this CPU.
+    pub fn send_nmi(&self) -> HvResult {
+        apic::send_nmi(self.apic_id)
+    }
+
+    /// Send an NMI to this CPU, which is forwarded to the guest even if it is
+    /// merged with an NMI of the hypervisor.
+    pub fn send_guest_nmi(&self) -> HvResult {
+        self.guest_nmi.store(true, Ordering::Release);
+        self.send_nmi()
+    }
+
+    /// Whether an NMI from `send_guest_nmi()` was received, consuming it.
+    pub fn take_guest_nmi(&self) -> bool {
+        self.guest_nmi.swap(false, Ordering::AcqRel)
+    }
 }
@@ -0,0 +1,224 @@
+//! KVM-compatible paravirtual clock (kvmclock).
+//!
+//! Guests find the KVM signature in the `KVM_CPUID_SIGNATURE` leaf, and
+//! register per-CPU `PvClockVcpuTimeInfo` structures by writing their guest
+//! physical addresses to `MSR_KVM_SYSTEM_TIME_NEW`. The structure describes
+//! the TSC to nanoseconds conversion, computed from the guest TSC frequency
+//! of the cell. As the TSC is invariant and synchronized, the structure never
+//! changes, and the clock is the guest TSC converted to nanoseconds. The wall
+//! clock at guest TSC 0 is derived from the RTC time read by `clocksource`.
+//!
+//! kvmclock is only available if the TSC frequency was determined, as a
+//! stable clock with a guessed frequency would be worse than none. Otherwise
+//! the KVM leaves are empty and the MSRs are unknown.
+
+use core::sync::atomic::{fence, Ordering};
+
+use super::{clocksource, cpu::current_cycle};
+use crate::cell::Cell;
+use crate::error::HvResult;
+use crate::memory::addr::{phys_to_virt, GuestPhysAddr};
+use crate::memory::{GenericPageTableImmut, MemFlags, PAGE_SIZE};
+
+pub const MSR_KVM_WALL_CLOCK_NEW: u32 = 0x4b56_4d00;
+pub const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b56_4d01;
+
+/// The KVM leaves, found by guests after the `HypervisorInfo` leaf of RVM.
+pub const KVM_CPUID_SIGNATURE: u32 = 0x4000_0100;
+pub const KVM_CPUID_FEATURES: u32 = 0x4000_0101;
+
+/// Features in EAX of `KVM_CPUID_FEATURES`.
+const KVM_FEATURE_CLOCKSOURCE2: u32 = 1 << 3;
+const KVM_FEATURE_CLOCKSOURCE_STABLE_BIT: u32 = 1 << 24;
+
+/// Bit 0 of `MSR_KVM_SYSTEM_TIME_NEW`.
+const SYSTEM_TIME_ENABLE: u64 = 1 << 0;
+/// The time is consistent across CPUs.
+const PVCLOCK_TSC_STABLE_BIT: u8 = 1 << 0;
+
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+/// Time is `system_time + ((tsc - tsc_timestamp) << tsc_shift) *
+/// tsc_to_system_mul >> 32` nanoseconds.
+#[repr(C)]
+struct PvClockVcpuTimeInfo {
+    /// Odd while being updated.
+    version: u32,
+    _pad0: u32,
+    tsc_timestamp: u64,
+    system_time: u64,
+    tsc_to_system_mul: u32,
+    tsc_shift: i8,
+    flags: u8,
+    _pad: [u8; 2],
+}
+
+/// Wall clock time when the system time is 0.
+#[repr(C)]
+struct PvClockWallClock {
+    version: u32,
+    sec: u32,
+    nsec: u32,
+}
+
+/// Values of the kvmclock MSRs of a CPU.
+#[derive(Debug, Default)]
+pub struct PvClock {
+    system_time: u64,
+    wall_clock: u64,
+}
+
+pub fn available() -> bool {
+    clocksource::tsc_khz_known()
+}
+
+/// `[EAX, EBX, ECX, EDX]` of the KVM leaves.
+pub fn kvm_cpuid(function: u32) -> [u32; 4] {
+    if !available() {
+        [0; 4]
+    } else if function == KVM_CPUID_SIGNATURE {
+        let signature = unsafe { &*("KVMKVMKVM\0\0\0".as_ptr() as *const [u32; 3]) };
+        [KVM_CPUID_FEATURES, signature[0], signature[1], signature[2]]
+    } else if function == KVM_CPUID_FEATURES {
+        [
+            KVM_FEATURE_CLOCKSOURCE2 | KVM_FEATURE_CLOCKSOURCE_STABLE_BIT,
+            0,
+            0,
+            0,
+        ]
+    } else {
+        [0; 4]
+    }
+}
+
+/// Find `shift` and `mul` to convert `base_hz` ticks to `scaled_hz` ticks by
+/// `(ticks << shift) * mul >> 32`, same as `kvm_get_time_scale()` in Linux.
+fn time_scale(scaled_hz: u64, base_hz: u64) -> (i8, u32) {
+    let mut shift = 0i8;
+    let mut scaled = scaled_hz;
+    let mut base = base_hz;
+    while base > scaled * 2 || base >> 32 != 0 {
+        base >>= 1;
+        shift -= 1;
+    }
+    let mut base = base as u32;
+    while base as u64 <= scaled || scaled >> 32 != 0 {
+        if scaled >> 32 != 0 || base & 0x8000_0000 != 0 {
+            scaled >>= 1;
+        } else {
+            base <<= 1;
+        }
+        shift += 1;
+    }
+    (shift, ((scaled << 32) / base as u64) as u32)
+}
+
+/// Get a structure in the guest memory, which must be writable by the guest.
+fn guest_struct<'a, T>(cell: &Cell, gpaddr: GuestPhysAddr) -> HvResult<&'a mut T> {
+    let size = core::mem::size_of::<T>();
+    if gpaddr % PAGE_SIZE + size > PAGE_SIZE {
+        return hv_result_err!(EINVAL, "kvmclock structures must not cross pages");
+    }
+    let (hpaddr, flags, _) = cell.gpm.page_table().query(gpaddr)?;
+    if !flags.contains(MemFlags::WRITE) {
+        return hv_result_err!(EPERM, format!("{:#x} is not writable", gpaddr));
+    }
+    Ok(unsafe { &mut *(phys_to_virt(hpaddr) as *mut T) })
+}
+
+impl PvClock {
+    pub fn read_msr(&self, msr: u32) -> Option<u64> {
+        if !available() {
+            return None;
+        }
+        match msr {
+            MSR_KVM_SYSTEM_TIME_NEW => Some(self.system_time),
+            MSR_KVM_WALL_CLOCK_NEW => Some(self.wall_clock),
+            _ => None,
+        }
+    }
+
+    /// Returns `None` if `msr` is not a kvmclock MSR.
+    pub fn write_msr(&mut self, cell: &Cell, msr: u32, value: u64) -> Option<HvResult> {
+        if !available() {
+            return None;
+        }
+        match msr {
+            MSR_KVM_SYSTEM_TIME_NEW => Some(self.set_system_time(cell, value)),
+            MSR_KVM_WALL_CLOCK_NEW => Some(self.set_wall_clock(cell, value)),
+            _ => None,
+        }
+    }
+
+    fn set_system_time(&mut self, cell: &Cell, value: u64) -> HvResult {
+        self.system_time = value;
+        if value & SYSTEM_TIME_ENABLE == 0 {
+            return Ok(());
+        }
+        let info = guest_struct::<PvClockVcpuTimeInfo>(cell, (value & !SYSTEM_TIME_ENABLE) as _)?;
+        let tsc_hz = cell.tsc.khz() as u64 * 1000;
+        let (shift, mul) = time_scale(NSEC_PER_SEC, tsc_hz);
+        info.version = info.version.wrapping_add(1) | 1;
+        fence(Ordering::SeqCst);
+        info.tsc_timestamp = 0;
+        info.system_time = 0;
+        info.tsc_to_system_mul = mul;
+        info.tsc_shift = shift;
+        info.flags = PVCLOCK_TSC_STABLE_BIT;
+        fence(Ordering::SeqCst);
+        info.version = info.version.wrapping_add(1);
+        Ok(())
+    }
+
+    fn set_wall_clock(&mut self, cell: &Cell, value: u64) -> HvResult {
+        self.wall_clock = value;
+        let wall_clock = guest_struct::<PvClockWallClock>(cell, value as _)?;
+        // The wall clock now, minus the system time now, which is the guest
+        // TSC in nanoseconds. Guests get the time from the RTC later if the
+        // hypervisor could not read it.
+        let now = current_cycle();
+        let system_nanos =
+            (cell.tsc.guest_tsc(now) as u128 * 1_000_000 / cell.tsc.khz() as u128) as u64;
+        let boot_nanos = clocksource::wall_clock_nanos(now)
+            .map_or(0, |wall_nanos| wall_nanos.saturating_sub(system_nanos));
+        wall_clock.version = wall_clock.version.wrapping_add(1) | 1;
+        fence(Ordering::SeqCst);
+        wall_clock.sec = (boot_nanos / NSEC_PER_SEC) as u32;
+        wall_clock.nsec = (boot_nanos % NSEC_PER_SEC) as u32;
+        fence(Ordering::SeqCst);
+        wall_clock.version = wall_clock.version.wrapping_add(1);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scale(ticks: u64, (shift, mul): (i8, u32)) -> u64 {
+        let ticks = if shift < 0 {
+            ticks >> -shift
+        } else {
+            ticks << shift
+        };
+        ((ticks as u128 * mul as u128) >> 32) as u64
+    }
+
+    #[test]
+    fn test_time_scale() {
+        for tsc_khz in [1_000_000, 2_112_000, 3_600_000, 800_000, 4_999_999] {
+            let tsc_hz = tsc_khz * 1000;
+            let s = time_scale(NSEC_PER_SEC, tsc_hz);
+            // One second of ticks, within 1 ppm.
+            let ns = scale(tsc_hz, s);
+            assert!(
+                (ns as i64 - NSEC_PER_SEC as i64).abs() <= 1000,
+                "{} {:?}",
+                tsc_khz,
+                s
+            );
+        }
+        // 2 GHz: half a nanosecond per tick.
+        assert_eq!(time_scale(NSEC_PER_SEC, 2_000_000_000), (0, 0x8000_0000));
+    }
+}
@@ -0,0 +1,37 @@
+//! Periodic hypervisor tick.
+//!
+//! Guests run until they cause a VM exit, so a per-CPU timer forces one at
+//! least every `TICK_PERIOD_NS`: the VMX preemption timer on Intel, and on AMD
+//! the local APIC timer, whose NMI is consumed by the hypervisor. Ticks are
+//! handled at the end of VM exits.
+
+use super::clocksource::Deadline;
+
+const TICK_PERIOD_NS: u64 = 10_000_000; // 10 ms
+
+pub struct HvTimer {
+    next_tick: Deadline,
+}
+
+impl HvTimer {
+    pub fn new() -> Self {
+        Self {
+            next_tick: Deadline::after_nanos(TICK_PERIOD_NS),
+        }
+    }
+
+    /// Whether a tick is due. If so, the next one is scheduled.
+    pub fn poll(&mut self) -> bool {
+        if self.next_tick.expired() {
+            self.next_tick = Deadline::after_nanos(TICK_PERIOD_NS);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// TSC ticks until the next hypervisor tick.
+    pub fn remaining_ticks(&self) -> u64 {
+        self.next_tick.remaining_ticks()
+    }
+}
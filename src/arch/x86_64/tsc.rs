@@ -0,0 +1,153 @@
+//! TSC virtualization of cells.
+//!
+//! The guest TSC is `(host_tsc * ratio >> TSC_RATIO_FRAC_BITS) + offset`,
+//! applied by the TSC offsetting and scaling of VMX or SVM. Scaling sets the
+//! guest TSC frequency, relative to the host one from `clocksource`. RDTSC and
+//! RDTSCP can also be intercepted, then the VM exit handler returns the same
+//! value.
+//! The settings come from the cell config, and are loaded when the vCPUs of
+//! the cell are created.
+
+use super::clocksource;
+use super::vmm::{has_tsc_scaling, TSC_RATIO_FRAC_BITS};
+use crate::config::{CellConfig, HvCellDesc};
+use crate::error::{HvResult, HvResultExt};
+
+#[derive(Debug)]
+pub struct CellTsc {
+    /// Added to the scaled host TSC.
+    offset: u64,
+    /// Fixed-point multiplier with `TSC_RATIO_FRAC_BITS` fractional bits.
+    ratio: u64,
+    /// Guest TSC frequency in kHz.
+    khz: u32,
+    intercept: bool,
+}
+
+/// `(tsc * ratio) >> frac_bits`, as done by the hardware.
+fn scale(tsc: u64, ratio: u64, frac_bits: u32) -> u64 {
+    ((tsc as u128 * ratio as u128) >> frac_bits) as u64
+}
+
+/// The ratio to convert `host_khz` ticks to `guest_khz` ticks.
+fn ratio(guest_khz: u32, host_khz: u32, frac_bits: u32) -> u64 {
+    (((guest_khz as u128) << frac_bits) / host_khz as u128) as u64
+}
+
+impl CellTsc {
+    /// The TSC settings in the config of a cell.
+    pub fn new(config: &CellConfig) -> HvResult<Self> {
+        let mut tsc = Self {
+            offset: 0,
+            ratio: 1 << TSC_RATIO_FRAC_BITS,
+            khz: clocksource::tsc_khz(),
+            intercept: false,
+        };
+        let (khz, flags) = (config.tsc_khz(), config.tsc_flags());
+        if khz != 0 {
+            tsc.set_frequency(khz)
+                .context("failed to set the TSC frequency")?;
+        }
+        if flags & HvCellDesc::TSC_RESET != 0 {
+            tsc.set(0);
+        }
+        tsc.set_intercept(flags & HvCellDesc::TSC_INTERCEPT != 0);
+        Ok(tsc)
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn ratio(&self) -> u64 {
+        self.ratio
+    }
+
+    #[cfg(feature = "intel")]
+    pub fn is_scaled(&self) -> bool {
+        self.ratio != 1 << TSC_RATIO_FRAC_BITS
+    }
+
+    pub fn khz(&self) -> u32 {
+        self.khz
+    }
+
+    /// Whether RDTSC and RDTSCP cause VM exits.
+    pub fn intercepted(&self) -> bool {
+        self.intercept
+    }
+
+    /// The TSC read by the guest when the host TSC is `host_tsc`.
+    pub fn guest_tsc(&self, host_tsc: u64) -> u64 {
+        scale(host_tsc, self.ratio, TSC_RATIO_FRAC_BITS).wrapping_add(self.offset)
+    }
+
+    /// The host TSC when the guest TSC is `guest_tsc`.
+    #[cfg(feature = "amd")]
+    pub fn host_tsc(&self, guest_tsc: u64) -> u64 {
+        let scaled = guest_tsc.wrapping_sub(self.offset) as u128;
+        ((scaled << TSC_RATIO_FRAC_BITS) / self.ratio as u128) as u64
+    }
+
+    /// Run the guest TSC at `khz`. Keeps the current guest TSC value.
+    pub fn set_frequency(&mut self, khz: u32) -> HvResult {
+        let ratio = ratio(khz, clocksource::tsc_khz(), TSC_RATIO_FRAC_BITS);
+        if ratio != 1 << TSC_RATIO_FRAC_BITS && !has_tsc_scaling() {
+            return hv_result_err!(ENODEV, "TSC scaling is not supported");
+        }
+        if ratio == 0 || ratio >> (TSC_RATIO_FRAC_BITS + 8) != 0 {
+            return hv_result_err!(EINVAL, format!("Invalid TSC frequency {} kHz", khz));
+        }
+        let now = super::cpu::current_cycle();
+        let value = self.guest_tsc(now);
+        self.ratio = ratio;
+        self.khz = khz;
+        self.set_at(value, now);
+        Ok(())
+    }
+
+    pub fn set_intercept(&mut self, intercept: bool) {
+        self.intercept = intercept;
+    }
+
+    /// Set the guest TSC to `value`, e.g. 0 for a fresh timebase.
+    pub fn set(&mut self, value: u64) {
+        self.set_at(value, super::cpu::current_cycle());
+    }
+
+    fn set_at(&mut self, value: u64, host_tsc: u64) {
+        self.offset = value.wrapping_sub(scale(host_tsc, self.ratio, TSC_RATIO_FRAC_BITS));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tsc_scale() {
+        // 2 GHz to 1 GHz, with the VMX and SVM ratio widths.
+        for frac_bits in [48, 32] {
+            let r = ratio(1_000_000, 2_000_000, frac_bits);
+            assert_eq!(r, 1 << (frac_bits - 1));
+            assert_eq!(scale(2_000_000_000, r, frac_bits), 1_000_000_000);
+            assert_eq!(scale(u64::MAX, r, frac_bits), u64::MAX >> 1);
+        }
+        let r = ratio(3_000_000, 2_000_000, 32);
+        assert_eq!(scale(2_000_000_000, r, 32), 3_000_000_000);
+
+        let mut tsc = CellTsc {
+            offset: 0,
+            ratio: 1 << TSC_RATIO_FRAC_BITS,
+            khz: 0,
+            intercept: false,
+        };
+        tsc.set_at(0, 1000);
+        assert_eq!(tsc.guest_tsc(1000), 0);
+        assert_eq!(tsc.guest_tsc(1500), 500);
+        tsc.set_at(1 << 40, 1000);
+        assert_eq!(tsc.guest_tsc(1000), 1 << 40);
+        #[cfg(feature = "amd")]
+        assert_eq!(tsc.host_tsc(1 << 40), 1000);
+    }
+}
@@ -6,12 +6,20 @@ mod vendor;
 #[path = "amd/mod.rs"]
 mod vendor;
 
+use libvmm::msr::Msr;
 use x86_64::registers::control::{Cr0Flags, Cr4Flags};
 
+use super::mmio::{MmioInstr, MAX_INSTR_LEN};
 use super::GeneralRegisters;
+use crate::memory::{gaccess::AsGuestPtr, GuestPhysAddr};
+use crate::stats::Instant;
+use crate::trace::TraceEvent;
 use crate::{error::HvResult, percpu::PerCpu};
 
-pub use vendor::{check_hypervisor_feature, NestedPageTable, Vcpu};
+pub use vendor::{
+    check_hypervisor_feature, exit_reason_name, has_tsc_scaling, iommu, pmu, NestedPageTable, Vcpu,
+    TSC_RATIO_FRAC_BITS,
+};
 
 pub trait VcpuAccessGuestState {
     // Architecture independent methods:
@@ -38,6 +46,8 @@ pub trait VcpuAccessGuestState {
 const VM_EXIT_LEN_CPUID: u8 = 2;
 const VM_EXIT_LEN_RDMSR: u8 = 2;
 const VM_EXIT_LEN_WRMSR: u8 = 2;
+const VM_EXIT_LEN_RDTSC: u8 = 2;
+const VM_EXIT_LEN_RDTSCP: u8 = 3;
 const VM_EXIT_LEN_HYPERCALL: u8 = 3;
 
 const HOST_CR0: Cr0Flags = Cr0Flags::from_bits_truncate(
@@ -62,12 +72,17 @@ impl VmExit<'_> {
     }
 
     pub fn handle_msr_read(&mut self) -> HvResult {
+        let id = self.cpu_data.vcpu.regs().rcx;
+        let value = self.cpu_data.arch.pvclock.read_msr(id as u32);
+        let value = value.or_else(|| self.cpu_data.vcpu.read_apic_timer(id as u32));
+        let value = value.unwrap_or_else(|| {
+            warn!("VM exit: RDMSR({:#x})", id);
+            // TODO
+            0
+        });
         let guest_regs = self.cpu_data.vcpu.regs_mut();
-        let id = guest_regs.rcx;
-        warn!("VM exit: RDMSR({:#x})", id);
-        // TODO
-        guest_regs.rax = 0;
-        guest_regs.rdx = 0;
+        guest_regs.rax = value & 0xffff_ffff;
+        guest_regs.rdx = value >> 32;
         self.cpu_data.vcpu.advance_rip(VM_EXIT_LEN_RDMSR)?;
         Ok(())
     }
@@ -75,49 +90,134 @@ impl VmExit<'_> {
     pub fn handle_msr_write(&mut self) -> HvResult {
         let guest_regs = self.cpu_data.vcpu.regs();
         let id = guest_regs.rcx;
-        let value = guest_regs.rax | (guest_regs.rdx << 32);
-        warn!("VM exit: WRMSR({:#x}) <- {:#x}", id, value);
-        // TODO
+        let value = (guest_regs.rax & 0xffff_ffff) | (guest_regs.rdx << 32);
+        let cell = self.cpu_data.cell();
+        match self.cpu_data.arch.pvclock.write_msr(cell, id as u32, value) {
+            Some(res) => res?,
+            None if self.cpu_data.vcpu.write_apic_timer(cell, id as u32, value) => {}
+            None if self.write_guest_evtsel(id as u32, value) => {}
+            None if id == Msr::IA32_X2APIC_ICR as u64 && super::apic::x2apic_enabled() => {
+                super::irq::write_icr(cell, value)?
+            }
+            None => {
+                warn!("VM exit: WRMSR({:#x}) <- {:#x}", id, value);
+                // TODO
+            }
+        }
         self.cpu_data.vcpu.advance_rip(VM_EXIT_LEN_WRMSR)?;
         Ok(())
     }
 
-    pub fn handle_cpuid(&mut self) -> HvResult {
-        use super::cpuid::{cpuid, CpuIdEax, FeatureInfoFlags};
-        let signature = unsafe { &*("RVMRVMRVMRVM".as_ptr() as *const [u32; 3]) };
-        let cr4_flags = Cr4Flags::from_bits_truncate(self.cpu_data.vcpu.cr(4));
+    /// Write an event selector of the PMU for the guest, restricted to count in
+    /// guest mode. Returns `false` if `msr` is not one.
+    fn write_guest_evtsel(&mut self, msr: u32, value: u64) -> bool {
+        match pmu::guest_evtsel(msr, value) {
+            Some((counter, value)) => {
+                // Counter 0 may be switched out for the profiler.
+                if counter != 0 || !self.cpu_data.profile.set_guest_evtsel(value) {
+                    unsafe { x86::msr::wrmsr(msr, value) };
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Handle the hypervisor tick if it is due, and arm the timer for the next
+    /// one.
+    fn handle_tick(&mut self) -> HvResult {
+        if self.cpu_data.arch.timer.poll() {
+            crate::watchdog::tick(self.cpu_data);
+            iommu::check_faults();
+        }
+        let ticks = self.cpu_data.arch.timer.remaining_ticks();
+        self.cpu_data.vcpu.arm_timer(ticks)
+    }
+
+    /// RDTSC or RDTSCP, intercepted if enabled by the cell.
+    pub fn handle_rdtsc(&mut self, rdtscp: bool) -> HvResult {
+        let tsc = self
+            .cpu_data
+            .cell()
+            .tsc
+            .guest_tsc(super::cpu::current_cycle());
         let guest_regs = self.cpu_data.vcpu.regs_mut();
-        let function = guest_regs.rax as u32;
+        guest_regs.rax = tsc & 0xffff_ffff;
+        guest_regs.rdx = tsc >> 32;
+        if rdtscp {
+            // IA32_TSC_AUX is not switched.
+            guest_regs.rcx = Msr::IA32_TSC_AUX.read() & 0xffff_ffff;
+            self.cpu_data.vcpu.advance_rip(VM_EXIT_LEN_RDTSCP)
+        } else {
+            self.cpu_data.vcpu.advance_rip(VM_EXIT_LEN_RDTSC)
+        }
+    }
+
+    /// Results of CPUID before applying the policy of the cell.
+    fn emulated_cpuid(function: u32, index: u32, cr4_flags: Cr4Flags) -> [u32; 4] {
+        use super::cpuid::{cpuid, CpuIdEax, FeatureInfoFlags, HvFeatures};
+        use super::pvclock::{kvm_cpuid, KVM_CPUID_SIGNATURE};
+        use crate::config::HvSystemConfig;
+        let signature = unsafe { &*("RVMRVMRVMRVM".as_ptr() as *const [u32; 3]) };
         if function == CpuIdEax::HypervisorInfo as _ {
-            guest_regs.rax = CpuIdEax::HypervisorFeatures as u32 as _;
-            guest_regs.rbx = signature[0] as _;
-            guest_regs.rcx = signature[1] as _;
-            guest_regs.rdx = signature[2] as _;
+            [
+                CpuIdEax::HypervisorFrequencies as _,
+                signature[0],
+                signature[1],
+                signature[2],
+            ]
         } else if function == CpuIdEax::HypervisorFeatures as _ {
-            guest_regs.rax = 0;
-            guest_regs.rbx = 0;
-            guest_regs.rcx = 0;
-            guest_regs.rdx = 0;
+            HvFeatures::leaf()
+        } else if function == CpuIdEax::HypervisorFrequencies as _ {
+            // In kHz, the APIC timer one is 0 if not measured by the driver.
+            let apic_khz = HvSystemConfig::get().apic_khz();
+            [super::clocksource::tsc_khz(), apic_khz, 0, 0]
+        } else if function & !0xff == KVM_CPUID_SIGNATURE {
+            kvm_cpuid(function)
+        } else if function & 0xc000_0000 == CpuIdEax::HypervisorInfo as _ {
+            [0; 4]
         } else {
-            let res = cpuid!(guest_regs.rax, guest_regs.rcx);
-            guest_regs.rax = res.eax as _;
-            guest_regs.rbx = res.ebx as _;
-            guest_regs.rcx = res.ecx as _;
-            guest_regs.rdx = res.edx as _;
+            let res = cpuid!(function, index);
+            let mut regs = [res.eax, res.ebx, res.ecx, res.edx];
             if function == CpuIdEax::FeatureInfo as _ {
-                let mut flags = FeatureInfoFlags::from_bits_truncate(guest_regs.rcx as _);
+                let mut flags = FeatureInfoFlags::from_bits_truncate(regs[2] as _);
                 if cr4_flags.contains(Cr4Flags::OSXSAVE) {
                     flags.insert(FeatureInfoFlags::OSXSAVE);
                 }
                 flags.remove(FeatureInfoFlags::VMX);
                 flags.insert(FeatureInfoFlags::HYPERVISOR);
-                guest_regs.rcx = flags.bits();
+                regs[2] = flags.bits() as _;
             } else if function == CpuIdEax::AmdFeatureInfo as _ {
-                let mut flags = FeatureInfoFlags::from_bits_truncate(guest_regs.rcx as _);
+                let mut flags = FeatureInfoFlags::from_bits_truncate(regs[2] as _);
                 flags.remove(FeatureInfoFlags::SVM);
-                guest_regs.rcx = flags.bits();
+                regs[2] = flags.bits() as _;
             }
+            regs
         }
+    }
+
+    pub fn handle_cpuid(&mut self) -> HvResult {
+        use super::cpuid::{limit_topology, CpuIdPolicy};
+        let cr4_flags = Cr4Flags::from_bits_truncate(self.cpu_data.vcpu.cr(4));
+        let cell = self.cpu_data.cell();
+        let policy = CpuIdPolicy::new(cell.config.cpuid_entries());
+        let apic_id = self.cpu_data.arch.apic_id();
+        let cpus_sharing = |shift: u32| {
+            super::irq::cell_apic_ids(cell)
+                .filter(|&id| id >> shift == apic_id >> shift)
+                .count() as u32
+        };
+        let guest_regs = self.cpu_data.vcpu.regs_mut();
+        let (function, index) = (guest_regs.rax as u32, guest_regs.rcx as u32);
+        let regs = policy.cpuid(function, index, |function, index| {
+            let mut regs = Self::emulated_cpuid(function, index, cr4_flags);
+            limit_topology(function, &mut regs, &cpus_sharing);
+            regs
+        });
+        guest_regs.rax = regs[0] as _;
+        guest_regs.rbx = regs[1] as _;
+        guest_regs.rcx = regs[2] as _;
+        guest_regs.rdx = regs[3] as _;
         self.cpu_data.vcpu.advance_rip(VM_EXIT_LEN_CPUID)?;
         Ok(())
     }
@@ -131,6 +231,60 @@ impl VmExit<'_> {
         Ok(())
     }
 
+    /// Emulate an access to an MMIO region of the cell at `gpaddr`, by the
+    /// instruction at the guest RIP.
+    pub fn handle_mmio(&mut self, gpaddr: GuestPhysAddr) -> HvResult {
+        let cell = self.cpu_data.cell();
+        let region = match cell.mmio.find(gpaddr) {
+            Some(region) => region,
+            None => return hv_result_err!(ENOSYS, format!("No MMIO region at {:#x}", gpaddr)),
+        };
+        let vcpu = &mut self.cpu_data.vcpu;
+        let rip = vcpu.instr_pointer();
+        let gpt = vcpu.guest_page_table();
+        let mut bytes = [0; MAX_INSTR_LEN];
+        let mut len = 0;
+        // The instruction may end before an unmapped page.
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            match (rip + i as u64).as_guest_ptr::<u8>(&gpt).read() {
+                Ok(b) => *byte = b,
+                Err(e) if i == 0 => return Err(e),
+                Err(_) => break,
+            }
+            len += 1;
+        }
+        let instr = MmioInstr::decode(&bytes[..len])?;
+
+        let value = if instr.is_write {
+            instr.write_value(vcpu.regs())?
+        } else {
+            0
+        };
+        let value = region.access(cell, gpaddr, instr.size, instr.is_write, value)?;
+        if !instr.is_write {
+            instr.complete_read(vcpu.regs_mut(), value)?;
+        }
+        vcpu.advance_rip(instr.len)
+    }
+
+    /// Emulate `IN` or `OUT` of `size` bytes on an intercepted port.
+    pub fn handle_io(&mut self, port: u16, size: u8, is_in: bool, instr_len: u8) -> HvResult {
+        let cell = self.cpu_data.cell();
+        let mask = (1u64 << (size * 8)) - 1;
+        let regs = self.cpu_data.vcpu.regs_mut();
+        let mut value = (regs.rax & mask) as u32;
+        let addr_port = &mut self.cpu_data.arch.pci_addr_port;
+        super::pci::handle_port_io(cell, addr_port, port, size, is_in, &mut value)?;
+        if is_in {
+            // 32-bit results are zero-extended to 64 bits.
+            regs.rax = match size {
+                4 => value as u64,
+                _ => (regs.rax & !mask) | (value as u64 & mask),
+            };
+        }
+        self.cpu_data.vcpu.advance_rip(instr_len)
+    }
+
     #[allow(dead_code)]
     fn test_read_guest_memory(&self, gvaddr: usize, size: usize) -> HvResult {
         use crate::cell;
@@ -151,12 +305,29 @@ impl VmExit<'_> {
 
 pub(super) fn vmexit_handler() {
     let mut vmexit = VmExit::new();
-    let res = vmexit.handle_exit();
+    vmexit.cpu_data.profile.exit_begin();
+    let start = Instant::now();
+    let reason = vmexit.cpu_data.vcpu.exit_reason();
+    crate::crash::record_exit(vmexit.cpu_data.id, reason);
+    let event = TraceEvent::begin(reason, &vmexit.cpu_data.vcpu);
+    let rip = vmexit.cpu_data.vcpu.instr_pointer();
+    vmexit.cpu_data.profile.record_exit(reason, rip);
+
+    let res = vmexit
+        .handle_exit()
+        .and_then(|_| vmexit.handle_tick())
+        .and_then(|_| vmexit.cpu_data.vcpu.inject_pending_nmi());
     if let Err(err) = res {
         error!(
             "Failed to handle VM exit, inject fault to guest...\n{:?}",
             err
         );
+        crate::crash::record_error(vmexit.cpu_data.id, &err);
         vmexit.cpu_data.fault().unwrap();
     }
+    vmexit.cpu_data.stats.exit(reason, start.elapsed());
+    vmexit.cpu_data.trace.record(event);
+    crate::lang::check_panic(vmexit.cpu_data);
+    crate::watchdog::check_stop(vmexit.cpu_data);
+    vmexit.cpu_data.profile.exit_end();
 }
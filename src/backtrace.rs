@@ -0,0 +1,141 @@
+//! Frame-pointer based stack unwinding and symbolization.
+//!
+//! The symbol table is reserved in the `.symbols` section and filled by
+//! `rvm-sym` after linking (see `make elf`), it is empty otherwise.
+
+use core::fmt::{Display, Formatter, Result};
+use core::ops::Range;
+
+use crate::arch::cpu;
+use crate::consts::HV_BASE;
+use crate::percpu::PerCpu;
+
+/// Maximum number of frames in a backtrace.
+const MAX_FRAMES: usize = 32;
+
+/// Must be the same as `SYMBOL_TABLE_SIZE` of `rvm-sym`.
+const SYMBOL_TABLE_SIZE: usize = 256 * 1024;
+
+#[used]
+#[link_section = ".symbols"]
+static SYMBOL_TABLE: [u8; SYMBOL_TABLE_SIZE] = [0; SYMBOL_TABLE_SIZE];
+
+/// Return addresses of the call chain, innermost first.
+pub struct Backtrace {
+    len: usize,
+    frames: [usize; MAX_FRAMES],
+}
+
+impl Backtrace {
+    /// Walk the frame-pointer chain on the stack of the current CPU.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let mut bt = Self {
+            len: 0,
+            frames: [0; MAX_FRAMES],
+        };
+        if cfg!(not(test)) {
+            let cpu_data = PerCpu::current();
+            bt.walk(
+                cpu::frame_pointer(),
+                cpu_data.stack_bottom()..cpu_data.stack_top(),
+            );
+        }
+        bt
+    }
+
+    /// Walk the call chain of an interrupted context, starting with its
+    /// instruction pointer `pc`.
+    #[cfg_attr(not(feature = "profile"), allow(dead_code))]
+    pub fn from_frame(pc: usize, fp: usize) -> Self {
+        let mut bt = Self {
+            len: 1,
+            frames: [0; MAX_FRAMES],
+        };
+        bt.frames[0] = pc;
+        let cpu_data = PerCpu::current();
+        bt.walk(fp, cpu_data.stack_bottom()..cpu_data.stack_top());
+        bt
+    }
+
+    fn walk(&mut self, mut fp: usize, stack: Range<usize>) {
+        // Each frame starts with the caller's frame pointer, followed by the
+        // return address.
+        while self.len < MAX_FRAMES && fp % 8 == 0 && stack.contains(&fp) && fp + 16 <= stack.end {
+            let (next_fp, ret_addr) =
+                unsafe { (*(fp as *const usize), *((fp + 8) as *const usize)) };
+            if ret_addr == 0 {
+                break;
+            }
+            self.frames[self.len] = ret_addr;
+            self.len += 1;
+            if next_fp <= fp {
+                break;
+            }
+            fp = next_fp;
+        }
+    }
+
+    pub fn frames(&self) -> &[usize] {
+        &self.frames[..self.len]
+    }
+}
+
+impl Display for Backtrace {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        for (i, &addr) in self.frames().iter().enumerate() {
+            write!(f, "\n  #{:<2} {:#x}", i, addr)?;
+            // The return address may be the start of the next function.
+            if let Some((name, offset)) = lookup(addr - 1) {
+                write!(f, " {}+{:#x}", name, offset + 1)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_u32(table: &[u8], offset: usize) -> Option<u32> {
+    let bytes = table.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Find the symbol containing `addr`, returning its name and the offset of
+/// `addr` in it.
+fn lookup(addr: usize) -> Option<(&'static str, usize)> {
+    // Read the address of the table through a volatile load, so that the
+    // compiler can not make any assumption on its content, which is written
+    // after linking.
+    let table: *const [u8; SYMBOL_TABLE_SIZE] = &SYMBOL_TABLE;
+    let table = unsafe { &*core::ptr::read_volatile(&table) };
+    let count = read_u32(table, 0)? as usize;
+    let entry = |i: usize| {
+        Some((
+            read_u32(table, 4 + i * 8)?,
+            read_u32(table, 8 + i * 8)? as usize,
+        ))
+    };
+    let names_start = 4 + count * 8;
+
+    let offset = addr.checked_sub(HV_BASE)?;
+    // Index of the first symbol after `addr`.
+    let (mut lo, mut hi) = (0, count);
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        if entry(mid)?.0 as usize <= offset {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    let (sym_addr, name_start) = entry(lo.checked_sub(1)?)?;
+    let name_end = if lo < count {
+        entry(lo)?.1
+    } else {
+        // The last name is followed by the zero padding.
+        let names = table.get(names_start..)?;
+        names.iter().position(|&b| b == 0).unwrap_or(names.len())
+    };
+    let name = table.get(names_start + name_start..names_start + name_end)?;
+    let name = core::str::from_utf8(name).ok()?;
+    Some((name, offset - sym_addr as usize))
+}
@@ -1,8 +1,13 @@
-use crate::arch::NestedPageTable;
+use spin::RwLock;
+
+use crate::arch::{CellIoapics, CellTsc, NestedPageTable};
 use crate::config::{CellConfig, HvSystemConfig};
-use crate::error::HvResult;
+use crate::error::{HvResult, HvResultExt};
 use crate::memory::addr::{GuestPhysAddr, HostPhysAddr};
-use crate::memory::{MemFlags, MemoryRegion, MemorySet};
+use crate::memory::{MemFlags, MemoryRegion, MemorySet, PAGE_SIZE};
+use crate::mmio::MmioRegions;
+use crate::pci::PciDevices;
+use crate::watchdog::Watchdog;
 
 #[derive(Debug)]
 pub struct Cell<'a> {
@@ -10,15 +15,34 @@ pub struct Cell<'a> {
     pub config: CellConfig<'a>,
     /// Guest physical memory set.
     pub gpm: MemorySet<NestedPageTable>,
+    /// TSC offset, scaling and interception of the guests.
+    pub tsc: CellTsc,
+    pub watchdog: Watchdog,
+    /// Emulated MMIO regions.
+    pub mmio: MmioRegions,
+    /// PCI devices visible to the cell.
+    pub pci: PciDevices<'a>,
+    /// IOAPICs with the pins of the cell.
+    pub ioapics: CellIoapics,
 }
 
 impl Cell<'_> {
+    /// Whether the CPU with hypervisor ID `cpu_id` belongs to this cell.
+    pub fn owns_cpu(&self, cpu_id: u32) -> bool {
+        let cpu_set = self.config.cpu_set();
+        let (idx, bit) = (cpu_id as usize / 64, cpu_id % 64);
+        cpu_set
+            .get(idx)
+            .map_or(false, |&bits| bits & (1 << bit) != 0)
+    }
+
     fn new_root() -> HvResult<Self> {
         let sys_config = HvSystemConfig::get();
         let cell_config = sys_config.root_cell.config();
         let hv_phys_start = sys_config.hypervisor_memory.phys_start as usize;
         let hv_phys_size = sys_config.hypervisor_memory.size as usize;
 
+        let pci = PciDevices::new(&cell_config).context("failed to add PCI devices")?;
         let mut gpm = MemorySet::new();
 
         // Map hypervisor memory to the empty page.
@@ -26,38 +50,97 @@ impl Cell<'_> {
             hv_phys_start,
             hv_phys_size,
             MemFlags::READ | MemFlags::NO_HUGEPAGES,
-        ))?;
-        // Map all physical memory regions.
-        for region in cell_config.mem_regions() {
+        ))
+        .context("failed to hide hypervisor memory")?;
+        // Map all physical memory regions, the state tables of shared memory
+        // are only written by the hypervisor.
+        for (i, region) in cell_config.mem_regions().iter().enumerate() {
+            let mut virt_start = region.virt_start as GuestPhysAddr;
+            let mut phys_start = region.phys_start as HostPhysAddr;
+            let mut size = region.size as usize;
+            if crate::pci::is_shmem_region(&cell_config, i) {
+                gpm.insert(MemoryRegion::new_with_offset_mapper(
+                    virt_start,
+                    phys_start,
+                    PAGE_SIZE,
+                    region.flags - MemFlags::WRITE,
+                ))
+                .context("failed to map ivshmem state table")?;
+                virt_start += PAGE_SIZE;
+                phys_start += PAGE_SIZE;
+                size -= PAGE_SIZE;
+            }
             gpm.insert(MemoryRegion::new_with_offset_mapper(
-                region.virt_start as GuestPhysAddr,
-                region.phys_start as HostPhysAddr,
-                region.size as usize,
+                virt_start,
+                phys_start,
+                size,
                 region.flags,
-            ))?;
+            ))
+            .context("failed to map cell memory region")?;
         }
         trace!("Guest phyiscal memory set: {:#x?}", gpm);
 
+        let mmio = MmioRegions::default();
+        pci.register_mmio(&mmio)?;
+        let ioapics = CellIoapics::new(&cell_config, &mmio).context("failed to add IOAPICs")?;
+        crate::arch::apic::register_mmio(&mmio).context("failed to add local APIC")?;
+        for region in cell_config.mem_regions() {
+            if mmio.overlaps(region.virt_start as _, region.size as _) {
+                return hv_result_err!(
+                    EINVAL,
+                    format!("Memory region {:#x} overlaps emulated MMIO", {
+                        region.virt_start
+                    })
+                );
+            }
+        }
+
+        let tsc = CellTsc::new(&cell_config)?;
+        let watchdog = Watchdog::new(cell_config.watchdog_action(), true)?;
+
         Ok(Self {
             config: cell_config,
             gpm,
+            tsc,
+            watchdog,
+            mmio,
+            pci,
+            ioapics,
         })
     }
 }
 
-static ROOT_CELL: spin::Once<Cell> = spin::Once::new();
+static ROOT_CELL: RwLock<Option<Cell<'static>>> = RwLock::new(None);
 
 pub fn root_cell<'a>() -> &'a Cell<'a> {
-    ROOT_CELL.get().expect("Uninitialized root cell!")
+    let cell = ROOT_CELL.read();
+    let cell = cell.as_ref().expect("Uninitialized root cell!");
+    // The root cell is only dropped by `reset()`, after all CPUs left the
+    // hypervisor.
+    unsafe { &*(cell as *const Cell) }
 }
 
 pub fn init() -> HvResult {
     crate::arch::vmm::check_hypervisor_feature()?;
 
-    let root_cell = Cell::new_root()?;
+    let root_cell = Cell::new_root().context("failed to create root cell")?;
     info!("Root cell init end.");
     debug!("{:#x?}", root_cell);
 
-    ROOT_CELL.call_once(|| root_cell);
+    *ROOT_CELL.write() = Some(root_cell);
     Ok(())
 }
+
+/// Write back the interrupts programmed by the root cell, before the hypervisor
+/// stops remapping them.
+pub fn shutdown() {
+    if let Some(cell) = ROOT_CELL.read().as_ref() {
+        cell.pci.restore_msis();
+        cell.ioapics.restore();
+    }
+}
+
+/// Free the root cell, so that it is created again on the next enabling.
+pub fn reset() {
+    ROOT_CELL.write().take();
+}
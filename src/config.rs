@@ -5,7 +5,8 @@ use crate::error::HvResult;
 use crate::memory::MemFlags;
 
 const CONFIG_SIGNATURE: [u8; 6] = *b"RVMSYS";
-const CONFIG_REVISION: u16 = 10;
+/// Version of the system and cell configuration layout.
+pub const CONFIG_REVISION: u16 = 13;
 
 const HV_CELL_NAME_MAXLEN: usize = 31;
 const HV_MAX_IOMMU_UNITS: usize = 8;
@@ -43,6 +44,12 @@ pub struct HvCellDesc {
     pub pio_bitmap_size: u32,
     pub num_pci_devices: u32,
     pub num_pci_caps: u32,
+    pub num_cpuid_entries: u32,
+    /// Guest TSC frequency in kHz, 0 for the host one.
+    pub tsc_khz: u32,
+    pub tsc_flags: u32,
+    /// See `WatchdogAction`.
+    pub watchdog_action: u32,
 
     vpci_irq_base: u32,
 
@@ -74,52 +81,99 @@ pub struct HvCacheRegion {
 #[derive(Debug)]
 #[repr(C, packed)]
 pub struct HvIrqChip {
-    address: u64,
-    id: u32,
-    pin_base: u32,
-    pin_bitmap: [u32; 4],
+    pub address: u64,
+    /// Source ID of the IOAPIC in interrupt requests.
+    pub id: u32,
+    /// First pin of `pin_bitmap`.
+    pub pin_base: u32,
+    /// Pins owned by the cell.
+    pub pin_bitmap: [u32; 4],
 }
 
 #[derive(Debug)]
 #[repr(C, packed)]
 pub struct HvPciDevice {
-    pci_device_type: u8,
+    pub pci_device_type: u8,
     iommu: u8,
     domain: u16,
-    bdf: u16,
-    bar_mask: [u32; 6],
-    caps_start: u16,
-    num_caps: u16,
-    num_msi_vectors: u8,
-    msi_64bits: u8,
-    num_msix_vectors: u16,
-    msix_region_size: u16,
-    msix_address: u64,
+    /// Bus, device and function, as the PCI requester ID.
+    pub bdf: u16,
+    /// Writable bits of each BAR, the others read back as is when sizing.
+    pub bar_mask: [u32; 6],
+    /// Index of the first capability in the capabilities of the cell.
+    pub caps_start: u16,
+    pub num_caps: u16,
+    pub num_msi_vectors: u8,
+    pub msi_64bits: u8,
+    pub num_msix_vectors: u16,
+    /// Size of the MSI-X region, the table followed by other registers in
+    /// the same pages.
+    pub msix_region_size: u16,
+    /// Physical address of the MSI-X table.
+    pub msix_address: u64,
     /// Memory region index of virtual shared memory device.
-    shmem_region: u32,
+    pub shmem_region: u32,
     /// PCI subclass and interface ID of virtual shared memory device.
-    shmem_protocol: u16,
+    pub shmem_protocol: u16,
     _padding: [u8; 2],
 }
 
+impl HvPciDevice {
+    pub const TYPE_BRIDGE: u8 = 2;
+    /// Virtual shared memory device, emulated by the hypervisor.
+    pub const TYPE_IVSHMEM: u8 = 3;
+}
+
 #[derive(Debug)]
 #[repr(C, packed)]
 pub struct HvPciCapability {
-    id: u16,
-    start: u16,
-    len: u16,
-    flags: u16,
+    /// Capability ID, with bit 15 set for extended capabilities.
+    pub id: u16,
+    /// Offset in the configuration space.
+    pub start: u16,
+    pub len: u16,
+    pub flags: u16,
+}
+
+impl HvPciCapability {
+    pub const ID_MSI: u16 = 0x05;
+    pub const ID_MSIX: u16 = 0x11;
+    /// The cell may write to the capability.
+    pub const WRITE: u16 = 1 << 0;
+}
+
+/// Override of CPUID output registers, each becomes
+/// `(native & !mask) | (value & mask)`.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct HvCpuidEntry {
+    pub function: u32,
+    /// Subleaf (ECX), only matched with `HvCpuidEntry::SIGNIFICANT_INDEX`.
+    pub index: u32,
+    pub flags: u32,
+    /// EAX, EBX, ECX and EDX.
+    pub mask: [u32; 4],
+    pub value: [u32; 4],
+}
+
+impl HvCpuidEntry {
+    pub const SIGNIFICANT_INDEX: u32 = 1 << 0;
 }
 
 #[derive(Debug)]
 #[repr(C, packed)]
-struct HvIommu {
-    base: u64,
-    size: u32,
-    amd_bdf: u16,
-    amd_base_cap: u8,
-    amd_msi_cap: u8,
-    amd_features: u32,
+pub struct HvIommu {
+    /// Physical address of the registers.
+    pub base: u64,
+    pub size: u32,
+    /// PCI device of an AMD IOMMU.
+    pub amd_bdf: u16,
+    /// Offset of the IOMMU capability in its PCI configuration space.
+    pub amd_base_cap: u8,
+    /// Offset of its MSI capability.
+    pub amd_msi_cap: u8,
+    /// Flags of its IVHD entry in the ACPI IVRS table.
+    pub amd_features: u32,
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -172,6 +226,7 @@ struct CellConfigLayout {
     pio_bitmap: [u8; 0],
     pci_devices: [HvPciDevice; 0],
     pci_caps: [HvPciCapability; 0],
+    cpuid_entries: [HvCpuidEntry; 0],
 }
 
 pub struct CellConfig<'a> {
@@ -179,6 +234,11 @@ pub struct CellConfig<'a> {
 }
 
 impl HvCellDesc {
+    /// Intercept RDTSC and RDTSCP in `tsc_flags`.
+    pub const TSC_INTERCEPT: u32 = 1 << 0;
+    /// Start the guest TSC from 0 in `tsc_flags`.
+    pub const TSC_RESET: u32 = 1 << 1;
+
     pub const fn config(&self) -> CellConfig {
         CellConfig::from(self)
     }
@@ -191,10 +251,14 @@ impl HvCellDesc {
             + self.pio_bitmap_size as usize
             + self.num_pci_devices as usize * size_of::<HvPciDevice>()
             + self.num_pci_caps as usize * size_of::<HvPciCapability>()
+            + self.num_cpuid_entries as usize * size_of::<HvCpuidEntry>()
     }
 }
 
 impl HvSystemConfig {
+    pub const APIC_MODE_XAPIC: u8 = 1;
+    pub const APIC_MODE_X2APIC: u8 = 2;
+
     pub fn get<'a>() -> &'a Self {
         unsafe { &*crate::consts::hv_config_ptr() }
     }
@@ -203,6 +267,50 @@ impl HvSystemConfig {
         size_of::<Self>() + self.root_cell.config_size()
     }
 
+    /// Physical address of the PCI MMCONFIG region, 0 if absent.
+    pub fn pci_mmconfig_base(&self) -> u64 {
+        self.platform_info.pci_mmconfig_base
+    }
+
+    /// Last bus covered by the MMCONFIG region.
+    pub fn pci_mmconfig_end_bus(&self) -> u8 {
+        self.platform_info.pci_mmconfig_end_bus
+    }
+
+    /// I/O port of the ACPI PM timer, 0 if absent.
+    pub fn pm_timer_address(&self) -> u16 {
+        self.platform_info.arch.pm_timer_address
+    }
+
+    /// Number of VT-d interrupt remapping table entries.
+    #[cfg_attr(not(feature = "intel"), allow(dead_code))]
+    pub fn vtd_interrupt_limit(&self) -> u32 {
+        self.platform_info.arch.vtd_interrupt_limit
+    }
+
+    /// TSC frequency measured by the driver, in kHz.
+    pub fn tsc_khz(&self) -> u32 {
+        self.platform_info.arch.tsc_khz
+    }
+
+    /// APIC timer frequency measured by the driver, in kHz.
+    pub fn apic_khz(&self) -> u32 {
+        self.platform_info.arch.apic_khz
+    }
+
+    /// Local APIC mode required by the configuration, or 0 to use the mode set
+    /// by Linux.
+    pub fn apic_mode(&self) -> u8 {
+        self.platform_info.arch.apic_mode
+    }
+
+    /// Configured IOMMU units, the list ends at the first unit with a zero base.
+    pub fn iommu_units(&self) -> &[HvIommu] {
+        let units = &self.platform_info.arch.iommu_units;
+        let num = units.iter().take_while(|unit| unit.base != 0).count();
+        &units[..num]
+    }
+
     pub fn check(&self) -> HvResult {
         if self.signature != CONFIG_SIGNATURE {
             return hv_result_err!(EINVAL, "HvSystemConfig signature not matched!");
@@ -227,6 +335,18 @@ impl<'a> CellConfig<'a> {
         self.desc.config_size()
     }
 
+    pub fn tsc_khz(&self) -> u32 {
+        self.desc.tsc_khz
+    }
+
+    pub fn tsc_flags(&self) -> u32 {
+        self.desc.tsc_flags
+    }
+
+    pub fn watchdog_action(&self) -> u32 {
+        self.desc.watchdog_action
+    }
+
     pub fn cpu_set(&self) -> &[u64] {
         // XXX: data may unaligned, which cause panic on debug mode. Same below.
         // See: https://doc.rust-lang.org/src/core/slice/mod.rs.html#6435-6443
@@ -239,6 +359,40 @@ impl<'a> CellConfig<'a> {
             slice::from_raw_parts(ptr, self.desc.num_memory_regions as usize)
         }
     }
+
+    pub fn irqchips(&self) -> &[HvIrqChip] {
+        unsafe {
+            let ptr = (self.mem_regions().as_ptr_range().end as *const HvCacheRegion)
+                .add(self.desc.num_cache_regions as usize);
+            slice::from_raw_parts(ptr as _, self.desc.num_irqchips as usize)
+        }
+    }
+
+    pub fn pci_devices(&self) -> &'a [HvPciDevice] {
+        unsafe {
+            let ptr = (self.irqchips().as_ptr_range().end as *const u8)
+                .add(self.desc.pio_bitmap_size as usize);
+            slice::from_raw_parts(ptr as _, self.desc.num_pci_devices as usize)
+        }
+    }
+
+    pub fn pci_caps(&self) -> &'a [HvPciCapability] {
+        unsafe {
+            let ptr = self.pci_devices().as_ptr_range().end;
+            slice::from_raw_parts(ptr as _, self.desc.num_pci_caps as usize)
+        }
+    }
+
+    /// CPUID overrides, the last part of the configuration.
+    pub fn cpuid_entries(&self) -> &[HvCpuidEntry] {
+        let num = self.desc.num_cpuid_entries as usize;
+        unsafe {
+            let ptr = self
+                .config_ptr::<u8>()
+                .add(self.size() - num * size_of::<HvCpuidEntry>());
+            slice::from_raw_parts(ptr as _, num)
+        }
+    }
 }
 
 impl Debug for CellConfig<'_> {
@@ -252,6 +406,10 @@ impl Debug for CellConfig<'_> {
             .field("name", &core::str::from_utf8(&name[..len]))
             .field("size", &self.size())
             .field("mem_regions", &self.mem_regions())
+            .field("irqchips", &self.irqchips())
+            .field("pci_devices", &self.pci_devices())
+            .field("pci_caps", &self.pci_caps())
+            .field("cpuid_entries", &self.cpuid_entries())
             .finish()
     }
 }
@@ -1,4 +1,5 @@
 use crate::config::HvSystemConfig;
+use crate::crash::CrashRecord;
 use crate::header::HvHeader;
 use crate::memory::addr::{align_up, VirtAddr};
 use crate::percpu::PerCpu;
@@ -11,6 +12,9 @@ pub const HV_HEAP_SIZE: usize = 32 * 1024 * 1024; // 32 MB
 /// Size of the per-CPU data (stack and other CPU-local data).
 pub const PER_CPU_SIZE: usize = 512 * 1024; // 512 KB
 
+/// Size of the crash record at the end of the hypervisor memory.
+pub const CRASH_RECORD_SIZE: usize = 64 * 1024; // 64 KB
+
 /// Start virtual address of the hypervisor memory.
 pub const HV_BASE: usize = 0xffff_ff00_0000_0000;
 
@@ -35,6 +39,11 @@ pub fn hv_end() -> VirtAddr {
     HV_BASE + HvSystemConfig::get().hypervisor_memory.size as usize
 }
 
+/// Pointer of the crash record, placed at the end of the hypervisor memory.
+pub fn crash_record_ptr() -> *mut CrashRecord {
+    (hv_end() - CRASH_RECORD_SIZE) as _
+}
+
 extern "C" {
     fn __header_start();
     fn __core_end();
@@ -0,0 +1,140 @@
+//! LLVM source-based code coverage, enabled by the `coverage` feature.
+//!
+//! With `make COVERAGE=on`, the hypervisor is instrumented by
+//! `-Z instrument-coverage` without the profiler runtime. The linker script
+//! keeps the profile data, counters and names emitted by LLVM in the image, and
+//! `COVERAGE_INFO` describes where they are. Its address is published through
+//! `HvHeader::gcov_info_head`. The counters are reset on every enable, as the
+//! driver reloads the image.
+//!
+//! The `CoverageGet` hypercall copies a dump to the guest: a `CoverageHeader`
+//! followed by the data, counters and names sections. `tools/rvm-cov` converts
+//! it to a raw profile for `llvm-profdata`, and then to an lcov report. All
+//! fields are little-endian.
+
+#![allow(dead_code)]
+
+use core::mem::size_of;
+
+use crate::error::HvResult;
+
+const COVERAGE_MAGIC: [u8; 8] = *b"RVMCOVER";
+/// Increased on every incompatible change of the dump format.
+const COVERAGE_VERSION: u32 = 1;
+
+/// Locations of the coverage sections, pointed by `HvHeader::gcov_info_head`.
+#[repr(C)]
+pub struct CoverageInfo {
+    magic: [u8; 8],
+    version: u32,
+    _padding: u32,
+    /// Address of `__llvm_profile_raw_version`.
+    raw_version: unsafe extern "C" fn(),
+    data_start: unsafe extern "C" fn(),
+    data_end: unsafe extern "C" fn(),
+    cnts_start: unsafe extern "C" fn(),
+    cnts_end: unsafe extern "C" fn(),
+    names_start: unsafe extern "C" fn(),
+    names_end: unsafe extern "C" fn(),
+}
+
+/// Header of the dump. Each section is copied right after the previous one.
+#[repr(C)]
+struct CoverageHeader {
+    magic: [u8; 8],
+    version: u32,
+    _padding: u32,
+    /// Value of `__llvm_profile_raw_version`.
+    raw_version: u64,
+    /// Load address and size in bytes of each section.
+    data_addr: u64,
+    data_size: u64,
+    cnts_addr: u64,
+    cnts_size: u64,
+    names_addr: u64,
+    names_size: u64,
+}
+
+extern "C" {
+    fn __llvm_profile_raw_version();
+    fn __prf_data_start();
+    fn __prf_data_end();
+    fn __prf_cnts_start();
+    fn __prf_cnts_end();
+    fn __prf_names_start();
+    fn __prf_names_end();
+}
+
+/// Referenced by the instrumented code to pull in the profiler runtime, which
+/// is replaced by this module.
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+static __llvm_profile_runtime: i32 = 0;
+
+pub static COVERAGE_INFO: CoverageInfo = CoverageInfo {
+    magic: COVERAGE_MAGIC,
+    version: COVERAGE_VERSION,
+    _padding: 0,
+    raw_version: __llvm_profile_raw_version,
+    data_start: __prf_data_start,
+    data_end: __prf_data_end,
+    cnts_start: __prf_cnts_start,
+    cnts_end: __prf_cnts_end,
+    names_start: __prf_names_start,
+    names_end: __prf_names_end,
+};
+
+unsafe fn section(start: unsafe extern "C" fn(), end: unsafe extern "C" fn()) -> &'static [u8] {
+    let start = start as usize;
+    core::slice::from_raw_parts(start as *const u8, end as usize - start)
+}
+
+impl CoverageInfo {
+    fn header(&self) -> CoverageHeader {
+        let [data, cnts, names] = self.sections();
+        CoverageHeader {
+            magic: self.magic,
+            version: self.version,
+            _padding: 0,
+            raw_version: unsafe { *(self.raw_version as *const u64) },
+            data_addr: data.as_ptr() as u64,
+            data_size: data.len() as u64,
+            cnts_addr: cnts.as_ptr() as u64,
+            cnts_size: cnts.len() as u64,
+            names_addr: names.as_ptr() as u64,
+            names_size: names.len() as u64,
+        }
+    }
+
+    fn sections(&self) -> [&'static [u8]; 3] {
+        unsafe {
+            [
+                section(self.data_start, self.data_end),
+                section(self.cnts_start, self.cnts_end),
+                section(self.names_start, self.names_end),
+            ]
+        }
+    }
+
+    /// Size of the dump.
+    pub fn dump_size(&self) -> usize {
+        size_of::<CoverageHeader>() + self.sections().iter().map(|s| s.len()).sum::<usize>()
+    }
+
+    /// Pass the parts of the dump in order to `write`, with their offsets.
+    pub fn dump(&self, mut write: impl FnMut(usize, &[u8]) -> HvResult) -> HvResult {
+        let header = self.header();
+        let header = unsafe {
+            core::slice::from_raw_parts(
+                &header as *const _ as *const u8,
+                size_of::<CoverageHeader>(),
+            )
+        };
+        let mut offset = 0;
+        for part in core::iter::once(header).chain(self.sections()) {
+            write(offset, part)?;
+            offset += part.len();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,274 @@
+//! Crash record for post-mortem analysis.
+//!
+//! The record occupies the last `CRASH_RECORD_SIZE` bytes of the hypervisor
+//! memory, which survive returning back to linux. The patched driver also keeps
+//! them when it loads the hypervisor image again (see `scripts/guest`). After
+//! disabling the hypervisor, `tools/rvm-crash` prints the record from
+//! `/dev/mem` at `hypervisor_memory.phys_start + hypervisor_memory.size -
+//! CRASH_RECORD_SIZE`, and sets `read` to 1 with `--mark-read`. Enabling the
+//! hypervisor again resets the record, unless it holds a panic that has not
+//! been read, which is kept and nothing is recorded during that run.
+//!
+//! All fields are little-endian, strings are NUL-padded UTF-8. Tools must check
+//! `magic` and `version` before parsing the rest of the record.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+
+use crate::arch::vmm::VcpuAccessGuestState;
+use crate::arch::{clocksource, GeneralRegisters};
+use crate::consts::{crash_record_ptr, CRASH_RECORD_SIZE};
+use crate::error::HvError;
+use crate::memory::addr::virt_to_phys;
+use crate::percpu::PerCpu;
+
+const CRASH_RECORD_MAGIC: [u8; 8] = *b"RVMCRASH";
+/// Increased on every incompatible change of the record layout.
+const CRASH_RECORD_VERSION: u32 = 2;
+
+/// Number of CPUs with a slot in the crash record.
+const CRASH_MAX_CPUS: usize = 64;
+/// Number of the last exit reasons kept for each CPU.
+const EXIT_HISTORY_LEN: usize = 32;
+const LOG_TAIL_SIZE: usize = 16 * 1024; // 16 KB
+
+const FILE_NAME_SIZE: usize = 128;
+const PANIC_MESSAGE_SIZE: usize = 1024;
+const ERROR_MESSAGE_SIZE: usize = 256;
+const NO_CPU: u32 = u32::MAX;
+
+/// Source location, as recorded by `HvError` or `PanicInfo`.
+#[repr(C)]
+struct Location {
+    file: [u8; FILE_NAME_SIZE],
+    line: u32,
+    col: u32,
+}
+
+/// The last `HvError` returned to the VM exit handler or the driver.
+#[repr(C)]
+struct ErrorRecord {
+    /// Negative errno, or 0 if no error was recorded.
+    code: i32,
+    _padding: u32,
+    location: Location,
+    message: [u8; ERROR_MESSAGE_SIZE],
+}
+
+#[repr(C)]
+struct CpuRecord {
+    /// Set to 1 when `regs` and the following system registers are dumped.
+    dumped: u32,
+    _padding: u32,
+    regs: GeneralRegisters,
+    rip: u64,
+    rsp: u64,
+    rflags: u64,
+    cr0: u64,
+    cr3: u64,
+    cr4: u64,
+    /// Total number of VM exits. The last reason is in
+    /// `exit_reasons[(exit_count - 1) % EXIT_HISTORY_LEN]`.
+    exit_count: u64,
+    /// Raw exit reasons (VMX basic exit reason or SVM exit code).
+    exit_reasons: [u32; EXIT_HISTORY_LEN],
+    last_error: ErrorRecord,
+}
+
+#[repr(C)]
+pub struct CrashRecord {
+    magic: [u8; 8],
+    version: u32,
+    /// Size of this structure.
+    size: u32,
+    /// ID of the CPU that panicked first, or `u32::MAX`.
+    panic_cpu: u32,
+    /// Number of valid entries in `cpus`.
+    num_cpus: u32,
+    /// Set to 1 by tools after dumping the record.
+    read: u32,
+    _padding: u32,
+    /// Time of the panic since boot, in nanoseconds.
+    panic_time_ns: u64,
+    panic_location: Location,
+    panic_message: [u8; PANIC_MESSAGE_SIZE],
+    cpus: [CpuRecord; CRASH_MAX_CPUS],
+    /// Total number of bytes written to `log_tail`. The ring starts at
+    /// `log_pos % LOG_TAIL_SIZE` once it has wrapped around.
+    log_pos: u64,
+    log_tail: [u8; LOG_TAIL_SIZE],
+}
+
+const _: () = assert!(core::mem::size_of::<CrashRecord>() <= CRASH_RECORD_SIZE);
+// The layout parsed by `tools/rvm-crash`.
+const _: () = assert!(core::mem::size_of::<CpuRecord>() == 720);
+const _: () = assert!(core::mem::size_of::<CrashRecord>() == 47288 + LOG_TAIL_SIZE);
+
+static READY: AtomicBool = AtomicBool::new(false);
+static LOG_LOCK: Mutex<()> = Mutex::new(());
+
+/// Write a string into a fixed-size buffer, truncating it if too long and
+/// always keeping a trailing NUL.
+struct BufWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> BufWriter<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        buf.fill(0);
+        Self { buf, len: 0 }
+    }
+}
+
+impl Write for BufWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut len = s.len().min(self.buf.len() - 1 - self.len);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+impl Location {
+    fn set(&mut self, file: &str, line: u32, col: u32) {
+        BufWriter::new(&mut self.file).write_str(file).ok();
+        self.line = line;
+        self.col = col;
+    }
+}
+
+fn record() -> Option<&'static mut CrashRecord> {
+    if READY.load(Ordering::Acquire) {
+        Some(unsafe { &mut *crash_record_ptr() })
+    } else {
+        None
+    }
+}
+
+fn cpu_record(cpu_id: u32) -> Option<&'static mut CpuRecord> {
+    record()?.cpus.get_mut(cpu_id as usize)
+}
+
+/// Reset the crash record, unless it holds a panic that has not been read.
+/// Called once by the primary CPU on each enable.
+pub fn init() {
+    READY.store(false, Ordering::Release);
+    let record_ptr = crash_record_ptr();
+    let old = unsafe { &*record_ptr };
+    if old.magic == CRASH_RECORD_MAGIC
+        && old.version == CRASH_RECORD_VERSION
+        && old.panic_cpu != NO_CPU
+        && old.read == 0
+    {
+        warn!(
+            "Crash record at {:#x} has not been read, keep it and record nothing.",
+            virt_to_phys(record_ptr as usize)
+        );
+        return;
+    }
+    unsafe { core::ptr::write_bytes(record_ptr as *mut u8, 0, CRASH_RECORD_SIZE) };
+    let record = unsafe { &mut *record_ptr };
+    record.magic = CRASH_RECORD_MAGIC;
+    record.version = CRASH_RECORD_VERSION;
+    record.size = core::mem::size_of::<CrashRecord>() as u32;
+    record.panic_cpu = NO_CPU;
+    record.num_cpus = crate::header::HvHeader::get()
+        .max_cpus
+        .min(CRASH_MAX_CPUS as u32);
+    READY.store(true, Ordering::Release);
+    info!(
+        "Crash record at {:#x}, size {:#x}.",
+        virt_to_phys(record_ptr as usize),
+        CRASH_RECORD_SIZE
+    );
+}
+
+/// Record the first panic of the hypervisor.
+pub fn record_panic(cpu_id: u32, info: &core::panic::PanicInfo) {
+    if let Some(record) = record() {
+        record.panic_cpu = cpu_id;
+        record.panic_time_ns = clocksource::current_time_nanos();
+        if let Some(loc) = info.location() {
+            record
+                .panic_location
+                .set(loc.file(), loc.line(), loc.column());
+        }
+        write!(BufWriter::new(&mut record.panic_message), "{}", info).ok();
+    }
+}
+
+/// Message of the first panic, if it was recorded.
+pub fn panic_message() -> Option<&'static str> {
+    let record = record()?;
+    if record.panic_cpu == NO_CPU {
+        return None;
+    }
+    let msg = &record.panic_message;
+    let len = msg.iter().position(|&b| b == 0).unwrap_or(msg.len());
+    core::str::from_utf8(&msg[..len]).ok()
+}
+
+/// Record an error returned to the VM exit handler or the driver.
+pub fn record_error(cpu_id: u32, err: &HvError) {
+    if let Some(cpu) = cpu_record(cpu_id) {
+        let (file, line, col) = err.location();
+        let error = &mut cpu.last_error;
+        error.code = err.code();
+        error.location.set(file, line, col);
+        let mut writer = BufWriter::new(&mut error.message);
+        if let Some(msg) = err.msg() {
+            writer.write_str(msg).ok();
+        }
+    }
+}
+
+/// Append an exit reason to the per-CPU exit history.
+pub fn record_exit(cpu_id: u32, reason: u32) {
+    if let Some(cpu) = cpu_record(cpu_id) {
+        cpu.exit_reasons[cpu.exit_count as usize % EXIT_HISTORY_LEN] = reason;
+        cpu.exit_count += 1;
+    }
+}
+
+/// Dump guest registers of the given CPU, which must have an active `Vcpu`.
+pub fn dump_cpu(cpu_data: &PerCpu) {
+    if let Some(cpu) = cpu_record(cpu_data.id) {
+        let vcpu = &cpu_data.vcpu;
+        cpu.regs = vcpu.regs().clone();
+        cpu.rip = vcpu.instr_pointer();
+        cpu.rsp = vcpu.stack_pointer();
+        cpu.rflags = vcpu.rflags();
+        cpu.cr0 = vcpu.cr(0);
+        cpu.cr3 = vcpu.cr(3);
+        cpu.cr4 = vcpu.cr(4);
+        cpu.dumped = 1;
+    }
+}
+
+/// Append formatted output to the log tail.
+pub fn log(args: fmt::Arguments) {
+    if let Some(record) = record() {
+        // Don't wait for the lock, this CPU may hold it if it panicked or got
+        // an NMI while logging. The tail may be garbled then.
+        let _guard = LOG_LOCK.try_lock();
+        LogWriter(record).write_fmt(args).ok();
+    }
+}
+
+struct LogWriter(&'static mut CrashRecord);
+
+impl Write for LogWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &b in s.as_bytes() {
+            self.0.log_tail[self.0.log_pos as usize % LOG_TAIL_SIZE] = b;
+            self.0.log_pos += 1;
+        }
+        Ok(())
+    }
+}
@@ -1,23 +1,32 @@
-use alloc::string::String;
-use core::fmt::{Debug, Formatter, Result};
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::fmt::{Debug, Display, Formatter, Result};
+use core::panic::Location;
+
+use crate::backtrace::Backtrace;
 
 /// POSIX errno
-#[repr(u32)]
 #[derive(Clone, Copy, Debug, PartialEq)]
 #[allow(dead_code, clippy::upper_case_acronyms)]
 pub enum HvErrorNum {
-    EPERM = 1,
-    ENOENT = 2,
-    EIO = 5,
-    E2BIG = 7,
-    ENOMEM = 12,
-    EFAULT = 14,
-    EBUSY = 16,
-    EEXIST = 17,
-    ENODEV = 19,
-    EINVAL = 22,
-    ERANGE = 34,
-    ENOSYS = 38,
+    EPERM,
+    ENOENT,
+    EIO,
+    E2BIG,
+    ENOMEM,
+    EFAULT,
+    EBUSY,
+    EEXIST,
+    ENODEV,
+    EINVAL,
+    ERANGE,
+    ENOSYS,
+    ETIMEDOUT,
+}
+
+/// Context added to an error when it is passed up to the caller.
+struct ErrorContext {
+    loc: &'static Location<'static>,
+    msg: String,
 }
 
 pub struct HvError {
@@ -26,6 +35,10 @@ pub struct HvError {
     loc_col: u32,
     loc_file: &'static str,
     msg: Option<String>,
+    /// Outer contexts, innermost first.
+    context: Vec<ErrorContext>,
+    /// Only captured with the `backtrace` feature.
+    backtrace: Option<Box<Backtrace>>,
 }
 
 pub type HvResult<T = ()> = core::result::Result<T, HvError>;
@@ -46,6 +59,28 @@ impl HvErrorNum {
             EINVAL => "Invalid argument",
             ERANGE => "Math result not representable",
             ENOSYS => "Function not implemented",
+            ETIMEDOUT => "Connection timed out",
+        }
+    }
+
+    /// The errno value returned to the driver. It is part of the hypercall ABI
+    /// and must be the same as Jailhouse (and Linux) uses.
+    pub fn errno(&self) -> i32 {
+        use HvErrorNum::*;
+        match *self {
+            EPERM => 1,
+            ENOENT => 2,
+            EIO => 5,
+            E2BIG => 7,
+            ENOMEM => 12,
+            EFAULT => 14,
+            EBUSY => 16,
+            EEXIST => 17,
+            ENODEV => 19,
+            EINVAL => 22,
+            ERANGE => 34,
+            ENOSYS => 38,
+            ETIMEDOUT => 110,
         }
     }
 }
@@ -64,16 +99,78 @@ impl HvError {
             loc_line,
             loc_col,
             msg,
+            context: Vec::new(),
+            backtrace: if cfg!(feature = "backtrace") {
+                Some(Box::new(Backtrace::capture()))
+            } else {
+                None
+            },
         }
     }
 
+    /// Negative errno returned through hypercalls.
     pub fn code(&self) -> i32 {
-        -(self.num as u32 as i32)
+        -self.num.errno()
+    }
+
+    /// File, line and column where the error was created.
+    pub fn location(&self) -> (&'static str, u32, u32) {
+        (self.loc_file, self.loc_line, self.loc_col)
+    }
+
+    pub fn msg(&self) -> Option<&str> {
+        self.msg.as_deref()
+    }
+
+    /// Wrap the error with a message, recording the location of the caller.
+    #[track_caller]
+    pub fn context(mut self, msg: impl Into<String>) -> Self {
+        self.context.push(ErrorContext {
+            loc: Location::caller(),
+            msg: msg.into(),
+        });
+        self
     }
 }
 
-impl Debug for HvError {
+/// Add context to the error of an `HvResult`.
+pub trait HvResultExt {
+    fn context(self, msg: impl Into<String>) -> Self;
+    /// Like `context()`, but the message is only built on error.
+    fn with_context<S: Into<String>>(self, f: impl FnOnce() -> S) -> Self;
+}
+
+impl<T> HvResultExt for HvResult<T> {
+    #[track_caller]
+    fn context(self, msg: impl Into<String>) -> Self {
+        match self {
+            Ok(v) => Ok(v),
+            Err(e) => Err(e.context(msg)),
+        }
+    }
+
+    #[track_caller]
+    fn with_context<S: Into<String>>(self, f: impl FnOnce() -> S) -> Self {
+        match self {
+            Ok(v) => Ok(v),
+            Err(e) => Err(e.context(f())),
+        }
+    }
+}
+
+/// Show the whole chain, outermost context first.
+impl Display for HvError {
     fn fmt(&self, f: &mut Formatter) -> Result {
+        for ctx in self.context.iter().rev() {
+            write!(
+                f,
+                "[{}:{}:{}] {}\n  caused by: ",
+                ctx.loc.file(),
+                ctx.loc.line(),
+                ctx.loc.column(),
+                ctx.msg
+            )?;
+        }
         write!(
             f,
             "[{}:{}:{}] {}",
@@ -89,6 +186,16 @@ impl Debug for HvError {
     }
 }
 
+impl Debug for HvError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        Display::fmt(self, f)?;
+        if let Some(ref bt) = self.backtrace {
+            write!(f, "\nBacktrace:{}", bt)?;
+        }
+        Ok(())
+    }
+}
+
 #[macro_export]
 macro_rules! hv_err {
     ($num: ident) => {{
@@ -110,3 +217,49 @@ macro_rules! hv_result_err {
         Err(hv_err!($num, $msg))
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jailhouse_errno() {
+        use HvErrorNum::*;
+        let expected = [
+            (EPERM, 1),
+            (ENOENT, 2),
+            (EIO, 5),
+            (E2BIG, 7),
+            (ENOMEM, 12),
+            (EFAULT, 14),
+            (EBUSY, 16),
+            (EEXIST, 17),
+            (ENODEV, 19),
+            (EINVAL, 22),
+            (ERANGE, 34),
+            (ENOSYS, 38),
+            (ETIMEDOUT, 110),
+        ];
+        for (num, errno) in expected {
+            assert_eq!(num.errno(), errno);
+        }
+        assert_eq!(hv_err!(ENOMEM).code(), -12);
+    }
+
+    #[test]
+    fn test_context_chain() {
+        let res: HvResult = hv_result_err!(ENOMEM, "no frame");
+        let err = res
+            .context("failed to map page")
+            .with_context(|| format!("failed to create cell {}", 0))
+            .unwrap_err();
+        let text = format!("{}", err);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with("] failed to create cell 0"));
+        assert!(lines[1].starts_with("  caused by: [src/error.rs:"));
+        assert!(lines[1].ends_with("] failed to map page"));
+        assert!(lines[2].ends_with("] Out of memory: no frame"));
+        assert_eq!(err.code(), -12);
+    }
+}
@@ -1,4 +1,5 @@
 use core::fmt::{Debug, Formatter, Result};
+use core::sync::atomic::AtomicI32;
 
 use crate::consts::{HV_HEADER_PTR, PER_CPU_SIZE};
 
@@ -11,12 +12,16 @@ pub struct HvHeader {
     pub percpu_size: usize,
     pub entry: usize,
     pub console_page: usize,
+    /// Address of `CoverageInfo` with the `coverage` feature, or 0.
     pub gcov_info_head: usize,
     pub max_cpus: u32,
     pub online_cpus: u32,
     pub debug_console_base: usize,
     pub arm_linux_hyp_vectors: u64,
     pub arm_linux_hyp_abi: u32,
+    /// Negative errno once the hypervisor stopped itself and returned all CPUs
+    /// back to linux, 0 while running. Checked by the driver before hypercalls.
+    pub stop_error: AtomicI32,
 }
 
 impl HvHeader {
@@ -32,12 +37,16 @@ struct HvHeaderStuff {
     percpu_size: usize,
     entry: unsafe extern "C" fn(),
     console_page: usize,
+    #[cfg(feature = "coverage")]
+    gcov_info_head: &'static crate::coverage::CoverageInfo,
+    #[cfg(not(feature = "coverage"))]
     gcov_info_head: usize,
     max_cpus: u32,
     online_cpus: u32,
     debug_console_base: usize,
     arm_linux_hyp_vectors: u64,
     arm_linux_hyp_abi: u32,
+    stop_error: i32,
 }
 
 extern "C" {
@@ -53,12 +62,16 @@ static HEADER_STUFF: HvHeaderStuff = HvHeaderStuff {
     percpu_size: PER_CPU_SIZE,
     entry: __entry_offset,
     console_page: 0,
+    #[cfg(feature = "coverage")]
+    gcov_info_head: &crate::coverage::COVERAGE_INFO,
+    #[cfg(not(feature = "coverage"))]
     gcov_info_head: 0,
     max_cpus: 0,
     online_cpus: 0,
     debug_console_base: 0,
     arm_linux_hyp_vectors: 0,
     arm_linux_hyp_abi: 0,
+    stop_error: 0,
 };
 
 impl Debug for HvHeader {
@@ -5,16 +5,101 @@ use bit_field::BitField;
 use numeric_enum_macro::numeric_enum;
 
 use crate::arch::vmm::VcpuAccessGuestState;
-use crate::arch::GuestPageTableImmut;
+use crate::arch::{clocksource::Deadline, GuestPageTableImmut};
 use crate::error::HvResult;
+use crate::memory::gaccess::AsGuestPtr;
 use crate::percpu::PerCpu;
+use crate::stats::CpuStats;
+
+/// How long a CPU waits for the other CPUs to request disabling.
+const DISABLE_TIMEOUT_NS: u64 = 1_000_000_000; // 1 s
 
 numeric_enum! {
     #[repr(u32)]
     #[derive(Debug, Eq, PartialEq, Copy, Clone)]
     pub enum HyperCallCode {
         HypervisorDisable = 0,
+        /// RVM specific hypercalls start from 0x100.
+        CpuGetStats = 0x100,
+        CpuGetTrace = 0x101,
+        CoverageGet = 0x102,
+        CpuGetProfile = 0x103,
+        WatchdogStart = 0x104,
+        WatchdogPing = 0x105,
+    }
+}
+
+numeric_enum! {
+    #[repr(u32)]
+    #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+    enum DisableState {
+        /// No disabling is in progress.
+        Idle = 0,
+        /// Some CPUs requested to disable, waiting for the others.
+        Waiting = 1,
+        /// All CPUs arrived, each of them is leaving the hypervisor.
+        Deactivating = 2,
+        /// Timed out, the arrived CPUs are going back to the guest.
+        Aborting = 3,
+    }
+}
+
+static DISABLE_STATE: AtomicU32 = AtomicU32::new(DisableState::Idle as u32);
+static DISABLE_ARRIVED_CPUS: AtomicU32 = AtomicU32::new(0);
+
+impl DisableState {
+    fn load() -> Self {
+        Self::try_from(DISABLE_STATE.load(Ordering::Acquire)).unwrap()
+    }
+
+    fn transit(from: Self, to: Self) -> bool {
+        DISABLE_STATE
+            .compare_exchange(from as _, to as _, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+}
+
+pub fn reset_disable_state() {
+    DISABLE_ARRIVED_CPUS.store(0, Ordering::Release);
+    DISABLE_STATE.store(DisableState::Idle as _, Ordering::Release);
+}
+
+/// Wait until all `cpus` activated CPUs request to disable the hypervisor.
+///
+/// Returns `Ok` if all of them arrived, and an error if it timed out or another
+/// disabling is still in progress. On error, the hypervisor stays enabled and
+/// disabling can be requested again.
+fn wait_for_disable(cpus: u32) -> HvResult {
+    use DisableState::*;
+    if !DisableState::transit(Idle, Waiting) && DisableState::load() != Waiting {
+        return hv_result_err!(EBUSY, "Another hypervisor disabling is in progress!");
+    }
+    DISABLE_ARRIVED_CPUS.fetch_add(1, Ordering::SeqCst);
+
+    // Only one of the transitions from `Waiting` can succeed, so all CPUs will
+    // make the same decision.
+    let deadline = Deadline::after_nanos(DISABLE_TIMEOUT_NS);
+    loop {
+        match DisableState::load() {
+            Deactivating => return Ok(()),
+            Waiting => {
+                if DISABLE_ARRIVED_CPUS.load(Ordering::Acquire) >= cpus {
+                    DisableState::transit(Waiting, Deactivating);
+                } else if deadline.expired() {
+                    DisableState::transit(Waiting, Aborting);
+                } else {
+                    core::hint::spin_loop();
+                }
+            }
+            Idle | Aborting => break,
+        }
+    }
+
+    // The last CPU backing out makes disabling available again.
+    if DISABLE_ARRIVED_CPUS.fetch_sub(1, Ordering::SeqCst) == 1 {
+        DISABLE_STATE.store(Idle as _, Ordering::Release);
     }
+    hv_result_err!(ETIMEDOUT, "Timed out waiting for other CPUs to disable!")
 }
 
 impl HyperCallCode {
@@ -27,18 +112,19 @@ pub type HyperCallResult = HvResult<usize>;
 
 pub struct HyperCall<'a> {
     cpu_data: &'a mut PerCpu,
-    _gpt: GuestPageTableImmut,
+    gpt: GuestPageTableImmut,
 }
 
 impl<'a> HyperCall<'a> {
     pub fn new(cpu_data: &'a mut PerCpu) -> Self {
         Self {
-            _gpt: cpu_data.vcpu.guest_page_table(),
+            gpt: cpu_data.vcpu.guest_page_table(),
             cpu_data,
         }
     }
 
-    pub fn hypercall(&mut self, code: u32, arg0: u64, _arg1: u64) -> HvResult {
+    pub fn hypercall(&mut self, code: u32, arg0: u64, arg1: u64) -> HvResult {
+        self.cpu_data.stats.hypercall(code);
         let code = match HyperCallCode::try_from(code) {
             Ok(code) => code,
             Err(_) => {
@@ -62,6 +148,12 @@ impl<'a> HyperCall<'a> {
         debug!("HyperCall: {:?} => arg0={:#x}", code, arg0);
         let ret = match code {
             HyperCallCode::HypervisorDisable => self.hypervisor_disable(),
+            HyperCallCode::CpuGetStats => self.cpu_get_stats(arg0, arg1),
+            HyperCallCode::CpuGetTrace => self.cpu_get_trace(arg0, arg1),
+            HyperCallCode::CoverageGet => self.coverage_get(arg0),
+            HyperCallCode::CpuGetProfile => self.cpu_get_profile(arg0, arg1),
+            HyperCallCode::WatchdogStart => self.watchdog_start(arg0),
+            HyperCallCode::WatchdogPing => self.watchdog_ping(),
         };
         if ret.is_err() {
             warn!("HyperCall: {:?} <= {:x?}", code, ret);
@@ -85,15 +177,93 @@ impl<'a> HyperCall<'a> {
     }
 
     fn hypervisor_disable(&mut self) -> HyperCallResult {
-        let cpus = PerCpu::activated_cpus();
+        wait_for_disable(PerCpu::activated_cpus())?;
+        self.cpu_data.deactivate_vmm(0)?;
+        unreachable!()
+    }
 
-        static TRY_DISABLE_CPUS: AtomicU32 = AtomicU32::new(0);
-        TRY_DISABLE_CPUS.fetch_add(1, Ordering::SeqCst);
-        while TRY_DISABLE_CPUS.load(Ordering::Acquire) < cpus {
-            core::hint::spin_loop();
+    /// Copy the VM exit statistics of CPU `cpu_id` to the guest buffer at
+    /// `buf_vaddr`. Returns the size of the statistics, and only returns the
+    /// size if `buf_vaddr` is 0. `cpu_id` must be the calling CPU, as other
+    /// CPUs update their statistics without locking.
+    fn cpu_get_stats(&mut self, cpu_id: u64, buf_vaddr: u64) -> HyperCallResult {
+        if !cfg!(feature = "stats") {
+            return hv_result_err!(ENOSYS, "Statistics are not enabled!");
         }
+        if cpu_id != self.cpu_data.id as u64 {
+            return hv_result_err!(
+                EINVAL,
+                format!("Statistics of CPU {} read on another CPU", cpu_id)
+            );
+        }
+        if buf_vaddr != 0 {
+            let stats = self.cpu_data.stats.clone();
+            buf_vaddr.as_guest_ptr(&self.gpt)._write(stats)?;
+        }
+        Ok(core::mem::size_of::<CpuStats>())
+    }
 
-        self.cpu_data.deactivate_vmm(0)?;
-        unreachable!()
+    /// Copy the VM exit trace buffer of CPU `cpu_id` to the guest buffer at
+    /// `buf_vaddr`, in the same way as `cpu_get_stats()`.
+    fn cpu_get_trace(&mut self, cpu_id: u64, buf_vaddr: u64) -> HyperCallResult {
+        if !cfg!(feature = "trace") {
+            return hv_result_err!(ENOSYS, "Tracing is not enabled!");
+        }
+        if cpu_id >= PerCpu::entered_cpus() as u64 {
+            return hv_result_err!(EINVAL, format!("Invalid CPU ID {}", cpu_id));
+        }
+        let trace = PerCpu::from_id(cpu_id as u32).trace.as_bytes();
+        if buf_vaddr != 0 {
+            buf_vaddr.as_guest_ptr(&self.gpt).write_slice(trace)?;
+        }
+        Ok(trace.len())
+    }
+
+    /// Copy the profiler samples of CPU `cpu_id` to the guest buffer at
+    /// `buf_vaddr`, in the same way as `cpu_get_stats()`.
+    fn cpu_get_profile(&mut self, cpu_id: u64, buf_vaddr: u64) -> HyperCallResult {
+        if !cfg!(feature = "profile") {
+            return hv_result_err!(ENOSYS, "Profiling is not enabled!");
+        }
+        if cpu_id >= PerCpu::entered_cpus() as u64 {
+            return hv_result_err!(EINVAL, format!("Invalid CPU ID {}", cpu_id));
+        }
+        let profile = PerCpu::from_id(cpu_id as u32).profile.as_bytes();
+        if buf_vaddr != 0 {
+            buf_vaddr.as_guest_ptr(&self.gpt).write_slice(profile)?;
+        }
+        Ok(profile.len())
+    }
+
+    /// Start the watchdog of the cell with a timeout of `timeout_ms`, or stop
+    /// it if `timeout_ms` is 0. The action is set in the cell config.
+    fn watchdog_start(&mut self, timeout_ms: u64) -> HyperCallResult {
+        self.cpu_data.cell().watchdog.start(timeout_ms);
+        Ok(0)
+    }
+
+    fn watchdog_ping(&mut self) -> HyperCallResult {
+        self.cpu_data.cell().watchdog.ping()?;
+        Ok(0)
+    }
+
+    /// Copy the coverage dump to the guest buffer at `buf_vaddr`. Returns the
+    /// size of the dump, and only returns the size if `buf_vaddr` is 0.
+    #[cfg_attr(not(feature = "coverage"), allow(unused_variables))]
+    fn coverage_get(&mut self, buf_vaddr: u64) -> HyperCallResult {
+        #[cfg(feature = "coverage")]
+        {
+            let info = &crate::coverage::COVERAGE_INFO;
+            if buf_vaddr != 0 {
+                info.dump(|offset, part| {
+                    (buf_vaddr + offset as u64)
+                        .as_guest_ptr(&self.gpt)
+                        .write_slice(part)
+                })?;
+            }
+            Ok(info.dump_size())
+        }
+        #[cfg(not(feature = "coverage"))]
+        hv_result_err!(ENOSYS, "Coverage is not enabled!")
     }
 }
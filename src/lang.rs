@@ -1,32 +1,165 @@
-use core::alloc::Layout;
-use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU32, Ordering};
 
-use crate::error::HvResult;
+use crate::arch::clocksource::Deadline;
+use crate::arch::vmm::VcpuAccessGuestState;
+use crate::consts::HV_BASE;
+use crate::error::{HvError, HvResult};
+use crate::memory::GenericPageTableImmut;
 use crate::percpu::{CpuState, PerCpu};
 
-fn try_handle_panic(cpu_data: &mut PerCpu) -> HvResult {
-    let ret_code = if cpu_data.state != CpuState::HvDisabled && cpu_data.vcpu.in_hypercall() {
-        hv_err!(EIO).code() as usize
+/// How long the panicking CPU waits for other CPUs to return back to linux.
+const STOP_TIMEOUT_NS: u64 = 1_000_000_000; // 1 s
+/// Interval of resending NMIs to the CPUs that are still running.
+const STOP_RETRY_NS: u64 = 1_000_000; // 1 ms
+
+const NO_CPU: u32 = u32::MAX;
+
+/// ID of the CPU that panicked first, or `NO_CPU`.
+static PANIC_CPU: AtomicU32 = AtomicU32::new(NO_CPU);
+
+/// ID of the CPU that panicked first, if the hypervisor has panicked.
+pub fn panic_cpu() -> Option<u32> {
+    match PANIC_CPU.load(Ordering::Acquire) {
+        NO_CPU => None,
+        id => Some(id),
+    }
+}
+
+/// Whether this CPU can return back to linux from the current guest state.
+///
+/// We return back to linux with the guest page table, so the guest must run
+/// in kernel mode, and the hypervisor must be mapped in that page table (not
+/// the case for a user page table with PTI).
+pub fn can_leave(cpu_data: &PerCpu) -> bool {
+    let vcpu = &cpu_data.vcpu;
+    vcpu.guest_is_privileged() && vcpu.guest_page_table().query(HV_BASE).is_ok()
+}
+
+/// Return this CPU back to linux after an unrecoverable error.
+///
+/// `err` is recorded as the last error of the CPU in the crash record. If the
+/// guest was in a hypercall, it also returns `err`. Otherwise, the guest
+/// continues from where it was interrupted.
+pub fn leave_on_error(cpu_data: &mut PerCpu, err: HvError) -> HvResult {
+    crate::crash::record_error(cpu_data.id, &err);
+    let ret_code = if cpu_data.vcpu.in_hypercall() {
+        err.code() as usize
     } else {
-        0
+        cpu_data.vcpu.regs().rax as usize
     };
-    match cpu_data.state {
-        CpuState::HvEnabled => cpu_data.deactivate_vmm(ret_code)?,
-        _ => return hv_result_err!(EIO, "Hypervisor is not enabled!"),
+    cpu_data.deactivate_vmm(ret_code)
+}
+
+/// Return this CPU back to linux after the hypervisor panicked, with `-EIO`.
+///
+/// CPUs that were not in a hypercall keep their RAX, the driver learns about the
+/// panic from `HvHeader::stop_error`.
+fn leave_on_panic(cpu_data: &mut PerCpu) -> HvResult {
+    // No message, as the heap may be locked by the panicking CPU.
+    leave_on_error(cpu_data, hv_err!(EIO))
+}
+
+/// Send NMIs to other CPUs until all of them returned back to linux or timed
+/// out. The NMIs cause VM exits, where these CPUs call `check_panic()`.
+fn stop_other_cpus(cpu_id: u32) {
+    let deadline = Deadline::after_nanos(STOP_TIMEOUT_NS);
+    loop {
+        let mut running = 0;
+        for id in (0..PerCpu::entered_cpus()).filter(|&id| id != cpu_id) {
+            let cpu_data = PerCpu::from_id(id);
+            // The state is changed by that CPU concurrently.
+            if unsafe { core::ptr::read_volatile(&cpu_data.state) } == CpuState::HvDisabled {
+                continue;
+            }
+            running += 1;
+            if let Err(e) = cpu_data.send_nmi() {
+                error!("Failed to stop CPU {}: {:?}", id, e);
+                return;
+            }
+        }
+        if running == 0 {
+            return;
+        }
+
+        if deadline.expired() {
+            error!("Timed out waiting for {} CPUs to stop!", running);
+            return;
+        }
+        let retry = Deadline::after_nanos(STOP_RETRY_NS);
+        while !retry.expired() {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Return this CPU back to linux if the hypervisor has panicked on another
+/// CPU. Called at the end of each VM exit handler.
+pub fn check_panic(cpu_data: &mut PerCpu) {
+    if let Some(id) = panic_cpu() {
+        // If we can't leave now, resume the guest and retry on the next VM exit.
+        if can_leave(cpu_data) {
+            error!(
+                "CPU {} stopped by hypervisor panic on CPU {}.",
+                cpu_data.id, id
+            );
+            crate::crash::dump_cpu(cpu_data);
+            let err = leave_on_panic(cpu_data);
+            error!("Failed to return back to linux: {:?}", err);
+            loop {
+                core::hint::spin_loop();
+            }
+        }
     }
-    Ok(())
 }
 
+#[cfg(not(test))]
 #[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    let backtrace = crate::backtrace::Backtrace::capture();
     let cpu_data = PerCpu::current_mut();
-    error!("\n{}\nCurrent Cpu: {:#x?}", info, cpu_data);
-    let err = try_handle_panic(cpu_data);
-    error!("Try handle panic failed: {:?}", err);
+    let cpu_id = cpu_data.id;
+    match PANIC_CPU.compare_exchange(NO_CPU, cpu_id, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => {
+            crate::crash::record_panic(cpu_id, info);
+            error!("\n{}\nBacktrace:{}", info, backtrace);
+            error!("Current Cpu: {:#x?}", cpu_data);
+            // Let other CPUs abort if they are still initializing, and the
+            // driver stop issuing hypercalls.
+            let code = hv_err!(EIO).code();
+            crate::ERROR_NUM.store(code, Ordering::Release);
+            crate::header::HvHeader::get()
+                .stop_error
+                .store(code, Ordering::Release);
+            stop_other_cpus(cpu_id);
+        }
+        Err(id) if id == cpu_id => {
+            error!("Nested panic on CPU {}: {}", cpu_id, info);
+            loop {}
+        }
+        Err(id) => {
+            error!("\n{}\nBacktrace:{}", info, backtrace);
+            error!("Current Cpu: {:#x?}", cpu_data);
+            error!("Hypervisor has already panicked on CPU {}.", id);
+        }
+    }
+
+    if cpu_data.state == CpuState::HvEnabled && PerCpu::activated_cpus() > 0 {
+        crate::crash::dump_cpu(cpu_data);
+        if can_leave(cpu_data) {
+            let err = leave_on_panic(cpu_data);
+            error!("Failed to return back to linux: {:?}", err);
+        } else {
+            // Leave on the next VM exit in `check_panic()`.
+            cpu_data.vcpu.resume();
+        }
+    } else {
+        error!("Hypervisor is not activated!");
+    }
     loop {}
 }
 
+#[cfg(not(test))]
 #[lang = "oom"]
-fn oom(_: Layout) -> ! {
+fn oom(_: core::alloc::Layout) -> ! {
     panic!("out of memory");
 }
@@ -18,6 +18,7 @@ pub fn init() {
 #[allow(dead_code)]
 pub fn print(args: fmt::Arguments) {
     crate::arch::serial::putfmt(args);
+    crate::crash::log(args);
 }
 
 #[cfg(not(test))]
@@ -74,7 +75,7 @@ impl Log for SimpleLogger {
             return;
         }
 
-        let time_micros = crate::arch::cpu::current_time_nanos() / 1000;
+        let time_micros = crate::arch::clocksource::current_time_nanos() / 1000;
         let cpu_id = crate::percpu::PerCpu::current().id;
         let level = record.level();
         let level_color = match level {
@@ -20,17 +20,24 @@ mod logging;
 #[macro_use]
 mod error;
 
+mod backtrace;
 mod cell;
 mod config;
 mod consts;
+#[cfg(feature = "coverage")]
+mod coverage;
+mod crash;
 mod header;
 mod hypercall;
+mod lang;
 mod memory;
+mod mmio;
+mod pci;
 mod percpu;
+mod profile;
 mod stats;
-
-#[cfg(not(test))]
-mod lang;
+mod trace;
+mod watchdog;
 
 #[cfg(target_arch = "x86_64")]
 #[path = "arch/x86_64/mod.rs"]
@@ -46,8 +53,29 @@ use percpu::PerCpu;
 static INITED_CPUS: AtomicU32 = AtomicU32::new(0);
 static INIT_EARLY_OK: AtomicU32 = AtomicU32::new(0);
 static INIT_LATE_OK: AtomicU32 = AtomicU32::new(0);
+static FAILED_CPUS: AtomicU32 = AtomicU32::new(0);
 static ERROR_NUM: AtomicI32 = AtomicI32::new(0);
 
+/// Reset all global bring-up states, so that the hypervisor can be enabled
+/// again after it was disabled or failed to enable.
+fn reset_global_state() {
+    // Linux takes over the devices again, with the interrupts it programmed.
+    memory::with_hv_page_table(|| {
+        cell::shutdown();
+        arch::vmm::iommu::shutdown();
+        cell::reset();
+    });
+    memory::reset_hv_page_table();
+    arch::clocksource::reset();
+    INITED_CPUS.store(0, Ordering::Release);
+    INIT_EARLY_OK.store(0, Ordering::Release);
+    INIT_LATE_OK.store(0, Ordering::Release);
+    FAILED_CPUS.store(0, Ordering::Release);
+    ERROR_NUM.store(0, Ordering::Release);
+    PerCpu::reset_counters();
+    hypercall::reset_disable_state();
+}
+
 fn has_err() -> bool {
     ERROR_NUM.load(Ordering::Acquire) != 0
 }
@@ -94,12 +122,19 @@ fn primary_init_early() -> HvResult {
 
     memory::init_heap();
     system_config.check()?;
+    arch::clocksource::init();
+    crash::init();
     info!("Hypervisor header: {:#x?}", HvHeader::get());
     debug!("System config: {:#x?}", system_config);
 
     memory::init_frame_allocator();
     memory::init_hv_page_table()?;
-    cell::init()?;
+    memory::with_hv_page_table(|| {
+        cell::init()?;
+        arch::vmm::iommu::init(cell::root_cell())?;
+        cell::root_cell().pci.remap_msis(cell::root_cell())?;
+        cell::root_cell().ioapics.remap(cell::root_cell())
+    })?;
 
     INIT_EARLY_OK.store(1, Ordering::Release);
     Ok(())
@@ -112,6 +147,14 @@ fn primary_init_late() {
 }
 
 fn main(cpu_data: &mut PerCpu, linux_sp: usize) -> HvResult {
+    if let Some(id) = lang::panic_cpu() {
+        let msg = crash::panic_message().unwrap_or("unknown");
+        return hv_result_err!(
+            EIO,
+            format!("Hypervisor has panicked on CPU {}: {}", id, msg)
+        );
+    }
+
     let is_primary = cpu_data.id == 0;
     let online_cpus = HvHeader::get().online_cpus;
     wait_for(|| PerCpu::entered_cpus() < online_cpus)?;
@@ -142,14 +185,22 @@ fn main(cpu_data: &mut PerCpu, linux_sp: usize) -> HvResult {
 }
 
 extern "sysv64" fn entry(cpu_data: &mut PerCpu, linux_sp: usize) -> i32 {
+    // `main()` never returns if the hypervisor is activated successfully.
     if let Err(e) = main(cpu_data, linux_sp) {
         error!("{:?}", e);
+        crash::record_error(cpu_data.id, &e);
         ERROR_NUM.store(e.code(), Ordering::Release);
     }
+    if let Err(e) = cpu_data.deinit() {
+        error!("CPU {} deinit failed: {:?}", cpu_data.id, e);
+    }
     let code = ERROR_NUM.load(Ordering::Acquire);
     println!(
         "CPU {} return back to driver with code {}.",
         cpu_data.id, code
     );
+    if FAILED_CPUS.fetch_add(1, Ordering::SeqCst) + 1 == HvHeader::get().online_cpus {
+        reset_global_state();
+    }
     code
 }
@@ -198,7 +198,7 @@ impl Drop for Frame {
 /// Initialize the physical frame allocator.
 pub(super) fn init() {
     let mem_pool_start = crate::consts::free_memory_start();
-    let mem_pool_end = align_down(crate::consts::hv_end());
+    let mem_pool_end = align_down(crate::consts::crash_record_ptr() as usize);
     let mem_pool_size = mem_pool_end - mem_pool_start;
     FRAME_ALLOCATOR
         .lock()
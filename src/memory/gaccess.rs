@@ -94,11 +94,17 @@ impl<T> GuestPtr<'_, T> {
     }
 
     pub fn _write(&mut self, data: T) -> HvResult {
-        self.check_ptr()?;
-        let mut src = &data as *const _ as *const u8;
+        self.write_raw(&data as *const _ as *const u8, size_of::<T>())
+    }
+
+    /// Write `data` to the consecutive elements starting from this pointer.
+    pub fn write_slice(&mut self, data: &[T]) -> HvResult {
+        self.write_raw(data.as_ptr() as *const u8, core::mem::size_of_val(data))
+    }
 
+    fn write_raw(&mut self, mut src: *const u8, mut size: usize) -> HvResult {
+        self.check_ptr()?;
         let mut gvaddr = self.gvaddr;
-        let mut size = size_of::<T>();
         while size > 0 {
             let (gpaddr, _, pg_size) = self.guest_pt.query(gvaddr)?;
             Self::check_gpaddr(gpaddr)?;
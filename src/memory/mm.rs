@@ -7,7 +7,7 @@ use core::fmt::{Debug, Formatter, Result};
 
 use super::addr::{align_down, align_up};
 use super::{mapper::Mapper, paging::GenericPageTable, MemFlags};
-use crate::error::HvResult;
+use crate::error::{HvResult, HvResultExt};
 
 #[derive(Clone)]
 pub struct MemoryRegion<VA> {
@@ -91,7 +91,14 @@ where
             );
             return hv_result_err!(EINVAL);
         }
-        self.pt.map(&region)?;
+        self.pt.map(&region).with_context(|| {
+            let start: usize = region.start.into();
+            format!(
+                "failed to insert memory region [{:#x}, {:#x})",
+                start,
+                start + region.size
+            )
+        })?;
         self.regions.insert(region.start, region);
         Ok(())
     }
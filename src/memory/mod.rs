@@ -43,6 +43,8 @@
 //!     :                                      :
 //!     :                                      :
 //!     |                                      |
+//!     +--------------------------------------+ - crash_record_ptr
+//!     |  Crash Record                        |
 //!     +--------------------------------------+ - hv_end (higher address)
 //!
 
@@ -58,7 +60,7 @@ pub mod gaccess;
 use core::ops::{Deref, DerefMut};
 
 use bitflags::bitflags;
-use spin::{Once, RwLock};
+use spin::RwLock;
 
 use crate::arch::HostPageTable;
 use crate::config::HvSystemConfig;
@@ -87,10 +89,40 @@ bitflags! {
 }
 
 /// Page table used for hypervisor.
-static HV_PT: Once<RwLock<MemorySet<HostPageTable>>> = Once::new();
+static HV_PT: RwLock<Option<MemorySet<HostPageTable>>> = RwLock::new(None);
 
-pub fn hv_page_table<'a>() -> &'a RwLock<MemorySet<HostPageTable>> {
-    HV_PT.get().expect("Uninitialized hypervisor page table!")
+/// Switch to the hypervisor page table on this CPU.
+///
+/// # Safety
+///
+/// All memory used afterwards must be mapped by the hypervisor page table.
+pub unsafe fn activate_hv_page_table() {
+    HV_PT
+        .read()
+        .as_ref()
+        .expect("Uninitialized hypervisor page table!")
+        .activate()
+}
+
+/// Forget the hypervisor page table when leaving the hypervisor. It is not
+/// dropped, as it may still be active on this CPU, and its frames are freed
+/// along with all others when re-enabling.
+pub fn reset_hv_page_table() {
+    core::mem::forget(HV_PT.write().take());
+}
+
+/// Run `f` on the hypervisor page table, which also maps device registers,
+/// then switch back. Used on the primary CPU before `PerCpu::init()`, and when
+/// the bring-up failed, as the page table of Linux is active.
+pub fn with_hv_page_table<T>(f: impl FnOnce() -> T) -> T {
+    let old_root = crate::arch::cpu::page_table_root();
+    match HV_PT.read().as_ref() {
+        Some(hv_pt) => unsafe { hv_pt.activate() },
+        None => return f(),
+    }
+    let ret = f();
+    unsafe { crate::arch::cpu::set_page_table_root(old_root) };
+    ret
 }
 
 pub fn init_heap() {
@@ -123,7 +155,7 @@ pub fn init_hv_page_table() -> HvResult {
         header.core_size,
         MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE,
     ))?;
-    // Map per-CPU data, configurations & free page pool.
+    // Map per-CPU data, configurations, free page pool & crash record.
     hv_pt.insert(MemoryRegion::new_with_offset_mapper(
         HV_BASE + header.core_size,
         hv_phys_start + header.core_size,
@@ -131,6 +163,74 @@ pub fn init_hv_page_table() -> HvResult {
         MemFlags::READ | MemFlags::WRITE,
     ))?;
 
+    // Map registers of IOMMU units.
+    for iommu in sys_config.iommu_units() {
+        hv_pt.insert(MemoryRegion::new_with_offset_mapper(
+            addr::phys_to_virt(iommu.base as HostPhysAddr),
+            iommu.base as HostPhysAddr,
+            iommu.size as usize,
+            MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
+        ))?;
+    }
+
+    // Map IOAPICs.
+    for chip in cell_config.irqchips() {
+        let paddr = chip.address as HostPhysAddr;
+        hv_pt.insert(MemoryRegion::new_with_offset_mapper(
+            addr::phys_to_virt(paddr),
+            paddr,
+            PAGE_SIZE,
+            MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
+        ))?;
+    }
+
+    // Map the local APIC page, used in xAPIC mode.
+    let apic_base = crate::arch::apic::xapic_base();
+    hv_pt.insert(MemoryRegion::new_with_offset_mapper(
+        addr::phys_to_virt(apic_base),
+        apic_base,
+        PAGE_SIZE,
+        MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
+    ))?;
+
+    // Map the PCI MMCONFIG region.
+    let mmcfg_base = sys_config.pci_mmconfig_base() as HostPhysAddr;
+    if mmcfg_base != 0 {
+        hv_pt.insert(MemoryRegion::new_with_offset_mapper(
+            addr::phys_to_virt(mmcfg_base),
+            mmcfg_base,
+            (sys_config.pci_mmconfig_end_bus() as usize + 1) << 20,
+            MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
+        ))?;
+    }
+
+    // Map MSI-X tables of PCI devices.
+    for dev in cell_config.pci_devices() {
+        if dev.num_msix_vectors != 0 {
+            let start = addr::align_down(dev.msix_address as HostPhysAddr);
+            let end = addr::align_up(dev.msix_address as usize + dev.msix_region_size as usize);
+            hv_pt.insert(MemoryRegion::new_with_offset_mapper(
+                addr::phys_to_virt(start),
+                start,
+                end - start,
+                MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
+            ))?;
+        }
+    }
+
+    // Map state tables of shared memory, other guest RAM is mapped below.
+    for (i, region) in cell_config.mem_regions().iter().enumerate() {
+        if crate::pci::is_shmem_region(&cell_config, i) && !region.flags.contains(MemFlags::DMA) {
+            let paddr = region.phys_start as HostPhysAddr;
+            hv_pt.insert(MemoryRegion::new_with_offset_mapper(
+                addr::phys_to_virt(paddr),
+                paddr,
+                PAGE_SIZE,
+                MemFlags::READ | MemFlags::WRITE,
+            ))?;
+        }
+    }
+
     // Map all guest RAM to directly access in hypervisor.
     for region in cell_config.mem_regions() {
         if region.flags.contains(MemFlags::DMA) {
@@ -155,7 +255,7 @@ pub fn init_hv_page_table() -> HvResult {
     info!("Hypervisor page table init end.");
     debug!("Hypervisor virtual memory set: {:#x?}", hv_pt);
 
-    HV_PT.call_once(|| RwLock::new(hv_pt));
+    *HV_PT.write() = Some(hv_pt);
     Ok(())
 }
 
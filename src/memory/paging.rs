@@ -81,8 +81,9 @@ pub trait GenericPTE: Debug + Clone {
     fn set_addr(&mut self, paddr: PhysAddr);
     /// Set flags for terminal entries.
     fn set_flags(&mut self, flags: MemFlags, is_huge: bool);
-    /// Set physical address and flags for intermediate table entries.
-    fn set_table(&mut self, paddr: PhysAddr);
+    /// Set physical address and flags for intermediate table entries, pointing
+    /// to a table of `next_level` (3 to 1, 1 for the last level).
+    fn set_table(&mut self, paddr: PhysAddr, next_level: usize);
     /// Set this entry to zero.
     fn clear(&mut self);
 }
@@ -286,19 +287,19 @@ where
         let p4 = table_of_mut::<PTE>(self.inner.root_paddr());
         let p4e = &mut p4[p4_index(vaddr)];
 
-        let p3 = next_table_mut_or_create(p4e, || self.alloc_intrm_table())?;
+        let p3 = next_table_mut_or_create(p4e, 3, || self.alloc_intrm_table())?;
         let p3e = &mut p3[p3_index(vaddr)];
         if page.size == PageSize::Size1G {
             return Ok(p3e);
         }
 
-        let p2 = next_table_mut_or_create(p3e, || self.alloc_intrm_table())?;
+        let p2 = next_table_mut_or_create(p3e, 2, || self.alloc_intrm_table())?;
         let p2e = &mut p2[p2_index(vaddr)];
         if page.size == PageSize::Size2M {
             return Ok(p2e);
         }
 
-        let p1 = next_table_mut_or_create(p2e, || self.alloc_intrm_table())?;
+        let p1 = next_table_mut_or_create(p2e, 1, || self.alloc_intrm_table())?;
         let p1e = &mut p1[p1_index(vaddr)];
         Ok(p1e)
     }
@@ -434,11 +435,10 @@ where
             self.inner
                 .map_page(page, paddr, region.flags)
                 .map_err(|e| {
-                    error!(
-                        "failed to map page: {:#x?}({:?}) -> {:#x?}, {:?}",
-                        vaddr, page_size, paddr, e
-                    );
-                    e
+                    HvError::from(e).context(format!(
+                        "failed to map page: {:#x?}({:?}) -> {:#x?}",
+                        vaddr, page_size, paddr
+                    ))
                 })?;
             vaddr += page_size as usize;
             size -= page_size as usize;
@@ -457,8 +457,7 @@ where
         let mut size = region.size;
         while size > 0 {
             let (_, page_size) = self.inner.unmap_page(vaddr.into()).map_err(|e| {
-                error!("failed to unmap page: {:#x?}, {:?}", vaddr, e);
-                e
+                HvError::from(e).context(format!("failed to unmap page: {:#x?}", vaddr))
             })?;
             assert!(page_size.is_aligned(vaddr));
             assert!(page_size as usize <= size);
@@ -527,11 +526,12 @@ fn next_table_mut<'a, E: GenericPTE>(entry: &E) -> PagingResult<&'a mut [E]> {
 
 fn next_table_mut_or_create<'a, E: GenericPTE>(
     entry: &mut E,
+    next_level: usize,
     mut allocator: impl FnMut() -> HvResult<PhysAddr>,
 ) -> PagingResult<&'a mut [E]> {
     if entry.is_unused() {
         let paddr = allocator().map_err(|_| PagingError::NoMemory)?;
-        entry.set_table(paddr);
+        entry.set_table(paddr, next_level);
         Ok(table_of_mut(paddr))
     } else {
         next_table_mut(entry)
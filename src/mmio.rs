@@ -0,0 +1,133 @@
+//! Emulated MMIO regions of a cell.
+//!
+//! Guest physical addresses not mapped to a cell cause VM exits. Accesses to a
+//! region registered here are decoded by the architecture, and forwarded to
+//! the handler of the region. Regions of virtual PCI devices follow their BARs,
+//! and may be registered or removed at runtime.
+
+use alloc::vec::Vec;
+use core::fmt::{Debug, Formatter, Result};
+
+use spin::RwLock;
+
+use crate::cell::Cell;
+use crate::error::HvResult;
+use crate::memory::addr::GuestPhysAddr;
+
+#[derive(Debug)]
+pub struct MmioAccess {
+    /// Offset from the start of the region.
+    pub offset: usize,
+    /// 1, 2, 4 or 8 bytes.
+    pub size: u8,
+    pub is_write: bool,
+    /// The written value, or the result of a read.
+    pub value: u64,
+}
+
+/// Handles an access to a region, with the argument given on registration.
+pub type MmioHandler = fn(&Cell, &mut MmioAccess, usize) -> HvResult;
+
+#[derive(Clone, Copy)]
+pub struct MmioRegion {
+    start: GuestPhysAddr,
+    size: usize,
+    handler: MmioHandler,
+    arg: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct MmioRegions {
+    regions: RwLock<Vec<MmioRegion>>,
+}
+
+impl MmioRegion {
+    fn overlaps(&self, start: GuestPhysAddr, size: usize) -> bool {
+        start < self.start + self.size && self.start < start + size
+    }
+
+    /// Emulate an access of `size` bytes at `gpaddr`, returns the value read.
+    pub fn access(
+        &self,
+        cell: &Cell,
+        gpaddr: GuestPhysAddr,
+        size: u8,
+        is_write: bool,
+        value: u64,
+    ) -> HvResult<u64> {
+        let offset = gpaddr - self.start;
+        if offset + size as usize > self.size {
+            return hv_result_err!(
+                EINVAL,
+                format!("MMIO access at {:#x} crosses the region end", gpaddr)
+            );
+        }
+        let mut access = MmioAccess {
+            offset,
+            size,
+            is_write,
+            value,
+        };
+        (self.handler)(cell, &mut access, self.arg)?;
+        Ok(access.value)
+    }
+}
+
+impl Debug for MmioRegion {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        f.debug_struct("MmioRegion")
+            .field("start", &self.start)
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+impl MmioRegions {
+    pub fn register(
+        &self,
+        start: GuestPhysAddr,
+        size: usize,
+        handler: MmioHandler,
+        arg: usize,
+    ) -> HvResult {
+        let mut regions = self.regions.write();
+        if regions.iter().any(|r| r.overlaps(start, size)) {
+            return hv_result_err!(
+                EEXIST,
+                format!("MMIO region {:#x} overlaps another one", start)
+            );
+        }
+        regions.push(MmioRegion {
+            start,
+            size,
+            handler,
+            arg,
+        });
+        Ok(())
+    }
+
+    pub fn unregister(&self, start: GuestPhysAddr) -> HvResult {
+        let mut regions = self.regions.write();
+        match regions.iter().position(|r| r.start == start) {
+            Some(i) => {
+                regions.remove(i);
+                Ok(())
+            }
+            None => hv_result_err!(ENOENT, format!("No MMIO region at {:#x}", start)),
+        }
+    }
+
+    pub fn overlaps(&self, start: GuestPhysAddr, size: usize) -> bool {
+        self.regions.read().iter().any(|r| r.overlaps(start, size))
+    }
+
+    /// The region containing `gpaddr`, copied so that its handler may change
+    /// the regions.
+    pub fn find(&self, gpaddr: GuestPhysAddr) -> Option<MmioRegion> {
+        self.regions
+            .read()
+            .iter()
+            .find(|r| (r.start..r.start + r.size).contains(&gpaddr))
+            .copied()
+    }
+}
@@ -0,0 +1,382 @@
+//! Virtual shared memory devices (ivshmem), compatible with version 2 of the
+//! ivshmem specification.
+//!
+//! Devices with the same BDF in several cells are linked, each cell being a
+//! peer with its own ID. The memory region of the device is shared: its first
+//! page is the state table, read-only to the cells, and the rest is the
+//! read/write section. There are no output sections. Peers notify each other
+//! through the doorbell register, delivered as MSI-X interrupts.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr::write_volatile;
+
+use spin::Mutex;
+
+use super::size_mask;
+use crate::arch::irq::IrqMsg;
+use crate::cell::Cell;
+use crate::config::{CellConfig, HvPciDevice};
+use crate::error::HvResult;
+use crate::memory::addr::{is_aligned, phys_to_virt, GuestPhysAddr, HostPhysAddr};
+use crate::memory::PAGE_SIZE;
+use crate::mmio::{MmioAccess, MmioRegions};
+
+const VENDOR_ID: u32 = 0x110a;
+const DEVICE_ID: u32 = 0x4106;
+const CLASS_OTHER: u32 = 0xff;
+
+const MAX_PEERS: usize = 16;
+const MAX_VECTORS: usize = 16;
+
+const VNDR_CAP: u32 = 0x40;
+const VNDR_CAP_LEN: u32 = 0x20;
+const MSIX_CAP: u32 = 0x60;
+
+const CMD_MEM: u16 = 1 << 1;
+const CMD_MASTER: u16 = 1 << 2;
+const CMD_INTX_DISABLE: u16 = 1 << 10;
+const STATUS_CAP_LIST: u32 = 1 << 4;
+
+const INT_CTRL_ENABLE: u32 = 1 << 0;
+
+const MSIX_CTRL_ENABLE: u16 = 1 << 15;
+const MSIX_CTRL_FMASK: u16 = 1 << 14;
+const MSIX_ENTRY_SIZE: usize = 16;
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+const MSIX_PBA_OFFSET: u32 = 0x800;
+
+/// Sizes of BAR0 (registers) and BAR1 (MSI-X table and pending bits).
+const BAR_SIZES: [u32; 2] = [0x100, 0x1000];
+
+/// Registers in BAR0.
+mod reg {
+    pub const ID: usize = 0x00;
+    pub const MAX_PEERS: usize = 0x04;
+    pub const INT_CTRL: usize = 0x08;
+    pub const DOORBELL: usize = 0x0c;
+    pub const STATE: usize = 0x10;
+}
+
+lazy_static! {
+    /// Links by BDF of their devices.
+    static ref LINKS: Mutex<BTreeMap<u16, Arc<Link>>> = Mutex::new(BTreeMap::new());
+}
+
+/// Peers sharing a memory region.
+#[derive(Debug)]
+struct Link {
+    shmem_start: HostPhysAddr,
+    shmem_size: usize,
+    peers: Mutex<[Option<Arc<Peer>>; MAX_PEERS]>,
+}
+
+#[derive(Debug)]
+struct Peer {
+    id: usize,
+    regs: Mutex<PeerRegs>,
+}
+
+/// Registers of the device of a peer.
+#[derive(Debug)]
+struct PeerRegs {
+    command: u16,
+    bars: [u32; 2],
+    /// BARs registered as MMIO regions of the cell.
+    mapped_bars: [Option<GuestPhysAddr>; 2],
+    int_ctrl: u32,
+    state: u32,
+    msix_ctrl: u16,
+    /// MSI-X table as written by the cell.
+    msix_table: Vec<[u32; 4]>,
+    /// Checked messages of the unmasked entries.
+    msgs: Vec<Option<IrqMsg>>,
+}
+
+impl Link {
+    fn set_state(&self, id: usize, state: u32) {
+        let table = phys_to_virt(self.shmem_start) as *mut u32;
+        unsafe { write_volatile(table.add(id), state) };
+        // Peers are notified of state changes with vector 0.
+        for peer in self.peers.lock().iter().flatten() {
+            if peer.id != id {
+                peer.trigger(0);
+            }
+        }
+    }
+
+    fn doorbell(&self, target: usize, vector: usize) {
+        if let Some(Some(peer)) = self.peers.lock().get(target) {
+            peer.trigger(vector);
+        }
+    }
+}
+
+impl Peer {
+    fn trigger(&self, vector: usize) {
+        let regs = self.regs.lock();
+        if regs.int_ctrl & INT_CTRL_ENABLE == 0
+            || regs.msix_ctrl & MSIX_CTRL_ENABLE == 0
+            || regs.msix_ctrl & MSIX_CTRL_FMASK != 0
+        {
+            return;
+        }
+        if let Some(Some(msg)) = regs.msgs.get(vector) {
+            if let Err(e) = msg.send() {
+                warn!(
+                    "Failed to send ivshmem interrupt to peer {}: {:?}",
+                    self.id, e
+                );
+            }
+        }
+    }
+}
+
+/// The device of a cell, one peer of a link.
+#[derive(Debug)]
+pub struct Ivshmem {
+    bdf: u16,
+    protocol: u16,
+    /// Guest physical address of the shared memory.
+    shmem_gpaddr: GuestPhysAddr,
+    link: Arc<Link>,
+    peer: Arc<Peer>,
+}
+
+impl Ivshmem {
+    pub fn new(config: &CellConfig, info: &HvPciDevice) -> HvResult<Self> {
+        let bdf = info.bdf;
+        let region = match config.mem_regions().get(info.shmem_region as usize) {
+            Some(region) => region,
+            None => {
+                return hv_result_err!(
+                    EINVAL,
+                    format!("Invalid memory region of ivshmem device {:#x}", bdf)
+                )
+            }
+        };
+        let (shmem_start, shmem_size) = (region.phys_start as HostPhysAddr, region.size as usize);
+        if !is_aligned(shmem_start) || shmem_size <= PAGE_SIZE {
+            return hv_result_err!(
+                EINVAL,
+                format!("Memory region of ivshmem device {:#x} is too small", bdf)
+            );
+        }
+        let num_vectors = info.num_msix_vectors as usize;
+        if !(1..=MAX_VECTORS).contains(&num_vectors) {
+            return hv_result_err!(
+                EINVAL,
+                format!("Invalid MSI-X vectors of ivshmem device {:#x}", bdf)
+            );
+        }
+
+        let mut links = LINKS.lock();
+        let link = links
+            .entry(bdf)
+            .or_insert_with(|| {
+                Arc::new(Link {
+                    shmem_start,
+                    shmem_size,
+                    peers: Mutex::new(Default::default()),
+                })
+            })
+            .clone();
+        if link.shmem_start != shmem_start || link.shmem_size != shmem_size {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "Memory region of ivshmem device {:#x} differs from peers",
+                    bdf
+                )
+            );
+        }
+        let mut peers = link.peers.lock();
+        let id = match peers.iter().position(Option::is_none) {
+            Some(id) => id,
+            None => return hv_result_err!(EBUSY, format!("Too many peers of ivshmem {:#x}", bdf)),
+        };
+        let peer = Arc::new(Peer {
+            id,
+            regs: Mutex::new(PeerRegs {
+                command: 0,
+                bars: [0; 2],
+                mapped_bars: [None; 2],
+                int_ctrl: 0,
+                state: 0,
+                msix_ctrl: 0,
+                msix_table: vec![[0, 0, 0, MSIX_ENTRY_MASKED]; num_vectors],
+                msgs: vec![None; num_vectors],
+            }),
+        });
+        peers[id] = Some(peer.clone());
+        drop(peers);
+        link.set_state(id, 0);
+
+        Ok(Self {
+            bdf,
+            protocol: info.shmem_protocol,
+            shmem_gpaddr: region.virt_start as GuestPhysAddr,
+            link,
+            peer,
+        })
+    }
+
+    fn read_dword(&self, reg: u16) -> u32 {
+        let regs = self.peer.regs.lock();
+        let rw_size = (self.link.shmem_size - PAGE_SIZE) as u64;
+        let num_vectors = regs.msix_table.len() as u16;
+        match reg as u32 {
+            0x00 | 0x2c => DEVICE_ID << 16 | VENDOR_ID,
+            0x04 => STATUS_CAP_LIST << 16 | regs.command as u32,
+            0x08 => CLASS_OTHER << 24 | (self.protocol as u32) << 8,
+            0x10 => regs.bars[0],
+            0x14 => regs.bars[1],
+            0x34 => VNDR_CAP,
+            VNDR_CAP => 0x09 | MSIX_CAP << 8 | VNDR_CAP_LEN << 16,
+            0x44 => PAGE_SIZE as u32, // State Table Size
+            0x48 => rw_size as u32,   // R/W Section Size
+            0x4c => (rw_size >> 32) as u32,
+            0x58 => self.shmem_gpaddr as u32, // Base Address
+            0x5c => (self.shmem_gpaddr as u64 >> 32) as u32,
+            MSIX_CAP => 0x11 | ((regs.msix_ctrl | (num_vectors - 1)) as u32) << 16,
+            0x64 => 1, // Table in BAR1
+            0x68 => MSIX_PBA_OFFSET | 1,
+            _ => 0,
+        }
+    }
+
+    pub fn config_read(&self, reg: u16, size: u8) -> u32 {
+        (self.read_dword(reg & !3) >> ((reg & 3) * 8)) & size_mask(size)
+    }
+
+    /// Emulate a write to the configuration space, only the command, BARs and
+    /// MSI-X control are writable.
+    pub fn config_write(&self, cell: &Cell, reg: u16, size: u8, value: u32) -> HvResult {
+        let shift = (reg & 3) * 8;
+        let mask = size_mask(size) << shift;
+        let merge = |old: u32| (old & !mask) | (value & size_mask(size)) << shift;
+
+        let mut regs = self.peer.regs.lock();
+        match reg as u32 & !3 {
+            0x04 => {
+                regs.command =
+                    merge(regs.command as u32) as u16 & (CMD_MEM | CMD_MASTER | CMD_INTX_DISABLE)
+            }
+            0x10 | 0x14 => {
+                let i = (reg as usize & !3) / 4 - 4;
+                regs.bars[i] = merge(regs.bars[i]) & !(BAR_SIZES[i] - 1);
+            }
+            MSIX_CAP => {
+                let ctrl = merge((regs.msix_ctrl as u32) << 16) >> 16;
+                regs.msix_ctrl = ctrl as u16 & (MSIX_CTRL_ENABLE | MSIX_CTRL_FMASK);
+            }
+            _ => return Ok(()),
+        }
+        self.update_bars(&cell.mmio, &mut regs)
+    }
+
+    /// Move the MMIO regions of the BARs, which are only decoded with memory
+    /// space enabled.
+    fn update_bars(&self, mmio: &MmioRegions, regs: &mut PeerRegs) -> HvResult {
+        for (i, &size) in BAR_SIZES.iter().enumerate() {
+            let bar = regs.bars[i] as GuestPhysAddr;
+            let new = Some(bar).filter(|_| bar != 0 && regs.command & CMD_MEM != 0);
+            if new == regs.mapped_bars[i] {
+                continue;
+            }
+            if let Some(old) = regs.mapped_bars[i].take() {
+                mmio.unregister(old)?;
+            }
+            if let Some(start) = new {
+                let arg = (self.bdf as usize) << 1 | i;
+                mmio.register(start, size as usize, bar_access, arg)?;
+                regs.mapped_bars[i] = new;
+            }
+        }
+        Ok(())
+    }
+
+    fn regs_access(&self, access: &mut MmioAccess) -> HvResult {
+        let value = access.value as u32;
+        match (access.offset, access.is_write) {
+            (reg::ID, false) => access.value = self.peer.id as u64,
+            (reg::MAX_PEERS, false) => access.value = MAX_PEERS as u64,
+            (reg::INT_CTRL, false) => access.value = self.peer.regs.lock().int_ctrl as u64,
+            (reg::INT_CTRL, true) => self.peer.regs.lock().int_ctrl = value & INT_CTRL_ENABLE,
+            (reg::DOORBELL, true) => self
+                .link
+                .doorbell((value >> 16) as usize, (value & 0xffff) as usize),
+            (reg::STATE, false) => access.value = self.peer.regs.lock().state as u64,
+            (reg::STATE, true) => {
+                self.peer.regs.lock().state = value;
+                self.link.set_state(self.peer.id, value);
+            }
+            (_, false) => access.value = 0,
+            (_, true) => {}
+        }
+        Ok(())
+    }
+
+    fn msix_access(&self, cell: &Cell, access: &mut MmioAccess) -> HvResult {
+        let mut regs = self.peer.regs.lock();
+        if access.offset >= regs.msix_table.len() * MSIX_ENTRY_SIZE {
+            // No pending bits, interrupts of masked vectors are dropped.
+            if !access.is_write {
+                access.value = 0;
+            }
+            return Ok(());
+        }
+        let (index, n) = (
+            access.offset / MSIX_ENTRY_SIZE,
+            access.offset % MSIX_ENTRY_SIZE / 4,
+        );
+        if !access.is_write {
+            access.value = regs.msix_table[index][n] as u64;
+            return Ok(());
+        }
+        let mut entry = regs.msix_table[index];
+        entry[n] = access.value as u32;
+        regs.msgs[index] = if entry[3] & MSIX_ENTRY_MASKED != 0 {
+            None
+        } else {
+            let msg = IrqMsg::from_msi((entry[1] as u64) << 32 | entry[0] as u64, entry[2])?;
+            msg.check_dest(cell)?;
+            Some(msg)
+        };
+        regs.msix_table[index] = entry;
+        Ok(())
+    }
+}
+
+impl Drop for Ivshmem {
+    fn drop(&mut self) {
+        let mut links = LINKS.lock();
+        let mut peers = self.link.peers.lock();
+        peers[self.peer.id] = None;
+        let last = peers.iter().all(Option::is_none);
+        drop(peers);
+        if last {
+            links.remove(&self.bdf);
+        } else {
+            self.link.set_state(self.peer.id, 0);
+        }
+    }
+}
+
+fn bar_access(cell: &Cell, access: &mut MmioAccess, arg: usize) -> HvResult {
+    let dev = match cell.pci.ivshmem.get(&((arg >> 1) as u16)) {
+        Some(dev) => dev,
+        None => return hv_result_err!(ENODEV),
+    };
+    if access.size != 4 || access.offset % 4 != 0 {
+        return hv_result_err!(
+            EINVAL,
+            format!("Invalid ivshmem register access at {:#x}", access.offset)
+        );
+    }
+    match arg & 1 {
+        0 => dev.regs_access(access),
+        _ => dev.msix_access(cell, access),
+    }
+}
@@ -0,0 +1,354 @@
+//! PCI configuration space mediation.
+//!
+//! A cell only sees the PCI devices of its configuration, others read as
+//! absent and ignore writes. Accesses come from the emulated MMCONFIG region
+//! or the configuration ports of the architecture. BARs are emulated, so that
+//! size probing with `HvPciDevice::bar_mask` does not reach the device. Header
+//! writes are limited to the fields a driver may change, and capabilities are
+//! only writable with `HvPciCapability::WRITE`, except for MSI and MSI-X which
+//! are emulated. Virtual shared memory devices are fully emulated.
+
+mod ivshmem;
+mod msi;
+
+use alloc::collections::BTreeMap;
+
+use spin::Mutex;
+
+use crate::arch::pci::{read_config, write_config};
+use crate::cell::Cell;
+use crate::config::{CellConfig, HvPciCapability, HvPciDevice, HvSystemConfig};
+use crate::error::HvResult;
+use crate::memory::addr::GuestPhysAddr;
+use crate::mmio::{MmioAccess, MmioRegions};
+use ivshmem::Ivshmem;
+use msi::{Msi, MsiX};
+
+const HEADER_SIZE: u16 = 0x40;
+const CONFIG_SPACE_SIZE: u16 = 0x1000;
+const BAR_BASE: u16 = 0x10;
+const NUM_BARS: usize = 6;
+const NUM_BRIDGE_BARS: usize = 2;
+
+/// Writable bits of each header dword of endpoints, besides the BARs.
+const ENDPOINT_WRITE_MASK: [u32; 16] = [
+    0,
+    0xffff_ffff, // Command, Status
+    0,
+    0xff00_ffff, // BIST, Latency Timer, Cache Line Size
+    0,
+    0,
+    0,
+    0,
+    0,
+    0,
+    0,
+    0,
+    0, // Expansion ROM
+    0,
+    0,
+    0x0000_00ff, // Interrupt Line
+];
+
+/// Writable bits of each header dword of bridges, besides the BARs.
+const BRIDGE_WRITE_MASK: [u32; 16] = [
+    0,
+    0xffff_ffff, // Command, Status
+    0,
+    0xff00_ffff, // BIST, Latency Timer, Cache Line Size
+    0,
+    0,
+    0,
+    0x0000_ffff, // I/O Limit, I/O Base
+    0xffff_ffff, // Memory Limit, Memory Base
+    0xffff_ffff, // Prefetchable Memory Limit and Base
+    0xffff_ffff, // Prefetchable Base Upper 32 Bits
+    0xffff_ffff, // Prefetchable Limit Upper 32 Bits
+    0xffff_ffff, // I/O Limit and Base Upper 16 Bits
+    0,
+    0,           // Expansion ROM
+    0xffff_00ff, // Bridge Control, Interrupt Line
+];
+
+fn size_mask(size: u8) -> u32 {
+    match size {
+        1 => 0xff,
+        2 => 0xffff,
+        _ => 0xffff_ffff,
+    }
+}
+
+fn check_access(bdf: u16, reg: u16, size: u8) -> HvResult {
+    if !matches!(size, 1 | 2 | 4) || reg % size as u16 != 0 || reg >= CONFIG_SPACE_SIZE {
+        return hv_result_err!(
+            EINVAL,
+            format!(
+                "Invalid PCI config access to {:#x}, register {:#x}, size {}",
+                bdf, reg, size
+            )
+        );
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct PciDevice<'a> {
+    info: &'a HvPciDevice,
+    caps: &'a [HvPciCapability],
+    /// BARs as seen by the cell.
+    bars: Mutex<[u32; NUM_BARS]>,
+    msi: Option<Msi>,
+    msix: Option<MsiX>,
+}
+
+impl<'a> PciDevice<'a> {
+    fn new(info: &'a HvPciDevice, caps: &'a [HvPciCapability]) -> Self {
+        let msi = caps
+            .iter()
+            .find(|cap| cap.id == HvPciCapability::ID_MSI && info.num_msi_vectors != 0)
+            .map(|cap| Msi::new(info, cap.start));
+        let msix = caps
+            .iter()
+            .any(|cap| cap.id == HvPciCapability::ID_MSIX && info.num_msix_vectors != 0)
+            .then(|| MsiX::new(info));
+        let mut dev = Self {
+            info,
+            caps,
+            bars: Mutex::new([0; NUM_BARS]),
+            msi,
+            msix,
+        };
+        let num_bars = dev.num_bars();
+        for (i, bar) in dev.bars.get_mut().iter_mut().enumerate().take(num_bars) {
+            *bar = read_config(info.bdf, BAR_BASE + i as u16 * 4, 4);
+        }
+        dev
+    }
+
+    fn is_bridge(&self) -> bool {
+        self.info.pci_device_type == HvPciDevice::TYPE_BRIDGE
+    }
+
+    fn num_bars(&self) -> usize {
+        if self.is_bridge() {
+            NUM_BRIDGE_BARS
+        } else {
+            NUM_BARS
+        }
+    }
+
+    fn bar_index(&self, reg: u16) -> Option<usize> {
+        let index = reg.checked_sub(BAR_BASE)? as usize / 4;
+        Some(index).filter(|&i| i < self.num_bars())
+    }
+
+    fn cap_writable(&self, reg: u16, size: u8) -> bool {
+        self.caps.iter().any(|cap| {
+            cap.flags & HvPciCapability::WRITE != 0
+                && reg >= cap.start
+                && reg + size as u16 <= cap.start + cap.len
+        })
+    }
+
+    fn msi_at(&self, reg: u16) -> Option<&Msi> {
+        self.msi.as_ref().filter(|msi| msi.contains(reg))
+    }
+
+    fn read(&self, reg: u16, size: u8) -> u32 {
+        if let Some(msi) = self.msi_at(reg) {
+            return msi.read(reg, size);
+        }
+        match self.bar_index(reg) {
+            Some(i) => (self.bars.lock()[i] >> ((reg & 3) * 8)) & size_mask(size),
+            None => read_config(self.info.bdf, reg, size),
+        }
+    }
+
+    fn write(&self, cell: &Cell, reg: u16, size: u8, value: u32) -> HvResult {
+        if let Some(msi) = self.msi_at(reg) {
+            return msi.write(cell, reg, size, value);
+        }
+        let bdf = self.info.bdf;
+        let shift = (reg & 3) * 8;
+        let mask = size_mask(size) << shift;
+        let value = (value & size_mask(size)) << shift;
+
+        if let Some(i) = self.bar_index(reg) {
+            let bar_mask = { self.info.bar_mask }[i];
+            let mut bars = self.bars.lock();
+            let new = (bars[i] & !mask) | value;
+            bars[i] = (new & bar_mask) | (bars[i] & !bar_mask);
+            // With all writable bits set, the cell probes the size, and the
+            // device is not moved.
+            if new & bar_mask != bar_mask {
+                write_config(bdf, reg & !3, 4, bars[i]);
+            }
+            return Ok(());
+        }
+
+        let writable = if reg < HEADER_SIZE {
+            let masks = if self.is_bridge() {
+                &BRIDGE_WRITE_MASK
+            } else {
+                &ENDPOINT_WRITE_MASK
+            };
+            masks[reg as usize / 4] & mask
+        } else if self.cap_writable(reg, size) {
+            mask
+        } else {
+            0
+        };
+        if writable == 0 {
+            warn!(
+                "Ignored PCI config write to {:#x}, register {:#x}: {:#x}",
+                bdf,
+                reg,
+                value >> shift
+            );
+        } else if writable == mask {
+            write_config(bdf, reg, size, value >> shift);
+        } else {
+            let old = read_config(bdf, reg, size) << shift;
+            let new = (value & writable) | (old & !writable);
+            write_config(bdf, reg, size, (new & mask) >> shift);
+        }
+        Ok(())
+    }
+}
+
+/// PCI devices of a cell, by bus, device and function.
+#[derive(Debug, Default)]
+pub struct PciDevices<'a> {
+    devices: BTreeMap<u16, PciDevice<'a>>,
+    ivshmem: BTreeMap<u16, Ivshmem>,
+}
+
+impl<'a> PciDevices<'a> {
+    pub fn new(config: &CellConfig<'a>) -> HvResult<Self> {
+        let caps = config.pci_caps();
+        let mut devices = BTreeMap::new();
+        let mut ivshmem = BTreeMap::new();
+        for info in config.pci_devices() {
+            let bdf = info.bdf;
+            if devices.contains_key(&bdf) || ivshmem.contains_key(&bdf) {
+                return hv_result_err!(EEXIST, format!("PCI device {:#x} added twice", bdf));
+            }
+            if info.pci_device_type == HvPciDevice::TYPE_IVSHMEM {
+                ivshmem.insert(bdf, Ivshmem::new(config, info)?);
+                continue;
+            }
+            let start = info.caps_start as usize;
+            let dev_caps = match caps.get(start..start + info.num_caps as usize) {
+                Some(dev_caps) => dev_caps,
+                None => {
+                    return hv_result_err!(
+                        EINVAL,
+                        format!("Invalid capabilities of PCI device {:#x}", bdf)
+                    )
+                }
+            };
+            devices.insert(bdf, PciDevice::new(info, dev_caps));
+        }
+        Ok(Self { devices, ivshmem })
+    }
+
+    /// Read `size` bytes at `reg` of the configuration space of `bdf`.
+    pub fn config_read(&self, bdf: u16, reg: u16, size: u8) -> HvResult<u32> {
+        check_access(bdf, reg, size)?;
+        if let Some(dev) = self.ivshmem.get(&bdf) {
+            return Ok(dev.config_read(reg, size));
+        }
+        Ok(match self.devices.get(&bdf) {
+            Some(dev) => dev.read(reg, size),
+            None => size_mask(size),
+        })
+    }
+
+    pub fn config_write(&self, cell: &Cell, bdf: u16, reg: u16, size: u8, value: u32) -> HvResult {
+        check_access(bdf, reg, size)?;
+        if let Some(dev) = self.ivshmem.get(&bdf) {
+            return dev.config_write(cell, reg, size, value);
+        }
+        match self.devices.get(&bdf) {
+            Some(dev) => dev.write(cell, reg, size, value),
+            None => Ok(()),
+        }
+    }
+
+    /// Emulate the MMCONFIG region if present, and the MSI-X tables.
+    pub fn register_mmio(&self, mmio: &MmioRegions) -> HvResult {
+        let sys_config = HvSystemConfig::get();
+        let base = sys_config.pci_mmconfig_base() as GuestPhysAddr;
+        if base != 0 {
+            let size = (sys_config.pci_mmconfig_end_bus() as usize + 1) << 20;
+            mmio.register(base, size, mmconfig_access, 0)?;
+        }
+        for (&bdf, dev) in &self.devices {
+            if dev.msix.is_some() {
+                let start = dev.info.msix_address as GuestPhysAddr;
+                let size = dev.info.msix_region_size as usize;
+                mmio.register(start, size, msix_access, bdf as usize)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Program the enabled MSI and MSI-X messages again, once the IOMMU
+    /// remaps interrupts.
+    pub fn remap_msis(&self, cell: &Cell) -> HvResult {
+        for dev in self.devices.values() {
+            if let Some(msi) = &dev.msi {
+                msi.remap(cell)?;
+            }
+            if let Some(msix) = &dev.msix {
+                msix.remap(cell)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Give the MSI and MSI-X messages of the cell back to the devices, before
+    /// the IOMMU stops remapping interrupts.
+    pub fn restore_msis(&self) {
+        for dev in self.devices.values() {
+            if let Some(msi) = &dev.msi {
+                msi.restore();
+            }
+            if let Some(msix) = &dev.msix {
+                msix.restore();
+            }
+        }
+    }
+}
+
+fn mmconfig_access(cell: &Cell, access: &mut MmioAccess, _arg: usize) -> HvResult {
+    let bdf = (access.offset >> 12) as u16;
+    let reg = (access.offset & 0xfff) as u16;
+    if access.is_write {
+        cell.pci
+            .config_write(cell, bdf, reg, access.size, access.value as u32)
+    } else {
+        access.value = cell.pci.config_read(bdf, reg, access.size)? as u64;
+        Ok(())
+    }
+}
+
+fn msix_access(cell: &Cell, access: &mut MmioAccess, bdf: usize) -> HvResult {
+    match cell
+        .pci
+        .devices
+        .get(&(bdf as u16))
+        .and_then(|dev| dev.msix.as_ref())
+    {
+        Some(msix) => msix.access(cell, access),
+        None => hv_result_err!(ENODEV),
+    }
+}
+
+/// Whether memory region `index` of the cell is shared by an ivshmem device.
+/// Its first page is the state table.
+pub fn is_shmem_region(config: &CellConfig, index: usize) -> bool {
+    config.pci_devices().iter().any(|dev| {
+        dev.pci_device_type == HvPciDevice::TYPE_IVSHMEM && dev.shmem_region as usize == index
+    })
+}
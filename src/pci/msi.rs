@@ -0,0 +1,296 @@
+//! MSI and MSI-X of assigned PCI devices.
+//!
+//! Messages written by a cell are kept in shadow registers. Before reaching
+//! the device, they are checked to only target CPUs of the cell, and remapped
+//! by the IOMMU. MSI messages are programmed while MSI is enabled, MSI-X table
+//! entries while they are unmasked. The address is always written before the
+//! data, which keeps the intermediate message valid for both IOMMUs.
+
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+
+use spin::Mutex;
+
+use super::size_mask;
+use crate::arch::pci::{read_config, write_config};
+use crate::arch::vmm::iommu;
+use crate::cell::Cell;
+use crate::config::HvPciDevice;
+use crate::error::HvResult;
+use crate::memory::addr::{phys_to_virt, HostVirtAddr};
+use crate::mmio::MmioAccess;
+
+const MSI_CTRL_ENABLE: u16 = 1 << 0;
+const MSI_CTRL_MULTIPLE_CAP: u16 = 0x7 << 1;
+const MSI_CTRL_MULTIPLE_MSG: u16 = 0x7 << 4;
+
+const MSIX_ENTRY_SIZE: usize = 16;
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+/// Index of the vector control dword in a table entry.
+const MSIX_ENTRY_CTRL: usize = 3;
+
+/// The MSI capability.
+#[derive(Debug)]
+pub struct Msi {
+    bdf: u16,
+    /// Offset of the capability.
+    cap: u16,
+    is_64bit: bool,
+    /// Message address and data as seen by the cell.
+    msg: Mutex<(u64, u32)>,
+}
+
+impl Msi {
+    pub fn new(info: &HvPciDevice, cap: u16) -> Self {
+        let mut msi = Self {
+            bdf: info.bdf,
+            cap,
+            is_64bit: info.msi_64bits != 0,
+            msg: Mutex::new((0, 0)),
+        };
+        let address = read_config(msi.bdf, cap + 4, 4) as u64;
+        let address_high = if msi.is_64bit {
+            read_config(msi.bdf, cap + 8, 4) as u64
+        } else {
+            0
+        };
+        let data = read_config(msi.bdf, msi.data_reg(), 2);
+        *msi.msg.get_mut() = (address_high << 32 | address, data);
+        msi
+    }
+
+    fn data_reg(&self) -> u16 {
+        self.cap + if self.is_64bit { 0xc } else { 8 }
+    }
+
+    fn enabled(&self) -> bool {
+        read_config(self.bdf, self.cap + 2, 2) as u16 & MSI_CTRL_ENABLE != 0
+    }
+
+    /// Whether `reg` is emulated: the header, address and data.
+    pub fn contains(&self, reg: u16) -> bool {
+        (self.cap..self.data_reg() + 4).contains(&reg)
+    }
+
+    fn read_dword(&self, offset: u16) -> u32 {
+        let (address, data) = *self.msg.lock();
+        match offset {
+            // Only a single vector is supported, report it as the capability.
+            0 => read_config(self.bdf, self.cap, 4) & !((MSI_CTRL_MULTIPLE_CAP as u32) << 16),
+            4 => address as u32,
+            8 if self.is_64bit => (address >> 32) as u32,
+            _ => data,
+        }
+    }
+
+    pub fn read(&self, reg: u16, size: u8) -> u32 {
+        let dword = self.read_dword((reg - self.cap) & !3);
+        (dword >> ((reg & 3) * 8)) & size_mask(size)
+    }
+
+    pub fn write(&self, cell: &Cell, reg: u16, size: u8, value: u32) -> HvResult {
+        let offset = (reg - self.cap) & !3;
+        let shift = (reg & 3) * 8;
+        let mask = size_mask(size) << shift;
+        let new = (self.read_dword(offset) & !mask) | (value & size_mask(size)) << shift;
+
+        let mut msg = self.msg.lock();
+        if offset == 0 {
+            if mask >> 16 == 0 {
+                return Ok(());
+            }
+            let mut ctrl = (new >> 16) as u16;
+            if ctrl & MSI_CTRL_MULTIPLE_MSG != 0 {
+                warn!(
+                    "Multiple MSI vectors of PCI device {:#x} are not supported",
+                    self.bdf
+                );
+                ctrl &= !MSI_CTRL_MULTIPLE_MSG;
+            }
+            if ctrl & MSI_CTRL_ENABLE != 0 {
+                self.program(cell, *msg)?;
+            }
+            write_config(self.bdf, self.cap + 2, 2, ctrl as u32);
+            return Ok(());
+        }
+
+        let (mut address, mut data) = *msg;
+        match offset {
+            4 => address = (address & !0xffff_ffff) | new as u64,
+            8 if self.is_64bit => address = (address & 0xffff_ffff) | (new as u64) << 32,
+            _ => data = new & 0xffff,
+        }
+        if self.enabled() {
+            self.program(cell, (address, data))?;
+        }
+        *msg = (address, data);
+        Ok(())
+    }
+
+    fn program(&self, cell: &Cell, (address, data): (u64, u32)) -> HvResult {
+        let (address, data) = iommu::map_msi(cell, self.bdf, 0, address, data)?;
+        self.write_msg(address, data);
+        Ok(())
+    }
+
+    fn write_msg(&self, address: u64, data: u32) {
+        write_config(self.bdf, self.cap + 4, 4, address as u32);
+        if self.is_64bit {
+            write_config(self.bdf, self.cap + 8, 4, (address >> 32) as u32);
+        }
+        write_config(self.bdf, self.data_reg(), 2, data);
+    }
+
+    /// Program the message of an enabled MSI again.
+    pub fn remap(&self, cell: &Cell) -> HvResult {
+        let msg = self.msg.lock();
+        if self.enabled() {
+            self.program(cell, *msg)?;
+        }
+        Ok(())
+    }
+
+    /// Write the message of the cell, without remapping.
+    pub fn restore(&self) {
+        let (address, data) = *self.msg.lock();
+        self.write_msg(address, data);
+    }
+}
+
+/// The MSI-X table, with the pages it shares with other registers.
+#[derive(Debug)]
+pub struct MsiX {
+    bdf: u16,
+    /// The table, mapped by the hypervisor.
+    table: HostVirtAddr,
+    /// Address, data and vector control of each entry as seen by the cell.
+    entries: Mutex<Vec<[u32; 4]>>,
+}
+
+impl MsiX {
+    pub fn new(info: &HvPciDevice) -> Self {
+        let table = phys_to_virt(info.msix_address as usize);
+        let entries = (0..info.num_msix_vectors as usize)
+            .map(|i| {
+                let mut entry = [0; 4];
+                for (n, dword) in entry.iter_mut().enumerate() {
+                    let vaddr = table + i * MSIX_ENTRY_SIZE + n * 4;
+                    *dword = unsafe { read_volatile(vaddr as *const u32) };
+                }
+                entry
+            })
+            .collect();
+        Self {
+            bdf: info.bdf,
+            table,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    fn dword_ptr(&self, index: usize, n: usize) -> *mut u32 {
+        (self.table + index * MSIX_ENTRY_SIZE + n * 4) as *mut u32
+    }
+
+    fn program(&self, cell: &Cell, index: usize, entry: &[u32; 4]) -> HvResult {
+        let address = (entry[1] as u64) << 32 | entry[0] as u64;
+        let (address, data) = iommu::map_msi(cell, self.bdf, index as u16, address, entry[2])?;
+        self.write_msg(index, address, data);
+        Ok(())
+    }
+
+    fn write_msg(&self, index: usize, address: u64, data: u32) {
+        unsafe {
+            write_volatile(self.dword_ptr(index, 0), address as u32);
+            write_volatile(self.dword_ptr(index, 1), (address >> 32) as u32);
+            write_volatile(self.dword_ptr(index, 2), data);
+        }
+    }
+
+    fn write_dword(
+        &self,
+        cell: &Cell,
+        entry: &mut [u32; 4],
+        index: usize,
+        n: usize,
+        value: u32,
+    ) -> HvResult {
+        let mut new = *entry;
+        new[n] = value;
+        let masked = new[MSIX_ENTRY_CTRL] & MSIX_ENTRY_MASKED != 0;
+        let unmasking = entry[MSIX_ENTRY_CTRL] & MSIX_ENTRY_MASKED != 0 && !masked;
+        if !masked && (n != MSIX_ENTRY_CTRL || unmasking) {
+            self.program(cell, index, &new)?;
+        }
+        if n == MSIX_ENTRY_CTRL {
+            unsafe { write_volatile(self.dword_ptr(index, n), value) };
+        }
+        *entry = new;
+        Ok(())
+    }
+
+    /// Emulate an access to the region, at `access.offset` from the table.
+    pub fn access(&self, cell: &Cell, access: &mut MmioAccess) -> HvResult {
+        let mut entries = self.entries.lock();
+        let size = access.size as usize;
+        if access.offset >= entries.len() * MSIX_ENTRY_SIZE {
+            // Pending bits and other registers are passed through.
+            let vaddr = self.table + access.offset;
+            unsafe {
+                match (access.size, access.is_write) {
+                    (1, false) => access.value = read_volatile(vaddr as *const u8) as u64,
+                    (2, false) => access.value = read_volatile(vaddr as *const u16) as u64,
+                    (4, false) => access.value = read_volatile(vaddr as *const u32) as u64,
+                    (_, false) => access.value = read_volatile(vaddr as *const u64),
+                    (1, true) => write_volatile(vaddr as *mut u8, access.value as u8),
+                    (2, true) => write_volatile(vaddr as *mut u16, access.value as u16),
+                    (4, true) => write_volatile(vaddr as *mut u32, access.value as u32),
+                    (_, true) => write_volatile(vaddr as *mut u64, access.value),
+                }
+            }
+            return Ok(());
+        }
+        if !matches!(size, 4 | 8) || access.offset % size != 0 {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "Invalid MSI-X table access of PCI device {:#x} at {:#x}",
+                    self.bdf, access.offset
+                )
+            );
+        }
+
+        let mut value = 0;
+        for i in 0..size / 4 {
+            let offset = access.offset + i * 4;
+            let (index, n) = (offset / MSIX_ENTRY_SIZE, offset % MSIX_ENTRY_SIZE / 4);
+            if access.is_write {
+                let dword = (access.value >> (i * 32)) as u32;
+                self.write_dword(cell, &mut entries[index], index, n, dword)?;
+            } else {
+                value |= (entries[index][n] as u64) << (i * 32);
+            }
+        }
+        if !access.is_write {
+            access.value = value;
+        }
+        Ok(())
+    }
+
+    /// Program the messages of unmasked entries again.
+    pub fn remap(&self, cell: &Cell) -> HvResult {
+        let entries = self.entries.lock();
+        for (index, entry) in entries.iter().enumerate() {
+            if entry[MSIX_ENTRY_CTRL] & MSIX_ENTRY_MASKED == 0 {
+                self.program(cell, index, entry)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the messages of the cell, without remapping.
+    pub fn restore(&self) {
+        for (index, entry) in self.entries.lock().iter().enumerate() {
+            self.write_msg(index, (entry[1] as u64) << 32 | entry[0] as u64, entry[2]);
+        }
+    }
+}
@@ -8,6 +8,9 @@ use crate::consts::{PER_CPU_ARRAY_PTR, PER_CPU_SIZE};
 use crate::error::HvResult;
 use crate::header::HvHeader;
 use crate::memory::VirtAddr;
+use crate::profile::ProfileBuffer;
+use crate::stats::CpuStats;
+use crate::trace::TraceBuffer;
 
 static ENTERED_CPUS: AtomicU32 = AtomicU32::new(0);
 static ACTIVATED_CPUS: AtomicU32 = AtomicU32::new(0);
@@ -26,7 +29,12 @@ pub struct PerCpu {
     pub id: u32,
     pub state: CpuState,
     pub vcpu: Vcpu,
-    arch: ArchPerCpu,
+    pub stats: CpuStats,
+    pub trace: TraceBuffer,
+    pub profile: ProfileBuffer,
+    pub arch: ArchPerCpu,
+    /// The cell this CPU belongs to, set by `init()`.
+    cell: *const Cell<'static>,
     linux: LinuxContext,
     // Stack will be placed here.
 }
@@ -38,7 +46,7 @@ impl PerCpu {
         }
 
         let cpu_id = ENTERED_CPUS.fetch_add(1, Ordering::SeqCst);
-        let vaddr = PER_CPU_ARRAY_PTR as VirtAddr + cpu_id as usize * PER_CPU_SIZE;
+        let vaddr = Self::vaddr(cpu_id);
         let ret = unsafe { &mut *(vaddr as *mut Self) };
         ret.id = cpu_id;
         ret.self_vaddr = vaddr;
@@ -46,6 +54,15 @@ impl PerCpu {
         Ok(ret)
     }
 
+    fn vaddr(cpu_id: u32) -> VirtAddr {
+        PER_CPU_ARRAY_PTR as VirtAddr + cpu_id as usize * PER_CPU_SIZE
+    }
+
+    /// Get the per-CPU data of another CPU.
+    pub fn from_id<'a>(cpu_id: u32) -> &'a Self {
+        unsafe { &*(Self::vaddr(cpu_id) as *const Self) }
+    }
+
     pub fn current<'a>() -> &'a Self {
         Self::current_mut()
     }
@@ -54,10 +71,19 @@ impl PerCpu {
         unsafe { &mut *(cpu::thread_pointer() as *mut Self) }
     }
 
+    /// The cell this CPU belongs to. Only valid after `init()`.
+    pub fn cell<'a>(&self) -> &'a Cell<'a> {
+        unsafe { &*(self.cell as *const Cell) }
+    }
+
     pub fn stack_top(&self) -> VirtAddr {
         self as *const _ as VirtAddr + PER_CPU_SIZE - 8
     }
 
+    pub fn stack_bottom(&self) -> VirtAddr {
+        self as *const _ as VirtAddr + core::mem::size_of::<Self>()
+    }
+
     pub fn entered_cpus() -> u32 {
         ENTERED_CPUS.load(Ordering::Acquire)
     }
@@ -66,24 +92,77 @@ impl PerCpu {
         ACTIVATED_CPUS.load(Ordering::Acquire)
     }
 
+    /// Reset the CPU counters, called when all CPUs have left the hypervisor.
+    pub fn reset_counters() {
+        ENTERED_CPUS.store(0, Ordering::Release);
+        ACTIVATED_CPUS.store(0, Ordering::Release);
+    }
+
     pub fn init(&mut self, linux_sp: usize, cell: &Cell) -> HvResult {
         info!("CPU {} init...", self.id);
 
         // Save CPU state used for linux.
         self.state = CpuState::HvDisabled;
+        self.cell = (cell as *const Cell).cast();
+        self.stats = CpuStats::new();
+        // The old buffers were dropped when leaving the hypervisor, use
+        // `ptr::write()` to avoid dropping them again. Create both first, so
+        // that none is leaked if the other one fails.
+        let trace = TraceBuffer::new(self.id)?;
+        let profile = ProfileBuffer::new(self.id)?;
+        unsafe { core::ptr::write(&mut self.trace, trace) };
+        unsafe { core::ptr::write(&mut self.profile, profile) };
         self.linux = LinuxContext::load_from(linux_sp);
-        self.arch.init();
 
-        // Activate hypervisor page table on each cpu.
-        unsafe { crate::memory::hv_page_table().read().activate() };
+        // Activate hypervisor page table on each cpu, which also maps the
+        // xAPIC page.
+        unsafe { crate::memory::activate_hv_page_table() };
+        self.arch.init();
 
         // Initialize vCPU. Use `ptr::write()` to avoid dropping
-        unsafe { core::ptr::write(&mut self.vcpu, Vcpu::new(&self.linux, cell)?) };
+        let vcpu = Vcpu::new(&self.linux, cell).map_err(|e| {
+            self.drop_buffers();
+            self.restore_linux();
+            e
+        })?;
+        unsafe { core::ptr::write(&mut self.vcpu, vcpu) };
+        // Sampling is set up in the VMCS on Intel CPUs.
+        self.profile.start();
 
         self.state = CpuState::HvEnabled;
         Ok(())
     }
 
+    /// Roll back `init()` after a failed bring-up, so that this CPU can return
+    /// to the driver with virtualization turned off.
+    pub fn deinit(&mut self) -> HvResult {
+        if self.state == CpuState::HvEnabled {
+            self.profile.stop();
+            self.vcpu.turn_off()?;
+            unsafe { core::ptr::drop_in_place(&mut self.vcpu) };
+            self.drop_buffers();
+            self.state = CpuState::HvDisabled;
+            self.restore_linux();
+        }
+        Ok(())
+    }
+
+    /// Free the buffers allocated by `init()`, before the frame allocator is
+    /// initialized again on the next enabling.
+    fn drop_buffers(&mut self) {
+        unsafe {
+            core::ptr::drop_in_place(&mut self.trace);
+            core::ptr::drop_in_place(&mut self.profile);
+        }
+    }
+
+    fn restore_linux(&mut self) {
+        self.linux.restore();
+        // Reloading segment registers may clobber GS_BASE, but we still need
+        // the per-CPU data until returning from `entry()`.
+        cpu::set_thread_pointer(self.self_vaddr);
+    }
+
     pub fn activate_vmm(&mut self) -> HvResult {
         println!("Activating hypervisor on CPU {}...", self.id);
         ACTIVATED_CPUS.fetch_add(1, Ordering::SeqCst);
@@ -94,13 +173,36 @@ impl PerCpu {
 
     pub fn deactivate_vmm(&mut self, ret_code: usize) -> HvResult {
         println!("Deactivating hypervisor on CPU {}...", self.id);
-        ACTIVATED_CPUS.fetch_sub(1, Ordering::SeqCst);
 
         self.vcpu.set_return_val(ret_code);
         self.vcpu.exit(&mut self.linux)?;
-        self.linux.restore();
+        self.profile.stop();
+
+        // Free VMX/SVM resources of this CPU, only guest registers are needed
+        // to return back to linux.
+        let regs = self.vcpu.regs().clone();
+        unsafe { core::ptr::drop_in_place(&mut self.vcpu) };
+        self.drop_buffers();
         self.state = CpuState::HvDisabled;
-        self.linux.return_to_linux(self.vcpu.regs());
+
+        if ACTIVATED_CPUS.fetch_sub(1, Ordering::SeqCst) == 1 {
+            // The last CPU leaving the hypervisor.
+            crate::stats::print_summary();
+            crate::reset_global_state();
+        }
+
+        self.linux.restore();
+        self.linux.return_to_linux(&regs);
+    }
+
+    /// Send an NMI to this CPU, to force a VM exit if it is running the guest.
+    pub fn send_nmi(&self) -> HvResult {
+        self.arch.send_nmi()
+    }
+
+    /// Send an NMI to this CPU, to be injected to its guest.
+    pub fn send_guest_nmi(&self) -> HvResult {
+        self.arch.send_guest_nmi()
     }
 
     pub fn fault(&mut self) -> HvResult {
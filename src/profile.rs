@@ -0,0 +1,319 @@
+//! Sampling profiler, enabled by the `profile` feature.
+//!
+//! A performance counter overflows every `SAMPLE_PERIOD` cycles spent in the
+//! hypervisor, and its interrupt is delivered as an NMI, which samples the call
+//! chain of the interrupted hypervisor code. The guest RIP is sampled with the
+//! exit reason on each VM exit. Both are counted in per-CPU hash tables, which
+//! can be copied to the guest by the `CpuGetProfile` hypercall and converted
+//! to folded stacks for flamegraphs by `tools/rvm-prof`. The counter is shared
+//! with the guest, and is switched on each VM exit and before each VM entry.
+//!
+//! The dump is a `ProfileHeader`, padded to `PROFILE_HEADER_SIZE` bytes,
+//! followed by `hv_capacity` `HvSample`s and `guest_capacity` `GuestSample`s.
+//! Slots with a zero count are empty. All fields are little-endian.
+
+#![allow(dead_code)]
+
+#[cfg(feature = "profile")]
+pub use _profile::*;
+
+#[cfg(not(feature = "profile"))]
+pub use _profile_empty::*;
+
+const PROFILE_MAGIC: [u8; 8] = *b"RVMPROF\0";
+/// Increased on every incompatible change of the dump format.
+const PROFILE_VERSION: u32 = 1;
+const PROFILE_HEADER_SIZE: usize = 64;
+/// Hypervisor cycles between two samples.
+const SAMPLE_PERIOD: u64 = 100_000;
+/// Maximum number of frames of a sampled call chain.
+const MAX_DEPTH: usize = 14;
+const HV_CAPACITY: usize = 1024;
+const GUEST_CAPACITY: usize = 4096;
+/// Maximum number of slots looked up in a table before dropping a sample.
+const MAX_PROBES: usize = 16;
+
+#[repr(C)]
+pub struct ProfileHeader {
+    magic: [u8; 8],
+    version: u32,
+    cpu_id: u32,
+    /// Value of `SAMPLE_PERIOD`.
+    period: u64,
+    max_depth: u32,
+    hv_capacity: u32,
+    guest_capacity: u32,
+    _padding: u32,
+    /// Number of samples not counted because their table was full.
+    hv_dropped: u64,
+    guest_dropped: u64,
+}
+
+/// A hypervisor call chain and how many times it was sampled.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct HvSample {
+    count: u64,
+    depth: u32,
+    _padding: u32,
+    /// Return addresses, the interrupted RIP first.
+    frames: [u64; MAX_DEPTH],
+}
+
+/// A guest RIP and exit reason, and how many VM exits occurred there.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct GuestSample {
+    count: u64,
+    rip: u64,
+    reason: u32,
+    _padding: u32,
+}
+
+const _: () = assert!(core::mem::size_of::<ProfileHeader>() <= PROFILE_HEADER_SIZE);
+
+/// FNV-1a hash of the given words.
+fn hash(words: impl Iterator<Item = u64>) -> usize {
+    words.fold(0xcbf2_9ce4_8422_2325u64, |h, w| {
+        (h ^ w).wrapping_mul(0x100_0000_01b3)
+    }) as usize
+}
+
+/// Find the slot of `key` in an open-addressing table, or an empty slot to
+/// insert it.
+fn find_slot<T>(
+    table: &mut [T],
+    hash: usize,
+    is_empty: impl Fn(&T) -> bool,
+    matches: impl Fn(&T) -> bool,
+) -> Option<&mut T> {
+    let len = table.len();
+    let idx = (0..MAX_PROBES.min(len))
+        .map(|i| (hash + i) % len)
+        .find(|&i| is_empty(&table[i]) || matches(&table[i]))?;
+    Some(&mut table[idx])
+}
+
+#[cfg(feature = "profile")]
+mod _profile {
+    use core::mem::size_of;
+
+    use super::*;
+    use crate::arch::apic;
+    use crate::arch::vmm::pmu::{self, CounterState};
+    use crate::backtrace::Backtrace;
+    use crate::error::HvResult;
+    use crate::memory::{Frame, PAGE_SIZE};
+    use crate::percpu::PerCpu;
+
+    const PROFILE_SIZE: usize = PROFILE_HEADER_SIZE
+        + HV_CAPACITY * size_of::<HvSample>()
+        + GUEST_CAPACITY * size_of::<GuestSample>();
+
+    pub struct ProfileBuffer {
+        frame: Frame,
+        sampling: bool,
+        /// State of the sampling counter, saved when it is switched out.
+        guest_counter: CounterState,
+        host_counter: CounterState,
+    }
+
+    impl ProfileBuffer {
+        pub fn new(cpu_id: u32) -> HvResult<Self> {
+            let mut frame = Frame::new_contiguous((PROFILE_SIZE + PAGE_SIZE - 1) / PAGE_SIZE, 0)?;
+            frame.zero();
+            let mut ret = Self {
+                frame,
+                sampling: false,
+                guest_counter: CounterState::default(),
+                host_counter: CounterState::default(),
+            };
+            *ret.header_mut() = ProfileHeader {
+                magic: PROFILE_MAGIC,
+                version: PROFILE_VERSION,
+                cpu_id,
+                period: SAMPLE_PERIOD,
+                max_depth: MAX_DEPTH as u32,
+                hv_capacity: HV_CAPACITY as u32,
+                guest_capacity: GUEST_CAPACITY as u32,
+                _padding: 0,
+                hv_dropped: 0,
+                guest_dropped: 0,
+            };
+            Ok(ret)
+        }
+
+        fn header_mut(&mut self) -> &mut ProfileHeader {
+            unsafe { &mut *(self.frame.as_mut_ptr() as *mut ProfileHeader) }
+        }
+
+        fn hv_samples_mut(&mut self) -> &mut [HvSample] {
+            unsafe {
+                let ptr = self.frame.as_mut_ptr().add(PROFILE_HEADER_SIZE) as *mut HvSample;
+                core::slice::from_raw_parts_mut(ptr, HV_CAPACITY)
+            }
+        }
+
+        fn guest_samples_mut(&mut self) -> &mut [GuestSample] {
+            unsafe {
+                let ptr = self
+                    .frame
+                    .as_mut_ptr()
+                    .add(PROFILE_HEADER_SIZE + HV_CAPACITY * size_of::<HvSample>())
+                    as *mut GuestSample;
+                core::slice::from_raw_parts_mut(ptr, GUEST_CAPACITY)
+            }
+        }
+
+        /// Start sampling the hypervisor on the current CPU. Guest RIPs are
+        /// still recorded if it failed.
+        pub fn start(&mut self) {
+            self.guest_counter = CounterState::save();
+            match pmu::sampling_start(SAMPLE_PERIOD).and_then(|_| apic::set_pmi_nmi()) {
+                Ok(_) => {
+                    self.host_counter = CounterState::save();
+                    self.sampling = true;
+                }
+                Err(e) => warn!("Failed to start sampling the hypervisor:\n{:?}", e),
+            }
+            self.guest_counter.restore();
+        }
+
+        /// Stop sampling, and give the counter back to the guest.
+        pub fn stop(&mut self) {
+            if self.sampling {
+                pmu::sampling_stop();
+                self.guest_counter.restore();
+                self.sampling = false;
+            }
+        }
+
+        /// Switch the counter to the hypervisor, called at the beginning of
+        /// the VM exit handler.
+        pub fn exit_begin(&mut self) {
+            if self.sampling {
+                self.guest_counter = CounterState::save();
+                self.host_counter.restore();
+            }
+        }
+
+        /// Switch the counter back to the guest, called at the end of the VM
+        /// exit handler.
+        pub fn exit_end(&mut self) {
+            if self.sampling {
+                self.host_counter = CounterState::save();
+                self.guest_counter.restore();
+            }
+        }
+
+        /// Set the event selector of counter 0 for the guest while it is
+        /// switched out. Returns `false` if it is not.
+        pub fn set_guest_evtsel(&mut self, evtsel: u64) -> bool {
+            if self.sampling {
+                self.guest_counter.set_evtsel(evtsel);
+            }
+            self.sampling
+        }
+
+        fn record_hv(&mut self, frames: &[usize]) {
+            let frames = &frames[..frames.len().min(MAX_DEPTH)];
+            let h = hash(frames.iter().map(|&f| f as u64));
+            let same_chain = |s: &HvSample| {
+                s.depth as usize == frames.len()
+                    && s.frames.iter().zip(frames).all(|(&a, &b)| a == b as u64)
+            };
+            match find_slot(self.hv_samples_mut(), h, |s| s.count == 0, same_chain) {
+                Some(s) => {
+                    if s.count == 0 {
+                        s.depth = frames.len() as u32;
+                        for (a, &b) in s.frames.iter_mut().zip(frames) {
+                            *a = b as u64;
+                        }
+                    }
+                    s.count += 1;
+                }
+                None => self.header_mut().hv_dropped += 1,
+            }
+        }
+
+        /// Count a VM exit at the guest `rip`.
+        pub fn record_exit(&mut self, reason: u32, rip: u64) {
+            let h = hash([rip, reason as u64].into_iter());
+            let same = |s: &GuestSample| s.rip == rip && s.reason == reason;
+            match find_slot(self.guest_samples_mut(), h, |s| s.count == 0, same) {
+                Some(s) => {
+                    s.rip = rip;
+                    s.reason = reason;
+                    s.count += 1;
+                }
+                None => self.header_mut().guest_dropped += 1,
+            }
+        }
+
+        /// The whole buffer in the dump format.
+        pub fn as_bytes(&self) -> &[u8] {
+            &self.frame.as_slice()[..PROFILE_SIZE]
+        }
+    }
+
+    /// Called by the NMI handler with the interrupted RIP and RBP. Returns
+    /// `false` if the NMI was not raised by the profiler.
+    pub fn handle_pmi(rip: usize, rbp: usize) -> bool {
+        let profile = &mut PerCpu::current_mut().profile;
+        if !profile.sampling || !pmu::sampling_overflowed() {
+            return false;
+        }
+        profile.record_hv(Backtrace::from_frame(rip, rbp).frames());
+        pmu::sampling_rearm(SAMPLE_PERIOD);
+        apic::set_pmi_nmi().ok();
+        true
+    }
+}
+
+#[cfg(not(feature = "profile"))]
+mod _profile_empty {
+    use crate::error::HvResult;
+
+    pub struct ProfileBuffer;
+    impl ProfileBuffer {
+        pub fn new(_cpu_id: u32) -> HvResult<Self> {
+            Ok(Self)
+        }
+        pub fn start(&mut self) {}
+        pub fn stop(&mut self) {}
+        pub fn exit_begin(&mut self) {}
+        pub fn exit_end(&mut self) {}
+        pub fn set_guest_evtsel(&mut self, _evtsel: u64) -> bool {
+            false
+        }
+        pub fn record_exit(&mut self, _reason: u32, _rip: u64) {}
+        pub fn as_bytes(&self) -> &[u8] {
+            &[]
+        }
+    }
+
+    pub fn handle_pmi(_rip: usize, _rbp: usize) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_slot() {
+        let mut table = [0u64; 4];
+        // Collisions go to the next empty slots, wrapping around.
+        for key in [5, 9, 13] {
+            *find_slot(&mut table, 3, |&k| k == 0, |&k| k == key).unwrap() = key;
+        }
+        assert_eq!(table, [9, 13, 0, 5]);
+        assert_eq!(
+            find_slot(&mut table, 3, |&k| k == 0, |&k| k == 13).map(|k| *k),
+            Some(13)
+        );
+        table[2] = 1;
+        assert!(find_slot(&mut table, 3, |&k| k == 0, |&k| k == 7).is_none());
+    }
+}
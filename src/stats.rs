@@ -6,9 +6,22 @@ pub use _stats::*;
 #[cfg(not(feature = "stats"))]
 pub use _stats_empty::*;
 
+/// Number of log2 buckets of a `Histogram`. The last one also counts all
+/// larger values.
+const HISTOGRAM_BUCKETS: usize = 32;
+/// Number of distinct exit reasons counted on each CPU.
+const MAX_EXIT_REASONS: usize = 32;
+/// Number of distinct hypercall codes counted on each CPU.
+const MAX_HYPERCALL_CODES: usize = 16;
+
+#[cfg(feature = "stats")]
 mod _stats {
+    use core::fmt::Write;
     use core::sync::atomic::{AtomicU64, Ordering};
 
+    use super::{HISTOGRAM_BUCKETS, MAX_EXIT_REASONS, MAX_HYPERCALL_CODES};
+    use crate::percpu::PerCpu;
+
     #[derive(Default)]
     pub struct StatsValue {
         count: AtomicU64,
@@ -62,6 +75,183 @@ mod _stats {
             Self::now().timestamp - self.timestamp
         }
     }
+
+    /// Count, sum, min, max and log2 buckets of values (e.g. cycles).
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct Histogram {
+        pub(super) count: u64,
+        pub(super) sum: u64,
+        pub(super) min: u64,
+        pub(super) max: u64,
+        /// Bucket `i` counts values in `[2^i, 2^(i+1))`, bucket 0 also counts 0.
+        pub(super) buckets: [u64; HISTOGRAM_BUCKETS],
+    }
+
+    impl Histogram {
+        pub const fn new() -> Self {
+            Self {
+                count: 0,
+                sum: 0,
+                min: u64::MAX,
+                max: 0,
+                buckets: [0; HISTOGRAM_BUCKETS],
+            }
+        }
+
+        pub fn add(&mut self, value: u64) {
+            self.count += 1;
+            self.sum += value;
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+            let idx = (63 - value.max(1).leading_zeros()) as usize;
+            self.buckets[idx.min(HISTOGRAM_BUCKETS - 1)] += 1;
+        }
+
+        pub fn merge(&mut self, other: &Self) {
+            self.count += other.count;
+            self.sum += other.sum;
+            self.min = self.min.min(other.min);
+            self.max = self.max.max(other.max);
+            for (a, b) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+                *a += b;
+            }
+        }
+
+        pub fn count(&self) -> u64 {
+            self.count
+        }
+
+        pub fn as_string(&self) -> alloc::string::String {
+            if self.count == 0 {
+                return "count = 0".into();
+            }
+            let mut s = format!(
+                "count = {}, min = {}, max = {}, average = {}\n   ",
+                self.count,
+                self.min,
+                self.max,
+                self.sum / self.count
+            );
+            for (i, &n) in self.buckets.iter().enumerate().filter(|(_, &n)| n > 0) {
+                write!(s, " [2^{}]={}", i, n).unwrap();
+            }
+            s
+        }
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct StatsEntry<V> {
+        key: u32,
+        value: V,
+    }
+
+    /// A small map from a `u32` key to `V`. New keys are dropped if it is full.
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct KeyedStats<V, const N: usize> {
+        len: u32,
+        dropped: u32,
+        entries: [StatsEntry<V>; N],
+    }
+
+    impl<V: Copy, const N: usize> KeyedStats<V, N> {
+        fn new(init: V) -> Self {
+            Self {
+                len: 0,
+                dropped: 0,
+                entries: [StatsEntry {
+                    key: 0,
+                    value: init,
+                }; N],
+            }
+        }
+
+        fn get_or_insert(&mut self, key: u32, init: V) -> Option<&mut V> {
+            let len = self.len as usize;
+            match self.entries[..len].iter().position(|e| e.key == key) {
+                Some(idx) => Some(&mut self.entries[idx].value),
+                None if len < N => {
+                    self.entries[len] = StatsEntry { key, value: init };
+                    self.len += 1;
+                    Some(&mut self.entries[len].value)
+                }
+                None => {
+                    self.dropped += 1;
+                    None
+                }
+            }
+        }
+
+        fn iter(&self) -> impl Iterator<Item = (u32, &V)> {
+            self.entries[..self.len as usize]
+                .iter()
+                .map(|e| (e.key, &e.value))
+        }
+    }
+
+    /// Per-CPU VM exit statistics, copied to the guest as is by the
+    /// `CpuGetStats` hypercall.
+    #[repr(C)]
+    #[derive(Clone)]
+    pub struct CpuStats {
+        /// Handling cycles of each exit reason.
+        exits: KeyedStats<Histogram, MAX_EXIT_REASONS>,
+        /// Number of calls of each hypercall code.
+        hypercalls: KeyedStats<u64, MAX_HYPERCALL_CODES>,
+    }
+
+    impl CpuStats {
+        pub fn new() -> Self {
+            Self {
+                exits: KeyedStats::new(Histogram::new()),
+                hypercalls: KeyedStats::new(0),
+            }
+        }
+
+        pub fn exit(&mut self, reason: u32, cycles: u64) {
+            if let Some(h) = self.exits.get_or_insert(reason, Histogram::new()) {
+                h.add(cycles);
+            }
+        }
+
+        pub fn hypercall(&mut self, code: u32) {
+            if let Some(n) = self.hypercalls.get_or_insert(code, 0) {
+                *n += 1;
+            }
+        }
+    }
+
+    /// Print VM exit statistics of all CPUs, merged by exit reason.
+    pub fn print_summary() {
+        use crate::arch::vmm::exit_reason_name;
+        let mut exits = KeyedStats::<Histogram, MAX_EXIT_REASONS>::new(Histogram::new());
+        let mut hypercalls = KeyedStats::<u64, MAX_HYPERCALL_CODES>::new(0);
+        println!("VM exit statistics (in cycles):");
+        for cpu_id in 0..PerCpu::entered_cpus() {
+            let stats = &PerCpu::from_id(cpu_id).stats;
+            let mut total = 0;
+            for (reason, h) in stats.exits.iter() {
+                total += h.count();
+                if let Some(m) = exits.get_or_insert(reason, Histogram::new()) {
+                    m.merge(h);
+                }
+            }
+            for (code, &n) in stats.hypercalls.iter() {
+                if let Some(m) = hypercalls.get_or_insert(code, 0) {
+                    *m += n;
+                }
+            }
+            println!("  CPU {}: {} exits", cpu_id, total);
+        }
+        for (reason, h) in exits.iter() {
+            println!("  {}: {}", exit_reason_name(reason), h.as_string());
+        }
+        for (code, n) in hypercalls.iter() {
+            println!("  Hypercall {:#x}: count = {}", code, n);
+        }
+    }
 }
 
 mod _stats_empty {
@@ -84,6 +274,18 @@ mod _stats_empty {
             0
         }
     }
+
+    #[derive(Clone)]
+    pub struct CpuStats;
+    impl CpuStats {
+        pub fn new() -> Self {
+            Self
+        }
+        pub fn exit(&mut self, _reason: u32, _cycles: u64) {}
+        pub fn hypercall(&mut self, _code: u32) {}
+    }
+
+    pub fn print_summary() {}
 }
 
 #[cfg(all(test, feature = "stats"))]
@@ -108,4 +310,18 @@ mod test {
         println!("stats: {}", stats.as_string());
         assert_eq!(c, 3311503426941990459);
     }
+
+    #[test]
+    fn test_histogram() {
+        let mut h = Histogram::new();
+        for v in [0, 1, 3, 4, 1000, 1 << 40] {
+            h.add(v);
+        }
+        assert_eq!((h.count, h.min, h.max), (6, 0, 1 << 40));
+        assert_eq!(h.buckets[0], 2);
+        assert_eq!(h.buckets[1], 1);
+        assert_eq!(h.buckets[2], 1);
+        assert_eq!(h.buckets[9], 1);
+        assert_eq!(h.buckets[HISTOGRAM_BUCKETS - 1], 1);
+    }
 }
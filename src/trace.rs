@@ -0,0 +1,157 @@
+//! Per-CPU binary trace of VM exits, enabled by the `trace` feature.
+//!
+//! Each CPU records the last `TRACE_CAPACITY` exits in a ring buffer, which can
+//! be copied to the guest by the `CpuGetTrace` hypercall and decoded on
+//! the host by `tools/rvm-trace`. The dump is a `TraceHeader`, padded to
+//! `TRACE_HEADER_SIZE` bytes, followed by `TraceHeader::capacity` entries. All
+//! fields are little-endian.
+
+#![allow(dead_code)]
+
+#[cfg(feature = "trace")]
+pub use _trace::*;
+
+#[cfg(not(feature = "trace"))]
+pub use _trace_empty::*;
+
+const TRACE_MAGIC: [u8; 8] = *b"RVMTRACE";
+/// Increased on every incompatible change of the dump format.
+const TRACE_VERSION: u32 = 1;
+const TRACE_HEADER_SIZE: usize = 64;
+/// Number of frames of each trace buffer (header included).
+const TRACE_FRAMES: usize = 32; // 128 KB
+
+#[repr(C)]
+pub struct TraceHeader {
+    magic: [u8; 8],
+    version: u32,
+    cpu_id: u32,
+    /// Size of a `TraceEntry`.
+    entry_size: u32,
+    /// Number of entries in the ring.
+    capacity: u32,
+    /// Total number of recorded exits. The oldest entry is at
+    /// `total % capacity` once the ring has wrapped around.
+    total: u64,
+    /// TSC frequency, to convert cycles to time.
+    tsc_mhz: u32,
+    _padding: u32,
+}
+
+/// A traced VM exit.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct TraceEntry {
+    /// TSC at the beginning of the VM exit handler.
+    pub tsc: u64,
+    /// Guest RIP at the VM exit.
+    pub rip: u64,
+    /// Exit qualification (VMX) or EXITINFO1 (SVM).
+    pub qualification: u64,
+    /// Basic exit reason (VMX) or exit code (SVM).
+    pub reason: u32,
+    /// Cycles spent in the VM exit handler.
+    pub cycles: u32,
+}
+
+const _: () = assert!(core::mem::size_of::<TraceHeader>() <= TRACE_HEADER_SIZE);
+
+#[cfg(feature = "trace")]
+mod _trace {
+    use core::mem::size_of;
+
+    use super::*;
+    use crate::arch::vmm::{Vcpu, VcpuAccessGuestState};
+    use crate::arch::{clocksource, cpu};
+    use crate::error::HvResult;
+    use crate::memory::Frame;
+
+    const TRACE_CAPACITY: usize =
+        (TRACE_FRAMES * crate::memory::PAGE_SIZE - TRACE_HEADER_SIZE) / size_of::<TraceEntry>();
+
+    /// A VM exit being handled.
+    pub struct TraceEvent(TraceEntry);
+
+    impl TraceEvent {
+        pub fn begin(reason: u32, vcpu: &Vcpu) -> Self {
+            Self(TraceEntry {
+                tsc: cpu::current_cycle(),
+                rip: vcpu.instr_pointer(),
+                qualification: vcpu.exit_qualification(),
+                reason,
+                cycles: 0,
+            })
+        }
+    }
+
+    pub struct TraceBuffer {
+        frame: Frame,
+    }
+
+    impl TraceBuffer {
+        pub fn new(cpu_id: u32) -> HvResult<Self> {
+            let mut frame = Frame::new_contiguous(TRACE_FRAMES, 0)?;
+            frame.zero();
+            let mut ret = Self { frame };
+            *ret.header_mut() = TraceHeader {
+                magic: TRACE_MAGIC,
+                version: TRACE_VERSION,
+                cpu_id,
+                entry_size: size_of::<TraceEntry>() as u32,
+                capacity: TRACE_CAPACITY as u32,
+                total: 0,
+                tsc_mhz: clocksource::tsc_khz() / 1000,
+                _padding: 0,
+            };
+            Ok(ret)
+        }
+
+        fn header_mut(&mut self) -> &mut TraceHeader {
+            unsafe { &mut *(self.frame.as_mut_ptr() as *mut TraceHeader) }
+        }
+
+        fn entries_mut(&mut self) -> &mut [TraceEntry] {
+            unsafe {
+                let ptr = self.frame.as_mut_ptr().add(TRACE_HEADER_SIZE) as *mut TraceEntry;
+                core::slice::from_raw_parts_mut(ptr, TRACE_CAPACITY)
+            }
+        }
+
+        pub fn record(&mut self, event: TraceEvent) {
+            let mut entry = event.0;
+            entry.cycles = (cpu::current_cycle() - entry.tsc).min(u32::MAX as u64) as u32;
+            let idx = self.header_mut().total as usize % TRACE_CAPACITY;
+            self.entries_mut()[idx] = entry;
+            self.header_mut().total += 1;
+        }
+
+        /// The whole buffer in the dump format.
+        pub fn as_bytes(&self) -> &[u8] {
+            self.frame.as_slice()
+        }
+    }
+}
+
+#[cfg(not(feature = "trace"))]
+mod _trace_empty {
+    use crate::arch::vmm::Vcpu;
+    use crate::error::HvResult;
+
+    pub struct TraceEvent;
+    impl TraceEvent {
+        pub fn begin(_reason: u32, _vcpu: &Vcpu) -> Self {
+            Self
+        }
+    }
+
+    pub struct TraceBuffer;
+    impl TraceBuffer {
+        pub fn new(_cpu_id: u32) -> HvResult<Self> {
+            Ok(Self)
+        }
+        pub fn record(&mut self, _event: TraceEvent) {}
+        pub fn as_bytes(&self) -> &[u8] {
+            &[]
+        }
+    }
+}
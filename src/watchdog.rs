@@ -0,0 +1,192 @@
+//! Per-cell watchdog.
+//!
+//! The guest starts it by the `WatchdogStart` hypercall, and must call
+//! `WatchdogPing` within the timeout. Otherwise the action set in the cell
+//! config is applied once on the next hypervisor tick of any CPU of the cell,
+//! until the guest pings again.
+//!
+//! Ticks come from the VMX preemption timer or the local APIC timer, which
+//! also expire while the guest is halted or has interrupts disabled, so such a
+//! guest is caught within one tick period after the timeout. Without the VMX
+//! preemption timer, the expiration is only noticed on VM exits of the guest.
+
+use core::convert::TryFrom;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use numeric_enum_macro::numeric_enum;
+
+use crate::arch::{clocksource, cpu};
+use crate::cell::Cell;
+use crate::error::HvResult;
+use crate::percpu::PerCpu;
+
+numeric_enum! {
+    #[repr(u32)]
+    #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+    pub enum WatchdogAction {
+        /// Log an error.
+        Log = 0,
+        /// Inject an NMI to all CPUs of the cell.
+        Nmi = 1,
+        /// Return all CPUs of the cell to Linux, with `ETIMEDOUT` as their last
+        /// error in the crash record. Not for the root cell, whose CPUs would
+        /// leave the hypervisor without the driver disabling it.
+        Stop = 2,
+    }
+}
+
+#[derive(Debug)]
+pub struct Watchdog {
+    action: WatchdogAction,
+    /// In TSC ticks, 0 if not started.
+    timeout: AtomicU64,
+    last_ping: AtomicU64,
+    /// The action was applied since the last ping.
+    expired: AtomicBool,
+    /// The cell was stopped by the `Stop` action. Its CPUs are leaving, and the
+    /// flag goes away with the cell when the last one has left.
+    stopped: AtomicBool,
+}
+
+impl Watchdog {
+    /// A stopped watchdog with `action`, from the config of a cell.
+    pub fn new(action: u32, is_root: bool) -> HvResult<Self> {
+        let action = match WatchdogAction::try_from(action) {
+            Ok(WatchdogAction::Stop) if is_root => {
+                return hv_result_err!(EINVAL, "Watchdog action Stop is invalid for the root cell")
+            }
+            Ok(action) => action,
+            Err(_) => return hv_result_err!(EINVAL, format!("Invalid watchdog action {}", action)),
+        };
+        Ok(Self {
+            action,
+            timeout: AtomicU64::new(0),
+            last_ping: AtomicU64::new(0),
+            expired: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+        })
+    }
+
+    /// Start the watchdog with a timeout in milliseconds, or stop it if
+    /// `timeout_ms` is 0.
+    pub fn start(&self, timeout_ms: u64) {
+        let timeout = clocksource::nanos_to_ticks(timeout_ms.saturating_mul(1_000_000));
+        self.start_at(timeout, cpu::current_cycle());
+    }
+
+    fn start_at(&self, timeout: u64, now: u64) {
+        self.timeout.store(0, Ordering::Release);
+        self.ping_at(now);
+        self.timeout.store(timeout, Ordering::Release);
+    }
+
+    pub fn ping(&self) -> HvResult {
+        if self.timeout.load(Ordering::Acquire) == 0 {
+            return hv_result_err!(ENODEV, "Watchdog is not started!");
+        }
+        self.ping_at(cpu::current_cycle());
+        Ok(())
+    }
+
+    fn ping_at(&self, now: u64) {
+        self.last_ping.store(now, Ordering::Release);
+        self.expired.store(false, Ordering::Release);
+    }
+
+    /// The action to apply if the watchdog has just expired.
+    fn check_at(&self, now: u64) -> Option<WatchdogAction> {
+        let timeout = self.timeout.load(Ordering::Acquire);
+        let last_ping = self.last_ping.load(Ordering::Acquire);
+        if timeout == 0 || now.saturating_sub(last_ping) <= timeout {
+            return None;
+        }
+        self.expired
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .ok()?;
+        Some(self.action)
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Acquire)
+    }
+}
+
+/// Send NMIs to the other CPUs of the cell, which get them as VM exits. They
+/// are injected to the guest if `for_guest` is true.
+fn nmi_other_cpus(cell: &Cell, cpu_id: u32, for_guest: bool) {
+    for id in (0..PerCpu::entered_cpus()).filter(|&id| id != cpu_id && cell.owns_cpu(id)) {
+        let cpu_data = PerCpu::from_id(id);
+        let res = if for_guest {
+            cpu_data.send_guest_nmi()
+        } else {
+            cpu_data.send_nmi()
+        };
+        if let Err(e) = res {
+            error!("Failed to send NMI to CPU {}: {:?}", id, e);
+        }
+    }
+}
+
+/// Check the watchdog of the cell of this CPU, called on each hypervisor tick.
+pub fn tick(cpu_data: &mut PerCpu) {
+    let cell = cpu_data.cell();
+    let action = match cell.watchdog.check_at(cpu::current_cycle()) {
+        Some(action) => action,
+        None => return,
+    };
+    error!(
+        "Watchdog expired on CPU {}, action: {:?}",
+        cpu_data.id, action
+    );
+    match action {
+        WatchdogAction::Log => {}
+        WatchdogAction::Nmi => {
+            cpu_data.vcpu.queue_nmi();
+            nmi_other_cpus(cell, cpu_data.id, true);
+        }
+        WatchdogAction::Stop => {
+            cell.watchdog.stopped.store(true, Ordering::Release);
+            // The other CPUs leave on the resulting VM exits.
+            nmi_other_cpus(cell, cpu_data.id, false);
+        }
+    }
+}
+
+/// Return this CPU back to linux if its cell was stopped by the watchdog.
+/// Called at the end of each VM exit handler.
+pub fn check_stop(cpu_data: &mut PerCpu) {
+    // If we can't leave now, resume the guest and retry on the next VM exit.
+    if cpu_data.cell().watchdog.is_stopped() && crate::lang::can_leave(cpu_data) {
+        error!("CPU {} stopped by the watchdog.", cpu_data.id);
+        let err = hv_err!(ETIMEDOUT, "Watchdog expired");
+        let err = crate::lang::leave_on_error(cpu_data, err);
+        error!("Failed to return back to linux: {:?}", err);
+        loop {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchdog() {
+        let wd = Watchdog::new(WatchdogAction::Nmi as u32, true).unwrap();
+        assert_eq!(wd.check_at(u64::MAX), None);
+        wd.start_at(100, 1000);
+        assert_eq!(wd.check_at(1100), None);
+        assert_eq!(wd.check_at(1101), Some(WatchdogAction::Nmi));
+        // Applied once until the next ping.
+        assert_eq!(wd.check_at(1200), None);
+        wd.ping_at(1200);
+        assert_eq!(wd.check_at(1250), None);
+        assert_eq!(wd.check_at(1400), Some(WatchdogAction::Nmi));
+        wd.start_at(0, 1400);
+        assert_eq!(wd.check_at(u64::MAX), None);
+        assert!(Watchdog::new(3, false).is_err());
+        assert!(Watchdog::new(WatchdogAction::Stop as u32, true).is_err());
+        assert!(Watchdog::new(WatchdogAction::Stop as u32, false).is_ok());
+    }
+}
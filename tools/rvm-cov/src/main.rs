@@ -0,0 +1,217 @@
+//! Convert coverage dumps from the `CoverageGet` hypercall to lcov reports.
+//!
+//! Usage: `rvm-cov [-o <report.lcov>] [--profraw <file>] <dump> <rvm-elf>`
+//!
+//! The dump (see `src/coverage.rs`) is converted to a LLVM raw profile, which
+//! is indexed by `llvm-profdata` and exported as lcov by `llvm-cov` with the
+//! coverage mapping of the instrumented ELF file. Both tools must be of the
+//! same LLVM version as the compiler; they can be overridden by the
+//! `LLVM_PROFDATA` and `LLVM_COV` environment variables.
+
+use std::io::{self, Write};
+use std::process::Command;
+
+const COVERAGE_MAGIC: &[u8; 8] = b"RVMCOVER";
+const COVERAGE_VERSION: u32 = 1;
+const COVERAGE_HEADER_SIZE: usize = 72;
+
+const PROFRAW_MAGIC: u64 = 0xff6c_7072_6f66_7281;
+const VARIANT_MASKS_ALL: u64 = 0xff00_0000_0000_0000;
+const VARIANT_MASK_BYTE_COVERAGE: u64 = 1 << 60;
+/// Size of a `__llvm_prf_data` record, the same in all supported versions.
+const PROF_DATA_SIZE: u64 = 48;
+/// `IPVK_Last`, the last kind of value profiling.
+const VALUE_KIND_LAST: u64 = 1;
+
+/// Supported raw profile versions (LLVM 11 to 14).
+const MIN_RAW_VERSION: u64 = 5;
+const MAX_RAW_VERSION: u64 = 8;
+
+fn u32_at(buf: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(buf[off..off + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(buf[off..off + 8].try_into().unwrap())
+}
+
+fn padding(size: u64) -> u64 {
+    (8 - size % 8) % 8
+}
+
+/// Build a raw profile, as written by the profiler runtime, from a dump.
+fn to_profraw(dump: &[u8]) -> Result<Vec<u8>, String> {
+    if dump.len() < COVERAGE_HEADER_SIZE || &dump[..8] != COVERAGE_MAGIC {
+        return Err("not a RVM coverage dump".into());
+    }
+    let version = u32_at(dump, 8);
+    if version != COVERAGE_VERSION {
+        return Err(format!("unsupported dump version {}", version));
+    }
+    let raw_version = u64_at(dump, 16);
+    let [data_addr, data_size, cnts_addr, cnts_size, names_addr, names_size] =
+        [24, 32, 40, 48, 56, 64].map(|off| u64_at(dump, off));
+    let sections = data_size + cnts_size + names_size;
+    if (dump.len() - COVERAGE_HEADER_SIZE) as u64 != sections {
+        return Err("truncated coverage dump".into());
+    }
+    if data_size == 0 {
+        return Err("empty coverage dump, is the hypervisor instrumented?".into());
+    }
+
+    let prof_version = raw_version & !VARIANT_MASKS_ALL;
+    if !(MIN_RAW_VERSION..=MAX_RAW_VERSION).contains(&prof_version) {
+        return Err(format!("unsupported raw profile version {}", prof_version));
+    }
+    let counter_size = if prof_version >= 8 && raw_version & VARIANT_MASK_BYTE_COVERAGE != 0 {
+        1
+    } else {
+        8
+    };
+    let counters_delta = if prof_version >= 7 {
+        // Counter pointers in data records are relative since version 7.
+        cnts_addr.wrapping_sub(data_addr)
+    } else {
+        cnts_addr
+    };
+
+    let mut header = vec![PROFRAW_MAGIC, raw_version];
+    if prof_version >= 6 {
+        header.push(0); // BinaryIdsSize
+    }
+    header.extend([
+        data_size / PROF_DATA_SIZE,
+        0, // PaddingBytesBeforeCounters
+        cnts_size / counter_size,
+        padding(cnts_size),
+        names_size,
+        counters_delta,
+        names_addr,
+        VALUE_KIND_LAST,
+    ]);
+
+    let mut out: Vec<u8> = header.iter().flat_map(|v| v.to_le_bytes()).collect();
+    let mut offset = COVERAGE_HEADER_SIZE;
+    for size in [data_size, cnts_size, names_size] {
+        out.extend_from_slice(&dump[offset..offset + size as usize]);
+        out.resize(out.len() + padding(size) as usize, 0);
+        offset += size as usize;
+    }
+    Ok(out)
+}
+
+fn run(cmd: &mut Command) -> Result<Vec<u8>, String> {
+    let output = cmd
+        .output()
+        .map_err(|e| format!("failed to run {:?}: {}", cmd.get_program(), e))?;
+    if !output.status.success() {
+        io::stderr().write_all(&output.stderr).ok();
+        return Err(format!(
+            "{:?} exited with {}",
+            cmd.get_program(),
+            output.status
+        ));
+    }
+    Ok(output.stdout)
+}
+
+fn convert(dump: &str, elf: &str, profraw: &str, output: Option<&str>) -> Result<(), String> {
+    let buf = std::fs::read(dump).map_err(|e| format!("{}: {}", dump, e))?;
+    let raw = to_profraw(&buf).map_err(|e| format!("{}: {}", dump, e))?;
+    std::fs::write(profraw, raw).map_err(|e| format!("{}: {}", profraw, e))?;
+
+    let profdata = format!("{}.profdata", profraw.trim_end_matches(".profraw"));
+    let llvm_profdata = std::env::var("LLVM_PROFDATA").unwrap_or_else(|_| "llvm-profdata".into());
+    let llvm_cov = std::env::var("LLVM_COV").unwrap_or_else(|_| "llvm-cov".into());
+    run(Command::new(llvm_profdata)
+        .args(["merge", "-sparse", profraw, "-o"])
+        .arg(&profdata))?;
+    let lcov = run(Command::new(llvm_cov)
+        .args(["export", "-format=lcov", "-instr-profile"])
+        .args([&profdata, elf]))?;
+
+    match output {
+        Some(path) => std::fs::write(path, lcov).map_err(|e| format!("{}: {}", path, e)),
+        None => io::stdout().write_all(&lcov).map_err(|e| e.to_string()),
+    }
+}
+
+fn main() {
+    let mut output = None;
+    let mut profraw = None;
+    let mut files = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = args.next(),
+            "--profraw" => profraw = args.next(),
+            _ => files.push(arg),
+        }
+    }
+    if files.len() != 2 {
+        eprintln!("Usage: rvm-cov [-o <report.lcov>] [--profraw <file>] <dump> <rvm-elf>");
+        std::process::exit(1);
+    }
+
+    let profraw = profraw.unwrap_or_else(|| format!("{}.profraw", files[0]));
+    if let Err(e) = convert(&files[0], &files[1], &profraw, output.as_deref()) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dump(raw_version: u64, sections: [&[u8]; 3]) -> Vec<u8> {
+        let mut buf = COVERAGE_MAGIC.to_vec();
+        buf.extend(COVERAGE_VERSION.to_le_bytes());
+        buf.extend(0u32.to_le_bytes());
+        buf.extend(raw_version.to_le_bytes());
+        let mut addr = 0xffff_ff00_0010_0000u64;
+        for s in sections {
+            buf.extend(addr.to_le_bytes());
+            buf.extend((s.len() as u64).to_le_bytes());
+            addr += s.len() as u64;
+        }
+        for s in sections {
+            buf.extend_from_slice(s);
+        }
+        buf
+    }
+
+    #[test]
+    fn test_profraw_layout() {
+        let data = [1u8; 96];
+        let cnts = [2u8; 24];
+        let names = [3u8; 5];
+        let raw = to_profraw(&dump(8 | 1 << 56, [&data, &cnts, &names])).unwrap();
+        let header: Vec<u64> = (0..11).map(|i| u64_at(&raw, i * 8)).collect();
+        assert_eq!(
+            header,
+            [
+                PROFRAW_MAGIC,
+                8 | 1 << 56,
+                0,
+                2,
+                0,
+                3,
+                0,
+                5,
+                96,
+                0xffff_ff00_0010_0078,
+                1
+            ]
+        );
+        assert_eq!(raw.len(), 11 * 8 + 96 + 24 + 8);
+        assert_eq!(&raw[88 + 96 + 24..][..6], &[3, 3, 3, 3, 3, 0]);
+
+        // No binary IDs, and absolute counter pointers before version 7.
+        let raw = to_profraw(&dump(5, [&data, &cnts, &names])).unwrap();
+        assert_eq!(u64_at(&raw, 2 * 8), 2);
+        assert_eq!(u64_at(&raw, 7 * 8), 0xffff_ff00_0010_0060);
+        assert!(to_profraw(&dump(4, [&data, &cnts, &names])).is_err());
+        assert!(to_profraw(&dump(8, [&[], &[], &[]])).is_err());
+    }
+}
@@ -0,0 +1,273 @@
+//! Print the crash record of RVM (see `src/crash.rs`).
+//!
+//! Usage: `rvm-crash [--mark-read] <file> [offset]`
+//!
+//! The record is kept in the last `CRASH_RECORD_SIZE` bytes of the hypervisor
+//! memory, also by the patched driver when it enables RVM again. Read it from
+//! `/dev/mem` at `hypervisor_memory.phys_start + hypervisor_memory.size -
+//! CRASH_RECORD_SIZE` after `jailhouse disable`, or from a dump of these bytes.
+//! `--mark-read` sets `read` in the record, so the next enable resets it.
+
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+const CRASH_RECORD_MAGIC: &[u8; 8] = b"RVMCRASH";
+const CRASH_RECORD_VERSION: u32 = 2;
+/// Must be the same as `consts::CRASH_RECORD_SIZE`.
+const CRASH_RECORD_SIZE: usize = 64 * 1024;
+
+const EXIT_HISTORY_LEN: usize = 32;
+const LOG_TAIL_SIZE: usize = 16 * 1024;
+const NO_CPU: u32 = u32::MAX;
+
+/// Offsets in `CrashRecord`.
+mod off {
+    pub const READ: usize = 24;
+    pub const PANIC_TIME_NS: usize = 32;
+    pub const PANIC_LOCATION: usize = 40;
+    pub const PANIC_MESSAGE: usize = 176;
+    pub const PANIC_MESSAGE_SIZE: usize = 1024;
+    pub const CPUS: usize = 1200;
+    pub const LOG_POS: usize = 47280;
+    pub const LOG_TAIL: usize = 47288;
+
+    /// Offsets in `CpuRecord`.
+    pub const CPU_SIZE: usize = 720;
+    pub const CPU_REGS: usize = 8;
+    pub const CPU_RIP: usize = 136;
+    pub const CPU_EXIT_COUNT: usize = 184;
+    pub const CPU_EXIT_REASONS: usize = 192;
+    pub const CPU_ERROR_CODE: usize = 320;
+    pub const CPU_ERROR_LOCATION: usize = 328;
+    pub const CPU_ERROR_MESSAGE: usize = 464;
+    pub const CPU_ERROR_MESSAGE_SIZE: usize = 256;
+
+    /// Size of `Location`, the file name and the line and column.
+    pub const FILE_NAME_SIZE: usize = 128;
+}
+
+/// Saved general registers, in the order of `GeneralRegisters`.
+const REG_NAMES: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15",
+];
+/// System registers following the general registers.
+const SYS_REG_NAMES: [&str; 6] = ["rip", "rsp", "rflags", "cr0", "cr3", "cr4"];
+
+fn u32_at(buf: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(buf[off..off + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(buf[off..off + 8].try_into().unwrap())
+}
+
+/// NUL-padded string at `off`.
+fn str_at(buf: &[u8], off: usize, size: usize) -> String {
+    let bytes = &buf[off..off + size];
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(size);
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
+
+fn location_at(buf: &[u8], off: usize) -> String {
+    let file = off::FILE_NAME_SIZE;
+    format!(
+        "{}:{}:{}",
+        str_at(buf, off, file),
+        u32_at(buf, off + file),
+        u32_at(buf, off + file + 4)
+    )
+}
+
+fn check(buf: &[u8]) -> Result<(), String> {
+    if buf.len() < CRASH_RECORD_SIZE || &buf[..8] != CRASH_RECORD_MAGIC {
+        return Err("not a RVM crash record".into());
+    }
+    let version = u32_at(buf, 8);
+    if version != CRASH_RECORD_VERSION {
+        return Err(format!("unsupported crash record version {}", version));
+    }
+    Ok(())
+}
+
+fn write_cpu(out: &mut String, buf: &[u8], id: usize) {
+    let cpu = off::CPUS + id * off::CPU_SIZE;
+    let exit_count = u64_at(buf, cpu + off::CPU_EXIT_COUNT);
+    let dumped = u32_at(buf, cpu) != 0;
+    let error = u32_at(buf, cpu + off::CPU_ERROR_CODE) as i32;
+    if exit_count == 0 && !dumped && error == 0 {
+        return;
+    }
+
+    writeln!(out, "CPU {}: {} exits", id, exit_count).unwrap();
+    if exit_count > 0 {
+        // The newest first.
+        let count = exit_count.min(EXIT_HISTORY_LEN as u64);
+        let reasons = (1..=count)
+            .map(|i| {
+                let idx = ((exit_count - i) % EXIT_HISTORY_LEN as u64) as usize;
+                format!("{:#x}", u32_at(buf, cpu + off::CPU_EXIT_REASONS + idx * 4))
+            })
+            .collect::<Vec<_>>();
+        writeln!(out, "  last exit reasons: {}", reasons.join(" ")).unwrap();
+    }
+    if error != 0 {
+        writeln!(
+            out,
+            "  last error: {} at {}: {}",
+            error,
+            location_at(buf, cpu + off::CPU_ERROR_LOCATION),
+            str_at(
+                buf,
+                cpu + off::CPU_ERROR_MESSAGE,
+                off::CPU_ERROR_MESSAGE_SIZE
+            )
+        )
+        .unwrap();
+    }
+    if dumped {
+        let regs = REG_NAMES
+            .iter()
+            .enumerate()
+            .filter(|(_, name)| !name.is_empty())
+            .map(|(i, name)| (*name, u64_at(buf, cpu + off::CPU_REGS + i * 8)));
+        let sys_regs = SYS_REG_NAMES
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (*name, u64_at(buf, cpu + off::CPU_RIP + i * 8)));
+        for (i, (name, value)) in sys_regs.chain(regs).enumerate() {
+            let sep = if i % 4 == 3 { "\n" } else { " " };
+            write!(out, "  {:>6}={:#018x}{}", name, value, sep).unwrap();
+        }
+        if !out.ends_with('\n') {
+            out.push('\n');
+        }
+    }
+}
+
+fn write_text(out: &mut String, buf: &[u8]) {
+    let panic_cpu = u32_at(buf, 16);
+    let num_cpus = u32_at(buf, 20) as usize;
+    if panic_cpu == NO_CPU {
+        writeln!(out, "No panic recorded.").unwrap();
+    } else {
+        writeln!(
+            out,
+            "Panic on CPU {} at {:.6}s ({}):\n{}",
+            panic_cpu,
+            u64_at(buf, off::PANIC_TIME_NS) as f64 / 1e9,
+            location_at(buf, off::PANIC_LOCATION),
+            str_at(buf, off::PANIC_MESSAGE, off::PANIC_MESSAGE_SIZE)
+        )
+        .unwrap();
+    }
+    if u32_at(buf, off::READ) != 0 {
+        writeln!(out, "(Already marked as read.)").unwrap();
+    }
+
+    for id in 0..num_cpus {
+        write_cpu(out, buf, id);
+    }
+
+    let log_pos = u64_at(buf, off::LOG_POS);
+    let tail = &buf[off::LOG_TAIL..off::LOG_TAIL + LOG_TAIL_SIZE];
+    let log = if log_pos > LOG_TAIL_SIZE as u64 {
+        let start = (log_pos % LOG_TAIL_SIZE as u64) as usize;
+        [&tail[start..], &tail[..start]].concat()
+    } else {
+        tail[..log_pos as usize].to_vec()
+    };
+    writeln!(out, "Log tail:\n{}", String::from_utf8_lossy(&log)).unwrap();
+}
+
+fn read_record(path: &str, offset: u64) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buf = vec![0; CRASH_RECORD_SIZE];
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn mark_read(path: &str, offset: u64) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.seek(SeekFrom::Start(offset + off::READ as u64))?;
+    file.write_all(&1u32.to_le_bytes())
+}
+
+fn main() {
+    let mut mark = false;
+    let mut args = Vec::new();
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--mark-read" => mark = true,
+            _ => args.push(arg),
+        }
+    }
+    let offset = match args.get(1).map(|s| s.trim_start_matches("0x")) {
+        Some(s) => u64::from_str_radix(s, 16).ok(),
+        None => Some(0),
+    };
+    let (path, offset) = match (args.len(), offset) {
+        (1 | 2, Some(offset)) => (&args[0], offset),
+        _ => {
+            eprintln!("Usage: rvm-crash [--mark-read] <file> [offset]");
+            std::process::exit(1);
+        }
+    };
+
+    let buf = read_record(path, offset)
+        .map_err(|e| e.to_string())
+        .and_then(|buf| check(&buf).map(|_| buf));
+    let buf = match buf {
+        Ok(buf) => buf,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+    let mut out = String::new();
+    write_text(&mut out, &buf);
+    io::stdout().write_all(out.as_bytes()).unwrap();
+
+    if mark {
+        if let Err(e) = mark_read(path, offset) {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_text() {
+        let mut buf = vec![0; CRASH_RECORD_SIZE];
+        buf[..8].copy_from_slice(CRASH_RECORD_MAGIC);
+        buf[8..12].copy_from_slice(&CRASH_RECORD_VERSION.to_le_bytes());
+        buf[16..20].copy_from_slice(&1u32.to_le_bytes());
+        buf[20..24].copy_from_slice(&2u32.to_le_bytes());
+        buf[off::PANIC_LOCATION..off::PANIC_LOCATION + 9].copy_from_slice(b"src/x.rs\0");
+        buf[off::PANIC_MESSAGE..off::PANIC_MESSAGE + 4].copy_from_slice(b"oops");
+        let cpu = off::CPUS + off::CPU_SIZE;
+        buf[cpu..cpu + 4].copy_from_slice(&1u32.to_le_bytes());
+        buf[cpu + off::CPU_RIP..cpu + off::CPU_RIP + 8].copy_from_slice(&0x1234u64.to_le_bytes());
+        buf[cpu + off::CPU_EXIT_COUNT] = 2;
+        buf[cpu + off::CPU_EXIT_REASONS] = 0x12;
+        buf[cpu + off::CPU_EXIT_REASONS + 4] = 0x30;
+        buf[off::LOG_POS] = 3;
+        buf[off::LOG_TAIL..off::LOG_TAIL + 3].copy_from_slice(b"log");
+        assert!(check(&buf).is_ok());
+        assert!(check(&buf[..CRASH_RECORD_SIZE - 1]).is_err());
+
+        let mut out = String::new();
+        write_text(&mut out, &buf);
+        assert!(out.starts_with("Panic on CPU 1 at 0.000000s (src/x.rs:0:0):\noops\n"));
+        assert!(!out.contains("CPU 0:"));
+        assert!(out.contains("CPU 1: 2 exits\n  last exit reasons: 0x30 0x12\n"));
+        assert!(out.contains("rip=0x0000000000001234"));
+        assert!(out.ends_with("Log tail:\nlog\n"));
+    }
+}
@@ -0,0 +1,216 @@
+//! Convert profiler samples dumped by the `CpuGetProfile` hypercall to folded
+//! stacks, which can be rendered by `flamegraph.pl` or speedscope.
+//!
+//! Usage: `rvm-prof [--symbols <rvm.sym>] [--guest-symbols <kallsyms>] <dump>...`
+//!
+//! Each dump is the profile buffer of one CPU (see `src/profile.rs`), samples
+//! of all CPUs are merged. Hypervisor call chains are rooted at `rvm`, and are
+//! symbolized by the `nm -n -C` output of the build (`rvm.sym`). Guest samples
+//! are rooted at `guest`, followed by the guest function and the exit reason,
+//! and are symbolized by a `/proc/kallsyms` copy of the guest.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+const PROFILE_MAGIC: &[u8; 8] = b"RVMPROF\0";
+const PROFILE_VERSION: u32 = 1;
+const PROFILE_HEADER_SIZE: usize = 64;
+const GUEST_SAMPLE_SIZE: usize = 24;
+
+#[derive(Debug, Default)]
+struct Profile {
+    /// Call chains, innermost first, with their counts.
+    hv_samples: Vec<(Vec<u64>, u64)>,
+    /// Guest RIPs and exit reasons, with their counts.
+    guest_samples: Vec<(u64, u32, u64)>,
+    hv_dropped: u64,
+    guest_dropped: u64,
+}
+
+/// Function symbols sorted by address.
+#[derive(Default)]
+struct Symbols(Vec<(u64, String)>);
+
+fn u32_at(buf: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(buf[off..off + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(buf[off..off + 8].try_into().unwrap())
+}
+
+fn parse(buf: &[u8], profile: &mut Profile) -> Result<(), String> {
+    if buf.len() < PROFILE_HEADER_SIZE || &buf[..8] != PROFILE_MAGIC {
+        return Err("not a RVM profile buffer".into());
+    }
+    let version = u32_at(buf, 8);
+    if version != PROFILE_VERSION {
+        return Err(format!("unsupported profile version {}", version));
+    }
+    let max_depth = u32_at(buf, 24) as usize;
+    let hv_capacity = u32_at(buf, 28) as usize;
+    let guest_capacity = u32_at(buf, 32) as usize;
+    let hv_sample_size = 16 + 8 * max_depth;
+    let guest_start = PROFILE_HEADER_SIZE + hv_capacity * hv_sample_size;
+    if buf.len() < guest_start + guest_capacity * GUEST_SAMPLE_SIZE {
+        return Err("truncated profile buffer".into());
+    }
+    profile.hv_dropped += u64_at(buf, 40);
+    profile.guest_dropped += u64_at(buf, 48);
+
+    for i in 0..hv_capacity {
+        let off = PROFILE_HEADER_SIZE + i * hv_sample_size;
+        let count = u64_at(buf, off);
+        let depth = (u32_at(buf, off + 8) as usize).min(max_depth);
+        if count != 0 {
+            let frames = (0..depth).map(|j| u64_at(buf, off + 16 + j * 8));
+            profile.hv_samples.push((frames.collect(), count));
+        }
+    }
+    for i in 0..guest_capacity {
+        let off = guest_start + i * GUEST_SAMPLE_SIZE;
+        let count = u64_at(buf, off);
+        if count != 0 {
+            profile
+                .guest_samples
+                .push((u64_at(buf, off + 8), u32_at(buf, off + 16), count));
+        }
+    }
+    Ok(())
+}
+
+impl Symbols {
+    /// Parse lines of `<address> <type> <name>`, as printed by `nm` and
+    /// `/proc/kallsyms`. Only function symbols are kept.
+    fn parse(text: &str) -> Self {
+        let mut syms: Vec<(u64, String)> = text
+            .lines()
+            .filter_map(|line| {
+                let mut it = line.splitn(3, ' ');
+                let addr = u64::from_str_radix(it.next()?, 16).ok()?;
+                let ty = it.next()?;
+                let name = it.next()?.trim();
+                matches!(ty, "t" | "T" | "w" | "W").then(|| (addr, name.replace(';', ":")))
+            })
+            .collect();
+        syms.sort_by_key(|s| s.0);
+        Self(syms)
+    }
+
+    fn name(&self, addr: u64) -> String {
+        match self.0.partition_point(|s| s.0 <= addr) {
+            0 => format!("{:#x}", addr),
+            i => self.0[i - 1].1.clone(),
+        }
+    }
+}
+
+fn fold(profile: &Profile, hv_syms: &Symbols, guest_syms: &Symbols) -> String {
+    let mut stacks = BTreeMap::<String, u64>::new();
+    for (frames, count) in &profile.hv_samples {
+        let mut stack = String::from("rvm");
+        for (i, &addr) in frames.iter().enumerate().rev() {
+            // Return addresses may be the start of the next function.
+            let pc = if i == 0 { addr } else { addr - 1 };
+            write!(stack, ";{}", hv_syms.name(pc)).unwrap();
+        }
+        *stacks.entry(stack).or_default() += count;
+    }
+    for &(rip, reason, count) in &profile.guest_samples {
+        let stack = format!("guest;{};exit_{:#x}", guest_syms.name(rip), reason);
+        *stacks.entry(stack).or_default() += count;
+    }
+    let mut out = String::new();
+    for (stack, count) in stacks {
+        writeln!(out, "{} {}", stack, count).unwrap();
+    }
+    out
+}
+
+fn read_symbols(path: Option<&str>) -> Symbols {
+    match path.map(std::fs::read_to_string) {
+        Some(Ok(text)) => Symbols::parse(&text),
+        Some(Err(e)) => {
+            eprintln!("{}: {}", path.unwrap(), e);
+            std::process::exit(1);
+        }
+        None => Symbols::default(),
+    }
+}
+
+fn main() {
+    let mut hv_syms = None;
+    let mut guest_syms = None;
+    let mut files = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--symbols" => hv_syms = args.next(),
+            "--guest-symbols" => guest_syms = args.next(),
+            _ => files.push(arg),
+        }
+    }
+    if files.is_empty() {
+        eprintln!("Usage: rvm-prof [--symbols <rvm.sym>] [--guest-symbols <kallsyms>] <dump>...");
+        std::process::exit(1);
+    }
+
+    let mut profile = Profile::default();
+    for file in &files {
+        let res = std::fs::read(file)
+            .map_err(|e| e.to_string())
+            .and_then(|buf| parse(&buf, &mut profile));
+        if let Err(e) = res {
+            eprintln!("{}: {}", file, e);
+            std::process::exit(1);
+        }
+    }
+    if profile.hv_dropped + profile.guest_dropped > 0 {
+        eprintln!(
+            "warning: {} hypervisor and {} guest samples were dropped",
+            profile.hv_dropped, profile.guest_dropped
+        );
+    }
+
+    let out = fold(
+        &profile,
+        &read_symbols(hv_syms.as_deref()),
+        &read_symbols(guest_syms.as_deref()),
+    );
+    io::stdout().write_all(out.as_bytes()).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fold() {
+        let max_depth = 2;
+        let hv_sample_size = 16 + 8 * max_depth;
+        let mut buf = vec![0; PROFILE_HEADER_SIZE + 2 * hv_sample_size + GUEST_SAMPLE_SIZE];
+        buf[..8].copy_from_slice(PROFILE_MAGIC);
+        buf[8..12].copy_from_slice(&PROFILE_VERSION.to_le_bytes());
+        buf[24..28].copy_from_slice(&(max_depth as u32).to_le_bytes());
+        buf[28..32].copy_from_slice(&2u32.to_le_bytes());
+        buf[32..36].copy_from_slice(&1u32.to_le_bytes());
+        // The second hypervisor slot: `leaf` called by `root`.
+        let off = PROFILE_HEADER_SIZE + hv_sample_size;
+        buf[off..off + 8].copy_from_slice(&3u64.to_le_bytes());
+        buf[off + 8..off + 12].copy_from_slice(&2u32.to_le_bytes());
+        buf[off + 16..off + 24].copy_from_slice(&0x2010u64.to_le_bytes());
+        buf[off + 24..off + 32].copy_from_slice(&0x1100u64.to_le_bytes());
+        let off = PROFILE_HEADER_SIZE + 2 * hv_sample_size;
+        buf[off..off + 8].copy_from_slice(&7u64.to_le_bytes());
+        buf[off + 8..off + 16].copy_from_slice(&0x5000u64.to_le_bytes());
+        buf[off + 16..off + 20].copy_from_slice(&10u32.to_le_bytes());
+
+        let mut profile = Profile::default();
+        parse(&buf, &mut profile).unwrap();
+        let hv_syms = Symbols::parse("0000000000001000 T root\n0000000000002000 t leaf\n");
+        let folded = fold(&profile, &hv_syms, &Symbols::default());
+        assert_eq!(folded, "guest;0x5000;exit_0xa 7\nrvm;root;leaf 3\n");
+        assert!(parse(&buf[..PROFILE_HEADER_SIZE], &mut profile).is_err());
+    }
+}
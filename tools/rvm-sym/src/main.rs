@@ -0,0 +1,124 @@
+//! Generate the symbol table of RVM for backtraces from the `nm -n -C` output
+//! of the linked hypervisor. `make elf` writes it into the `.symbols` section
+//! of the same ELF file, so the addresses always match.
+//!
+//! Usage: `rvm-sym <rvm.sym> <symbols.bin>`
+//!
+//! Layout (little-endian): the number of symbols `n: u32`, then `n` entries of
+//! `(addr - HV_BASE: u32, name_offset: u32)` sorted by address, then the names,
+//! zero-padded to `SYMBOL_TABLE_SIZE`. Symbols that do not fit are dropped.
+
+/// Must be the same as `consts::HV_BASE`.
+const HV_BASE: u64 = 0xffff_ff00_0000_0000;
+/// Must be the same as `backtrace::SYMBOL_TABLE_SIZE`.
+const SYMBOL_TABLE_SIZE: usize = 256 * 1024;
+
+/// Strip the hash suffix (e.g. `::h0123456789abcdef`) of a demangled name.
+fn strip_hash(name: &str) -> &str {
+    match name.rsplit_once("::h") {
+        Some((prefix, hash)) if hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit()) => {
+            prefix
+        }
+        _ => name,
+    }
+}
+
+/// Function symbols in the hypervisor, sorted by address.
+fn parse(content: &str) -> Vec<(u32, String)> {
+    let mut symbols = Vec::new();
+    for line in content.lines() {
+        let mut fields = line.splitn(3, ' ');
+        let (addr, ty, name) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(addr), Some(ty), Some(name)) => (addr, ty, name),
+            _ => continue,
+        };
+        let addr = match u64::from_str_radix(addr, 16) {
+            Ok(addr) if addr >= HV_BASE => addr - HV_BASE,
+            _ => continue,
+        };
+        if !matches!(ty, "t" | "T" | "W" | "w") {
+            continue;
+        }
+        symbols.push((addr as u32, strip_hash(name).to_string()));
+    }
+    symbols.sort_by_key(|&(addr, _)| addr);
+    symbols.dedup_by_key(|&mut (addr, _)| addr);
+    symbols
+}
+
+/// Build the table, returning it and the number of dropped symbols.
+fn build_table(mut symbols: Vec<(u32, String)>) -> (Vec<u8>, usize) {
+    let total = symbols.len();
+    let table_size = |symbols: &[(u32, String)]| {
+        4 + symbols.len() * 8 + symbols.iter().map(|(_, name)| name.len()).sum::<usize>()
+    };
+    while table_size(&symbols) > SYMBOL_TABLE_SIZE {
+        symbols.pop();
+    }
+
+    let mut table = Vec::with_capacity(SYMBOL_TABLE_SIZE);
+    table.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    let mut name_offset = 0;
+    for (addr, name) in &symbols {
+        table.extend_from_slice(&addr.to_le_bytes());
+        table.extend_from_slice(&(name_offset as u32).to_le_bytes());
+        name_offset += name.len();
+    }
+    for (_, name) in &symbols {
+        table.extend_from_slice(name.as_bytes());
+    }
+    table.resize(SYMBOL_TABLE_SIZE, 0);
+    (table, total - symbols.len())
+}
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if args.len() != 2 {
+        eprintln!("Usage: rvm-sym <rvm.sym> <symbols.bin>");
+        std::process::exit(1);
+    }
+
+    let content = match std::fs::read_to_string(&args[0]) {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("{}: {}", args[0], e);
+            std::process::exit(1);
+        }
+    };
+    let (table, dropped) = build_table(parse(&content));
+    if dropped > 0 {
+        eprintln!("warning: {} symbols do not fit in the table", dropped);
+    }
+    if let Err(e) = std::fs::write(&args[1], table) {
+        eprintln!("{}: {}", args[1], e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_table() {
+        let symbols = parse(
+            "ffffff0000001000 T rvm::main::h0123456789abcdef\n\
+             ffffff0000000800 t entry\n\
+             ffffff0000002000 r RODATA\n\
+             0000000000000400 T low\n",
+        );
+        assert_eq!(
+            symbols,
+            [
+                (0x800, "entry".to_string()),
+                (0x1000, "rvm::main".to_string())
+            ]
+        );
+        let (table, dropped) = build_table(symbols);
+        assert_eq!(dropped, 0);
+        assert_eq!(table.len(), SYMBOL_TABLE_SIZE);
+        assert_eq!(&table[..4], &2u32.to_le_bytes());
+        assert_eq!(&table[12..20], &[0, 0x10, 0, 0, 5, 0, 0, 0]);
+        assert_eq!(&table[20..35], b"entryrvm::main\0");
+    }
+}
@@ -0,0 +1,321 @@
+//! Decode VM exit trace buffers dumped by the `CpuGetTrace` hypercall.
+//!
+//! Usage: `rvm-trace [--json] [--vendor intel|amd] <dump>...`
+//!
+//! Each dump is the trace buffer of one CPU (see `src/trace.rs`). Entries are
+//! printed as text, or as Chrome trace JSON (`--json`) that can be loaded in
+//! `chrome://tracing` or Perfetto.
+
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+const TRACE_MAGIC: &[u8; 8] = b"RVMTRACE";
+const TRACE_VERSION: u32 = 1;
+const TRACE_HEADER_SIZE: usize = 64;
+const TRACE_ENTRY_SIZE: usize = 32;
+
+#[derive(Debug, PartialEq)]
+struct TraceEntry {
+    tsc: u64,
+    rip: u64,
+    qualification: u64,
+    reason: u32,
+    cycles: u32,
+}
+
+#[derive(Debug)]
+struct Trace {
+    cpu_id: u32,
+    tsc_mhz: u32,
+    /// Entries from the oldest to the newest.
+    entries: Vec<TraceEntry>,
+}
+
+#[derive(Clone, Copy)]
+enum Vendor {
+    Intel,
+    Amd,
+}
+
+fn u32_at(buf: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(buf[off..off + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(buf[off..off + 8].try_into().unwrap())
+}
+
+fn parse(buf: &[u8]) -> Result<Trace, String> {
+    if buf.len() < TRACE_HEADER_SIZE || &buf[..8] != TRACE_MAGIC {
+        return Err("not a RVM trace buffer".into());
+    }
+    let version = u32_at(buf, 8);
+    if version != TRACE_VERSION {
+        return Err(format!("unsupported trace version {}", version));
+    }
+    let cpu_id = u32_at(buf, 12);
+    let entry_size = u32_at(buf, 16) as usize;
+    let capacity = u32_at(buf, 20) as usize;
+    let total = u64_at(buf, 24);
+    let tsc_mhz = u32_at(buf, 32);
+    if entry_size < TRACE_ENTRY_SIZE || capacity == 0 {
+        return Err(format!(
+            "invalid entry size {} or capacity {}",
+            entry_size, capacity
+        ));
+    }
+    if buf.len() < TRACE_HEADER_SIZE + entry_size * capacity {
+        return Err("truncated trace buffer".into());
+    }
+
+    let count = total.min(capacity as u64) as usize;
+    let first = if total > capacity as u64 {
+        (total % capacity as u64) as usize
+    } else {
+        0
+    };
+    let entries = (0..count)
+        .map(|i| {
+            let off = TRACE_HEADER_SIZE + (first + i) % capacity * entry_size;
+            TraceEntry {
+                tsc: u64_at(buf, off),
+                rip: u64_at(buf, off + 8),
+                qualification: u64_at(buf, off + 16),
+                reason: u32_at(buf, off + 24),
+                cycles: u32_at(buf, off + 28),
+            }
+        })
+        .collect();
+    Ok(Trace {
+        cpu_id,
+        tsc_mhz: tsc_mhz.max(1),
+        entries,
+    })
+}
+
+fn reason_name(vendor: Option<Vendor>, reason: u32) -> String {
+    const VMX: &[&str] = &[
+        "EXCEPTION_NMI",
+        "EXTERNAL_INTERRUPT",
+        "TRIPLE_FAULT",
+        "INIT",
+        "SIPI",
+        "SMI",
+        "OTHER_SMI",
+        "INTERRUPT_WINDOW",
+        "NMI_WINDOW",
+        "TASK_SWITCH",
+        "CPUID",
+        "GETSEC",
+        "HLT",
+        "INVD",
+        "INVLPG",
+        "RDPMC",
+        "RDTSC",
+        "RSM",
+        "VMCALL",
+        "VMCLEAR",
+        "VMLAUNCH",
+        "VMPTRLD",
+        "VMPTRST",
+        "VMREAD",
+        "VMRESUME",
+        "VMWRITE",
+        "VMOFF",
+        "VMON",
+        "CR_ACCESS",
+        "DR_ACCESS",
+        "IO_INSTRUCTION",
+        "MSR_READ",
+        "MSR_WRITE",
+        "INVALID_GUEST_STATE",
+        "MSR_LOAD_FAIL",
+        "RESERVED_35",
+        "MWAIT_INSTRUCTION",
+        "MONITOR_TRAP_FLAG",
+        "RESERVED_38",
+        "MONITOR_INSTRUCTION",
+        "PAUSE_INSTRUCTION",
+        "MCE_DURING_VMENTRY",
+        "RESERVED_42",
+        "TPR_BELOW_THRESHOLD",
+        "APIC_ACCESS",
+        "VIRTUALIZED_EOI",
+        "GDTR_IDTR",
+        "LDTR_TR",
+        "EPT_VIOLATION",
+        "EPT_MISCONFIG",
+        "INVEPT",
+        "RDTSCP",
+        "PREEMPTION_TIMER",
+        "INVVPID",
+        "WBINVD",
+        "XSETBV",
+        "APIC_WRITE",
+        "RDRAND",
+        "INVPCID",
+        "VMFUNC",
+        "ENCLS",
+        "RDSEED",
+        "PML_FULL",
+        "XSAVES",
+        "XRSTORS",
+    ];
+    let name = match vendor {
+        Some(Vendor::Intel) => VMX.get(reason as usize).map(|s| s.to_string()),
+        Some(Vendor::Amd) => match reason {
+            0x00..=0x0f => Some(format!("CR{}_READ", reason)),
+            0x10..=0x1f => Some(format!("CR{}_WRITE", reason - 0x10)),
+            0x40..=0x5f => Some(format!("EXCP{}", reason - 0x40)),
+            0x60 => Some("INTR".into()),
+            0x61 => Some("NMI".into()),
+            0x72 => Some("CPUID".into()),
+            0x78 => Some("HLT".into()),
+            0x7b => Some("IOIO".into()),
+            0x7c => Some("MSR".into()),
+            0x7f => Some("SHUTDOWN".into()),
+            0x81 => Some("VMMCALL".into()),
+            0x400 => Some("NPF".into()),
+            _ => None,
+        },
+        None => None,
+    };
+    name.unwrap_or_else(|| format!("{:#x}", reason))
+}
+
+fn write_text(out: &mut String, traces: &[Trace], vendor: Option<Vendor>) {
+    for trace in traces {
+        let base = trace.entries.first().map_or(0, |e| e.tsc);
+        writeln!(out, "CPU {}: {} exits", trace.cpu_id, trace.entries.len()).unwrap();
+        for e in &trace.entries {
+            writeln!(
+                out,
+                "{:>14.3}us {:<20} rip={:#018x} qual={:#x} cycles={}",
+                (e.tsc - base) as f64 / trace.tsc_mhz as f64,
+                reason_name(vendor, e.reason),
+                e.rip,
+                e.qualification,
+                e.cycles
+            )
+            .unwrap();
+        }
+    }
+}
+
+fn write_json(out: &mut String, traces: &[Trace], vendor: Option<Vendor>) {
+    // Use the same time base for all CPUs, so that their events line up.
+    let base = traces
+        .iter()
+        .filter_map(|t| t.entries.first().map(|e| e.tsc))
+        .min()
+        .unwrap_or(0);
+    out.push_str("{\"traceEvents\":[");
+    let mut first = true;
+    for trace in traces {
+        let mhz = trace.tsc_mhz as f64;
+        for e in &trace.entries {
+            if !first {
+                out.push(',');
+            }
+            first = false;
+            write!(
+                out,
+                "\n{{\"name\":\"{}\",\"cat\":\"vmexit\",\"ph\":\"X\",\"pid\":0,\"tid\":{},\
+                \"ts\":{:.3},\"dur\":{:.3},\"args\":{{\"rip\":\"{:#x}\",\"qual\":\"{:#x}\"}}}}",
+                reason_name(vendor, e.reason),
+                trace.cpu_id,
+                e.tsc.saturating_sub(base) as f64 / mhz,
+                e.cycles as f64 / mhz,
+                e.rip,
+                e.qualification
+            )
+            .unwrap();
+        }
+    }
+    out.push_str("\n]}\n");
+}
+
+fn main() {
+    let mut json = false;
+    let mut vendor = None;
+    let mut files = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--vendor" => {
+                vendor = match args.next().as_deref() {
+                    Some("intel") => Some(Vendor::Intel),
+                    Some("amd") => Some(Vendor::Amd),
+                    _ => {
+                        eprintln!("--vendor must be `intel` or `amd`");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            _ => files.push(arg),
+        }
+    }
+    if files.is_empty() {
+        eprintln!("Usage: rvm-trace [--json] [--vendor intel|amd] <dump>...");
+        std::process::exit(1);
+    }
+
+    let mut traces = Vec::new();
+    for file in &files {
+        let trace = std::fs::read(file)
+            .map_err(|e| e.to_string())
+            .and_then(|buf| parse(&buf));
+        match trace {
+            Ok(trace) => traces.push(trace),
+            Err(e) => {
+                eprintln!("{}: {}", file, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let mut out = String::new();
+    if json {
+        write_json(&mut out, &traces, vendor);
+    } else {
+        write_text(&mut out, &traces, vendor);
+    }
+    io::stdout().write_all(out.as_bytes()).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dump(capacity: u32, entries: &[(u64, u32)]) -> Vec<u8> {
+        let mut buf = vec![0; TRACE_HEADER_SIZE + capacity as usize * TRACE_ENTRY_SIZE];
+        buf[..8].copy_from_slice(TRACE_MAGIC);
+        buf[8..12].copy_from_slice(&TRACE_VERSION.to_le_bytes());
+        buf[12..16].copy_from_slice(&3u32.to_le_bytes());
+        buf[16..20].copy_from_slice(&(TRACE_ENTRY_SIZE as u32).to_le_bytes());
+        buf[20..24].copy_from_slice(&capacity.to_le_bytes());
+        buf[24..32].copy_from_slice(&(entries.len() as u64).to_le_bytes());
+        buf[32..36].copy_from_slice(&1000u32.to_le_bytes());
+        for (i, &(tsc, reason)) in entries.iter().enumerate() {
+            let off = TRACE_HEADER_SIZE + i % capacity as usize * TRACE_ENTRY_SIZE;
+            buf[off..off + 8].copy_from_slice(&tsc.to_le_bytes());
+            buf[off + 24..off + 28].copy_from_slice(&reason.to_le_bytes());
+        }
+        buf
+    }
+
+    #[test]
+    fn test_parse_wrapped_ring() {
+        let buf = dump(2, &[(100, 10), (200, 18), (300, 48)]);
+        let trace = parse(&buf).unwrap();
+        assert_eq!(trace.cpu_id, 3);
+        let tscs: Vec<u64> = trace.entries.iter().map(|e| e.tsc).collect();
+        assert_eq!(tscs, [200, 300]);
+        assert_eq!(
+            reason_name(Some(Vendor::Intel), trace.entries[1].reason),
+            "EPT_VIOLATION"
+        );
+        assert!(parse(&buf[..TRACE_HEADER_SIZE]).is_err());
+    }
+}